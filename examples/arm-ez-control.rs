@@ -1,322 +1,129 @@
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use kcp_bindings::{HexSocketOpcode, HexSocketParser, KcpPortOwner};
+use futures_util::{FutureExt, StreamExt};
 use log::info;
-use prost::Message;
-use robot_demos::proto_public_api_version;
+use robot_demos::proto_public_api::{ApiDown, SingleMotorTarget};
 use robot_demos::{
-    confirm_and_continue, connect_websocket, create_kcp_socket, decode_message,
-    decode_websocket_message, init_logger, proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, init_logger, proto_public_api, ControlLoop, ControlStep, KcpConnection,
+    ParkingStopHandler, ParkingStopPolicy, RobotStateCache, RobotTarget,
 };
 
 const INTRO_TEXT: &str = "Control arm to zero torque, while printing data from the arm.";
 
-static MOTOR_CNT: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
-
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
+    #[command(flatten)]
+    robot: RobotTarget,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url)
+    // Connects over websocket, enables KCP, and sets websocket report frequency to 1Hz.
+    // Read `establish_kcp_session` to see how the handshake is done.
+    let mut session = profile
+        .establish_kcp_session()
         .await
-        .expect("Error during websocket handshake");
-    let (mut ws_sink, mut ws_stream) = ws_stream.split();
-
-    let (session_id, mut ws_stream) = {
-        let msg = decode_websocket_message(ws_stream.next().await.unwrap().unwrap(), true).unwrap();
-        (msg.session_id, ws_stream)
-    };
-
-    let (kcp_socket, local_port) = create_kcp_socket(&args.url).await.unwrap();
-
-    // Enable KCP
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::EnableKcp(
-                proto_public_api::EnableKcp {
-                    client_peer_port: local_port as u32,
-                    kcp_config: Some(proto_public_api::KcpConfig {
-                        window_size_snd_wnd: 64,
-                        window_size_rcv_wnd: 64,
-                        interval_ms: 10,
-                        no_delay: true,
-                        nc: true,
-                        resend: 2,
-                    }),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send enable KCP message");
-
-    let kcp_server_status = loop {
-        let msg = decode_websocket_message(ws_stream.next().await.unwrap().unwrap(), true).unwrap();
-        if msg.kcp_server_status.is_some() {
-            info!("KCP Enabled");
-            break msg.kcp_server_status.unwrap();
-        }
-    };
-
-    // KCP port is in kcp_config
-    let kcp_server_addr = format!("{}:{}", args.url, kcp_server_status.server_port)
-        .parse()
-        .unwrap();
-
-    // Please makesure kcp_port_owner lives long enough.
-    // You can consider moving it to Arc
-    let (kcp_port_owner, tx, mut rx) =
-        kcp_bindings::KcpPortOwner::new_costom_socket(kcp_socket, session_id, kcp_server_addr)
-            .await
-            .unwrap();
-
-    // Send any message to activate KCP connection.
-    // Here we just send a placeholder message.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::PlaceholderMessage(true)),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send placeholder message");
-
-    // Set websocket report frequency to 1Hz.
-    // Because we will be decoding KCP messages from now on.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf1Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send set report frequency message");
-
-    // Unconditionally clear parking stop on first connect.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::ArmCommand(
-                proto_public_api::ArmCommand {
-                    command: Some(proto_public_api::arm_command::Command::ArmSharedCommand(
-                        proto_public_api::ArmSharedCommand {
-                            command: Some(
-                                proto_public_api::arm_shared_command::Command::ClearParkingStop(
-                                    true,
-                                ),
-                            ),
-                        },
-                    )),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send clear parking stop message");
+        .expect("Failed to establish KCP session");
+
+    // Clear a parking stop on first connect, but only one our own program could have caused, like an API
+    // communication timeout of a previous run. Anything else, e.g. the emergency stop button, needs someone to look
+    // at the robot first.
+    let mut stream = session.take_stream().unwrap();
+    ParkingStopHandler::new(ParkingStopPolicy::default())
+        .settle(
+            &mut session,
+            &mut stream,
+            std::time::Duration::from_secs(5),
+            |session, msg| session.send_over_websocket(msg).boxed(),
+        )
+        .await
+        .expect("Robot is in a parking stop that is not cleared automatically");
 
-    // Spawn the websocket handle task
-    // Just ignore all messages from websocket.
-    // You can ofc still decode message if want to. Just be aware that you must keep the websocket connection alive.
+    // Spawn KCP data incoming handle task. It also keeps the latest arm status for the control loop.
+    let cache = RobotStateCache::new();
+    let print_cache = cache.clone();
     tokio::spawn(async move {
-        loop {
-            let _ = ws_stream.next().await;
-        }
-    });
-    // Spawn KCP data incoming handle task
-    tokio::spawn(async move {
-        let mut parser = HexSocketParser::new();
-        loop {
-            let bytes = match rx.recv().await {
-                Some(bytes) => bytes,
-                None => {
-                    println!("KCP connection lost");
-                    break;
-                }
-            };
-            if let Some(messages) = parser.parse(&bytes).unwrap() {
-                for (optcode, bytes) in messages {
-                    if optcode == HexSocketOpcode::Binary {
-                        let msg = decode_message(&bytes, true).unwrap();
-                        if let Some(status) = msg.status.clone() {
-                            match status {
-                                proto_public_api::api_up::Status::ArmStatus(arm_status) => {
-                                    // Prints motor status
-                                    let mut pos = Vec::new();
-                                    let len = arm_status.motor_status.len();
-                                    MOTOR_CNT.get_or_init(|| len);
-                                    for motor_status in arm_status.motor_status {
-                                        pos.push(motor_status.position);
-                                    }
-                                    info!("Position: {:?}", pos);
-                                }
-                                _ => {
-                                    panic!("Expected ArmStatus, got other robot status {:?}", msg)
-                                }
-                            }
+        while let Some(msg) = stream.next().await {
+            print_cache.update(&msg);
+            if let Some(status) = msg.status.clone() {
+                match status {
+                    proto_public_api::api_up::Status::ArmStatus(arm_status) => {
+                        // Prints motor status
+                        let mut pos = Vec::new();
+                        for motor_status in arm_status.motor_status {
+                            pos.push(motor_status.position);
                         }
+                        info!("Position: {:?}", pos);
+                    }
+                    _ => {
+                        panic!("Expected ArmStatus, got other robot status {:?}", msg)
                     }
                 }
             }
         }
+        println!("KCP connection lost");
     });
 
     // Change KCP Report Frequency to 250Hz.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf250Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send change frequency message");
-
-    let motor_cnt = loop {
-        let res = MOTOR_CNT.get();
-        if let Some(motor_cnt) = res {
-            break *motor_cnt;
-        } else {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
-    };
+    session
+        .send(ApiDown::set_report_frequency(profile.report_frequency_or(
+            proto_public_api::ReportFrequency::Rf250Hz,
+        )))
+        .await
+        .expect("Failed to send change frequency message");
 
     // Before sending move command, we need to set initialize the arm first.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::ArmCommand(
-                proto_public_api::ArmCommand {
-                    command: Some(proto_public_api::arm_command::Command::ArmExclusiveCommand(
-                        proto_public_api::ArmExclusiveCommand {
-                        exclusive_command: Some(proto_public_api::arm_exclusive_command::ExclusiveCommand::ApiControlInitialize(
-                            true,
-                        )),
-                        }
-                    )),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send initialize message");
+    session
+        .send(ApiDown::arm_api_control_initialize(true))
+        .await
+        .expect("Failed to send initialize message");
 
     // Calibrate the arm API control.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::ArmCommand(
-                proto_public_api::ArmCommand {
-                    command: Some(proto_public_api::arm_command::Command::ArmExclusiveCommand(
-                        proto_public_api::ArmExclusiveCommand {
-                        exclusive_command: Some(proto_public_api::arm_exclusive_command::ExclusiveCommand::Calibrate(
-                            true,
-                        )),
-                        }
-                    )),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send initialize message");
-
-    // Down, arm command, command, arm_exclusive_command, exclusive_command, command, motor_targets, targets, torque = 0.0
-    let zero_torque_message = proto_public_api::ApiDown {
-        down: Some(proto_public_api::api_down::Down::ArmCommand(
-            proto_public_api::ArmCommand {
-                command: Some(proto_public_api::arm_command::Command::ArmExclusiveCommand(
-                    proto_public_api::ArmExclusiveCommand {
-                        exclusive_command: Some(proto_public_api::arm_exclusive_command::ExclusiveCommand::ArmApiControlCommand(
-                            proto_public_api::ArmApiControlCommand {
-                                command: Some(proto_public_api::arm_api_control_command::Command::MotorTargets(
-                                    proto_public_api::MotorTargets {
-                                        targets: (0..motor_cnt).map(|_i| proto_public_api::SingleMotorTarget {
-                                            target: Some(proto_public_api::single_motor_target::Target::Torque(0.0)),
-                                        }).collect(),
-                                    },
-                                )),
-                            }
-                        )),
-                    }
-                )),
+    session
+        .send(ApiDown::arm_calibrate())
+        .await
+        .expect("Failed to send initialize message");
+
+    // Send zero torque at 250Hz for 10 seconds. The loop keeps the rate no matter how long sending takes,
+    // and warns if it can't keep up.
+    let mut connection = KcpConnection::from_session(session);
+    let stats = ControlLoop::new(250.0)
+        .expect("250Hz is a valid rate")
+        .run(&mut connection, &cache, |cache, tick| {
+            if tick.elapsed >= std::time::Duration::from_secs(10) {
+                return ControlStep::Stop;
             }
-        )),
-        protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-        protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-    };
-
-    let start_time = std::time::Instant::now();
-    while start_time.elapsed() < std::time::Duration::from_secs(10) {
-        // You can also use tokio's tick if you want
-        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
-        // Send binary messages
-        KcpPortOwner::send_binary(&tx, zero_torque_message.encode_to_vec())
-            .await
-            .expect("Failed to send zero torque message");
-    }
+            // Wait for the first arm status, to know how many motors there are.
+            let Some(arm_status) = cache.arm() else {
+                return ControlStep::Skip;
+            };
+            // Down, arm command, command, arm_exclusive_command, exclusive_command, command, motor_targets, targets, torque = 0.0
+            ControlStep::Send(ApiDown::arm_motor_targets(
+                arm_status
+                    .motor_status
+                    .iter()
+                    .map(|_| SingleMotorTarget::torque(0.0))
+                    .collect(),
+            ))
+        })
+        .await
+        .expect("Failed to send zero torque message");
+    info!("Control loop finished: {}", stats);
+    let mut session = connection.into_session();
 
     // This is essential because if arm lost control for a long time, it will enter protected state.
     // So lets tell the arm we are finishing our control session.
     // This is the last message we send to the arm, so inorder to make absolutely sure the arm is deinitialized,
     // we will send it over Websocket.
-    let deinitialize_bytes = proto_public_api::ApiDown {
-        down: Some(proto_public_api::api_down::Down::ArmCommand(
-            proto_public_api::ArmCommand {
-                command: Some(proto_public_api::arm_command::Command::ArmExclusiveCommand(
-                    proto_public_api::ArmExclusiveCommand {
-                        exclusive_command: Some(proto_public_api::arm_exclusive_command::ExclusiveCommand::ApiControlInitialize(false)),
-                    }
-                )),
-            }
-        )),
-        protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-        protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-    }
-    .encode_to_vec();
-    if let Err(e) = ws_sink
-        .send(tungstenite::Message::Binary(deinitialize_bytes.into()))
+    session
+        .send_over_websocket(ApiDown::arm_api_control_initialize(false))
         .await
-    {
-        panic!("Failed to send deinitialize message: {}", e);
-    }
-    ws_sink.close().await.expect("Failed to close websocket");
-    drop(tx);
-    drop(kcp_port_owner);
+        .expect("Failed to send deinitialize message");
+    session.close().await.expect("Failed to close websocket");
     info!("Successfully deinitialized arm");
 }
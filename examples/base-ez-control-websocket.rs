@@ -1,46 +1,59 @@
 use clap::Parser;
 use futures_util::StreamExt;
 use log::{info, warn};
-use robot_demos::proto_public_api_version;
+use robot_demos::proto_public_api::{ApiDown, RobotType};
 use robot_demos::{
-    confirm_and_continue, connect_websocket, decode_websocket_message, init_logger,
-    proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, decode_websocket_message, init_logger, proto_public_api,
+    send_api_down_message_to_websocket, PoseTracker, PoseTrackerOptions, RobotTarget,
+    VelocitySmoother,
 };
+use std::sync::{Arc, Mutex};
 
 const INTRO_TEXT: &str = "Control base to rotate at 0.1 rad/s, while printing data from the base.";
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Write the integrated trajectory here when done, as GeoJSON if the name ends in .geojson, CSV otherwise
+    #[arg(long)]
+    trajectory_out: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url)
+    let ws_stream = profile
+        .connect_websocket()
         .await
         .expect("Error during websocket handshake. Did you type the correct URL?");
     let (mut ws_sink, mut ws_stream) = ws_stream.split();
 
-    // Spawn the print task
+    // Spawn the print task, integrating the base's odometry into a pose as it goes
+    let tracker = Arc::new(Mutex::new(PoseTracker::with_options(PoseTrackerOptions {
+        record_trajectory: args.trajectory_out.is_some(),
+        ..Default::default()
+    })));
+    let print_tracker = tracker.clone();
     tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_stream.next().await {
             let msg = decode_websocket_message(msg, true).unwrap();
-            match msg.status {
+            match &msg.status {
                 Some(proto_public_api::api_up::Status::BaseStatus(base_status)) => {
-                    if let Some(estimated_odometry) = base_status.estimated_odometry {
+                    if let Some(estimated_odometry) = &base_status.estimated_odometry {
                         info!("Estimated odometry: {:?}", estimated_odometry);
                     }
+                    if let Some(sample) = print_tracker.lock().unwrap().update(&msg) {
+                        info!(
+                            "Pose: x {:.3}m, y {:.3}m, yaw {:.3}rad",
+                            sample.pose.x, sample.pose.y, sample.pose.yaw
+                        );
+                    }
                 }
                 _ => {
                     warn!("Unexpected status type: {}. Are you sure you are connecting to the correct robot?", msg.robot_type().as_str_name());
@@ -55,84 +68,69 @@ async fn main() {
     // This will only work for the current session, different sessions have independent report frequency settings.
     send_api_down_message_to_websocket(
         &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf50Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
+        ApiDown::set_report_frequency(
+            profile.report_frequency_or(proto_public_api::ReportFrequency::Rf50Hz),
+        ),
     )
     .await
     .expect("Failed to send set report frequency message");
 
     // Before sending move command, we need to set initialize the base first.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::BaseCommand(
-                proto_public_api::BaseCommand {
-                    command: Some(
-                        proto_public_api::base_command::Command::ApiControlInitialize(true),
-                    ),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send initialize message");
+    send_api_down_message_to_websocket(&mut ws_sink, ApiDown::base_api_control_initialize(true))
+        .await
+        .expect("Failed to send initialize message");
+    // Ramp the speed up and down instead of stepping it, so the base does not lurch.
+    // Without a robot type in the profile, the most careful limits are used.
+    let mut smoother =
+        VelocitySmoother::for_robot_type(profile.robot_type.unwrap_or(RobotType::RtUnknown))
+            .expect("Profile robot type is not a base");
+    // Down, base command, command, simple_move_command, vx = 0.0, vy = 0, w = 0.1
+    smoother
+        .set_target(0.0, 0.0, 0.1)
+        .expect("Constant speeds are finite");
+    let period = std::time::Duration::from_millis(20);
+
     let start_time = std::time::Instant::now();
     while start_time.elapsed() < std::time::Duration::from_secs(10) {
         // You can also use tokio's tick if you want
-        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        tokio::time::sleep(period).await;
 
-        // Down, base command, command, simple_move_command, vx = 0.0, vy = 0, w = 0.1
-        send_api_down_message_to_websocket(
-            &mut ws_sink,
-            proto_public_api::ApiDown {
-                down: Some(proto_public_api::api_down::Down::BaseCommand(
-                    proto_public_api::BaseCommand {
-                        command: Some(proto_public_api::base_command::Command::SimpleMoveCommand(
-                            proto_public_api::SimpleBaseMoveCommand {
-                                command: Some(
-                                    proto_public_api::simple_base_move_command::Command::XyzSpeed(
-                                        proto_public_api::XyzSpeed {
-                                            speed_x: 0.0,
-                                            speed_y: 0.0,
-                                            speed_z: 0.1,
-                                        },
-                                    ),
-                                ),
-                            },
-                        )),
-                    },
-                )),
-                protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-                protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-            },
-        )
-        .await
-        .expect("Failed to send move message");
+        send_api_down_message_to_websocket(&mut ws_sink, smoother.command(period))
+            .await
+            .expect("Failed to send move message");
+    }
+
+    // Ramp down to zero before deinitializing.
+    smoother
+        .set_target(0.0, 0.0, 0.0)
+        .expect("Constant speeds are finite");
+    while !smoother.is_stopped() {
+        tokio::time::sleep(period).await;
+        send_api_down_message_to_websocket(&mut ws_sink, smoother.command(period))
+            .await
+            .expect("Failed to send move message");
     }
     // This is essential because if base lost control for a long time, it will enter protected state.
     // So lets tell the base we are finishing our control session.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::BaseCommand(
-                proto_public_api::BaseCommand {
-                    command: Some(
-                        proto_public_api::base_command::Command::ApiControlInitialize(false),
-                    ),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send deinitialize message");
+    send_api_down_message_to_websocket(&mut ws_sink, ApiDown::base_api_control_initialize(false))
+        .await
+        .expect("Failed to send deinitialize message");
     info!("Successfully deinitialized base");
+
+    if let Some(path) = args.trajectory_out {
+        let tracker = tracker.lock().unwrap();
+        let file = std::fs::File::create(&path).expect("Failed to create trajectory file");
+        if path.ends_with(".geojson") {
+            tracker.write_geojson(file)
+        } else {
+            tracker.write_csv(file)
+        }
+        .expect("Failed to write trajectory");
+        info!(
+            "Wrote {} poses to {}, final pose {:?}",
+            tracker.trajectory().len(),
+            path,
+            tracker.pose()
+        );
+    }
 }
@@ -1,242 +1,127 @@
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use kcp_bindings::{HexSocketOpcode, HexSocketParser, KcpPortOwner};
+use futures_util::{FutureExt, StreamExt};
 use log::info;
-use prost::Message;
-use robot_demos::proto_public_api_version;
+use robot_demos::proto_public_api::ApiDown;
 use robot_demos::{
-    confirm_and_continue, connect_websocket, create_kcp_socket, decode_message,
-    decode_websocket_message, init_logger, proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, init_logger, proto_public_api, ParkingStopHandler, ParkingStopPolicy,
+    PoseTracker, PoseTrackerOptions, RobotTarget, VelocitySmoother,
 };
+use std::sync::{Arc, Mutex};
 
 const INTRO_TEXT: &str = "Control base to rotate at 0.1 rad/s, while printing data from the base.";
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Write the integrated trajectory here when done, as GeoJSON if the name ends in .geojson, CSV otherwise
+    #[arg(long)]
+    trajectory_out: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url).await.expect("Error during websocket handshake");
-    let (mut ws_sink, mut ws_stream) = ws_stream.split();
-
-    let (session_id, mut ws_stream) = {
-        let msg = decode_websocket_message(ws_stream.next().await.unwrap().unwrap(), true).unwrap();
-        (msg.session_id, ws_stream)
-    };
-
-    let (kcp_socket, local_port) = create_kcp_socket(&args.url).await.unwrap();
-
-    // Enable KCP
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::EnableKcp(
-                proto_public_api::EnableKcp {
-                    client_peer_port: local_port as u32,
-                    kcp_config: Some(proto_public_api::KcpConfig {
-                        window_size_snd_wnd: 64,
-                        window_size_rcv_wnd: 64,
-                        interval_ms: 10,
-                        no_delay: true,
-                        nc: true,
-                        resend: 2,
-                    }),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send enable KCP message");
-
-    let kcp_server_status = loop {
-        let msg = decode_websocket_message(ws_stream.next().await.unwrap().unwrap(), true).unwrap();
-        if msg.kcp_server_status.is_some() {
-            info!("KCP Enabled");
-            break msg.kcp_server_status.unwrap();
-        }
-    };
-
-    // KCP port is in kcp_config
-    let kcp_server_addr = format!("{}:{}", args.url, kcp_server_status.server_port)
-        .parse()
-        .unwrap();
-
-    // Please makesure kcp_port_owner lives long enough.
-    // You can consider moving it to Arc
-    let (kcp_port_owner, tx, mut rx) =
-        kcp_bindings::KcpPortOwner::new_costom_socket(kcp_socket, session_id, kcp_server_addr)
-            .await
-            .unwrap();
-
-    // Send any message to activate KCP connection.
-    // Here we just send a placeholder message.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::PlaceholderMessage(true)),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send placeholder message");
-
-    // Set websocket report frequency to 1Hz.
-    // Because we will be decoding KCP messages from now on.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf1Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send set report frequency message");
-
-    // Unconditionally clear parking stop on first connect.
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::BaseCommand(
-                proto_public_api::BaseCommand {
-                    command: Some(proto_public_api::base_command::Command::ClearParkingStop(
-                        true,
-                    )),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send clear parking stop message");
-
-    // Spawn the websocket handle task
-    // Just ignore all messages from websocket.
-    // You can ofc still decode message if want to. Just be aware that you must keep the websocket connection alive.
-    tokio::spawn(async move {
-        loop {
-            let _ = ws_stream.next().await;
-        }
-    });
+    // Connects over websocket, enables KCP, and sets websocket report frequency to 1Hz.
+    // Read `establish_kcp_session` to see how the handshake is done.
+    let mut session = profile
+        .establish_kcp_session()
+        .await
+        .expect("Failed to establish KCP session");
+
+    // Clear a parking stop on first connect, but only one our own program could have caused, like an API
+    // communication timeout of a previous run. Anything else, e.g. the emergency stop button, needs someone to look
+    // at the robot first.
+    let mut stream = session.take_stream().unwrap();
+    ParkingStopHandler::new(ParkingStopPolicy::default())
+        .settle(
+            &mut session,
+            &mut stream,
+            std::time::Duration::from_secs(5),
+            |session, msg| session.send_over_websocket(msg).boxed(),
+        )
+        .await
+        .expect("Robot is in a parking stop that is not cleared automatically");
 
     // Spawn KCP data incoming handle task
+    let tracker = Arc::new(Mutex::new(PoseTracker::with_options(PoseTrackerOptions {
+        record_trajectory: args.trajectory_out.is_some(),
+        ..Default::default()
+    })));
+    let print_tracker = tracker.clone();
     tokio::spawn(async move {
-        let mut parser = HexSocketParser::new();
-        loop {
-            let bytes = match rx.recv().await {
-                Some(bytes) => bytes,
-                None => {
-                    println!("KCP connection lost");
-                    break;
-                }
-            };
-            if let Some(messages) = parser.parse(&bytes).unwrap() {
-                for (optcode, bytes) in messages {
-                    if optcode == HexSocketOpcode::Binary {
-                        let msg = decode_message(&bytes, true).unwrap();
-                        if let Some(status) = msg.status.clone() {
-                            match status {
-                                proto_public_api::api_up::Status::BaseStatus(base_status) => {
-                                    // Prints Odom
-                                    if let Some(estimated_odometry) = base_status.estimated_odometry
-                                    {
-                                        info!("Estimated odometry: {:?}", estimated_odometry);
-                                    }
-                                }
-                                _ => {
-                                    panic!("Expected BaseStatus, got other robot status {:?}", msg)
-                                }
-                            }
+        while let Some(msg) = stream.next().await {
+            if let Some(status) = msg.status.clone() {
+                match status {
+                    proto_public_api::api_up::Status::BaseStatus(base_status) => {
+                        // Prints Odom
+                        if let Some(estimated_odometry) = base_status.estimated_odometry {
+                            info!("Estimated odometry: {:?}", estimated_odometry);
+                        }
+                        // And the pose integrated from it
+                        if let Some(sample) = print_tracker.lock().unwrap().update(&msg) {
+                            info!(
+                                "Pose: x {:.3}m, y {:.3}m, yaw {:.3}rad",
+                                sample.pose.x, sample.pose.y, sample.pose.yaw
+                            );
                         }
                     }
+                    _ => {
+                        panic!("Expected BaseStatus, got other robot status {:?}", msg)
+                    }
                 }
             }
         }
+        println!("KCP connection lost");
     });
 
     // Change KCP Report Frequency to 250Hz.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf250Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send initialize message");
+    session
+        .send(ApiDown::set_report_frequency(profile.report_frequency_or(
+            proto_public_api::ReportFrequency::Rf250Hz,
+        )))
+        .await
+        .expect("Failed to send initialize message");
 
     // Before sending move command, we need to set initialize the base first.
-    KcpPortOwner::send_binary(
-        &tx,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::BaseCommand(
-                proto_public_api::BaseCommand {
-                    command: Some(
-                        proto_public_api::base_command::Command::ApiControlInitialize(true),
-                    ),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        }
-        .encode_to_vec(),
-    )
-    .await
-    .expect("Failed to send initialize message");
+    session
+        .send(ApiDown::base_api_control_initialize(true))
+        .await
+        .expect("Failed to send initialize message");
 
+    // Ramp the speed up and down instead of stepping it, so the base does not lurch.
+    let mut smoother = VelocitySmoother::for_robot_type(session.robot_type())
+        .expect("Connected robot is not a base");
     // Down, base command, command, simple_move_command, vx = 0.0, vy = 0, w = 0.1
-    let move_message = proto_public_api::ApiDown {
-        down: Some(proto_public_api::api_down::Down::BaseCommand(
-            proto_public_api::BaseCommand {
-                command: Some(proto_public_api::base_command::Command::SimpleMoveCommand(
-                    proto_public_api::SimpleBaseMoveCommand {
-                        command: Some(
-                            proto_public_api::simple_base_move_command::Command::XyzSpeed(
-                                proto_public_api::XyzSpeed {
-                                    speed_x: 0.0,
-                                    speed_y: 0.0,
-                                    speed_z: 0.1,
-                                },
-                            ),
-                        ),
-                    },
-                )),
-            },
-        )),
-        protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-        protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-    };
+    smoother
+        .set_target(0.0, 0.0, 0.1)
+        .expect("Constant speeds are finite");
+    let period = std::time::Duration::from_millis(20);
 
     let start_time = std::time::Instant::now();
     while start_time.elapsed() < std::time::Duration::from_secs(10) {
         // You can also use tokio's tick if you want
-        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        tokio::time::sleep(period).await;
         // Send binary messages
-        KcpPortOwner::send_binary(&tx, move_message.encode_to_vec())
+        session
+            .send(smoother.command(period))
+            .await
+            .expect("Failed to send move message");
+    }
+
+    // Ramp down to zero before deinitializing.
+    smoother
+        .set_target(0.0, 0.0, 0.0)
+        .expect("Constant speeds are finite");
+    while !smoother.is_stopped() {
+        tokio::time::sleep(period).await;
+        session
+            .send(smoother.command(period))
             .await
             .expect("Failed to send move message");
     }
@@ -245,24 +130,27 @@ async fn main() {
     // So lets tell the base we are finishing our control session.
     // This is the last message we send to the base, so inorder to make absolutely sure the base is deinitialized,
     // we will send it over Websocket.
-    let deinitialize_bytes = proto_public_api::ApiDown {
-        down: Some(proto_public_api::api_down::Down::BaseCommand(
-            proto_public_api::BaseCommand {
-                command: Some(                proto_public_api::base_command::Command::ApiControlInitialize(false)),
-            },
-        )),
-        protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-        protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-    }
-    .encode_to_vec();
-    if let Err(e) = ws_sink
-        .send(tungstenite::Message::Binary(deinitialize_bytes.into()))
+    session
+        .send_over_websocket(ApiDown::base_api_control_initialize(false))
         .await
-    {
-        panic!("Failed to send deinitialize message: {}", e);
-    }
-    ws_sink.close().await.expect("Failed to close websocket");
-    drop(tx);
-    drop(kcp_port_owner);
+        .expect("Failed to send deinitialize message");
+    session.close().await.expect("Failed to close websocket");
     info!("Successfully deinitialized base");
+
+    if let Some(path) = args.trajectory_out {
+        let tracker = tracker.lock().unwrap();
+        let file = std::fs::File::create(&path).expect("Failed to create trajectory file");
+        if path.ends_with(".geojson") {
+            tracker.write_geojson(file)
+        } else {
+            tracker.write_csv(file)
+        }
+        .expect("Failed to write trajectory");
+        info!(
+            "Wrote {} poses to {}, final pose {:?}",
+            tracker.trajectory().len(),
+            path,
+            tracker.pose()
+        );
+    }
 }
@@ -0,0 +1,525 @@
+// Drive a base from the terminal.
+//
+// W/S, A/D and Q/E hold speed_x, speed_y and speed_z (yaw) for as long as the key keeps repeating. Speed is ramped
+// with `VelocitySmoother`, scaled by +/-. Space stops the base on the spot. The dead man: an axis whose key has not
+// been seen for `--deadman-ms` goes back to zero, so releasing a key (or losing the terminal) stops the base.
+// Most terminals only report presses, not releases; where they do report releases, the axis stops right away.
+//
+// The panel shows battery, base state, parking stop detail and odometry, live.
+//
+// With `--record-path`, the odometry path and speeds are saved on quit, to be replayed by the `teach-repeat` example.
+// With `--geofence`, every command goes through `GeofencedConnection`, which slows and stops the base at the fence.
+// The target is fenced before it is ramped, so the ramp starts from what the base really does once the fence lets go.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use futures_util::StreamExt;
+use ratatui::{
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Paragraph},
+    Frame,
+};
+use robot_demos::proto_public_api::{self, ApiDown};
+use robot_demos::{
+    confirm_and_continue, ControlLoop, ControlLoopStats, ControlSession, ControlStep, Geofence,
+    GeofencedConnection, Intervention, PathRecorder, PoseTracker, RobotConnection, RobotStateCache,
+    RobotTarget, VelocitySmoother,
+};
+
+const INTRO_TEXT: &str = "Drive the base with the keyboard. Make sure there is room around it.";
+
+const SPEED_SCALE_STEP: f64 = 0.1;
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Fraction of the maximum velocity to drive at, changed with +/-
+    #[arg(long, default_value_t = 0.3)]
+    speed_scale: f64,
+    /// An axis stops when its key has not been seen for this long. Must be longer than your keyboard's repeat delay
+    #[arg(long, default_value_t = 600)]
+    deadman_ms: u64,
+    /// Rate to send commands at
+    #[arg(long, default_value_t = 50.0)]
+    rate_hz: f64,
+    /// Save the driven path here on quit, for `teach-repeat --repeat`
+    #[arg(long)]
+    record_path: Option<String>,
+    /// Keep the base inside the geofence in this TOML file, see examples/geofence/lab.toml
+    #[arg(long)]
+    geofence: Option<String>,
+}
+
+/// One speed axis: the direction its keys ask for, and when they last did.
+#[derive(Default, Clone, Copy)]
+struct HeldAxis {
+    direction: f64,
+    pressed_at: Option<Instant>,
+}
+
+impl HeldAxis {
+    fn press(&mut self, direction: f64) {
+        self.direction = direction;
+        self.pressed_at = Some(Instant::now());
+    }
+
+    fn release(&mut self) {
+        self.direction = 0.0;
+        self.pressed_at = None;
+    }
+
+    /// The direction, or 0 if the dead man timed out.
+    fn direction(&self, deadman: Duration) -> f64 {
+        match self.pressed_at {
+            Some(at) if at.elapsed() <= deadman => self.direction,
+            _ => 0.0,
+        }
+    }
+}
+
+enum Action {
+    Quit,
+    Continue,
+}
+
+struct App {
+    /// speed_x, speed_y, speed_z.
+    axes: [HeldAxis; 3],
+    speed_scale: f64,
+    deadman: Duration,
+    emergency_zero: bool,
+    clear_parking_stop: bool,
+    status_line: String,
+}
+
+impl App {
+    fn held(&self) -> bool {
+        self.axes
+            .iter()
+            .any(|axis| axis.direction(self.deadman) != 0.0)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Deliberately no logger: log lines would scribble over the panel.
+    let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
+    let geofence = args.geofence.as_ref().map(Geofence::load).transpose()?;
+
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
+
+    let mut connection = profile.connect().await?;
+    let mut limiter = None;
+    if let Some(fence) = geofence {
+        let fenced = GeofencedConnection::new(connection, fence)?;
+        limiter = Some(fenced.limiter());
+        connection = Box::new(fenced);
+    }
+    let mut interventions = limiter.as_ref().map(|_| Vec::new());
+    let mut smoother =
+        VelocitySmoother::for_robot_type(connection.robot_type()).ok_or_else(|| {
+            anyhow::anyhow!("{} is not a base", connection.robot_type().as_str_name())
+        })?;
+    let mut session = ControlSession::base(connection).await?;
+    session
+        .send(ApiDown::set_report_frequency(profile.report_frequency_or(
+            proto_public_api::ReportFrequency::Rf50Hz,
+        )))
+        .await?;
+
+    let cache = RobotStateCache::new();
+    let tracker = Arc::new(Mutex::new(PoseTracker::new()));
+    let mut stream = cache.track(session.take_stream().unwrap());
+    let stream_tracker = tracker.clone();
+    let recorder = Arc::new(Mutex::new(PathRecorder::new()));
+    let stream_recorder = recorder.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = stream.next().await {
+            stream_tracker.lock().unwrap().update(&msg);
+            stream_recorder.lock().unwrap().update(&msg);
+        }
+    });
+
+    let control_loop = ControlLoop::new(args.rate_hz)?;
+    let period = control_loop.period();
+    let stats = control_loop.stats();
+    let mut app = App {
+        axes: [HeldAxis::default(); 3],
+        speed_scale: args.speed_scale.clamp(SPEED_SCALE_STEP, 1.0),
+        deadman: Duration::from_millis(args.deadman_ms),
+        emergency_zero: false,
+        clear_parking_stop: false,
+        status_line: String::new(),
+    };
+
+    let mut terminal = ratatui::init();
+    let original_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = crossterm::execute!(std::io::stdout(), PopKeyboardEnhancementFlags);
+        ratatui::restore();
+        original_hook(info);
+    }));
+    // Ask for key release events. Terminals that don't support it keep working on the dead man alone.
+    let release_events = crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if release_events {
+        crossterm::execute!(
+            std::io::stdout(),
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+        app.status_line = "Key releases reported, axes stop on release".to_string();
+    }
+
+    let mut ui_error = None;
+    let mut target_error = None;
+    let result = control_loop
+        .run(&mut session, &cache, |cache, _tick| {
+            let action = poll_keys(&mut app);
+            if let Err(e) = terminal.draw(|f| {
+                ui(
+                    f,
+                    &app,
+                    &smoother,
+                    cache,
+                    &tracker,
+                    &stats,
+                    interventions.as_deref(),
+                )
+            }) {
+                ui_error = Some(e);
+                return ControlStep::Stop;
+            }
+            match action {
+                Ok(Action::Quit) => return ControlStep::Stop,
+                Ok(Action::Continue) => {}
+                Err(e) => {
+                    ui_error = Some(e);
+                    return ControlStep::Stop;
+                }
+            }
+
+            if app.clear_parking_stop {
+                app.clear_parking_stop = false;
+                return ControlStep::Send(ApiDown::base_clear_parking_stop());
+            }
+            if app.emergency_zero {
+                // No ramp, stop on the spot.
+                app.emergency_zero = false;
+                app.axes = [HeldAxis::default(); 3];
+                smoother.reset();
+                return ControlStep::Send(ApiDown::base_xyz_speed(0.0, 0.0, 0.0));
+            }
+            let limits = smoother.limits();
+            let [x, y, z] = app.axes.map(|axis| axis.direction(app.deadman));
+            let mut target = [
+                x * limits.x.max_velocity * app.speed_scale,
+                y * limits.y.max_velocity * app.speed_scale,
+                z * limits.z.max_velocity * app.speed_scale,
+            ];
+            if let Some(limiter) = &limiter {
+                let limited = limiter.limit(target);
+                target = limited.speed;
+                interventions = Some(limited.interventions);
+            }
+            let [x, y, z] = target;
+            if let Err(e) = smoother.set_target(x, y, z) {
+                target_error = Some(e);
+                return ControlStep::Stop;
+            }
+            ControlStep::Send(smoother.command(period))
+        })
+        .await;
+
+    if release_events {
+        let _ = crossterm::execute!(std::io::stdout(), PopKeyboardEnhancementFlags);
+    }
+    ratatui::restore();
+
+    // Whatever ended the loop, bring the base to rest and hand control back.
+    if let Err(e) = smoother.ramp_down(&mut session, period).await {
+        eprintln!("Failed to ramp down: {}", e);
+    }
+    let confirmed = session.finish(Duration::from_secs(1)).await?;
+    println!(
+        "Base deinitialized{}",
+        if confirmed {
+            ""
+        } else {
+            " (not confirmed by the base)"
+        }
+    );
+    if let Some(path) = &args.record_path {
+        let taught = std::mem::take(&mut *recorder.lock().unwrap()).finish();
+        taught.save(path)?;
+        println!(
+            "Saved {:.2}m, {:.1}s path to {}",
+            taught.length(),
+            taught.duration().as_secs_f64(),
+            path
+        );
+    }
+    if let Some(e) = ui_error {
+        return Err(e.into());
+    }
+    if let Some(e) = target_error {
+        return Err(e.into());
+    }
+    let stats = result?;
+    println!("Control loop: {}", stats);
+    Ok(())
+}
+
+// ── Key handling ────────────────────────────────────────────────────────────
+
+/// Handles every key event that is already waiting, without blocking.
+fn poll_keys(app: &mut App) -> std::io::Result<Action> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()? {
+            if let Action::Quit = handle_key(app, key) {
+                return Ok(Action::Quit);
+            }
+        }
+    }
+    Ok(Action::Continue)
+}
+
+fn handle_key(app: &mut App, key: KeyEvent) -> Action {
+    let code = match key.code {
+        KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+        code => code,
+    };
+    if key.kind == KeyEventKind::Release {
+        let axis = match code {
+            KeyCode::Char('w' | 's') => 0,
+            KeyCode::Char('a' | 'd') => 1,
+            KeyCode::Char('q' | 'e') => 2,
+            _ => return Action::Continue,
+        };
+        app.axes[axis].release();
+        return Action::Continue;
+    }
+
+    match code {
+        KeyCode::Esc => return Action::Quit,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Action::Quit,
+        KeyCode::Char('w') => app.axes[0].press(1.0),
+        KeyCode::Char('s') => app.axes[0].press(-1.0),
+        // speed_y and speed_z are positive to the left.
+        KeyCode::Char('a') => app.axes[1].press(1.0),
+        KeyCode::Char('d') => app.axes[1].press(-1.0),
+        KeyCode::Char('q') => app.axes[2].press(1.0),
+        KeyCode::Char('e') => app.axes[2].press(-1.0),
+        KeyCode::Char(' ') => {
+            app.emergency_zero = true;
+            app.status_line = "Stopped".to_string();
+        }
+        KeyCode::Char('+' | '=') => {
+            app.speed_scale = (app.speed_scale + SPEED_SCALE_STEP).min(1.0);
+        }
+        KeyCode::Char('-' | '_') => {
+            app.speed_scale = (app.speed_scale - SPEED_SCALE_STEP).max(SPEED_SCALE_STEP);
+        }
+        KeyCode::Char('c') => {
+            app.clear_parking_stop = true;
+            app.status_line = "Clear parking stop sent".to_string();
+        }
+        _ => {}
+    }
+    Action::Continue
+}
+
+// ── UI rendering ────────────────────────────────────────────────────────────
+
+fn label(text: &str) -> Span<'static> {
+    Span::styled(text.to_string(), Style::default().fg(Color::Yellow))
+}
+
+fn heading(text: &str) -> Line<'static> {
+    Line::from(Span::styled(
+        text.to_string(),
+        Style::default()
+            .fg(Color::Green)
+            .add_modifier(Modifier::BOLD),
+    ))
+}
+
+fn ui(
+    frame: &mut Frame,
+    app: &App,
+    smoother: &VelocitySmoother,
+    cache: &RobotStateCache,
+    tracker: &Mutex<PoseTracker>,
+    stats: &tokio::sync::watch::Receiver<ControlLoopStats>,
+    interventions: Option<&[Intervention]>,
+) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .split(frame.area());
+
+    let mut lines = vec![heading("--- Command ---")];
+    let [tx, ty, tz] = smoother.target();
+    let [vx, vy, vz] = smoother.velocity();
+    lines.push(Line::from(vec![
+        label("Target: "),
+        Span::raw(format!("x {tx:+.2}m/s  y {ty:+.2}m/s  z {tz:+.2}rad/s")),
+    ]));
+    lines.push(Line::from(vec![
+        label("Sent:   "),
+        Span::raw(format!("x {vx:+.2}m/s  y {vy:+.2}m/s  z {vz:+.2}rad/s")),
+    ]));
+    lines.push(Line::from(vec![
+        label("Speed scale: "),
+        Span::raw(format!("{:.0}%", app.speed_scale * 100.0)),
+        Span::raw("   "),
+        if app.held() {
+            Span::styled("DRIVING", Style::default().fg(Color::Cyan))
+        } else {
+            Span::styled("idle", Style::default().fg(Color::DarkGray))
+        },
+    ]));
+    lines.push(Line::from(vec![
+        label("Loop: "),
+        Span::raw(stats.borrow().to_string()),
+    ]));
+    if let Some(interventions) = interventions {
+        lines.push(Line::from(vec![
+            label("Geofence: "),
+            if interventions.is_empty() {
+                Span::raw("clear")
+            } else {
+                Span::styled(
+                    interventions
+                        .iter()
+                        .map(|i| i.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    Style::default().fg(Color::Red),
+                )
+            },
+        ]));
+    }
+
+    lines.push(Line::from(""));
+    lines.push(heading("--- Base ---"));
+    match cache.base() {
+        None => lines.push(Line::from(Span::styled(
+            "Waiting for data...",
+            Style::default().fg(Color::DarkGray),
+        ))),
+        Some(base) => {
+            let state = proto_public_api::BaseState::try_from(base.state)
+                .map(|s| s.as_str_name().to_string())
+                .unwrap_or_else(|_| format!("Unknown({})", base.state));
+            lines.push(Line::from(vec![
+                label("State: "),
+                Span::raw(state),
+                Span::raw(format!(
+                    "   API control initialized: {}   session holder: {}",
+                    base.api_control_initialized, base.session_holder
+                )),
+            ]));
+
+            let mut battery = format!(
+                "{:.2}V  {:.1}%",
+                base.battery_voltage,
+                base.battery_thousandth as f64 / 10.0
+            );
+            if let Some(current) = base.battery_current {
+                battery += &format!("  {current:.2}A");
+            }
+            if base.battery_charging == Some(true) {
+                battery += "  charging";
+            }
+            lines.push(Line::from(vec![label("Battery: "), Span::raw(battery)]));
+
+            match &base.parking_stop_detail {
+                Some(detail) => {
+                    let category = proto_public_api::ParkingStopCategory::try_from(detail.category)
+                        .map(|c| c.as_str_name().to_string())
+                        .unwrap_or_else(|_| format!("Unknown({})", detail.category));
+                    lines.push(Line::from(vec![
+                        label("Parking stop: "),
+                        Span::styled(
+                            format!(
+                                "{} \"{}\" ({})",
+                                category,
+                                detail.reason,
+                                if detail.is_remotely_clearable {
+                                    "press C to clear"
+                                } else {
+                                    "not remotely clearable"
+                                }
+                            ),
+                            Style::default().fg(Color::Red),
+                        ),
+                    ]));
+                }
+                None => lines.push(Line::from(vec![label("Parking stop: "), Span::raw("none")])),
+            }
+            if let Some(warning) = base.warning {
+                let warning = proto_public_api::WarningCategory::try_from(warning)
+                    .map(|w| w.as_str_name().to_string())
+                    .unwrap_or_else(|_| format!("Unknown({})", warning));
+                lines.push(Line::from(vec![
+                    label("Warning: "),
+                    Span::styled(warning, Style::default().fg(Color::Red)),
+                ]));
+            }
+
+            if let Some(odometry) = &base.estimated_odometry {
+                lines.push(Line::from(vec![
+                    label("Odometry speed: "),
+                    Span::raw(format!(
+                        "x {:+.3}m/s  y {:+.3}m/s  z {:+.3}rad/s",
+                        odometry.speed_x, odometry.speed_y, odometry.speed_z
+                    )),
+                ]));
+                lines.push(Line::from(vec![
+                    label("Odometry position: "),
+                    Span::raw(format!(
+                        "x {:+.3}m  y {:+.3}m  z {:+.3}rad",
+                        odometry.pos_x, odometry.pos_y, odometry.pos_z
+                    )),
+                ]));
+            }
+            let pose = tracker.lock().unwrap().pose();
+            lines.push(Line::from(vec![
+                label("Integrated pose: "),
+                Span::raw(format!(
+                    "x {:+.3}m  y {:+.3}m  yaw {:+.3}rad",
+                    pose.x, pose.y, pose.yaw
+                )),
+            ]));
+        }
+    }
+
+    if !app.status_line.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from(Span::styled(
+            app.status_line.clone(),
+            Style::default().fg(Color::Cyan),
+        )));
+    }
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Base teleop ")),
+        chunks[0],
+    );
+    frame.render_widget(
+        Paragraph::new(
+            "W/S x  A/D y  Q/E yaw  +/- speed  Space stop  C clear parking stop  Esc quit",
+        )
+        .style(Style::default().fg(Color::DarkGray)),
+        chunks[1],
+    );
+}
@@ -1,29 +1,26 @@
 use clap::Parser;
 use futures_util::StreamExt;
 use log::info;
-use robot_demos::{confirm_and_continue, connect_websocket, decode_websocket_message, init_logger};
+use robot_demos::{confirm_and_continue, decode_websocket_message, init_logger, RobotTarget};
 
 const INTRO_TEXT: &str = "Print it's basic information.";
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
+    #[command(flatten)]
+    robot: RobotTarget,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url)
+    let ws_stream = profile
+        .connect_websocket()
         .await
         .expect("Error during websocket handshake. Did you type the correct URL?");
     let (_, mut ws_stream) = ws_stream.split();
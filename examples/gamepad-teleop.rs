@@ -0,0 +1,149 @@
+// Drive a base with the gamepad paired to the robot, through our own control stack.
+//
+// The robot reports its gamepad as a secondary device. `GamepadTeleop` maps the sticks to base speeds, which go
+// through `VelocitySmoother` like any other command. Hold the deadman button (left bumper by default) to drive;
+// release it, or let the gamepad go out of range, and the base ramps down. `--mapping` loads another layout, see
+// `examples/gamepad/mapping.toml`. `--geofence` keeps the base inside a fence, see `GeofencedConnection`; the
+// target is fenced before it is ramped, so the ramp starts from what the base really does once the fence lets go.
+// Ctrl-C ramps the base down, deinitializes it and quits.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Parser;
+use futures_util::StreamExt;
+use log::{info, warn};
+use robot_demos::proto_public_api::{self, ApiDown};
+use robot_demos::{
+    confirm_and_continue, init_logger, ControlLoop, ControlSession, ControlStep, GamepadMapping,
+    GamepadTeleop, Geofence, GeofencedConnection, RobotConnection, RobotStateCache, RobotTarget,
+    VelocitySmoother,
+};
+
+const INTRO_TEXT: &str =
+    "Drive the base with the gamepad paired to it. Hold the deadman button to move. Make sure there is room around it.";
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Gamepad mapping TOML file. Without it, the layout of examples/gamepad/mapping.toml is used
+    #[arg(long)]
+    mapping: Option<String>,
+    /// Fraction of the maximum velocity at full stick
+    #[arg(long, default_value_t = 0.3)]
+    speed_scale: f64,
+    /// Stop when the gamepad has not been reported for this long
+    #[arg(long, default_value_t = 300)]
+    timeout_ms: u64,
+    /// Rate to send commands at
+    #[arg(long, default_value_t = 50.0)]
+    rate_hz: f64,
+    /// Keep the base inside the geofence in this TOML file, see examples/geofence/lab.toml
+    #[arg(long)]
+    geofence: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    init_logger();
+    let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
+    let mapping = match &args.mapping {
+        Some(path) => GamepadMapping::load(path)?,
+        None => GamepadMapping::default(),
+    };
+    if mapping.base.is_none() {
+        return Err(anyhow::anyhow!("The mapping has no [base] section"));
+    }
+    let speed_scale = args.speed_scale.clamp(0.0, 1.0);
+    let geofence = args.geofence.as_ref().map(Geofence::load).transpose()?;
+
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
+
+    let mut connection = profile.connect().await?;
+    let mut limiter = None;
+    if let Some(fence) = geofence {
+        let fenced = GeofencedConnection::new(connection, fence)?;
+        limiter = Some(fenced.limiter());
+        connection = Box::new(fenced);
+    }
+    let robot_type = connection.robot_type();
+    let mut smoother = VelocitySmoother::for_robot_type(robot_type)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a base", robot_type.as_str_name()))?;
+    // The session deinitializes the base however this program ends.
+    let mut session = ControlSession::base(connection).await?;
+    session
+        .send(ApiDown::set_report_frequency(profile.report_frequency_or(
+            proto_public_api::ReportFrequency::Rf50Hz,
+        )))
+        .await?;
+
+    let teleop = Arc::new(Mutex::new(
+        GamepadTeleop::new(mapping).timeout(Duration::from_millis(args.timeout_ms)),
+    ));
+    let cache = RobotStateCache::new();
+    let mut stream = cache.track(session.take_stream().unwrap());
+    let stream_teleop = teleop.clone();
+    tokio::spawn(async move {
+        let mut paired = false;
+        while let Some(msg) = stream.next().await {
+            let found = stream_teleop.lock().unwrap().update_api_up(&msg);
+            if found != paired {
+                paired = found;
+                if paired {
+                    info!("Gamepad found");
+                } else {
+                    warn!("Gamepad no longer reported");
+                }
+            }
+        }
+    });
+
+    info!("Waiting for the gamepad, hold the deadman button to drive");
+    let control_loop = ControlLoop::new(args.rate_hz)?;
+    let period = control_loop.period();
+    let mut enabled = false;
+    let result = control_loop
+        .run_until(
+            &mut session,
+            &cache,
+            tokio::signal::ctrl_c(),
+            |_cache, _tick| {
+                let command = teleop.lock().unwrap().command();
+                if command.enabled != enabled {
+                    enabled = command.enabled;
+                    info!(
+                        "{}",
+                        if enabled {
+                            "Driving"
+                        } else {
+                            "Deadman released or gamepad lost, stopping"
+                        }
+                    );
+                }
+                let mut target = command
+                    .base_speed(smoother.limits())
+                    .map(|v| v * speed_scale);
+                if let Some(limiter) = &limiter {
+                    target = limiter.limit(target).speed;
+                }
+                let [x, y, z] = target;
+                if let Err(e) = smoother.set_target(x, y, z) {
+                    warn!("{}, stopping", e);
+                    return ControlStep::Stop;
+                }
+                ControlStep::Send(smoother.command(period))
+            },
+        )
+        .await;
+
+    if let Err(e) = smoother.ramp_down(&mut session, period).await {
+        warn!("Failed to ramp down: {}", e);
+    }
+    if !session.finish(Duration::from_secs(1)).await? {
+        warn!("Base did not confirm deinitialize");
+    }
+    info!("Control loop: {}", result?);
+    Ok(())
+}
@@ -2,15 +2,16 @@
 use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
+use robot_demos::proto_public_api::ApiDown;
 use robot_demos::{
-    confirm_and_continue, connect_websocket, decode_websocket_message, init_logger,
-    proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, decode_websocket_message, init_logger, proto_public_api,
+    send_api_down_message_to_websocket, RobotStateCache, RobotTarget,
 };
 use socketcan::tokio::CanFdSocket;
 use socketcan::{CanAnyFrame, CanDataFrame, EmbeddedFrame, ExtendedId, Id};
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicI32, AtomicU16, AtomicU8, Ordering};
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::sync::{mpsc, watch};
 
 const INTRO_TEXT: &str =
     "Do not run this demo unless you are told to do so by HexFellow. This demo will connect to a current protocol lift, then simulate as an old protocol one.";
@@ -33,16 +34,55 @@ const SPEED_AVERAGE_WINDOW: usize = 25;
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
-    #[arg(help = "Local CAN bus name to use.")]
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(long, help = "Local CAN bus name to use.")]
     local_can_bus: String,
 }
 
+/// The lift as the legacy protocol describes it, converted from the latest `LinearLiftStatus`.
+struct LegacyLift {
+    pulse_per_rotation: u32,
+    /// Can be negative (reverse).
+    current_pos_mm: i32,
+    /// Can be negative (reverse).
+    max_pos_mm: i32,
+    /// From the physical max speed, for CAN_ID_STATUS_FEEDBACK.
+    max_speed_mm_s: u16,
+    calibrating: bool,
+    abnormal: bool,
+}
+
+impl LegacyLift {
+    /// `None` until the robot reported a lift with a known `pulse_per_rotation`.
+    fn from_cache(cache: &RobotStateCache) -> Option<Self> {
+        let s = cache.linear_lift()?;
+        let ppr = s.pulse_per_rotation;
+        if ppr == 0 {
+            return None;
+        }
+        // 0: normal, 1: abnormal per protocol; treat only moving states as normal
+        let normal = matches!(
+            s.state(),
+            proto_public_api::LiftState::LsAlgrithmControl
+                | proto_public_api::LiftState::LsOvertakeControl
+        );
+        Some(Self {
+            pulse_per_rotation: ppr,
+            current_pos_mm: pulses_to_mm(s.current_pos, ppr),
+            max_pos_mm: pulses_to_mm(s.max_pos, ppr),
+            max_speed_mm_s: ((s.max_speed as u64 * 1000) / ppr as u64).min(u16::MAX as u64) as u16,
+            calibrating: s.state() == proto_public_api::LiftState::LsCalibrating,
+            abnormal: !normal,
+        })
+    }
+}
+
+/// Position in mm: pulses (can be negative) * 1000 / pulse_per_rotation
+fn pulses_to_mm(pulses: i64, pulse_per_rotation: u32) -> i32 {
+    (pulses * 1000 / pulse_per_rotation as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
 /// Commands from CAN handler to be sent to robot via WebSocket
 enum LiftCommand {
     TargetPos(i64),
@@ -54,9 +94,9 @@ enum LiftCommand {
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
     let (local_can_tx, local_can_rx) = CanFdSocket::open(&args.local_can_bus)
         .expect("Failed to open local CAN bus")
@@ -64,23 +104,17 @@ async fn main() {
 
     let (can_frame_tx, can_frame_rx) = mpsc::channel::<CanAnyFrame>(64);
 
-    // Shared state for legacy protocol (updated from WebSocket, read by CAN TX tasks).
-    // Position and velocity can be negative (reverse); use signed types so we don't overflow.
+    // Lift state reported by the robot (updated from WebSocket, read by CAN TX tasks), see `LegacyLift`.
+    let cache = RobotStateCache::new();
+    // Velocity in mm/s, can be negative (reverse). The protobuf has no current velocity field, so we derive it.
+    let (velocity_tx, velocity_rx) = watch::channel(0i32);
+    // Set by the controller over CAN.
     let enabled = std::sync::Arc::new(AtomicU8::new(0));
-    let current_pos_mm = std::sync::Arc::new(AtomicI32::new(0));
-    let current_velocity_mm_s = std::sync::Arc::new(AtomicI32::new(0));
-    let max_pos_mm = std::sync::Arc::new(AtomicI32::new(0));
-    let max_speed_from_cmd = std::sync::Arc::new(AtomicU16::new(0));
-    let calibrating = std::sync::Arc::new(AtomicU8::new(0));
-    let status_abnormal = std::sync::Arc::new(AtomicU8::new(0));
-    let error_code = std::sync::Arc::new(AtomicU16::new(0));
-    let pulse_per_rotation = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
-    // Max velocity (mm/s) from robot protobuf LinearLiftStatus.max_speed; used in CAN_ID_STATUS_FEEDBACK.
-    let robot_max_speed_mm_s = std::sync::Arc::new(AtomicU16::new(0));
 
     let (cmd_tx, mut cmd_rx) = mpsc::channel::<LiftCommand>(16);
 
-    let ws_stream = connect_websocket(&url)
+    let ws_stream = profile
+        .connect_websocket()
         .await
         .expect("Error during websocket handshake");
     let (mut ws_sink, mut ws_stream) = ws_stream.split();
@@ -88,52 +122,27 @@ async fn main() {
     // Set report frequency to 250Hz so we get frequent position/velocity updates
     send_api_down_message_to_websocket(
         &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf250Hz as i32,
-            )),
-        },
+        ApiDown::set_report_frequency(
+            profile.report_frequency_or(proto_public_api::ReportFrequency::Rf250Hz),
+        ),
     )
     .await
     .expect("Failed to send set report frequency message");
 
     // Task: receive WebSocket status and update shared state; receive commands and send to robot
-    let current_pos_mm_ws = current_pos_mm.clone();
-    let current_velocity_mm_ws = current_velocity_mm_s.clone();
-    let max_pos_mm_ws = max_pos_mm.clone();
-    let calibrating_ws = calibrating.clone();
-    let pulse_per_rotation_ws = pulse_per_rotation.clone();
-    let status_abnormal_ws = status_abnormal.clone();
-    let robot_max_speed_mm_s_ws = robot_max_speed_mm_s.clone();
+    let cache_ws = cache.clone();
     tokio::spawn(async move {
         let mut ws_sink = ws_sink;
         let mut speed_dq: VecDeque<(i64, u64)> = VecDeque::new();
         loop {
             tokio::select! {
                 Some(cmd) = cmd_rx.recv() => {
-                    let down = match cmd {
-                        LiftCommand::TargetPos(pos) => proto_public_api::api_down::Down::LinearLiftCommand(
-                            proto_public_api::LinearLiftCommand {
-                                command: Some(proto_public_api::linear_lift_command::Command::TargetPos(pos)),
-                            },
-                        ),
-                        LiftCommand::SetSpeed(speed) => proto_public_api::api_down::Down::LinearLiftCommand(
-                            proto_public_api::LinearLiftCommand {
-                                command: Some(proto_public_api::linear_lift_command::Command::SetSpeed(speed)),
-                            },
-                        ),
-                        LiftCommand::Calibrate => proto_public_api::api_down::Down::LinearLiftCommand(
-                            proto_public_api::LinearLiftCommand {
-                                command: Some(proto_public_api::linear_lift_command::Command::Calibrate(true)),
-                            },
-                        ),
+                    let msg = match cmd {
+                        LiftCommand::TargetPos(pos) => ApiDown::linear_lift_target_pos(pos),
+                        LiftCommand::SetSpeed(speed) => ApiDown::linear_lift_set_speed(speed),
+                        LiftCommand::Calibrate => ApiDown::linear_lift_calibrate(),
                     };
-                    if let Err(e) = send_api_down_message_to_websocket(
-                        &mut ws_sink,
-                        proto_public_api::ApiDown { down: Some(down) },
-                    )
-                    .await
-                    {
+                    if let Err(e) = send_api_down_message_to_websocket(&mut ws_sink, msg).await {
                         error!("Failed to send command to robot: {}", e);
                     }
                 }
@@ -147,21 +156,10 @@ async fn main() {
                                     continue;
                                 }
                             };
-                            if let Some(proto_public_api::api_up::Status::LinearLiftStatus(s)) = msg.status.clone() {
+                            cache_ws.update(&msg);
+                            if let Some(proto_public_api::api_up::Status::LinearLiftStatus(s)) = &msg.status {
                                 let ppr = s.pulse_per_rotation;
                                 if ppr > 0 {
-                                    pulse_per_rotation_ws.store(ppr, Ordering::Relaxed);
-                                    // position in mm: current_pos (pulses, can be negative) * 1000 / pulse_per_rotation
-                                    let pos_mm = (s.current_pos * 1000 / ppr as i64)
-                                        .clamp(i32::MIN as i64, i32::MAX as i64) as i32;
-                                    current_pos_mm_ws.store(pos_mm, Ordering::Relaxed);
-                                    let max_mm = (s.max_pos * 1000 / ppr as i64)
-                                        .clamp(i32::MIN as i64, i32::MAX as i64) as i32;
-                                    max_pos_mm_ws.store(max_mm, Ordering::Relaxed);
-                                    // Robot max_speed (physical max, pulses/s) -> mm/s for CAN_ID_STATUS_FEEDBACK
-                                    let max_speed_mm_s =
-                                        ((s.max_speed as u64 * 1000) / ppr as u64).min(u16::MAX as u64) as u16;
-                                    robot_max_speed_mm_s_ws.store(max_speed_mm_s, Ordering::Relaxed);
                                     // Velocity from position delta / time delta (protobuf has no current velocity field)
                                     if let Some(ts) = msg.time_stamp.as_ref() {
                                         let mono = ts.monotonic_time_stamp.as_ref().unwrap();
@@ -178,25 +176,10 @@ async fn main() {
                                                 let velocity_mm_s = (position_diff_mm / time_diff_s)
                                                     .clamp(i32::MIN as f64, i32::MAX as f64) as i32;
                                                 // info!("time diff: {} us, position diff: {} pulses, velocity: {} mm/s", time_diff_us, position_diff_pulses, velocity_mm_s);
-                                                current_velocity_mm_ws.store(velocity_mm_s, Ordering::Relaxed);
+                                                velocity_tx.send_replace(velocity_mm_s);
                                             }
                                         }
                                     }
-                                    calibrating_ws.store(
-                                        if s.state() == proto_public_api::LiftState::LsCalibrating {
-                                            1
-                                        } else {
-                                            0
-                                        },
-                                        Ordering::Relaxed,
-                                    );
-                                    // 0: normal, 1: abnormal per protocol; treat only moving states as normal
-                                    let normal = matches!(
-                                        s.state(),
-                                        proto_public_api::LiftState::LsAlgrithmControl
-                                            | proto_public_api::LiftState::LsOvertakeControl
-                                    );
-                                    status_abnormal_ws.store(if normal { 0 } else { 1 }, Ordering::Relaxed);
                                 }
                             }
                             if let Some(log_msg) = msg.log {
@@ -216,9 +199,7 @@ async fn main() {
 
     // Task: receive CAN frames (controller -> lifting platform) and update state / send commands
     let enabled_can = enabled.clone();
-    let max_speed_from_cmd_can = max_speed_from_cmd.clone();
-    let pulse_per_rotation_can = pulse_per_rotation.clone();
-    let max_pos_mm_can = max_pos_mm.clone();
+    let cache_can = cache.clone();
     let mut local_can_rx = local_can_rx;
     let can_frame_tx_reply = can_frame_tx.clone();
     tokio::spawn(async move {
@@ -241,19 +222,18 @@ async fn main() {
                 CAN_ID_STATUS_CMD if data.len() >= 3 => {
                     let return_to_zero = data[0];
                     let max_vel_mm_s = u16::from_le_bytes([data[1], data[2]]);
-                    max_speed_from_cmd_can.store(max_vel_mm_s, Ordering::Relaxed);
                     if return_to_zero != 0 {
                         let _ = cmd_tx.send(LiftCommand::Calibrate).await;
                     }
                     // Set robot speed from legacy max move velocity (mm/s -> pulses/s)
-                    let ppr = pulse_per_rotation_can.load(Ordering::Relaxed);
-                    if ppr > 0 {
-                        let speed_pulses_s = (max_vel_mm_s as u32 * ppr) / 1000;
+                    if let Some(lift) = LegacyLift::from_cache(&cache_can) {
+                        let speed_pulses_s = (max_vel_mm_s as u32 * lift.pulse_per_rotation) / 1000;
                         let _ = cmd_tx.send(LiftCommand::SetSpeed(speed_pulses_s)).await;
                     }
                 }
                 CAN_ID_REQUEST_MOVE_RANGE => {
-                    let max_mm = max_pos_mm_can.load(Ordering::Relaxed);
+                    let max_mm =
+                        LegacyLift::from_cache(&cache_can).map_or(0, |lift| lift.max_pos_mm);
                     // Payload: move_range u16 (mm, unsigned) + reverse u8 → 3 bytes
                     let move_range_u = max_mm.abs().min(u16::MAX as i32) as u16;
                     let reverse = if max_mm < 0 { 1u8 } else { 0u8 };
@@ -278,9 +258,8 @@ async fn main() {
                     } else {
                         pos_u as i32
                     };
-                    let ppr = pulse_per_rotation_can.load(Ordering::Relaxed);
-                    if ppr > 0 {
-                        let target_pulses = (pos_mm as i64 * ppr as i64) / 1000;
+                    if let Some(lift) = LegacyLift::from_cache(&cache_can) {
+                        let target_pulses = (pos_mm as i64 * lift.pulse_per_rotation as i64) / 1000;
                         let _ = cmd_tx.send(LiftCommand::TargetPos(target_pulses)).await;
                     }
                 }
@@ -317,22 +296,19 @@ async fn main() {
     });
 
     let status_tx = can_frame_tx.clone();
-    let status_abnormal_s = status_abnormal.clone();
-    let calibrating_s = calibrating.clone();
-    let error_code_s = error_code.clone();
-    let robot_max_speed_mm_s_fb = robot_max_speed_mm_s.clone();
+    let cache_s = cache.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
         loop {
             interval.tick().await;
             // Payload: status u8 + return_to_zero_status u8 + errorcode u16 + max_velocity u16 (from protobuf max_speed) → 6 bytes
+            // Until the lift is reported, everything reads as zero. The error code is always zero.
             let mut data = [0u8; 6];
-            data[0] = status_abnormal_s.load(Ordering::Relaxed);
-            data[1] = calibrating_s.load(Ordering::Relaxed);
-            let ec = error_code_s.load(Ordering::Relaxed);
-            data[2..4].copy_from_slice(&ec.to_le_bytes());
-            let max_vel = robot_max_speed_mm_s_fb.load(Ordering::Relaxed);
-            data[4..6].copy_from_slice(&max_vel.to_le_bytes());
+            if let Some(lift) = LegacyLift::from_cache(&cache_s) {
+                data[0] = lift.abnormal as u8;
+                data[1] = lift.calibrating as u8;
+                data[4..6].copy_from_slice(&lift.max_speed_mm_s.to_le_bytes());
+            }
             if let Some(f) = CanDataFrame::new(
                 Id::Extended(ExtendedId::new(CAN_ID_STATUS_FEEDBACK).unwrap()),
                 &data,
@@ -342,13 +318,12 @@ async fn main() {
         }
     });
 
-    let velocity_tx = can_frame_tx.clone();
-    let current_velocity_v = current_velocity_mm_s.clone();
+    let velocity_frame_tx = can_frame_tx.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(50));
         loop {
             interval.tick().await;
-            let vel = current_velocity_v.load(Ordering::Relaxed);
+            let vel = *velocity_rx.borrow();
             // Payload: velocity u16 (mm/s, unsigned) + reverse u8 → 3 bytes
             let vel_u = vel.abs().min(u16::MAX as i32) as u16;
             let reverse = if vel < 0 { 1u8 } else { 0u8 };
@@ -357,18 +332,18 @@ async fn main() {
                 Id::Extended(ExtendedId::new(CAN_ID_VELOCITY_FEEDBACK).unwrap()),
                 &data,
             ) {
-                let _ = velocity_tx.send(f.into()).await;
+                let _ = velocity_frame_tx.send(f.into()).await;
             }
         }
     });
 
     let position_tx = can_frame_tx.clone();
-    let current_pos_p = current_pos_mm.clone();
+    let cache_p = cache.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(50));
         loop {
             interval.tick().await;
-            let pos = current_pos_p.load(Ordering::Relaxed);
+            let pos = LegacyLift::from_cache(&cache_p).map_or(0, |lift| lift.current_pos_mm);
             // Payload: real position u16 (mm, unsigned) + reverse u8 → 3 bytes
             let pos_u = pos.abs().min(u16::MAX as i32) as u16;
             let reverse = if pos < 0 { 1u8 } else { 0u8 };
@@ -384,7 +359,8 @@ async fn main() {
 
     info!(
         "Legacy lift simulator running: WebSocket {} local CAN {}",
-        url, args.local_can_bus
+        profile.websocket_url(),
+        args.local_can_bus
     );
     std::future::pending::<()>().await;
 }
@@ -1,24 +1,20 @@
-#![allow(clippy::clone_on_copy)]
 use clap::Parser;
 use futures_util::StreamExt;
 use log::{error, info, warn};
-use robot_demos::proto_public_api_version;
+use robot_demos::proto_public_api::ApiDown;
 use robot_demos::{
-    confirm_and_continue, connect_websocket, decode_websocket_message, init_logger,
-    proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, decode_websocket_message, init_logger, proto_public_api,
+    send_api_down_message_to_websocket, RobotStateCache, RobotTarget,
 };
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
-    #[arg(help = "Percentage of max position to move to (e.g. 0.5)")]
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(long, help = "Percentage of max position to move to (e.g. 0.5)")]
     percentage: f64,
     #[arg(
+        long,
         help = "How fast to move (e.g. 0.9), default is 0.9",
         default_value = "0.9"
     )]
@@ -33,15 +29,11 @@ struct Args {
 
 const INTRO_TEXT: &str = "Control lift to move to a certain percentage of the max position.";
 
-lazy_static::lazy_static! {
-    static ref LINEAR_LIFT_MAX_POS: std::sync::OnceLock<i64> = std::sync::OnceLock::new();
-    static ref LINEAR_LIFT_MAX_SPEED: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
-}
-
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
     assert!(
         args.speed_factor > 0.0 && args.speed_factor <= 1.0,
         "Speed factor must be between 0.0 and 1.0, got: {}",
@@ -53,24 +45,27 @@ async fn main() {
             args.percentage
         );
     }
-    let url = format!("ws://{}:{}", args.url, args.port);
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url).await.expect("Error during websocket handshake");
+    let ws_stream = profile
+        .connect_websocket()
+        .await
+        .expect("Error during websocket handshake");
     let (mut ws_sink, mut ws_stream) = ws_stream.split();
+    // Latest lift status, shared with the main task.
+    let cache = RobotStateCache::new();
+    let mut linear_lift_status = cache.subscribe_linear_lift();
     // Spawn the print task
     tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_stream.next().await {
             let msg = decode_websocket_message(msg, true).unwrap();
+            cache.update(&msg);
             #[allow(clippy::single_match)]
             match msg.status {
                 Some(proto_public_api::api_up::Status::LinearLiftStatus(linear_lift_status)) => {
                     if linear_lift_status.calibrated {
                         let max = linear_lift_status.max_pos;
-                        // We don't care if this fails to make code simple
-                        let _ = LINEAR_LIFT_MAX_POS.set(max);
-                        let _ = LINEAR_LIFT_MAX_SPEED.set(linear_lift_status.max_speed);
                         let current = linear_lift_status.current_pos;
                         let percentage = current as f64 / max as f64;
                         let pulse_per_meter = linear_lift_status.pulse_per_rotation as f64;
@@ -99,68 +94,37 @@ async fn main() {
     // Set report frequency to 50Hz; Since its a simple demo.
     send_api_down_message_to_websocket(
         &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf50Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
+        ApiDown::set_report_frequency(
+            profile.report_frequency_or(proto_public_api::ReportFrequency::Rf50Hz),
+        ),
     )
     .await
     .expect("Failed to send set report frequency message");
 
     // Send calibrate command if required
     if args.re_calibrate {
-        send_api_down_message_to_websocket(
-            &mut ws_sink,
-            proto_public_api::ApiDown {
-                down: Some(proto_public_api::api_down::Down::LinearLiftCommand(
-                    proto_public_api::LinearLiftCommand {
-                        command: Some(proto_public_api::linear_lift_command::Command::Calibrate(
-                            true,
-                        )),
-                    },
-                )),
-                protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-                protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-            },
-        )
-        .await
-        .expect("Failed to send calibrate message");
+        send_api_down_message_to_websocket(&mut ws_sink, ApiDown::linear_lift_calibrate())
+            .await
+            .expect("Failed to send calibrate message");
     }
 
     let start_time = std::time::Instant::now();
+    // Max position is only valid once the lift is calibrated.
     let (max, max_speed) = {
-        loop {
-            if let Some(max) = LINEAR_LIFT_MAX_POS.get() {
-                if let Some(max_speed) = LINEAR_LIFT_MAX_SPEED.get() {
-                    break (max.clone(), max_speed.clone());
-                }
-            }
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
+        let status = linear_lift_status
+            .wait_for(|status| status.as_ref().is_some_and(|status| status.calibrated))
+            .await
+            .expect("Connection to robot lost");
+        let status = status.as_ref().unwrap();
+        (status.max_pos, status.max_speed)
     };
     let move_target = (args.percentage * max as f64) as i64;
 
     // Set speed to 90% of max speed
     let speed = (max_speed as f64 * args.speed_factor) as u32;
-    send_api_down_message_to_websocket(
-        &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::LinearLiftCommand(
-                proto_public_api::LinearLiftCommand {
-                    command: Some(proto_public_api::linear_lift_command::Command::SetSpeed(
-                        speed,
-                    )),
-                },
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
-    )
-    .await
-    .expect("Failed to send set speed message");
+    send_api_down_message_to_websocket(&mut ws_sink, ApiDown::linear_lift_set_speed(speed))
+        .await
+        .expect("Failed to send set speed message");
 
     // To keep this demo simple, we quit after 5 seconds no matter what.
     while start_time.elapsed() < std::time::Duration::from_secs(5) {
@@ -168,17 +132,7 @@ async fn main() {
         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
         send_api_down_message_to_websocket(
             &mut ws_sink,
-            proto_public_api::ApiDown {
-                down: Some(proto_public_api::api_down::Down::LinearLiftCommand(
-                    proto_public_api::LinearLiftCommand {
-                        command: Some(proto_public_api::linear_lift_command::Command::TargetPos(
-                            move_target,
-                    )),
-                },
-            )),
-                protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-                protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-            },
+            ApiDown::linear_lift_target_pos(move_target),
         )
         .await
         .expect("Failed to send move message");
@@ -6,7 +6,7 @@
 use clap::Parser;
 use futures_util::StreamExt;
 use log::info;
-use robot_demos::{confirm_and_continue, connect_websocket, decode_websocket_message, init_logger};
+use robot_demos::{confirm_and_continue, decode_websocket_message, init_logger, RobotTarget};
 use std::fs::File;
 use std::io;
 use std::os::fd::{AsRawFd, RawFd};
@@ -52,13 +52,9 @@ const INTRO_TEXT: &str = "Read time stamp from the robot.";
 
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
-    #[arg(help = "Device name to use for PTP (e.g. /dev/ptp0)")]
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(long, help = "Device name to use for PTP (e.g. /dev/ptp0)")]
     device: std::path::PathBuf,
 }
 
@@ -66,13 +62,16 @@ struct Args {
 async fn main() {
     init_logger();
     let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
     let ptp = PtpClock::open(args.device.to_str().unwrap())
         .expect("Failed to open PTP device, are you root? Did you add udev rules?");
-    let url = format!("ws://{}:{}", args.url, args.port);
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url).await.expect("Error during websocket handshake");
+    let ws_stream = profile
+        .connect_websocket()
+        .await
+        .expect("Error during websocket handshake");
     let (_, mut ws_stream) = ws_stream.split();
     while let Some(Ok(msg)) = ws_stream.next().await {
         let msg = decode_websocket_message(msg, true).unwrap();
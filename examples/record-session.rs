@@ -0,0 +1,77 @@
+use clap::Parser;
+use futures_util::StreamExt;
+use log::info;
+use robot_demos::{
+    confirm_and_continue, init_logger, Recorder, RecordingConnection, RobotConnection, RobotTarget,
+    Transport,
+};
+use std::time::Duration;
+
+const INTRO_TEXT: &str =
+    "Record everything the robot sends to a file, without sending any command.";
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        value_enum,
+        help = "Transport to record. Defaults to the profile's, then websocket"
+    )]
+    transport: Option<Transport>,
+    #[arg(long, default_value_t = 10, help = "How many seconds to record")]
+    seconds: u64,
+    #[arg(
+        long,
+        default_value = "session.rec",
+        help = "File to write the recording to"
+    )]
+    output: String,
+}
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+    let args = Args::parse();
+    let mut profile = args.robot.resolve().expect("Failed to load robot profile");
+    if let Some(transport) = args.transport {
+        profile.transport = Some(transport);
+    }
+
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
+
+    let connection = profile.connect().await.expect("Failed to connect to robot");
+    let recorder = Recorder::create(&args.output).expect("Failed to create recording file");
+    let mut connection = RecordingConnection::new(connection, recorder);
+    info!(
+        "Recording session {} of {:?} over {} to {}",
+        connection.session_id(),
+        connection.robot_type(),
+        connection.transport(),
+        args.output
+    );
+
+    // The recording happens while the stream is polled, so keep polling it.
+    let mut stream = connection.take_stream().unwrap();
+    let mut count = 0;
+    let deadline = tokio::time::sleep(Duration::from_secs(args.seconds));
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(_) => count += 1,
+                None => {
+                    info!("Connection lost");
+                    break;
+                }
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    connection
+        .recorder()
+        .flush()
+        .expect("Failed to write recording");
+    info!("Recorded {} messages to {}", count, args.output);
+}
@@ -0,0 +1,53 @@
+use clap::Parser;
+use futures_util::StreamExt;
+use log::info;
+use robot_demos::{init_logger, read_recording, replay, RecordedDirection, Transport};
+
+/// Print a recording made with `record-session` (or `RecordingConnection`), with its original timing.
+#[derive(Parser)]
+struct Args {
+    #[arg(help = "Recording file to replay")]
+    input: String,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Playback speed, e.g. 2 replays twice as fast. Use inf to replay as fast as possible"
+    )]
+    speed: f64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    init_logger();
+    let args = Args::parse();
+
+    let messages = read_recording(&args.input)?;
+    info!("Replaying {} messages from {}", messages.len(), args.input);
+
+    let mut stream = replay(messages, args.speed)?;
+    while let Some(msg) = stream.next().await {
+        let transport = Transport::from(msg.transport());
+        // Goes through the same decode path as live traffic.
+        match msg.direction() {
+            RecordedDirection::Up => match msg.decode_api_up() {
+                Ok(up) => info!("[{:?}] {} up: {:?}", msg.elapsed(), transport, up),
+                Err(e) => info!(
+                    "[{:?}] {} up, failed to decode: {}",
+                    msg.elapsed(),
+                    transport,
+                    e
+                ),
+            },
+            RecordedDirection::Down => match msg.decode_api_down() {
+                Ok(down) => info!("[{:?}] {} down: {:?}", msg.elapsed(), transport, down),
+                Err(e) => info!(
+                    "[{:?}] {} down, failed to decode: {}",
+                    msg.elapsed(),
+                    transport,
+                    e
+                ),
+            },
+        }
+    }
+    Ok(())
+}
@@ -1,52 +1,44 @@
-#![allow(clippy::clone_on_copy)]
 use clap::Parser;
 use futures_util::StreamExt;
 use log::info;
-use robot_demos::proto_public_api_version;
+use robot_demos::proto_public_api::{ApiDown, SingleMotorTarget};
 use robot_demos::{
-    confirm_and_continue, connect_websocket, decode_websocket_message, init_logger,
-    proto_public_api, send_api_down_message_to_websocket,
+    confirm_and_continue, decode_websocket_message, init_logger, proto_public_api,
+    send_api_down_message_to_websocket, RobotStateCache, RobotTarget,
 };
 
 const INTRO_TEXT: &str = "Control lift to move back zero.";
 
-lazy_static::lazy_static! {
-    static ref MOTOR_STATUS: std::sync::Mutex<Vec<proto_public_api::MotorStatus>> = std::sync::Mutex::new(Vec::new());
-}
-
 #[derive(Parser)]
 struct Args {
-    #[arg(
-        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
-    )]
-    url: String,
-    #[arg(help = "Port to connect to (e.g. 8439)")]
-    port: u16,
+    #[command(flatten)]
+    robot: RobotTarget,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let args = Args::parse();
-    let url = format!("ws://{}:{}", args.url, args.port);
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
 
-    confirm_and_continue(INTRO_TEXT, &args.url, args.port).await;
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
 
-    let ws_stream = connect_websocket(&url)
+    let ws_stream = profile
+        .connect_websocket()
         .await
         .expect("Error during websocket handshake");
     let (mut ws_sink, mut ws_stream) = ws_stream.split();
+    // Latest lift status, shared with the main task.
+    let cache = RobotStateCache::new();
+    let mut rotate_lift_status = cache.subscribe_rotate_lift();
     // Spawn the print task
     tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_stream.next().await {
             let msg = decode_websocket_message(msg, true).unwrap();
+            cache.update(&msg);
             #[allow(clippy::single_match)]
             match msg.status {
                 Some(proto_public_api::api_up::Status::RotateLiftStatus(lift_status)) => {
-                    // Collect motor status and print them
-                    {
-                        *MOTOR_STATUS.lock().unwrap() = lift_status.motor_status.clone();
-                    }
                     info!("Motor status: {:?}", lift_status.motor_status);
                 }
                 // Add other handles yourself
@@ -57,23 +49,23 @@ async fn main() {
     // Set report frequency to 250Hz; Since its a simple demo.
     send_api_down_message_to_websocket(
         &mut ws_sink,
-        proto_public_api::ApiDown {
-            down: Some(proto_public_api::api_down::Down::SetReportFrequency(
-                proto_public_api::ReportFrequency::Rf250Hz as i32,
-            )),
-            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-        },
+        ApiDown::set_report_frequency(
+            profile.report_frequency_or(proto_public_api::ReportFrequency::Rf250Hz),
+        ),
     )
     .await
     .expect("Failed to send set report frequency message");
 
     loop {
-        let motor_status = MOTOR_STATUS.lock().unwrap().clone();
-        if motor_status.is_empty() {
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-            continue;
-        }
+        // Wait for a new status, so we don't flood the lift with commands.
+        rotate_lift_status
+            .changed()
+            .await
+            .expect("Connection to robot lost");
+        let motor_status = match rotate_lift_status.borrow_and_update().as_ref() {
+            Some(status) if !status.motor_status.is_empty() => status.motor_status.clone(),
+            _ => continue,
+        };
         // Read current position of each motor. Get pulse per rotation from motor status. If error is with in 1Deg, break the loop.
         let mut break_loop = true;
         'inner: for motor in &motor_status {
@@ -91,25 +83,11 @@ async fn main() {
         }
         send_api_down_message_to_websocket(
             &mut ws_sink,
-            proto_public_api::ApiDown {
-                down: Some(proto_public_api::api_down::Down::RotateLiftCommand(
-                    proto_public_api::RotateLiftCommand {
-                        command: Some(proto_public_api::rotate_lift_command::Command::MotorTargets(
-                            proto_public_api::MotorTargets {
-                                targets: (0..motor_status.len())
-                                    .map(|_| proto_public_api::SingleMotorTarget {
-                                        target: Some(
-                                            proto_public_api::single_motor_target::Target::Position(0),
-                                        ),
-                                    })
-                                    .collect(),
-                            },
-                        )),
-                    },
-                )),
-                protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
-                protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
-            },
+            ApiDown::rotate_lift_motor_targets(
+                (0..motor_status.len())
+                    .map(|_| SingleMotorTarget::position(0))
+                    .collect(),
+            ),
         )
         .await
         .expect("Failed to send move to zero position message");
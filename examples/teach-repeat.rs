@@ -0,0 +1,145 @@
+// Teach a base a path by driving it, then let it repeat the path on its own.
+//
+// `--teach path.csv` only listens: drive the base with its own gamepad (or with `base-teleop` from another
+// terminal) and press Ctrl-C when done. `base-teleop --record-path path.csv` records while driving by keyboard.
+// `--repeat path.csv` drives the path again, from where it started. Put the base back there first.
+
+use clap::{ArgGroup, Parser};
+use futures_util::StreamExt;
+use log::{info, warn};
+use robot_demos::{
+    confirm_and_continue, init_logger, write_trajectory_csv, ControlSession, PathRecorder,
+    PathReplayer, ReplayEvent, RobotTarget, TaughtPath,
+};
+use std::time::Duration;
+
+const TEACH_INTRO_TEXT: &str =
+    "Record the path of the base while you drive it. Nothing is sent to the base.";
+const REPEAT_INTRO_TEXT: &str =
+    "Drive the base along a recorded path. Put it where the path starts, and make sure the path is clear.";
+
+#[derive(Parser)]
+#[command(group(ArgGroup::new("mode").required(true).args(["teach", "repeat"])))]
+struct Args {
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Record the path driven until Ctrl-C, and save it here
+    #[arg(long)]
+    teach: Option<String>,
+    /// Replay the path saved here
+    #[arg(long)]
+    repeat: Option<String>,
+    /// Replay speed relative to the recording, e.g. 0.5 for half speed
+    #[arg(long, default_value_t = 1.0)]
+    speed_scale: f64,
+    /// Stop the replay when the base gets further than this from the path, in meters
+    #[arg(long, default_value_t = 0.3)]
+    max_error: f64,
+    /// Stop the replay when the heading gets further than this from the path, in radians
+    #[arg(long, default_value_t = 0.5)]
+    max_yaw_error: f64,
+    /// With --repeat, write the driven trajectory here as CSV when done
+    #[arg(long)]
+    trajectory_out: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    init_logger();
+    let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
+
+    if let Some(path) = &args.teach {
+        confirm_and_continue(TEACH_INTRO_TEXT, &profile.address, profile.port).await;
+        let mut connection = profile.connect().await?;
+        let mut stream = connection.take_stream().unwrap();
+        let mut recorder = PathRecorder::new();
+        info!("Recording, drive the base and press Ctrl-C when done");
+        loop {
+            tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(msg) => {
+                        recorder.update(&msg);
+                    }
+                    None => {
+                        warn!("Connection lost, saving what was recorded");
+                        break;
+                    }
+                },
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+        let taught = recorder.finish();
+        if taught.points.len() < 2 {
+            return Err(anyhow::anyhow!("The base did not move, nothing to save"));
+        }
+        taught.save(path)?;
+        info!(
+            "Saved {} points, {:.2}m in {:.1}s, to {}",
+            taught.points.len(),
+            taught.length(),
+            taught.duration().as_secs_f64(),
+            path
+        );
+        return Ok(());
+    }
+
+    if !args.speed_scale.is_finite() || args.speed_scale <= 0.0 {
+        anyhow::bail!("--speed-scale must be positive, got: {}", args.speed_scale);
+    }
+    let path = TaughtPath::load(args.repeat.as_ref().unwrap())?;
+    confirm_and_continue(REPEAT_INTRO_TEXT, &profile.address, profile.port).await;
+    let connection = profile.connect().await?;
+    let robot_type = connection.robot_type();
+    let replayer = PathReplayer::for_robot_type(path, robot_type)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a base", robot_type.as_str_name()))?
+        .speed_scale(args.speed_scale)
+        .max_position_error(args.max_error)
+        .max_yaw_error(args.max_yaw_error);
+
+    let mut events = replayer.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            if let ReplayEvent::Progress {
+                path_time,
+                pose,
+                position_error,
+                ..
+            } = event
+            {
+                info!(
+                    "{:.1}s into the path: at ({:.2}, {:.2}, {:.2}), {:.3}m off",
+                    path_time.as_secs_f64(),
+                    pose.x,
+                    pose.y,
+                    pose.yaw,
+                    position_error
+                );
+            }
+        }
+    });
+
+    // The session deinitializes the base however this program ends. Ctrl-C aborts the replay.
+    let mut session = ControlSession::base(connection).await?;
+    let report = replayer
+        .run_until(&mut session, tokio::signal::ctrl_c())
+        .await?;
+    if !session.finish(Duration::from_secs(1)).await? {
+        warn!("Base did not confirm deinitialize");
+    }
+
+    match &report.aborted {
+        Some(reason) => warn!("Replay aborted: {}", reason),
+        None => info!("Replay finished"),
+    }
+    info!(
+        "Drove for {:.1}s, at most {:.3}m off the path",
+        report.elapsed.as_secs_f64(),
+        report.max_position_error
+    );
+    if let Some(path) = args.trajectory_out {
+        write_trajectory_csv(&report.trajectory, std::fs::File::create(&path)?)?;
+        info!("Wrote {} poses to {}", report.trajectory.len(), path);
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use clap::Parser;
+use log::{info, warn};
+use robot_demos::{
+    confirm_and_continue, init_logger, write_trajectory_csv, write_trajectory_geojson,
+    ControlSession, Mission, MissionEvent, Pose2D, RobotTarget, WaypointFollower,
+};
+use std::time::Duration;
+
+const INTRO_TEXT: &str =
+    "Drive the base through the waypoints of a mission file. Make sure the path is clear.";
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    robot: RobotTarget,
+    /// Mission file to drive, see examples/missions/square.toml
+    #[arg(long)]
+    mission: String,
+    /// Write the driven trajectory here when done, as GeoJSON if the name ends in .geojson, CSV otherwise
+    #[arg(long)]
+    trajectory_out: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    init_logger();
+    let args = Args::parse();
+    let profile = args.robot.resolve().expect("Failed to load robot profile");
+    let mission = Mission::load(&args.mission)?;
+
+    confirm_and_continue(INTRO_TEXT, &profile.address, profile.port).await;
+
+    let connection = profile.connect().await?;
+    let follower = WaypointFollower::for_robot_type(mission, connection.robot_type())?;
+
+    let mut events = follower.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            if let MissionEvent::Progress {
+                index,
+                pose,
+                distance,
+                yaw_error,
+            } = event
+            {
+                info!(
+                    "Waypoint {}: at ({:.2}, {:.2}, {:.2}), {:.2}m and {:.2}rad to go",
+                    index, pose.x, pose.y, pose.yaw, distance, yaw_error
+                );
+            }
+        }
+    });
+
+    // The session deinitializes the base however this program ends. Ctrl-C aborts the mission, the trajectory so
+    // far is still written.
+    let mut session = ControlSession::base(connection).await?;
+    let report = follower
+        .run_until(&mut session, tokio::signal::ctrl_c())
+        .await?;
+    if !session.finish(Duration::from_secs(1)).await? {
+        warn!("Base did not confirm deinitialize");
+    }
+
+    match &report.aborted {
+        Some(reason) => warn!("Mission aborted: {}", reason),
+        None => info!("Mission finished"),
+    }
+    info!(
+        "Reached {} of {} waypoints in {:.1}s, {} timed out",
+        report.reached.len(),
+        follower.mission().waypoints.len(),
+        report.elapsed.as_secs_f64(),
+        report.timed_out.len()
+    );
+
+    if let Some(path) = args.trajectory_out {
+        let file = std::fs::File::create(&path)?;
+        if path.ends_with(".geojson") {
+            write_trajectory_geojson(&report.trajectory, Pose2D::default(), file)?;
+        } else {
+            write_trajectory_csv(&report.trajectory, file)?;
+        }
+        info!("Wrote {} poses to {}", report.trajectory.len(), path);
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! The loop shared by [`crate::WaypointFollower`] and [`crate::PathReplayer`]: driving a base by its odometry.
+
+use crate::connection::RobotConnection;
+use crate::control_loop::{ControlLoop, ControlStep, Tick};
+use crate::odometry::{Pose2D, PoseSample, PoseTracker};
+use crate::state_cache::RobotStateCache;
+use crate::velocity_smoother::{VelocityLimits, VelocitySmoother};
+use futures_util::StreamExt;
+use log::warn;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What the control closure of [`BaseDrive::run`] wants done on a tick.
+pub(crate) enum DriveStep {
+    /// Ramp towards this body frame speed.
+    Target([f64; 3]),
+    /// Done, ramp down.
+    Finish,
+    /// Stop early for this reason, and ramp down.
+    Abort(String),
+}
+
+/// How a [`BaseDrive::run`] ended.
+pub(crate) struct DriveOutcome {
+    /// Why the drive stopped early, `None` if the control closure finished it.
+    pub aborted: Option<String>,
+    pub elapsed: Duration,
+    pub trajectory: Vec<PoseSample>,
+}
+
+/// The odometry the control loop reads, written by the stream task.
+struct Odometry {
+    tracker: PoseTracker,
+    /// When the last pose arrived, to notice odometry going stale.
+    updated_at: Option<Instant>,
+}
+
+pub(crate) struct BaseDrive {
+    pub limits: VelocityLimits,
+    pub rate_hz: f64,
+    /// Stop when no pose arrived for this long, or for four times as long at the start.
+    pub stale_odometry: Duration,
+}
+
+impl BaseDrive {
+    /// Takes the connection's stream into `tracker`, and calls `control` with the pose every tick, ramping the
+    /// base towards the speed it returns. Stops on a parking stop, on stale odometry, or once `stop` completes,
+    /// telling `on_abort` why. However it ends, the base is ramped down to zero speed before this returns.
+    pub async fn run<C, F, A>(
+        &self,
+        connection: &mut C,
+        tracker: PoseTracker,
+        stop: impl Future,
+        mut control: F,
+        mut on_abort: A,
+    ) -> Result<DriveOutcome, anyhow::Error>
+    where
+        C: RobotConnection + ?Sized,
+        F: FnMut(Pose2D, &Tick) -> DriveStep,
+        A: FnMut(&str),
+    {
+        let control_loop = ControlLoop::new(self.rate_hz)?;
+        let period = control_loop.period();
+        let mut smoother = VelocitySmoother::new(self.limits)?;
+        let stream = connection
+            .take_stream()
+            .ok_or_else(|| anyhow::anyhow!("The connection's stream was already taken"))?;
+        let cache = RobotStateCache::new();
+        let odometry = Arc::new(Mutex::new(Odometry {
+            tracker,
+            updated_at: None,
+        }));
+        let stream_odometry = odometry.clone();
+        let mut stream = cache.track(stream);
+        let stream_task = tokio::spawn(async move {
+            while let Some(msg) = stream.next().await {
+                let mut odometry = stream_odometry.lock().unwrap();
+                if odometry.tracker.update(&msg).is_some() {
+                    odometry.updated_at = Some(Instant::now());
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let mut aborted = None;
+        let mut abort = |reason: String| {
+            on_abort(&reason);
+            aborted = Some(reason);
+            ControlStep::Stop
+        };
+        let mut stopped = false;
+        let stop = async {
+            stop.await;
+            stopped = true;
+        };
+
+        let result = control_loop
+            .run_until(connection, &cache, stop, |cache, tick| {
+                if let Some(detail) = cache.base().and_then(|base| base.parking_stop_detail) {
+                    return abort(format!("base is in parking stop: {}", detail.reason));
+                }
+                let (pose, updated_at) = {
+                    let odometry = odometry.lock().unwrap();
+                    (odometry.tracker.pose(), odometry.updated_at)
+                };
+                match updated_at {
+                    // Nothing to go on yet.
+                    None if tick.elapsed < self.stale_odometry * 4 => return ControlStep::Skip,
+                    Some(at) if at.elapsed() <= self.stale_odometry => {}
+                    _ => return abort("no odometry from the base".to_string()),
+                }
+
+                match control(pose, tick) {
+                    DriveStep::Target([x, y, z]) => match smoother.set_target(x, y, z) {
+                        Ok(()) => ControlStep::Send(smoother.command(period)),
+                        Err(e) => abort(e.to_string()),
+                    },
+                    DriveStep::Finish => ControlStep::Stop,
+                    DriveStep::Abort(reason) => abort(reason),
+                }
+            })
+            .await;
+        if stopped {
+            abort("stopped".to_string());
+        }
+
+        // Stop the base before anything else, also when sending failed, in case it was a hiccup.
+        if let Err(e) = smoother.ramp_down(connection, period).await {
+            warn!("Failed to ramp down: {}", e);
+        }
+        stream_task.abort();
+        result?;
+
+        let trajectory = odometry.lock().unwrap().tracker.trajectory().to_vec();
+        Ok(DriveOutcome {
+            aborted,
+            elapsed: start.elapsed(),
+            trajectory,
+        })
+    }
+}
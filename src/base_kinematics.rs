@@ -0,0 +1,382 @@
+use crate::proto_public_api::{ApiDown, MotorStatus, SingleMotorTarget};
+use std::fmt;
+
+/// Velocity of the base in its own frame, in `XyzSpeed` units: `x` forward and `y` left in m/s, `z` the yaw rate
+/// in rad/s, counter-clockwise positive.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Twist {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Twist {
+    pub const fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+}
+
+/// Where one wheel sits and how its motor turns it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wheel {
+    /// Contact point in the base frame, m. `x` forward, `y` left.
+    pub x: f64,
+    pub y: f64,
+    /// How much sideways base speed shows up as rolling speed. 0 for a plain wheel, ±1 for a 45° mecanum wheel.
+    ///
+    /// The wheel rolls forward at `twist.x - y * twist.z + roller * (twist.y + x * twist.z)`.
+    pub roller: f64,
+    /// 1.0 if a positive motor speed rolls the wheel forward, -1.0 if it rolls it backward. Motor speed is positive
+    /// counter-clockwise around the motor axis, so mirrored motors on the two sides turn opposite ways.
+    pub direction: f64,
+}
+
+impl Wheel {
+    pub const fn new(x: f64, y: f64, roller: f64, direction: f64) -> Self {
+        Self {
+            x,
+            y,
+            roller,
+            direction,
+        }
+    }
+
+    /// Row of the Jacobian from body twist to the wheel's rolling speed.
+    fn jacobian(&self) -> [f64; 3] {
+        [1.0, self.roller, -self.y + self.roller * self.x]
+    }
+}
+
+#[derive(Debug)]
+pub enum KinematicsError {
+    /// A motor list does not have one entry per wheel.
+    MotorCount { expected: usize, actual: usize },
+    /// A motor reported a wheel radius that can't be used, e.g. 0 on a robot that does not fill it in.
+    WheelRadius { motor: usize, radius: f64 },
+}
+
+impl fmt::Display for KinematicsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KinematicsError::MotorCount { expected, actual } => write!(
+                f,
+                "Base kinematics has {} wheels, but got {} motors",
+                expected, actual
+            ),
+            KinematicsError::WheelRadius { motor, radius } => {
+                write!(f, "Motor {} has unusable wheel radius {}", motor, radius)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KinematicsError {}
+
+/// Forward and inverse kinematics of a wheeled base, to command the motors directly with `MotorTargets` instead of
+/// `XyzSpeed`.
+///
+/// [`BaseKinematics::motor_targets`] turns a [`Twist`] into one speed target per motor, and
+/// [`BaseKinematics::twist_from_status`] turns the motor feedback back into the [`Twist`] the wheels are driving,
+/// which can be compared against `estimated_odometry` to check the model.
+///
+/// Wheel speeds are converted using the `wheel_radius` each motor reports in its `MotorStatus`, so only the wheel
+/// placement is part of the model. Wheels are in the same order as the motors in `BaseStatus::motor_status`.
+/// There is no built-in geometry per `RobotType`: measure your base, and check the model by comparing
+/// [`BaseKinematics::twist_from_status`] with `estimated_odometry`.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::{api_up, ApiDown};
+/// use robot_demos::{connect_robot, BaseKinematics, RobotConnection, Transport, Twist};
+/// use futures_util::StreamExt;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     // A differential base with its wheels 0.4m apart.
+///     let kinematics = BaseKinematics::differential(0.4);
+///     let mut stream = connection.take_stream().unwrap();
+///     let motor_status = loop {
+///         if let Some(api_up::Status::BaseStatus(status)) = stream.next().await.unwrap().status {
+///             break status.motor_status;
+///         }
+///     };
+///     connection.send(ApiDown::base_api_control_initialize(true)).await?;
+///     let targets = kinematics.motor_targets(Twist::new(0.2, 0.0, 0.0), &motor_status, 10.0)?;
+///     connection.send(ApiDown::base_motor_targets(targets)).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaseKinematics {
+    wheels: Vec<Wheel>,
+}
+
+impl BaseKinematics {
+    pub fn new(wheels: Vec<Wheel>) -> Self {
+        Self { wheels }
+    }
+
+    /// Two wheels, `[left, right]`, `track_width` apart.
+    pub fn differential(track_width: f64) -> Self {
+        let half = track_width / 2.0;
+        Self::new(vec![
+            Wheel::new(0.0, half, 0.0, -1.0),
+            Wheel::new(0.0, -half, 0.0, 1.0),
+        ])
+    }
+
+    /// Four plain wheels, `[front left, front right, rear left, rear right]`, steered by driving the two sides at
+    /// different speeds. Wheel slip while turning is ignored, so the yaw rate is only approximate.
+    pub fn skid_steer(track_width: f64, wheelbase: f64) -> Self {
+        Self::four_wheels(track_width, wheelbase, 0.0)
+    }
+
+    /// Four 45° mecanum wheels in the usual X layout, `[front left, front right, rear left, rear right]`. Driving
+    /// only the front left and rear right wheel forward moves the base forward and to the right.
+    pub fn mecanum(track_width: f64, wheelbase: f64) -> Self {
+        Self::four_wheels(track_width, wheelbase, 1.0)
+    }
+
+    fn four_wheels(track_width: f64, wheelbase: f64, roller: f64) -> Self {
+        let (half_x, half_y) = (wheelbase / 2.0, track_width / 2.0);
+        Self::new(vec![
+            Wheel::new(half_x, half_y, -roller, -1.0),
+            Wheel::new(half_x, -half_y, roller, 1.0),
+            Wheel::new(-half_x, half_y, roller, -1.0),
+            Wheel::new(-half_x, -half_y, -roller, 1.0),
+        ])
+    }
+
+    pub fn wheels(&self) -> &[Wheel] {
+        &self.wheels
+    }
+
+    /// Whether the base can move sideways. If not, the `y` of a twist is ignored.
+    pub fn is_holonomic(&self) -> bool {
+        self.wheels.iter().any(|wheel| wheel.roller != 0.0)
+    }
+
+    /// Inverse kinematics: the motor speed of each wheel in rad/s, given the wheel radii in m.
+    pub fn wheel_speeds(
+        &self,
+        twist: Twist,
+        wheel_radius: &[f64],
+    ) -> Result<Vec<f64>, KinematicsError> {
+        self.check_radii(wheel_radius)?;
+        let twist = [twist.x, twist.y, twist.z];
+        Ok(self
+            .wheels
+            .iter()
+            .zip(wheel_radius)
+            .map(|(wheel, radius)| {
+                let rolling: f64 = wheel.jacobian().iter().zip(twist).map(|(j, t)| j * t).sum();
+                wheel.direction * rolling / radius
+            })
+            .collect())
+    }
+
+    /// Forward kinematics: the twist that best matches the motor speeds in rad/s, given the wheel radii in m.
+    ///
+    /// With more wheels than the base has degrees of freedom this is the least squares fit, so wheels fighting each
+    /// other (or slipping) average out.
+    pub fn twist(
+        &self,
+        motor_speeds: &[f64],
+        wheel_radius: &[f64],
+    ) -> Result<Twist, KinematicsError> {
+        self.check_count(motor_speeds.len())?;
+        self.check_radii(wheel_radius)?;
+        // Normal equations JᵀJ t = Jᵀv, where v are the rolling speeds at the contact points.
+        let mut jtj = [[0.0; 3]; 3];
+        let mut jtv = [0.0; 3];
+        for ((wheel, speed), radius) in self.wheels.iter().zip(motor_speeds).zip(wheel_radius) {
+            let row = wheel.jacobian();
+            let rolling = wheel.direction * speed * radius;
+            for i in 0..3 {
+                jtv[i] += row[i] * rolling;
+                for j in 0..3 {
+                    jtj[i][j] += row[i] * row[j];
+                }
+            }
+        }
+        if self.is_holonomic() {
+            let [x, y, z] = solve3(jtj, jtv);
+            Ok(Twist::new(x, y, z))
+        } else {
+            // Without rollers the `y` column is all zero, solve for `x` and `z` only.
+            let det = jtj[0][0] * jtj[2][2] - jtj[0][2] * jtj[2][0];
+            let x = (jtv[0] * jtj[2][2] - jtj[0][2] * jtv[2]) / det;
+            let z = (jtj[0][0] * jtv[2] - jtv[0] * jtj[2][0]) / det;
+            Ok(Twist::new(x, 0.0, z))
+        }
+    }
+
+    /// [`BaseKinematics::wheel_speeds`] as `MotorTargets`, using the wheel radius of each motor and limiting every
+    /// motor to `max_current` A.
+    pub fn motor_targets(
+        &self,
+        twist: Twist,
+        motor_status: &[MotorStatus],
+        max_current: f64,
+    ) -> Result<Vec<SingleMotorTarget>, KinematicsError> {
+        self.check_count(motor_status.len())?;
+        let radii: Vec<f64> = motor_status.iter().map(|m| m.wheel_radius).collect();
+        Ok(self
+            .wheel_speeds(twist, &radii)?
+            .into_iter()
+            .map(|speed| SingleMotorTarget::speed_with_max_current(speed, max_current))
+            .collect())
+    }
+
+    /// [`BaseKinematics::motor_targets`], as the message to send.
+    pub fn command(
+        &self,
+        twist: Twist,
+        motor_status: &[MotorStatus],
+        max_current: f64,
+    ) -> Result<ApiDown, KinematicsError> {
+        self.motor_targets(twist, motor_status, max_current)
+            .map(ApiDown::base_motor_targets)
+    }
+
+    /// [`BaseKinematics::twist`] of the speeds and wheel radii the motors report.
+    pub fn twist_from_status(
+        &self,
+        motor_status: &[MotorStatus],
+    ) -> Result<Twist, KinematicsError> {
+        let speeds: Vec<f64> = motor_status.iter().map(|m| m.speed).collect();
+        let radii: Vec<f64> = motor_status.iter().map(|m| m.wheel_radius).collect();
+        self.twist(&speeds, &radii)
+    }
+
+    fn check_count(&self, actual: usize) -> Result<(), KinematicsError> {
+        if actual != self.wheels.len() {
+            return Err(KinematicsError::MotorCount {
+                expected: self.wheels.len(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_radii(&self, wheel_radius: &[f64]) -> Result<(), KinematicsError> {
+        self.check_count(wheel_radius.len())?;
+        match wheel_radius
+            .iter()
+            .position(|r| !r.is_finite() || *r <= 0.0)
+        {
+            Some(motor) => Err(KinematicsError::WheelRadius {
+                motor,
+                radius: wheel_radius[motor],
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Solves a 3x3 linear system with Cramer's rule.
+fn solve3(a: [[f64; 3]; 3], b: [f64; 3]) -> [f64; 3] {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(a);
+    let mut x = [0.0; 3];
+    for (column, x) in x.iter_mut().enumerate() {
+        let mut m = a;
+        for row in 0..3 {
+            m[row][column] = b[row];
+        }
+        *x = det(m) / d;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADII: [f64; 4] = [0.1, 0.1, 0.12, 0.12];
+
+    fn layouts() -> [BaseKinematics; 3] {
+        [
+            BaseKinematics::differential(0.4),
+            BaseKinematics::skid_steer(0.5, 0.45),
+            BaseKinematics::mecanum(0.5, 0.45),
+        ]
+    }
+
+    fn assert_twist(actual: Twist, expected: Twist) {
+        assert!(
+            (actual.x - expected.x).abs() < 1e-9
+                && (actual.y - expected.y).abs() < 1e-9
+                && (actual.z - expected.z).abs() < 1e-9,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for kinematics in layouts() {
+            let radii = &RADII[..kinematics.wheels().len()];
+            for twist in [
+                Twist::new(0.3, 0.0, 0.0),
+                Twist::new(0.0, 0.2, 0.0),
+                Twist::new(0.0, 0.0, -0.8),
+                Twist::new(-0.2, 0.1, 0.5),
+            ] {
+                let speeds = kinematics.wheel_speeds(twist, radii).unwrap();
+                let expected = if kinematics.is_holonomic() {
+                    twist
+                } else {
+                    Twist { y: 0.0, ..twist }
+                };
+                assert_twist(kinematics.twist(&speeds, radii).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn differential_inverse() {
+        let kinematics = BaseKinematics::differential(0.4);
+        // Mirrored motors turn opposite ways to drive forward.
+        let speeds = kinematics
+            .wheel_speeds(Twist::new(0.2, 0.0, 0.0), &[0.1, 0.1])
+            .unwrap();
+        assert_eq!(speeds, [-2.0, 2.0]);
+        // Turning left in place rolls the left wheel backward and the right one forward.
+        let speeds = kinematics
+            .wheel_speeds(Twist::new(0.0, 0.0, 1.0), &[0.1, 0.1])
+            .unwrap();
+        assert!((speeds[0] - 2.0).abs() < 1e-9 && (speeds[1] - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mecanum_diagonal() {
+        let kinematics = BaseKinematics::mecanum(0.5, 0.45);
+        // Front left and rear right forward only.
+        let twist = kinematics.twist(&[-1.0, 0.0, 0.0, 1.0], &[0.1; 4]).unwrap();
+        assert!(twist.x > 0.0 && twist.y < 0.0 && twist.z.abs() < 1e-9);
+        assert!((twist.x + twist.y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_bad_motors() {
+        let kinematics = BaseKinematics::differential(0.4);
+        assert!(matches!(
+            kinematics.wheel_speeds(Twist::default(), &[0.1; 3]),
+            Err(KinematicsError::MotorCount {
+                expected: 2,
+                actual: 3
+            })
+        ));
+        assert!(matches!(
+            kinematics.twist(&[1.0, 1.0], &[0.1, 0.0]),
+            Err(KinematicsError::WheelRadius { motor: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,679 @@
+use crate::proto_public_api;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+/// One battery reading of a base.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatterySample {
+    /// The robot's monotonic timestamp of the `ApiUp` it came from.
+    pub time: Duration,
+    /// State of charge, 0 to 100.
+    pub percent: f64,
+    /// V
+    pub voltage: f32,
+    /// `None` if the base does not report it.
+    pub charging: Option<bool>,
+    /// A, `None` if the base does not report it.
+    pub current: Option<f32>,
+    /// Whether the base itself warns `WcBatteryLow`.
+    pub low_warning: bool,
+}
+
+impl BatterySample {
+    /// Reads the battery of a `BaseStatus`. `None` for other messages, or without a valid monotonic timestamp.
+    pub fn from_api_up(msg: &proto_public_api::ApiUp) -> Option<Self> {
+        let Some(proto_public_api::api_up::Status::BaseStatus(status)) = &msg.status else {
+            return None;
+        };
+        let stamp = msg.time_stamp.as_ref()?.monotonic_time_stamp.as_ref()?;
+        // `Duration::new` would panic on a corrupt stamp.
+        let time = Duration::from_secs(stamp.seconds)
+            .checked_add(Duration::from_nanos(stamp.nanoseconds.into()))?;
+        Some(Self {
+            time,
+            percent: status.battery_thousandth as f64 / 10.0,
+            voltage: status.battery_voltage,
+            charging: status.battery_charging,
+            current: status.battery_current,
+            low_warning: status.warning
+                == Some(proto_public_api::WarningCategory::WcBatteryLow as i32),
+        })
+    }
+}
+
+/// Whether a [`ChargeCycle`] charged or discharged the battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CycleKind {
+    Charge,
+    Discharge,
+}
+
+impl CycleKind {
+    fn of(charging: Option<bool>) -> Self {
+        if charging == Some(true) {
+            CycleKind::Charge
+        } else {
+            CycleKind::Discharge
+        }
+    }
+}
+
+/// One stretch of charging, or of running on battery, from plugging the charger in or out to the next time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChargeCycle {
+    pub kind: CycleKind,
+    /// Local wall clock, seconds since the unix epoch.
+    pub started_at: f64,
+    pub ended_at: f64,
+    /// Seconds, measured on the robot's monotonic clock.
+    pub duration_s: f64,
+    pub start_percent: f64,
+    pub end_percent: f64,
+    pub start_voltage: f32,
+    pub end_voltage: f32,
+    /// Monitoring started or stopped during the cycle, so it really was longer than recorded.
+    pub partial: bool,
+}
+
+/// Something worth telling someone about the battery.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BatteryEvent {
+    /// The charge dropped to or below one of [`BatteryMonitorOptions::thresholds`].
+    ThresholdCrossed {
+        threshold: f64,
+        percent: f64,
+        voltage: f32,
+        /// Seconds, see [`BatteryMonitor::runtime_estimate`].
+        runtime_estimate_s: Option<f64>,
+    },
+    /// The base started warning `WcBatteryLow`.
+    LowBatteryWarning {
+        percent: f64,
+        voltage: f32,
+    },
+    ChargingStarted {
+        percent: f64,
+        voltage: f32,
+    },
+    ChargingStopped {
+        percent: f64,
+        voltage: f32,
+    },
+    /// A charge or discharge cycle ended. Also see [`BatteryMonitor::finish`].
+    CycleCompleted(ChargeCycle),
+}
+
+impl BatteryEvent {
+    /// Whether this calls for action, rather than being informational.
+    pub fn is_alert(&self) -> bool {
+        matches!(
+            self,
+            BatteryEvent::ThresholdCrossed { .. } | BatteryEvent::LowBatteryWarning { .. }
+        )
+    }
+}
+
+impl fmt::Display for BatteryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatteryEvent::ThresholdCrossed {
+                threshold,
+                percent,
+                voltage,
+                runtime_estimate_s,
+            } => {
+                write!(
+                    f,
+                    "Battery at {:.1}% ({:.2}V), below {}%",
+                    percent, voltage, threshold
+                )?;
+                if let Some(seconds) = runtime_estimate_s {
+                    write!(f, ", about {:.0} min left", seconds / 60.0)?;
+                }
+                Ok(())
+            }
+            BatteryEvent::LowBatteryWarning { percent, voltage } => write!(
+                f,
+                "Base warns battery low at {:.1}% ({:.2}V)",
+                percent, voltage
+            ),
+            BatteryEvent::ChargingStarted { percent, voltage } => {
+                write!(f, "Charging started at {:.1}% ({:.2}V)", percent, voltage)
+            }
+            BatteryEvent::ChargingStopped { percent, voltage } => {
+                write!(f, "Charging stopped at {:.1}% ({:.2}V)", percent, voltage)
+            }
+            BatteryEvent::CycleCompleted(cycle) => write!(
+                f,
+                "{:?} cycle{} of {:.0}s, {:.1}% to {:.1}%",
+                cycle.kind,
+                if cycle.partial { " (partial)" } else { "" },
+                cycle.duration_s,
+                cycle.start_percent,
+                cycle.end_percent
+            ),
+        }
+    }
+}
+
+/// Options of a [`BatteryMonitor`].
+#[derive(Debug, Clone)]
+pub struct BatteryMonitorOptions {
+    /// Alert when the charge drops to each of these, in percent.
+    pub thresholds: Vec<f64>,
+    /// Once alerted, a threshold alerts again only after the charge went this many percent above it.
+    pub hysteresis: f64,
+    /// The charge rate is fitted over this much of the latest history.
+    pub slope_window: Duration,
+    /// No charge rate until the history spans at least this long. `battery_thousandth` moves in steps of 0.1%, so
+    /// short spans give jumpy estimates.
+    pub min_slope_span: Duration,
+}
+
+impl Default for BatteryMonitorOptions {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![30.0, 20.0, 10.0],
+            hysteresis: 2.0,
+            slope_window: Duration::from_secs(600),
+            min_slope_span: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Where the current cycle started.
+#[derive(Debug, Clone, Copy)]
+struct CycleStart {
+    kind: CycleKind,
+    started_at: SystemTime,
+    sample: BatterySample,
+    partial: bool,
+}
+
+/// Tracks the battery of a base over time, and tells when something about it is worth an alert.
+///
+/// Feed it every `ApiUp` with [`BatteryMonitor::update`], which returns the [`BatteryEvent`]s that message caused.
+/// It estimates the charge rate from a least squares fit of the charge over the last
+/// [`BatteryMonitorOptions::slope_window`], and from that the remaining runtime. Plugging the charger in or out
+/// ends a [`ChargeCycle`], which [`ChargeCycleLog`] keeps in a file.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, AlertSink, BatteryMonitor, RobotConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     let mut monitor = BatteryMonitor::new();
+///     while let Some(msg) = stream.next().await {
+///         for event in monitor.update(&msg) {
+///             AlertSink::Log.send(&event).await?;
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BatteryMonitor {
+    options: BatteryMonitorOptions,
+    latest: Option<BatterySample>,
+    /// `(monotonic seconds, percent)`, at most one per second, since the charger was last plugged in or out.
+    history: VecDeque<(f64, f64)>,
+    /// Per threshold, whether it alerts when crossed.
+    armed: Vec<bool>,
+    cycle: Option<CycleStart>,
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        Self::with_options(BatteryMonitorOptions::default())
+    }
+
+    pub fn with_options(mut options: BatteryMonitorOptions) -> Self {
+        options.thresholds.sort_by(|a, b| b.total_cmp(a));
+        Self {
+            armed: vec![true; options.thresholds.len()],
+            options,
+            latest: None,
+            history: VecDeque::new(),
+            cycle: None,
+        }
+    }
+
+    pub fn options(&self) -> &BatteryMonitorOptions {
+        &self.options
+    }
+
+    /// The last reading.
+    pub fn latest(&self) -> Option<&BatterySample> {
+        self.latest.as_ref()
+    }
+
+    /// Takes in one `ApiUp`. Returns what changed, in order; nothing for messages without a battery reading.
+    pub fn update(&mut self, msg: &proto_public_api::ApiUp) -> Vec<BatteryEvent> {
+        match BatterySample::from_api_up(msg) {
+            Some(sample) => self.update_sample(sample),
+            None => Vec::new(),
+        }
+    }
+
+    /// [`BatteryMonitor::update`], with an already extracted reading.
+    pub fn update_sample(&mut self, sample: BatterySample) -> Vec<BatteryEvent> {
+        let mut events = Vec::new();
+        if let Some(last) = self.latest {
+            if sample.time == last.time {
+                return events;
+            }
+            if sample.time < last.time {
+                warn!(
+                    "Battery timestamp went back from {:?} to {:?}, restarting charge rate",
+                    last.time, sample.time
+                );
+                self.history.clear();
+            }
+            if sample.charging.is_some()
+                && last.charging.is_some()
+                && sample.charging != last.charging
+            {
+                let (percent, voltage) = (sample.percent, sample.voltage);
+                events.push(if sample.charging == Some(true) {
+                    BatteryEvent::ChargingStarted { percent, voltage }
+                } else {
+                    BatteryEvent::ChargingStopped { percent, voltage }
+                });
+                events.extend(
+                    self.end_cycle(&sample, false)
+                        .map(BatteryEvent::CycleCompleted),
+                );
+                // The old slope says nothing about the new direction.
+                self.history.clear();
+            }
+            if sample.low_warning && !last.low_warning {
+                events.push(BatteryEvent::LowBatteryWarning {
+                    percent: sample.percent,
+                    voltage: sample.voltage,
+                });
+            }
+        } else if sample.low_warning {
+            events.push(BatteryEvent::LowBatteryWarning {
+                percent: sample.percent,
+                voltage: sample.voltage,
+            });
+        }
+        if self.cycle.is_none() {
+            self.cycle = Some(CycleStart {
+                kind: CycleKind::of(sample.charging),
+                started_at: SystemTime::now(),
+                sample,
+                // Unless it started with a charger change just now, it was already going.
+                partial: self.latest.is_none(),
+            });
+        }
+        self.latest = Some(sample);
+
+        let seconds = sample.time.as_secs_f64();
+        if self.history.back().is_none_or(|(t, _)| seconds - t >= 1.0) {
+            self.history.push_back((seconds, sample.percent));
+        }
+        let window = self.options.slope_window.as_secs_f64();
+        while self
+            .history
+            .front()
+            .is_some_and(|(t, _)| seconds - t > window)
+        {
+            self.history.pop_front();
+        }
+
+        if sample.charging != Some(true) {
+            events.extend(self.check_thresholds(sample.percent, sample.voltage));
+        }
+        for (threshold, armed) in self.options.thresholds.iter().zip(&mut self.armed) {
+            if sample.percent > threshold + self.options.hysteresis {
+                *armed = true;
+            }
+        }
+        events
+    }
+
+    /// Alerts for the lowest armed threshold at or above `percent`, disarming it and every threshold above it.
+    fn check_thresholds(&mut self, percent: f64, voltage: f32) -> Option<BatteryEvent> {
+        let mut crossed = None;
+        for (threshold, armed) in self.options.thresholds.iter().zip(&mut self.armed) {
+            if *armed && percent <= *threshold {
+                *armed = false;
+                crossed = Some(*threshold);
+            }
+        }
+        let runtime_estimate_s = self.runtime_estimate().map(|d| d.as_secs_f64());
+        crossed.map(|threshold| BatteryEvent::ThresholdCrossed {
+            threshold,
+            percent,
+            voltage,
+            runtime_estimate_s,
+        })
+    }
+
+    /// How fast the charge changes, in percent per hour. Negative while discharging.
+    ///
+    /// `None` until the history since the charger was last plugged in or out spans
+    /// [`BatteryMonitorOptions::min_slope_span`].
+    pub fn percent_per_hour(&self) -> Option<f64> {
+        let (first, last) = (self.history.front()?, self.history.back()?);
+        if last.0 - first.0 < self.options.min_slope_span.as_secs_f64() {
+            return None;
+        }
+        let n = self.history.len() as f64;
+        let mean_t = self.history.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_p = self.history.iter().map(|(_, p)| p).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for (t, p) in &self.history {
+            covariance += (t - mean_t) * (p - mean_p);
+            variance += (t - mean_t) * (t - mean_t);
+        }
+        Some(covariance / variance * 3600.0)
+    }
+
+    /// Time until the battery is empty at the current discharge rate. `None` while charging, or not discharging.
+    pub fn runtime_estimate(&self) -> Option<Duration> {
+        let latest = self.latest?;
+        if latest.charging == Some(true) {
+            return None;
+        }
+        let rate = self.percent_per_hour()?;
+        if rate >= 0.0 {
+            return None;
+        }
+        Duration::try_from_secs_f64(latest.percent / -rate * 3600.0).ok()
+    }
+
+    /// Time until the battery is full at the current charge rate. `None` unless charging.
+    pub fn time_to_full(&self) -> Option<Duration> {
+        let latest = self.latest?;
+        if latest.charging != Some(true) {
+            return None;
+        }
+        let rate = self.percent_per_hour()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        Duration::try_from_secs_f64((100.0 - latest.percent) / rate * 3600.0).ok()
+    }
+
+    /// Ends the current cycle because monitoring stops, so it can still be logged. It is marked partial.
+    pub fn finish(&mut self) -> Option<ChargeCycle> {
+        let latest = self.latest?;
+        self.end_cycle(&latest, true)
+    }
+
+    fn end_cycle(&mut self, end: &BatterySample, partial: bool) -> Option<ChargeCycle> {
+        let start = self.cycle.take()?;
+        let unix = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+        Some(ChargeCycle {
+            kind: start.kind,
+            started_at: unix(start.started_at),
+            ended_at: unix(SystemTime::now()),
+            duration_s: end.time.saturating_sub(start.sample.time).as_secs_f64(),
+            start_percent: start.sample.percent,
+            end_percent: end.percent,
+            start_voltage: start.sample.voltage,
+            end_voltage: end.voltage,
+            partial: partial || start.partial,
+        })
+    }
+}
+
+/// A CSV file of [`ChargeCycle`]s, one per line, appended to across runs.
+pub struct ChargeCycleLog {
+    file: std::fs::File,
+}
+
+impl ChargeCycleLog {
+    const HEADER: &'static str = "kind,started_at,ended_at,duration_s,start_percent,end_percent,start_voltage,end_voltage,partial";
+
+    /// Opens `path` for appending, creating it with a header if it does not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", Self::HEADER)?;
+        }
+        Ok(Self { file })
+    }
+
+    pub fn append(&mut self, cycle: &ChargeCycle) -> Result<(), std::io::Error> {
+        writeln!(
+            self.file,
+            "{},{:.3},{:.3},{:.3},{:.1},{:.1},{:.3},{:.3},{}",
+            match cycle.kind {
+                CycleKind::Charge => "charge",
+                CycleKind::Discharge => "discharge",
+            },
+            cycle.started_at,
+            cycle.ended_at,
+            cycle.duration_s,
+            cycle.start_percent,
+            cycle.end_percent,
+            cycle.start_voltage,
+            cycle.end_voltage,
+            cycle.partial
+        )?;
+        self.file.flush()
+    }
+}
+
+/// Where [`BatteryEvent`]s go.
+#[derive(Debug, Clone)]
+pub enum AlertSink {
+    /// The `log` crate, alerts as warnings and the rest as info.
+    Log,
+    /// One JSON object per line on stdout, tagged with `"event"`.
+    StdoutJson,
+    /// An HTTP POST of the JSON object to a local endpoint. Plain `http://` only.
+    Webhook(url::Url),
+}
+
+impl AlertSink {
+    /// How long a webhook may take to answer.
+    pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub async fn send(&self, event: &BatteryEvent) -> Result<(), anyhow::Error> {
+        match self {
+            AlertSink::Log if event.is_alert() => warn!("{}", event),
+            AlertSink::Log => info!("{}", event),
+            AlertSink::StdoutJson => println!("{}", serde_json::to_string(event)?),
+            AlertSink::Webhook(url) => {
+                tokio::time::timeout(
+                    Self::WEBHOOK_TIMEOUT,
+                    post_json(url, &serde_json::to_string(event)?),
+                )
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "Webhook {} did not answer in {:?}",
+                        url,
+                        Self::WEBHOOK_TIMEOUT
+                    )
+                })??;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A minimal HTTP/1.1 POST, which is all a local webhook needs. Fails unless the answer is 2xx.
+async fn post_json(url: &url::Url, body: &str) -> Result<(), anyhow::Error> {
+    if url.scheme() != "http" {
+        return Err(anyhow::anyhow!(
+            "Only http:// webhooks are supported, got {}",
+            url
+        ));
+    }
+    let host = match url.host() {
+        Some(url::Host::Ipv6(addr)) => addr.to_string(),
+        Some(host) => host.to_string(),
+        None => return Err(anyhow::anyhow!("Webhook URL {} has no host", url)),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{}?{}", path, query);
+    }
+
+    let mut stream = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        &url[url::Position::BeforeHost..url::Position::AfterPort],
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status_line = String::new();
+    tokio::io::BufReader::new(stream)
+        .read_line(&mut status_line)
+        .await?;
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(anyhow::anyhow!(
+            "Webhook {} answered {:?}",
+            url,
+            status_line.trim_end()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(seconds: u64, percent: f64, charging: bool) -> BatterySample {
+        BatterySample {
+            time: Duration::from_secs(seconds),
+            percent,
+            voltage: 24.0,
+            charging: Some(charging),
+            current: None,
+            low_warning: false,
+        }
+    }
+
+    fn crossed(events: &[BatteryEvent]) -> Vec<f64> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                BatteryEvent::ThresholdCrossed { threshold, .. } => Some(*threshold),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thresholds_alert_once_each() {
+        let mut monitor = BatteryMonitor::new();
+        assert!(crossed(&monitor.update_sample(sample(0, 31.0, false))).is_empty());
+        assert_eq!(
+            crossed(&monitor.update_sample(sample(1, 30.0, false))),
+            [30.0]
+        );
+        assert!(crossed(&monitor.update_sample(sample(2, 25.0, false))).is_empty());
+        // Skipping past two thresholds alerts only the lowest.
+        assert_eq!(
+            crossed(&monitor.update_sample(sample(3, 9.5, false))),
+            [10.0]
+        );
+        assert!(crossed(&monitor.update_sample(sample(4, 5.0, false))).is_empty());
+    }
+
+    #[test]
+    fn thresholds_rearm_past_the_hysteresis() {
+        let mut monitor = BatteryMonitor::new();
+        assert_eq!(
+            crossed(&monitor.update_sample(sample(0, 30.0, false))),
+            [30.0]
+        );
+        // Jitter around the threshold, within the 2% hysteresis.
+        monitor.update_sample(sample(1, 31.5, false));
+        assert!(crossed(&monitor.update_sample(sample(2, 29.9, false))).is_empty());
+        monitor.update_sample(sample(3, 32.5, false));
+        assert_eq!(
+            crossed(&monitor.update_sample(sample(4, 29.9, false))),
+            [30.0]
+        );
+    }
+
+    #[test]
+    fn no_threshold_alerts_while_charging() {
+        let mut monitor = BatteryMonitor::new();
+        monitor.update_sample(sample(0, 35.0, false));
+        let events = monitor.update_sample(sample(1, 15.0, true));
+        assert!(crossed(&events).is_empty());
+        assert!(matches!(events[0], BatteryEvent::ChargingStarted { .. }));
+        assert!(matches!(
+            &events[1],
+            BatteryEvent::CycleCompleted(cycle) if cycle.kind == CycleKind::Discharge && cycle.partial
+        ));
+    }
+
+    #[test]
+    fn slope_and_runtime_estimate() {
+        let mut monitor = BatteryMonitor::new();
+        // 1% a minute.
+        for t in 0..59 {
+            monitor.update_sample(sample(t, 80.0 - t as f64 / 60.0, false));
+        }
+        assert_eq!(monitor.percent_per_hour(), None);
+        for t in 59..120 {
+            monitor.update_sample(sample(t, 80.0 - t as f64 / 60.0, false));
+        }
+        let rate = monitor.percent_per_hour().unwrap();
+        assert!((rate + 60.0).abs() < 1e-6, "{}", rate);
+        // 78% left at 60% an hour.
+        let runtime = monitor.runtime_estimate().unwrap().as_secs_f64();
+        let expected = (80.0 - 119.0 / 60.0) / 60.0 * 3600.0;
+        assert!((runtime - expected).abs() < 1e-3, "{}", runtime);
+        assert_eq!(monitor.time_to_full(), None);
+    }
+
+    #[test]
+    fn corrupt_timestamps_are_skipped() {
+        let msg = proto_public_api::ApiUp {
+            status: Some(proto_public_api::api_up::Status::BaseStatus(
+                Default::default(),
+            )),
+            time_stamp: Some(proto_public_api::TimeStamp {
+                monotonic_time_stamp: Some(proto_public_api::MonotonicTimeStamp {
+                    seconds: u64::MAX,
+                    nanoseconds: 2_000_000_000,
+                }),
+                ptp_time_stamp: None,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(BatterySample::from_api_up(&msg), None);
+        assert!(BatteryMonitor::new().update(&msg).is_empty());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::connection::{ApiUpStream, RobotConnection, Transport};
+use crate::proto_public_api::{self, ApiDown, ReportFrequency};
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Options for [`run_benchmark`].
+#[derive(Debug, Clone)]
+pub struct BenchmarkOptions {
+    /// Report frequencies to measure inter-arrival times at, one after the other.
+    pub report_frequencies: Vec<ReportFrequency>,
+    /// How long to measure each report frequency.
+    pub duration_per_frequency: Duration,
+    /// Messages are not measured for this long after switching frequency, to let queues drain.
+    pub warmup: Duration,
+    /// How many command-to-status round trips to measure.
+    pub latency_samples: u32,
+    /// A round trip that takes longer than this counts as a timeout.
+    pub latency_timeout: Duration,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            report_frequencies: vec![ReportFrequency::Rf250Hz, ReportFrequency::Rf1000Hz],
+            duration_per_frequency: Duration::from_secs(5),
+            warmup: Duration::from_millis(500),
+            latency_samples: 100,
+            latency_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One bucket of a [`DurationSamples::histogram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramBucket {
+    pub start: Duration,
+    pub end: Duration,
+    pub count: u64,
+}
+
+/// Measured durations, sorted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DurationSamples {
+    samples: Vec<Duration>,
+}
+
+impl DurationSamples {
+    pub fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Self { samples }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// All samples, shortest first.
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
+    /// Nearest-rank percentile, `percentile` in 0..=100. `None` without samples.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * self.samples.len() as f64).ceil();
+        let index = (rank as usize).clamp(1, self.samples.len()) - 1;
+        Some(self.samples[index])
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.first().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.last().copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let total: Duration = self.samples.iter().sum();
+        total.checked_div(self.samples.len() as u32)
+    }
+
+    /// At most this many buckets in a [`DurationSamples::histogram`].
+    pub const MAX_HISTOGRAM_BUCKETS: u128 = 100_000;
+
+    /// Counts samples in buckets of `bucket_width`, from 0 up to the largest sample. Empty buckets are included,
+    /// so the result plots as is. A width of zero, or one that would take more than
+    /// [`DurationSamples::MAX_HISTOGRAM_BUCKETS`], is widened to fit.
+    pub fn histogram(&self, bucket_width: Duration) -> Vec<HistogramBucket> {
+        let Some(max) = self.max() else {
+            return Vec::new();
+        };
+        let width = bucket_width
+            .as_nanos()
+            .max(max.as_nanos() / Self::MAX_HISTOGRAM_BUCKETS + 1);
+        let mut counts = vec![0u64; (max.as_nanos() / width) as usize + 1];
+        for sample in &self.samples {
+            counts[(sample.as_nanos() / width) as usize] += 1;
+        }
+        let at = |nanos: u128| {
+            Duration::new(
+                (nanos / 1_000_000_000) as u64,
+                (nanos % 1_000_000_000) as u32,
+            )
+        };
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| HistogramBucket {
+                start: at(width * i as u128),
+                end: at(width * (i as u128 + 1)),
+                count,
+            })
+            .collect()
+    }
+}
+
+/// `ApiUp` arrival at one report frequency.
+#[derive(Debug, Clone)]
+pub struct InterArrivalResult {
+    pub report_frequency: ReportFrequency,
+    /// Time between consecutive `ApiUp`s, as seen by us.
+    pub inter_arrival: DurationSamples,
+    pub received: u64,
+    /// Gaps in the robot's timestamps, counted in report periods: messages lost on the way, or never sent
+    /// because the robot fell behind. `None` if the robot sends no monotonic timestamp.
+    pub lost: Option<u64>,
+    /// Messages that arrived after a newer one. `None` if the robot sends no monotonic timestamp.
+    pub reordered: Option<u64>,
+}
+
+/// Everything [`run_benchmark`] measured over one connection.
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub transport: Transport,
+    pub robot_type: proto_public_api::RobotType,
+    pub inter_arrival: Vec<InterArrivalResult>,
+    /// Time from sending `SetReportFrequency` to the first `ApiUp` reporting the new frequency.
+    pub command_to_status: DurationSamples,
+    pub command_timeouts: u32,
+}
+
+/// Expected time between two reports at `frequency`.
+pub fn report_period(frequency: ReportFrequency) -> Duration {
+    let hz = match frequency {
+        ReportFrequency::Rf1000Hz => 1000,
+        ReportFrequency::Rf500Hz => 500,
+        ReportFrequency::Rf250Hz => 250,
+        ReportFrequency::Rf100Hz => 100,
+        ReportFrequency::Rf50Hz => 50,
+        ReportFrequency::Rf1Hz => 1,
+    };
+    Duration::from_micros(1_000_000 / hz)
+}
+
+fn monotonic_time_stamp(msg: &proto_public_api::ApiUp) -> Option<Duration> {
+    let stamp = msg.time_stamp.as_ref()?.monotonic_time_stamp.as_ref()?;
+    // `Duration::new` would panic on a corrupt stamp.
+    Duration::from_secs(stamp.seconds).checked_add(Duration::from_nanos(stamp.nanoseconds.into()))
+}
+
+async fn next_message(stream: &mut ApiUpStream) -> Result<proto_public_api::ApiUp, anyhow::Error> {
+    stream
+        .next()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Connection lost during benchmark"))
+}
+
+/// Sets the report frequency, and waits until the robot reports at it.
+async fn switch_report_frequency<C: RobotConnection + ?Sized>(
+    connection: &mut C,
+    stream: &mut ApiUpStream,
+    frequency: ReportFrequency,
+    timeout: Duration,
+) -> Result<Option<Duration>, anyhow::Error> {
+    let sent_at = Instant::now();
+    connection
+        .send(ApiDown::set_report_frequency(frequency))
+        .await?;
+    let wait = async {
+        loop {
+            let msg = next_message(stream).await?;
+            if msg.report_frequency() == frequency {
+                return Ok::<_, anyhow::Error>(sent_at.elapsed());
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(elapsed) => Ok(Some(elapsed?)),
+        Err(_) => Ok(None),
+    }
+}
+
+async fn measure_inter_arrival<C: RobotConnection + ?Sized>(
+    connection: &mut C,
+    stream: &mut ApiUpStream,
+    frequency: ReportFrequency,
+    options: &BenchmarkOptions,
+) -> Result<InterArrivalResult, anyhow::Error> {
+    if switch_report_frequency(connection, stream, frequency, options.latency_timeout)
+        .await?
+        .is_none()
+    {
+        return Err(anyhow::anyhow!(
+            "Robot did not switch to {} within {:?}",
+            frequency.as_str_name(),
+            options.latency_timeout
+        ));
+    }
+    let warmup_end = Instant::now() + options.warmup;
+    while let Ok(msg) = tokio::time::timeout_at(warmup_end, next_message(stream)).await {
+        msg?;
+    }
+
+    let period = report_period(frequency);
+    let end = Instant::now() + options.duration_per_frequency;
+    let mut inter_arrival = Vec::new();
+    let mut received = 0;
+    let mut lost = 0;
+    let mut reordered = 0;
+    let mut has_time_stamps = true;
+    let mut last_arrival: Option<Instant> = None;
+    let mut newest_stamp: Option<Duration> = None;
+    while let Ok(msg) = tokio::time::timeout_at(end, next_message(stream)).await {
+        let msg = msg?;
+        let arrival = Instant::now();
+        received += 1;
+        if let Some(last) = last_arrival {
+            inter_arrival.push(arrival - last);
+        }
+        last_arrival = Some(arrival);
+
+        let Some(stamp) = monotonic_time_stamp(&msg) else {
+            has_time_stamps = false;
+            continue;
+        };
+        match newest_stamp {
+            Some(newest) if stamp <= newest => reordered += 1,
+            Some(newest) => {
+                // Round, so timer jitter on the robot doesn't count as loss.
+                let steps = ((stamp - newest).as_secs_f64() / period.as_secs_f64()).round();
+                lost += (steps as u64).saturating_sub(1);
+                newest_stamp = Some(stamp);
+            }
+            None => newest_stamp = Some(stamp),
+        }
+    }
+
+    Ok(InterArrivalResult {
+        report_frequency: frequency,
+        inter_arrival: DurationSamples::new(inter_arrival),
+        received,
+        lost: has_time_stamps.then_some(lost),
+        reordered: has_time_stamps.then_some(reordered),
+    })
+}
+
+/// Measures `ApiUp` inter-arrival times, loss and reordering at each of `options.report_frequencies`, then the
+/// time from sending a command to the first status reflecting it.
+///
+/// The command is `SetReportFrequency`, toggled between two frequencies, since every robot reflects it in the
+/// very next `ApiUp`. Nothing moves, so it is safe to run against a real robot. Takes the connection's stream,
+/// which must not have been taken yet.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, run_benchmark, BenchmarkOptions, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let report = run_benchmark(connection.as_mut(), &BenchmarkOptions::default()).await?;
+///     for result in &report.inter_arrival {
+///         println!("{:?}: p99 {:?}", result.report_frequency, result.inter_arrival.percentile(99.0));
+///     }
+///     println!("Command to status p50: {:?}", report.command_to_status.percentile(50.0));
+///     Ok(())
+/// }
+/// ```
+pub async fn run_benchmark<C: RobotConnection + ?Sized>(
+    connection: &mut C,
+    options: &BenchmarkOptions,
+) -> Result<BenchmarkReport, anyhow::Error> {
+    let mut stream = connection
+        .take_stream()
+        .ok_or_else(|| anyhow::anyhow!("The connection's stream was already taken"))?;
+
+    let mut inter_arrival = Vec::new();
+    for &frequency in &options.report_frequencies {
+        inter_arrival
+            .push(measure_inter_arrival(connection, &mut stream, frequency, options).await?);
+    }
+
+    // Toggle between two fast frequencies, so a slow report rate doesn't add to the measured latency. Start with
+    // the one we are not at, otherwise the first sample would be a message already on its way.
+    let mut toggle = [ReportFrequency::Rf500Hz, ReportFrequency::Rf1000Hz];
+    if options.report_frequencies.last() == Some(&ReportFrequency::Rf500Hz) {
+        toggle.swap(0, 1);
+    }
+    let mut command_to_status = Vec::new();
+    let mut command_timeouts = 0;
+    for i in 0..options.latency_samples {
+        let frequency = toggle[i as usize % 2];
+        match switch_report_frequency(connection, &mut stream, frequency, options.latency_timeout)
+            .await?
+        {
+            Some(elapsed) => command_to_status.push(elapsed),
+            None => command_timeouts += 1,
+        }
+    }
+
+    Ok(BenchmarkReport {
+        transport: connection.transport(),
+        robot_type: connection.robot_type(),
+        inter_arrival,
+        command_to_status: DurationSamples::new(command_to_status),
+        command_timeouts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let samples = DurationSamples::new([250, 50, 120, 199].map(Duration::from_micros).to_vec());
+        let histogram = samples.histogram(Duration::from_micros(100));
+        assert_eq!(
+            histogram.iter().map(|b| b.count).collect::<Vec<_>>(),
+            [1, 2, 1]
+        );
+        assert_eq!(histogram[2].start, Duration::from_micros(200));
+        assert_eq!(histogram[2].end, Duration::from_micros(300));
+    }
+
+    #[test]
+    fn histogram_widens_tiny_buckets() {
+        let samples = DurationSamples::new(vec![Duration::ZERO, Duration::from_secs(3600)]);
+        for width in [Duration::ZERO, Duration::from_nanos(1)] {
+            let histogram = samples.histogram(width);
+            assert!(histogram.len() as u128 <= DurationSamples::MAX_HISTOGRAM_BUCKETS);
+            assert_eq!(histogram.iter().map(|b| b.count).sum::<u64>(), 2);
+            assert!(histogram.last().unwrap().end > Duration::from_secs(3600));
+        }
+    }
+}
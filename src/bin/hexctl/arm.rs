@@ -0,0 +1,88 @@
+use crate::{parse_seconds, Context, ParkingStopArgs, TakeoverArgs};
+use clap::{Args, Subcommand};
+use robot_demos::proto_public_api::{ApiDown, SingleMotorTarget};
+use robot_demos::{ControlTarget, RobotConnection, RobotStateCache, RobotTarget};
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub enum ArmCommand {
+    /// Command zero torque on every joint for a while, so the arm can be moved by hand
+    TorqueZero(TorqueZeroArgs),
+}
+
+#[derive(Args)]
+pub struct TorqueZeroArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        default_value_t = 10.0,
+        value_parser = parse_seconds,
+        help = "How long to keep zero torque, in seconds"
+    )]
+    seconds: f64,
+    #[command(flatten)]
+    parking_stop: ParkingStopArgs,
+    #[command(flatten)]
+    takeover: TakeoverArgs,
+}
+
+pub async fn run(ctx: &Context, command: ArmCommand) -> Result<(), anyhow::Error> {
+    match command {
+        ArmCommand::TorqueZero(args) => torque_zero(ctx, args).await,
+    }
+}
+
+async fn torque_zero(ctx: &Context, args: TorqueZeroArgs) -> Result<(), anyhow::Error> {
+    let intro_text = format!(
+        "Set the arm to zero torque for {} seconds. The arm WILL fall if not supported.",
+        args.seconds
+    );
+    let mut session = args
+        .takeover
+        .start(ctx, &args.robot, &intro_text, ControlTarget::Arm)
+        .await?;
+    let cache = RobotStateCache::new();
+    cache.spawn(args.parking_stop.settle(&mut session).await?);
+
+    // Motor count is only known from the status.
+    let motor_count = cache
+        .subscribe_arm()
+        .wait_for(|arm| arm.is_some())
+        .await?
+        .as_ref()
+        .map(|arm| arm.motor_status.len())
+        .unwrap();
+    session.send(ApiDown::arm_calibrate()).await?;
+
+    let zero_torque_message = ApiDown::arm_motor_targets(
+        (0..motor_count)
+            .map(|_| SingleMotorTarget::torque(0.0))
+            .collect(),
+    );
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    let duration = Duration::from_secs_f64(args.seconds);
+    let start_time = tokio::time::Instant::now();
+    while start_time.elapsed() < duration {
+        interval.tick().await;
+        session.send(zero_torque_message.clone()).await?;
+    }
+
+    let confirmed = session.finish(Duration::from_secs(1)).await?;
+    ctx.print(
+        json!({ "motor_count": motor_count, "deinitialize_confirmed": confirmed }),
+        || {
+            format!(
+                "Held {} joints at zero torque, arm {} deinitialize",
+                motor_count,
+                if confirmed {
+                    "confirmed"
+                } else {
+                    "did NOT confirm"
+                }
+            )
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::{parse_seconds, Context, ParkingStopArgs, TakeoverArgs};
+use clap::{Args, Subcommand};
+use robot_demos::proto_public_api::ApiDown;
+use robot_demos::{ControlTarget, RobotConnection, RobotTarget};
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub enum BaseCommand {
+    /// Move the base at a constant speed for a while, then stop
+    Move(MoveArgs),
+}
+
+#[derive(Args)]
+pub struct MoveArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help = "Forward speed, m/s"
+    )]
+    vx: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help = "Left speed, m/s"
+    )]
+    vy: f32,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help = "Counterclockwise speed, rad/s"
+    )]
+    wz: f32,
+    #[arg(
+        long,
+        default_value_t = 5.0,
+        value_parser = parse_seconds,
+        help = "How long to move, in seconds"
+    )]
+    seconds: f64,
+    #[command(flatten)]
+    parking_stop: ParkingStopArgs,
+    #[command(flatten)]
+    takeover: TakeoverArgs,
+}
+
+pub async fn run(ctx: &Context, command: BaseCommand) -> Result<(), anyhow::Error> {
+    match command {
+        BaseCommand::Move(args) => move_base(ctx, args).await,
+    }
+}
+
+async fn move_base(ctx: &Context, args: MoveArgs) -> Result<(), anyhow::Error> {
+    let intro_text = format!(
+        "Move the base at vx {} m/s, vy {} m/s, wz {} rad/s for {} seconds.",
+        args.vx, args.vy, args.wz, args.seconds
+    );
+    let mut session = args
+        .takeover
+        .start(ctx, &args.robot, &intro_text, ControlTarget::Base)
+        .await?;
+    // Moving blind, the status is only needed to clear the parking stop.
+    drop(args.parking_stop.settle(&mut session).await?);
+
+    let move_message = ApiDown::base_xyz_speed(args.vx, args.vy, args.wz);
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    let duration = Duration::from_secs_f64(args.seconds);
+    let start_time = tokio::time::Instant::now();
+    while start_time.elapsed() < duration {
+        interval.tick().await;
+        session.send(move_message.clone()).await?;
+    }
+
+    let confirmed = session.finish(Duration::from_secs(1)).await?;
+    ctx.print(
+        json!({ "moved_seconds": args.seconds, "deinitialize_confirmed": confirmed }),
+        || {
+            format!(
+                "Moved for {} seconds, base {} deinitialize",
+                args.seconds,
+                if confirmed {
+                    "confirmed"
+                } else {
+                    "did NOT confirm"
+                }
+            )
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use crate::Context;
+use clap::Args;
+use futures_util::StreamExt;
+use log::{info, warn};
+use robot_demos::{
+    AlertSink, BatteryEvent, BatteryMonitor, BatteryMonitorOptions, ChargeCycleLog, RobotTarget,
+};
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+#[derive(Args)]
+pub struct BatteryArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long = "threshold",
+        default_values_t = [30.0, 20.0, 10.0],
+        help = "Alert when the charge drops to this percentage. Repeat for several"
+    )]
+    thresholds: Vec<f64>,
+    #[arg(
+        long,
+        help = "Also POST every event as JSON to this local http:// endpoint"
+    )]
+    webhook: Option<url::Url>,
+    #[arg(long, help = "Append charge and discharge cycles to this CSV file")]
+    cycle_log: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "Print the charge and runtime estimate every this many seconds"
+    )]
+    report_every: u64,
+    #[arg(long, help = "Stop after this many seconds, instead of at Ctrl-C")]
+    seconds: Option<u64>,
+}
+
+pub async fn battery(ctx: &Context, args: BatteryArgs) -> Result<(), anyhow::Error> {
+    let mut connection = ctx
+        .connect(
+            &args.robot,
+            "Monitor its battery, without sending any command.",
+        )
+        .await?;
+    let mut stream = connection.take_stream().unwrap();
+    let mut monitor = BatteryMonitor::with_options(BatteryMonitorOptions {
+        thresholds: args.thresholds,
+        ..Default::default()
+    });
+    let mut cycle_log = args.cycle_log.map(ChargeCycleLog::open).transpose()?;
+
+    let mut sinks = vec![if ctx.json {
+        AlertSink::StdoutJson
+    } else {
+        AlertSink::Log
+    }];
+    sinks.extend(args.webhook.map(AlertSink::Webhook));
+    let mut pending = JoinSet::new();
+
+    let mut report = tokio::time::interval(Duration::from_secs(args.report_every.max(1)));
+    let deadline = tokio::time::sleep(
+        args.seconds
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::MAX),
+    );
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(msg) => {
+                    for event in monitor.update(&msg) {
+                        dispatch(&sinks, &mut cycle_log, &mut pending, event).await;
+                    }
+                }
+                None => {
+                    info!("Connection lost");
+                    break;
+                }
+            },
+            _ = report.tick() => print_report(ctx, &monitor),
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Keep the cycle that was going, so a restart does not lose it from the log.
+    if let Some(cycle) = monitor.finish() {
+        let event = BatteryEvent::CycleCompleted(cycle);
+        dispatch(&sinks, &mut cycle_log, &mut pending, event).await;
+    }
+    // Webhooks still on their way. Each gives up after `AlertSink::WEBHOOK_TIMEOUT`.
+    while pending.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Sends `event` to every sink, and logs it if it ends a cycle. Webhooks are sent in the background, so a slow
+/// endpoint does not hold up the monitor.
+async fn dispatch(
+    sinks: &[AlertSink],
+    cycle_log: &mut Option<ChargeCycleLog>,
+    pending: &mut JoinSet<()>,
+    event: BatteryEvent,
+) {
+    if let (BatteryEvent::CycleCompleted(cycle), Some(log)) = (&event, cycle_log.as_mut()) {
+        if let Err(e) = log.append(cycle) {
+            warn!("Failed to write charge cycle: {}", e);
+        }
+    }
+    // Reap the webhooks that are done, so they don't pile up.
+    while pending.try_join_next().is_some() {}
+    for sink in sinks {
+        if let AlertSink::Webhook(_) = sink {
+            let (sink, event) = (sink.clone(), event.clone());
+            pending.spawn(async move {
+                if let Err(e) = sink.send(&event).await {
+                    warn!("Failed to send battery event: {}", e);
+                }
+            });
+        } else if let Err(e) = sink.send(&event).await {
+            warn!("Failed to send battery event: {}", e);
+        }
+    }
+}
+
+fn print_report(ctx: &Context, monitor: &BatteryMonitor) {
+    let Some(sample) = monitor.latest() else {
+        return;
+    };
+    let rate = monitor.percent_per_hour();
+    let runtime = monitor.runtime_estimate();
+    let to_full = monitor.time_to_full();
+    ctx.print(
+        json!({
+            "event": "report",
+            "percent": sample.percent,
+            "voltage": sample.voltage,
+            "current": sample.current,
+            "charging": sample.charging,
+            "low_warning": sample.low_warning,
+            "percent_per_hour": rate,
+            "runtime_estimate_s": runtime.map(|d| d.as_secs_f64()),
+            "time_to_full_s": to_full.map(|d| d.as_secs_f64()),
+        }),
+        || {
+            let mut text = format!("Battery {:.1}%  {:.2}V", sample.percent, sample.voltage);
+            if let Some(current) = sample.current {
+                text += &format!("  {:.2}A", current);
+            }
+            if sample.charging == Some(true) {
+                text += "  charging";
+            }
+            match (rate, runtime, to_full) {
+                (Some(rate), Some(runtime), _) => {
+                    text += &format!(
+                        "  {:+.1}%/h, about {:.0} min left",
+                        rate,
+                        runtime.as_secs_f64() / 60.0
+                    )
+                }
+                (Some(rate), _, Some(to_full)) => {
+                    text += &format!(
+                        "  {:+.1}%/h, full in about {:.0} min",
+                        rate,
+                        to_full.as_secs_f64() / 60.0
+                    )
+                }
+                (Some(rate), None, None) => text += &format!("  {:+.1}%/h", rate),
+                (None, _, _) => text += "  (estimating rate)",
+            }
+            text
+        },
+    );
+}
@@ -0,0 +1,230 @@
+use crate::{parse_seconds, Context};
+use clap::Args;
+use robot_demos::proto_public_api::ReportFrequency;
+use robot_demos::{
+    run_benchmark, BenchmarkOptions, BenchmarkReport, DurationSamples, RobotTarget, Transport,
+};
+use serde_json::json;
+use std::io::Write;
+use std::time::Duration;
+
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 100.0];
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Transports to compare, e.g. ws,kcp. Defaults to --transport if given, otherwise every transport this build supports"
+    )]
+    transports: Vec<Transport>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "Rf250Hz,Rf1000Hz",
+        value_parser = parse_report_frequency,
+        help = "Report frequencies to measure inter-arrival times at"
+    )]
+    frequencies: Vec<ReportFrequency>,
+    #[arg(
+        long,
+        default_value_t = 5.0,
+        value_parser = parse_seconds,
+        help = "Seconds to measure each report frequency"
+    )]
+    seconds: f64,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "Command-to-status round trips to measure"
+    )]
+    samples: u32,
+    #[arg(long, help = "Write histograms of every measurement to this CSV file")]
+    histogram_out: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Histogram bucket width in microseconds"
+    )]
+    bucket_us: u64,
+}
+
+fn parse_report_frequency(name: &str) -> Result<ReportFrequency, String> {
+    ReportFrequency::from_str_name(name)
+        .ok_or_else(|| format!("unknown report frequency \"{}\", e.g. Rf250Hz", name))
+}
+
+fn supported_transports() -> Vec<Transport> {
+    if cfg!(feature = "kcp") {
+        vec![Transport::WebSocket, Transport::Kcp]
+    } else {
+        vec![Transport::WebSocket]
+    }
+}
+
+fn micros(duration: Option<Duration>) -> Option<f64> {
+    duration.map(|d| d.as_secs_f64() * 1e6)
+}
+
+fn samples_json(samples: &DurationSamples) -> serde_json::Value {
+    json!({
+        "count": samples.len(),
+        "min_us": micros(samples.min()),
+        "mean_us": micros(samples.mean()),
+        "p50_us": micros(samples.percentile(50.0)),
+        "p90_us": micros(samples.percentile(90.0)),
+        "p99_us": micros(samples.percentile(99.0)),
+        "p999_us": micros(samples.percentile(99.9)),
+        "max_us": micros(samples.max()),
+    })
+}
+
+fn samples_text(samples: &DurationSamples) -> String {
+    let format = |d: Option<Duration>| {
+        d.map(|d| format!("{:.3}ms", d.as_secs_f64() * 1e3))
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let mut parts = vec![
+        format!("n={}", samples.len()),
+        format!("min {}", format(samples.min())),
+    ];
+    for p in PERCENTILES {
+        let label = if p == 100.0 {
+            "max".to_string()
+        } else {
+            format!("p{}", p)
+        };
+        parts.push(format!("{} {}", label, format(samples.percentile(p))));
+    }
+    parts.join("  ")
+}
+
+fn count_text(count: Option<u64>) -> String {
+    count
+        .map(|c| c.to_string())
+        .unwrap_or_else(|| "n/a (no timestamps)".to_string())
+}
+
+fn report_json(report: &BenchmarkReport) -> serde_json::Value {
+    json!({
+        "transport": report.transport,
+        "robot_type": report.robot_type.as_str_name(),
+        "inter_arrival": report.inter_arrival.iter().map(|result| json!({
+            "report_frequency": result.report_frequency.as_str_name(),
+            "received": result.received,
+            "lost": result.lost,
+            "reordered": result.reordered,
+            "inter_arrival": samples_json(&result.inter_arrival),
+        })).collect::<Vec<_>>(),
+        "command_to_status": samples_json(&report.command_to_status),
+        "command_timeouts": report.command_timeouts,
+    })
+}
+
+fn report_text(report: &BenchmarkReport) -> String {
+    let mut lines = vec![format!(
+        "{} ({})",
+        report.transport,
+        report.robot_type.as_str_name()
+    )];
+    for result in &report.inter_arrival {
+        lines.push(format!(
+            "  {} inter-arrival: {}",
+            result.report_frequency.as_str_name(),
+            samples_text(&result.inter_arrival)
+        ));
+        lines.push(format!(
+            "  {} received {}, lost {}, reordered {}",
+            result.report_frequency.as_str_name(),
+            result.received,
+            count_text(result.lost),
+            count_text(result.reordered)
+        ));
+    }
+    lines.push(format!(
+        "  command to status: {}",
+        samples_text(&report.command_to_status)
+    ));
+    lines.push(format!("  command timeouts: {}", report.command_timeouts));
+    lines.join("\n")
+}
+
+fn write_histograms(
+    path: &str,
+    reports: &[BenchmarkReport],
+    bucket_width: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        file,
+        "transport,measurement,bucket_start_us,bucket_end_us,count"
+    )?;
+    for report in reports {
+        let mut measurements: Vec<(String, &DurationSamples)> = report
+            .inter_arrival
+            .iter()
+            .map(|result| {
+                (
+                    format!("inter_arrival_{}", result.report_frequency.as_str_name()),
+                    &result.inter_arrival,
+                )
+            })
+            .collect();
+        measurements.push(("command_to_status".to_string(), &report.command_to_status));
+        for (name, samples) in measurements {
+            for bucket in samples.histogram(bucket_width) {
+                writeln!(
+                    file,
+                    "{},{},{},{},{}",
+                    report.transport,
+                    name,
+                    bucket.start.as_micros(),
+                    bucket.end.as_micros(),
+                    bucket.count
+                )?;
+            }
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+pub async fn bench(ctx: &Context, args: BenchArgs) -> Result<(), anyhow::Error> {
+    let transports = if !args.transports.is_empty() {
+        args.transports.clone()
+    } else if let Some(transport) = ctx.transport {
+        vec![transport]
+    } else {
+        supported_transports()
+    };
+    let options = BenchmarkOptions {
+        report_frequencies: args.frequencies.clone(),
+        duration_per_frequency: Duration::from_secs_f64(args.seconds),
+        latency_samples: args.samples,
+        ..Default::default()
+    };
+
+    let mut reports = Vec::new();
+    for transport in transports {
+        let intro_text = format!(
+            "Benchmark over {}. Only the report frequency is changed, nothing moves.",
+            transport
+        );
+        let mut connection = ctx
+            .connect_over(&args.robot, &intro_text, Some(transport))
+            .await?;
+        let report = run_benchmark(connection.as_mut(), &options).await?;
+        ctx.print(report_json(&report), || report_text(&report));
+        reports.push(report);
+    }
+
+    if let Some(path) = &args.histogram_out {
+        write_histograms(path, &reports, Duration::from_micros(args.bucket_us))?;
+        log::info!("Histograms written to {}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use crate::Context;
+use clap::{Args, Subcommand};
+use robot_demos::RobotTarget;
+
+#[derive(Subcommand)]
+pub enum CanCommand {
+    /// Forward a CAN bus of the robot to a local SocketCAN interface, both ways
+    Forward(ForwardArgs),
+}
+
+#[derive(Args)]
+#[cfg_attr(not(feature = "socketcan"), allow(dead_code))]
+pub struct ForwardArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        help = "CAN bus of the robot to forward. You can only use 0,1,2."
+    )]
+    remote_bus: u8,
+    #[arg(long, help = "Local SocketCAN interface name, e.g. can0")]
+    local_bus: String,
+}
+
+#[cfg(not(feature = "socketcan"))]
+pub async fn run(_ctx: &Context, _command: CanCommand) -> Result<(), anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "CAN forwarding needs the `socketcan` feature, rebuild with --features socketcan"
+    ))
+}
+
+#[cfg(feature = "socketcan")]
+pub async fn run(ctx: &Context, command: CanCommand) -> Result<(), anyhow::Error> {
+    match command {
+        CanCommand::Forward(args) => forward(ctx, args).await,
+    }
+}
+
+#[cfg(feature = "socketcan")]
+async fn forward(ctx: &Context, args: ForwardArgs) -> Result<(), anyhow::Error> {
+    use futures_util::{SinkExt, StreamExt};
+    use log::{info, warn};
+    use robot_demos::can::{can_any_frame_to_hex, hex_to_can_any_frame};
+    use robot_demos::proto_public_api::{self, ApiDown};
+    use serde_json::json;
+    use socketcan::tokio::CanFdSocket;
+
+    let remote_bus = match args.remote_bus {
+        0 => proto_public_api::HexCanApiCanBusNumber::Hcan0,
+        1 => proto_public_api::HexCanApiCanBusNumber::Hcan1,
+        2 => proto_public_api::HexCanApiCanBusNumber::Hcan2,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Invalid remote CAN bus number: {}",
+                args.remote_bus
+            ))
+        }
+    };
+    let (mut local_tx, mut local_rx) = CanFdSocket::open(&args.local_bus)?.split();
+
+    let intro_text = format!(
+        "Forward CAN bus {} of the robot to local {}.",
+        args.remote_bus, args.local_bus
+    );
+    let mut connection = ctx.connect(&args.robot, &intro_text).await?;
+    let mut stream = connection.take_stream().unwrap();
+    info!("Forwarding, press Ctrl-C to stop");
+
+    let (mut to_local, mut to_remote) = (0u64, 0u64);
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if let Some(proto_public_api::api_up::Status::HexCanApiCanAnyFrames(frames)) = msg.status {
+                    for frame in frames.frames {
+                        if frame.bus_number() != remote_bus {
+                            continue;
+                        }
+                        match hex_to_can_any_frame(frame) {
+                            Ok((frame, _)) => {
+                                local_tx.send(frame).await?;
+                                to_local += 1;
+                            }
+                            Err(e) => warn!("Dropping frame from robot: {}", e),
+                        }
+                    }
+                }
+            }
+            frame = local_rx.next() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                match can_any_frame_to_hex(frame?, remote_bus) {
+                    Ok(frame) => {
+                        connection.send(ApiDown::hex_can_any_frame(frame)).await?;
+                        to_remote += 1;
+                    }
+                    Err(e) => warn!("Dropping local frame: {}", e),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    ctx.print(
+        json!({ "to_local": to_local, "to_remote": to_remote }),
+        || {
+            format!(
+                "Forwarded {} frames to {}, {} frames to the robot",
+                to_local, args.local_bus, to_remote
+            )
+        },
+    );
+    Ok(())
+}
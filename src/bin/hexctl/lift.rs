@@ -0,0 +1,194 @@
+use crate::Context;
+use clap::{Args, Subcommand};
+use robot_demos::proto_public_api::{self, ApiDown, SingleMotorTarget};
+use robot_demos::{RobotStateCache, RobotTarget};
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub enum LiftCommand {
+    /// Move to a percentage of the max position
+    Move(LiftMoveArgs),
+}
+
+#[derive(Args)]
+pub struct LiftMoveArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(long, help = "Target, in percent of the max position (0 to 100)")]
+    percent: f64,
+    #[arg(
+        long,
+        default_value_t = 0.9,
+        help = "Fraction of the max speed to move at (0 to 1)"
+    )]
+    speed_factor: f64,
+    #[arg(long, help = "Calibrate before moving")]
+    re_calibrate: bool,
+    #[arg(long, default_value_t = 30, help = "Give up after this many seconds")]
+    timeout: u64,
+}
+
+#[derive(Subcommand)]
+pub enum RotliftCommand {
+    /// Move every joint back to position zero
+    Zero(RotliftZeroArgs),
+}
+
+#[derive(Args)]
+pub struct RotliftZeroArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        default_value_t = 0.2,
+        help = "Done once every joint is within this many degrees of zero"
+    )]
+    tolerance_deg: f64,
+    #[arg(long, default_value_t = 30, help = "Give up after this many seconds")]
+    timeout: u64,
+}
+
+pub async fn run_lift(ctx: &Context, command: LiftCommand) -> Result<(), anyhow::Error> {
+    match command {
+        LiftCommand::Move(args) => lift_move(ctx, args).await,
+    }
+}
+
+pub async fn run_rotlift(ctx: &Context, command: RotliftCommand) -> Result<(), anyhow::Error> {
+    match command {
+        RotliftCommand::Zero(args) => rotlift_zero(ctx, args).await,
+    }
+}
+
+async fn lift_move(ctx: &Context, args: LiftMoveArgs) -> Result<(), anyhow::Error> {
+    if !(0.0..=100.0).contains(&args.percent) {
+        return Err(anyhow::anyhow!(
+            "Percent must be between 0 and 100, got {}",
+            args.percent
+        ));
+    }
+    if !(args.speed_factor > 0.0 && args.speed_factor <= 1.0) {
+        return Err(anyhow::anyhow!(
+            "Speed factor must be between 0 and 1, got {}",
+            args.speed_factor
+        ));
+    }
+    let intro_text = format!("Move the lift to {}% of its max position.", args.percent);
+    let mut connection = ctx.connect(&args.robot, &intro_text).await?;
+    let cache = RobotStateCache::new();
+    cache.spawn(connection.take_stream().unwrap());
+    connection
+        .send(ApiDown::set_report_frequency(
+            proto_public_api::ReportFrequency::Rf50Hz,
+        ))
+        .await?;
+    if args.re_calibrate {
+        connection.send(ApiDown::linear_lift_calibrate()).await?;
+    }
+
+    let mut status = cache.subscribe_linear_lift();
+    let (max_pos, max_speed) = {
+        let status = tokio::time::timeout(
+            Duration::from_secs(args.timeout),
+            status.wait_for(|status| status.as_ref().is_some_and(|status| status.calibrated)),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Lift is not calibrated, try --re-calibrate"))??;
+        let status = status.as_ref().unwrap();
+        (status.max_pos, status.max_speed)
+    };
+    let target = (args.percent / 100.0 * max_pos as f64) as i64;
+    connection
+        .send(ApiDown::linear_lift_set_speed(
+            (max_speed as f64 * args.speed_factor) as u32,
+        ))
+        .await?;
+
+    // Close enough is one thousandth of the travel.
+    let tolerance = (max_pos / 1000).max(1);
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    let start_time = tokio::time::Instant::now();
+    let reached = loop {
+        interval.tick().await;
+        let current = status
+            .borrow()
+            .as_ref()
+            .map_or(0, |status| status.current_pos);
+        if (current - target).abs() <= tolerance {
+            break true;
+        }
+        if start_time.elapsed() > Duration::from_secs(args.timeout) {
+            break false;
+        }
+        connection
+            .send(ApiDown::linear_lift_target_pos(target))
+            .await?;
+    };
+
+    let current = status
+        .borrow()
+        .as_ref()
+        .map_or(0, |status| status.current_pos);
+    ctx.print(
+        json!({ "target_pos": target, "current_pos": current, "max_pos": max_pos, "reached": reached }),
+        || format!("Lift at {}/{} (target {}), {}", current, max_pos, target, if reached { "reached" } else { "TIMED OUT" }),
+    );
+    if !reached {
+        return Err(anyhow::anyhow!(
+            "Lift did not reach the target within {} seconds",
+            args.timeout
+        ));
+    }
+    Ok(())
+}
+
+async fn rotlift_zero(ctx: &Context, args: RotliftZeroArgs) -> Result<(), anyhow::Error> {
+    let mut connection = ctx
+        .connect(&args.robot, "Move the rotational lift back to zero.")
+        .await?;
+    let cache = RobotStateCache::new();
+    cache.spawn(connection.take_stream().unwrap());
+    connection
+        .send(ApiDown::set_report_frequency(
+            proto_public_api::ReportFrequency::Rf250Hz,
+        ))
+        .await?;
+
+    let mut status = cache.subscribe_rotate_lift();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+    let errors_deg = loop {
+        // One command per status, so we don't flood the lift.
+        tokio::time::timeout_at(deadline, status.changed())
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Rotational lift did not reach zero within {} seconds",
+                    args.timeout
+                )
+            })??;
+        let motor_status = match status.borrow_and_update().as_ref() {
+            Some(status) if !status.motor_status.is_empty() => status.motor_status.clone(),
+            _ => continue,
+        };
+        let errors_deg: Vec<f64> = motor_status
+            .iter()
+            .map(|motor| motor.position as f64 / motor.pulse_per_rotation as f64 * 360.0)
+            .collect();
+        if errors_deg.iter().all(|err| err.abs() <= args.tolerance_deg) {
+            break errors_deg;
+        }
+        connection
+            .send(ApiDown::rotate_lift_motor_targets(
+                (0..motor_status.len())
+                    .map(|_| SingleMotorTarget::position(0))
+                    .collect(),
+            ))
+            .await?;
+    };
+
+    ctx.print(json!({ "errors_deg": errors_deg }), || {
+        format!("Rotational lift at zero, errors {:?} degrees", errors_deg)
+    });
+    Ok(())
+}
@@ -0,0 +1,280 @@
+//! One command-line tool for everything the examples do.
+//!
+//! ```bash
+//! hexctl discover
+//! hexctl status 172.18.23.92
+//! hexctl --transport kcp base move 172.18.23.92 --wz 0.1 --seconds 10
+//! hexctl base move --profile lab-base-1 --wz 0.1 --seconds 10
+//! hexctl battery 172.18.23.92 --cycle-log cycles.csv
+//! ```
+
+mod arm;
+mod base;
+mod battery;
+mod bench;
+mod can;
+mod lift;
+mod record;
+mod status;
+
+use clap::{Args, Parser, Subcommand};
+use robot_demos::proto_public_api::ParkingStopCategory;
+use robot_demos::{
+    confirm_and_continue, init_logger, ApiUpStream, ControlSession, ControlTarget,
+    ParkingStopHandler, ParkingStopPolicy, RobotConnection, RobotTarget, Transport,
+};
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(name = "hexctl", about = "Control and inspect HexFellow robots")]
+struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "Transport to use. Defaults to the profile's, then ws. kcp needs the `kcp` feature"
+    )]
+    transport: Option<Transport>,
+    #[arg(
+        long,
+        global = true,
+        help = "Don't ask for confirmation before connecting"
+    )]
+    yes: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Print machine readable JSON, one object per line, instead of text"
+    )]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Find devices on the local network using mDNS
+    Discover(status::DiscoverArgs),
+    /// Print the status of a robot
+    Status(status::StatusArgs),
+    /// Control a base
+    #[command(subcommand)]
+    Base(base::BaseCommand),
+    /// Monitor the battery of a base, with alerts and a charge cycle log
+    Battery(battery::BatteryArgs),
+    /// Control an arm
+    #[command(subcommand)]
+    Arm(arm::ArmCommand),
+    /// Control a linear lift
+    #[command(subcommand)]
+    Lift(lift::LiftCommand),
+    /// Control a rotational lift
+    #[command(subcommand)]
+    Rotlift(lift::RotliftCommand),
+    /// Bridge CAN buses
+    #[command(subcommand)]
+    Can(can::CanCommand),
+    /// Measure latency and message loss over websocket and KCP
+    Bench(bench::BenchArgs),
+    /// Record everything a robot sends to a file
+    Record(record::RecordArgs),
+    /// Print a recording with its original timing
+    Replay(record::ReplayArgs),
+}
+
+/// Whether to clear a parking stop before taking control. Shared by every subcommand that takes control.
+#[derive(Args)]
+struct ParkingStopArgs {
+    #[arg(
+        long,
+        help = "Clear a parking stop before starting, if it is remotely clearable and its category is allowed"
+    )]
+    clear_parking_stop: bool,
+    #[arg(
+        long = "allow-clear",
+        value_parser = parse_parking_stop_category,
+        requires = "clear_parking_stop",
+        help = "Also allow clearing this parking stop category (e.g. PscBmsTimeout). Only PscAPICommunicationTimeout is allowed by default"
+    )]
+    allow_clear: Vec<ParkingStopCategory>,
+}
+
+fn parse_parking_stop_category(name: &str) -> Result<ParkingStopCategory, String> {
+    ParkingStopCategory::from_str_name(name)
+        .ok_or_else(|| format!("Unknown parking stop category {}", name))
+}
+
+/// Parses a `--seconds` value, which must turn into a `Duration`: not negative, NaN or infinite.
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    let seconds: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("Expected a non-negative number of seconds, got {}", value))?;
+    Ok(seconds)
+}
+
+impl ParkingStopArgs {
+    fn policy(&self) -> ParkingStopPolicy {
+        self.allow_clear
+            .iter()
+            .fold(ParkingStopPolicy::default(), |policy, category| {
+                policy.allow(*category)
+            })
+    }
+
+    /// Takes the stream of a freshly started `session`. With `--clear-parking-stop`, first clears its parking stop
+    /// as far as the policy allows, and fails if it is one that is not cleared.
+    async fn settle(&self, session: &mut ControlSession) -> Result<ApiUpStream, anyhow::Error> {
+        let mut stream = session.take_stream().unwrap();
+        if self.clear_parking_stop {
+            let clears = ParkingStopHandler::new(self.policy())
+                .settle(
+                    session,
+                    &mut stream,
+                    Duration::from_secs(5),
+                    |session, msg| session.send(msg),
+                )
+                .await?;
+            if clears > 0 {
+                // A parking stop ends API control, so start it again.
+                let initialize = session.target().api_control_initialize(true);
+                session.send(initialize).await?;
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// Getting control from another session, and handing it over. Shared by every subcommand that takes control.
+#[derive(Args)]
+struct TakeoverArgs {
+    #[arg(
+        long,
+        requires = "takeover_token",
+        help = "If another session holds control, ask it to hand over and wait, instead of failing on the first move"
+    )]
+    take_over: bool,
+    #[arg(
+        long,
+        default_value_t = 10,
+        requires = "take_over",
+        help = "How long to wait for the other session to hand over, in seconds"
+    )]
+    takeover_timeout: u64,
+    #[arg(
+        long,
+        requires = "takeover_token",
+        help = "Hand over control when another session with the same --takeover-token asks to take over"
+    )]
+    accept_takeover: bool,
+    #[arg(
+        long,
+        help = "Shared secret that signs takeover requests. Needed by both sides, with --take-over and --accept-takeover"
+    )]
+    takeover_token: Option<String>,
+}
+
+impl TakeoverArgs {
+    /// Connects to `robot` and starts a control session of `target`, taking over from another session with
+    /// `--take-over`.
+    async fn start(
+        &self,
+        ctx: &Context,
+        robot: &RobotTarget,
+        intro_text: &str,
+        target: ControlTarget,
+    ) -> Result<ControlSession, anyhow::Error> {
+        let address = robot.resolve()?.address;
+        let connection = ctx.connect(robot, intro_text).await?;
+        // Deinitializes when done, on Ctrl-C and on panic. Nothing else to clean up, so Ctrl-C can just exit.
+        ControlSession::exit_on_ctrl_c();
+        let token = self.takeover_token.as_deref().unwrap_or_default();
+        let session = if self.take_over {
+            let timeout = Duration::from_secs(self.takeover_timeout);
+            ControlSession::take_over(connection, target, &address, token, timeout).await?
+        } else {
+            ControlSession::new(connection, target).await?
+        };
+        if self.accept_takeover {
+            if let Err(e) = session.accept_takeover(&address, token).await {
+                log::warn!("{}, other sessions can not take over", e);
+            }
+        }
+        Ok(session)
+    }
+}
+
+/// Options every subcommand gets.
+struct Context {
+    transport: Option<Transport>,
+    yes: bool,
+    json: bool,
+}
+
+impl Context {
+    /// Asks for confirmation unless `--yes`, then connects. Fails if the robot is not the type the profile expects.
+    async fn connect(
+        &self,
+        robot: &RobotTarget,
+        intro_text: &str,
+    ) -> Result<Box<dyn RobotConnection>, anyhow::Error> {
+        self.connect_over(robot, intro_text, self.transport).await
+    }
+
+    /// Same as [`Context::connect`], but over `transport` instead of `--transport`.
+    async fn connect_over(
+        &self,
+        robot: &RobotTarget,
+        intro_text: &str,
+        transport: Option<Transport>,
+    ) -> Result<Box<dyn RobotConnection>, anyhow::Error> {
+        let mut profile = robot.resolve()?;
+        if let Some(transport) = transport {
+            profile.transport = Some(transport);
+        }
+        if !self.yes {
+            confirm_and_continue(intro_text, &profile.address, profile.port).await;
+        }
+        let connection = profile.connect().await?;
+        log::info!(
+            "Connected to {:?} over {}, session {}",
+            connection.robot_type(),
+            connection.transport(),
+            connection.session_id()
+        );
+        Ok(connection)
+    }
+
+    /// Prints `value` as one line of JSON with `--json`, `text` otherwise.
+    fn print(&self, value: serde_json::Value, text: impl FnOnce() -> String) {
+        if self.json {
+            println!("{}", value);
+        } else {
+            println!("{}", text());
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    init_logger();
+    let cli = Cli::parse();
+    let ctx = Context {
+        transport: cli.transport,
+        yes: cli.yes,
+        json: cli.json,
+    };
+
+    match cli.command {
+        Command::Discover(args) => status::discover(&ctx, args).await,
+        Command::Status(args) => status::status(&ctx, args).await,
+        Command::Base(command) => base::run(&ctx, command).await,
+        Command::Battery(args) => battery::battery(&ctx, args).await,
+        Command::Arm(command) => arm::run(&ctx, command).await,
+        Command::Lift(command) => lift::run_lift(&ctx, command).await,
+        Command::Rotlift(command) => lift::run_rotlift(&ctx, command).await,
+        Command::Can(command) => can::run(&ctx, command).await,
+        Command::Bench(args) => bench::bench(&ctx, args).await,
+        Command::Record(args) => record::record(&ctx, args).await,
+        Command::Replay(args) => record::replay(&ctx, args).await,
+    }
+}
@@ -0,0 +1,106 @@
+use crate::status::{api_up_json, api_up_text};
+use crate::Context;
+use clap::Args;
+use futures_util::StreamExt;
+use log::info;
+use robot_demos::{
+    read_recording, RecordedDirection, Recorder, RecordingConnection, RobotConnection, RobotTarget,
+    Transport,
+};
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct RecordArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(long, default_value_t = 10, help = "How many seconds to record")]
+    seconds: u64,
+    #[arg(
+        long,
+        short,
+        default_value = "session.rec",
+        help = "File to write the recording to"
+    )]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    #[arg(help = "Recording file to replay")]
+    input: String,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Playback speed, e.g. 2 replays twice as fast. Use inf to replay as fast as possible"
+    )]
+    speed: f64,
+}
+
+pub async fn record(ctx: &Context, args: RecordArgs) -> Result<(), anyhow::Error> {
+    let intro_text = format!(
+        "Record everything it sends to {} for {} seconds, without sending any command.",
+        args.output, args.seconds
+    );
+    let connection = ctx.connect(&args.robot, &intro_text).await?;
+    let recorder = Recorder::create(&args.output)?;
+    let mut connection = RecordingConnection::new(connection, recorder);
+
+    // The recording happens while the stream is polled.
+    let mut stream = connection.take_stream().unwrap();
+    let mut count = 0u64;
+    let deadline = tokio::time::sleep(Duration::from_secs(args.seconds));
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(_) => count += 1,
+                None => {
+                    info!("Connection lost");
+                    break;
+                }
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    connection.recorder().flush()?;
+    ctx.print(json!({ "output": args.output, "messages": count }), || {
+        format!("Recorded {} messages to {}", count, args.output)
+    });
+    Ok(())
+}
+
+pub async fn replay(ctx: &Context, args: ReplayArgs) -> Result<(), anyhow::Error> {
+    let messages = read_recording(&args.input)?;
+    info!("Replaying {} messages from {}", messages.len(), args.input);
+
+    let mut stream = robot_demos::replay(messages, args.speed)?;
+    while let Some(msg) = stream.next().await {
+        let transport = Transport::from(msg.transport());
+        let elapsed = msg.elapsed().as_secs_f64();
+        // Goes through the same decode path as live traffic.
+        match msg.direction() {
+            RecordedDirection::Up => match msg.decode_api_up() {
+                Ok(up) => ctx.print(
+                    json!({ "elapsed": elapsed, "transport": transport, "direction": "up", "message": api_up_json(&up) }),
+                    || format!("[{:.3}s] {} up\n{}", elapsed, transport, api_up_text(&up)),
+                ),
+                Err(e) => ctx.print(
+                    json!({ "elapsed": elapsed, "transport": transport, "direction": "up", "error": e.to_string() }),
+                    || format!("[{:.3}s] {} up, failed to decode: {}", elapsed, transport, e),
+                ),
+            },
+            RecordedDirection::Down => match msg.decode_api_down() {
+                Ok(down) => ctx.print(
+                    json!({ "elapsed": elapsed, "transport": transport, "direction": "down", "message": format!("{:?}", down.down) }),
+                    || format!("[{:.3}s] {} down: {:?}", elapsed, transport, down.down),
+                ),
+                Err(e) => ctx.print(
+                    json!({ "elapsed": elapsed, "transport": transport, "direction": "down", "error": e.to_string() }),
+                    || format!("[{:.3}s] {} down, failed to decode: {}", elapsed, transport, e),
+                ),
+            },
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use crate::Context;
+use clap::Args;
+use futures_util::StreamExt;
+use robot_demos::proto_public_api::{self, api_up::Status};
+use robot_demos::{scan_devices, RobotTarget};
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct DiscoverArgs {
+    #[arg(long, default_value_t = 3, help = "How many seconds to scan for")]
+    seconds: u64,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    robot: RobotTarget,
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Give up if no status arrives within this many seconds"
+    )]
+    timeout: u64,
+}
+
+pub async fn discover(ctx: &Context, args: DiscoverArgs) -> Result<(), anyhow::Error> {
+    let devices = scan_devices(Duration::from_secs(args.seconds)).await?;
+    for device in &devices {
+        ctx.print(
+            json!({
+                "hostname": device.hostname,
+                "port": device.port,
+                "connect_url": device.connect_url(),
+                "ipv4": device.ipv4,
+                "ipv6": device
+                    .ipv6_with_scope_id
+                    .iter()
+                    .map(|(addr, scope_id)| json!({ "addr": addr, "scope_id": scope_id }))
+                    .collect::<Vec<_>>(),
+                "main_robot_type": device.main_robot_type.as_str_name(),
+                "main_robot_type_name": device.main_robot_type_name,
+                "secondary_robot_type": device.secondary_robot_type.as_str_name(),
+                "secondary_robot_type_name": device.secondary_robot_type_name,
+            }),
+            || {
+                format!(
+                    "{}  {}  {}",
+                    device.hostname,
+                    device.main_robot_type_name,
+                    device
+                        .connect_url()
+                        .map(|url| format!("{} {}", url, device.port))
+                        .unwrap_or_else(|| "<no usable address>".to_string())
+                )
+            },
+        );
+    }
+    if !ctx.json {
+        println!("{} device(s) found", devices.len());
+    }
+    Ok(())
+}
+
+pub async fn status(ctx: &Context, args: StatusArgs) -> Result<(), anyhow::Error> {
+    let mut connection = ctx
+        .connect(
+            &args.robot,
+            "Print its status, without sending any command.",
+        )
+        .await?;
+    let mut stream = connection.take_stream().unwrap();
+    let msg = tokio::time::timeout(Duration::from_secs(args.timeout), async {
+        while let Some(msg) = stream.next().await {
+            if msg.status.is_some() {
+                return Some(msg);
+            }
+        }
+        None
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("No status within {} seconds", args.timeout))?
+    .ok_or_else(|| anyhow::anyhow!("Connection lost before any status arrived"))?;
+
+    ctx.print(api_up_json(&msg), || api_up_text(&msg));
+    Ok(())
+}
+
+fn parking_stop_json(detail: &Option<proto_public_api::ParkingStopDetail>) -> serde_json::Value {
+    match detail {
+        Some(detail) => json!({
+            "reason": detail.reason,
+            "category": detail.category().as_str_name(),
+            "is_remotely_clearable": detail.is_remotely_clearable,
+        }),
+        None => serde_json::Value::Null,
+    }
+}
+
+fn motors_json(motors: &[proto_public_api::MotorStatus]) -> serde_json::Value {
+    motors
+        .iter()
+        .map(|motor| {
+            json!({
+                "position": motor.position,
+                "speed": motor.speed,
+                "torque": motor.torque,
+                "pulse_per_rotation": motor.pulse_per_rotation,
+                "error": motor.error().map(|e| e.as_str_name()).collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+/// Summary of an `ApiUp`, for `--json`.
+pub fn api_up_json(msg: &proto_public_api::ApiUp) -> serde_json::Value {
+    let status = match &msg.status {
+        Some(Status::BaseStatus(s)) => json!({
+            "kind": "base",
+            "state": s.state().as_str_name(),
+            "api_control_initialized": s.api_control_initialized,
+            "session_holder": s.session_holder,
+            "battery_voltage": s.battery_voltage,
+            "battery_thousandth": s.battery_thousandth,
+            "battery_charging": s.battery_charging,
+            "parking_stop": parking_stop_json(&s.parking_stop_detail),
+            "odometry": s.estimated_odometry.as_ref().map(|o| json!({
+                "pos_x": o.pos_x, "pos_y": o.pos_y, "pos_z": o.pos_z,
+                "speed_x": o.speed_x, "speed_y": o.speed_y, "speed_z": o.speed_z,
+            })),
+            "motors": motors_json(&s.motor_status),
+        }),
+        Some(Status::ArmStatus(s)) => json!({
+            "kind": "arm",
+            "api_control_initialized": s.api_control_initialized,
+            "calibrated": s.calibrated,
+            "session_holder": s.session_holder,
+            "parking_stop": parking_stop_json(&s.parking_stop_detail),
+            "motors": motors_json(&s.motor_status),
+        }),
+        Some(Status::LinearLiftStatus(s)) => json!({
+            "kind": "linear_lift",
+            "state": s.state().as_str_name(),
+            "calibrated": s.calibrated,
+            "current_pos": s.current_pos,
+            "max_pos": s.max_pos,
+            "speed": s.speed,
+            "max_speed": s.max_speed,
+            "pulse_per_rotation": s.pulse_per_rotation,
+            "parking_stop": parking_stop_json(&s.parking_stop_detail),
+        }),
+        Some(Status::RotateLiftStatus(s)) => json!({
+            "kind": "rotate_lift",
+            "state": s.state().as_str_name(),
+            "calibrated": s.calibrated,
+            "session_holder": s.session_holder,
+            "parking_stop": parking_stop_json(&s.parking_stop_detail),
+            "motors": motors_json(&s.motor_status),
+        }),
+        Some(Status::HexCanApiCanAnyFrames(frames)) => json!({
+            "kind": "can_frames",
+            "count": frames.frames.len(),
+        }),
+        None => serde_json::Value::Null,
+    };
+    json!({
+        "robot_type": msg.robot_type().as_str_name(),
+        "session_id": msg.session_id,
+        "protocol_version": format!("{}.{}", msg.protocol_major_version, msg.protocol_minor_version),
+        "report_frequency": msg.report_frequency().as_str_name(),
+        "main_bus_voltage": msg.main_bus_voltage,
+        "status": status,
+        "secondary_devices": msg.secondary_device_status.len(),
+        "log": msg.log,
+    })
+}
+
+/// Summary of an `ApiUp`, for people.
+pub fn api_up_text(msg: &proto_public_api::ApiUp) -> String {
+    let mut lines = vec![format!(
+        "{} (session {}, protocol {}.{})",
+        msg.robot_type().as_str_name(),
+        msg.session_id,
+        msg.protocol_major_version,
+        msg.protocol_minor_version
+    )];
+    let parking_stop = |detail: &Option<proto_public_api::ParkingStopDetail>| match detail {
+        Some(detail) => format!(
+            "PARKING STOP: {} ({}, {})",
+            detail.reason,
+            detail.category().as_str_name(),
+            if detail.is_remotely_clearable {
+                "remotely clearable"
+            } else {
+                "not remotely clearable"
+            }
+        ),
+        None => "no parking stop".to_string(),
+    };
+    match &msg.status {
+        Some(Status::BaseStatus(s)) => {
+            lines.push(format!(
+                "Base: {}, api control {}, held by session {}",
+                s.state().as_str_name(),
+                if s.api_control_initialized {
+                    "initialized"
+                } else {
+                    "not initialized"
+                },
+                s.session_holder
+            ));
+            lines.push(format!(
+                "Battery: {:.1}% {:.2}V{}",
+                s.battery_thousandth as f64 / 10.0,
+                s.battery_voltage,
+                if s.battery_charging == Some(true) {
+                    ", charging"
+                } else {
+                    ""
+                }
+            ));
+            if let Some(o) = &s.estimated_odometry {
+                lines.push(format!(
+                    "Odometry: x {:.3} y {:.3} yaw {:.3}, speed x {:.3} y {:.3} yaw {:.3}",
+                    o.pos_x, o.pos_y, o.pos_z, o.speed_x, o.speed_y, o.speed_z
+                ));
+            }
+            lines.push(parking_stop(&s.parking_stop_detail));
+        }
+        Some(Status::ArmStatus(s)) => {
+            lines.push(format!(
+                "Arm: {} motors, {}, api control {}, held by session {}",
+                s.motor_status.len(),
+                if s.calibrated {
+                    "calibrated"
+                } else {
+                    "NOT calibrated"
+                },
+                if s.api_control_initialized {
+                    "initialized"
+                } else {
+                    "not initialized"
+                },
+                s.session_holder
+            ));
+            lines.push(format!(
+                "Positions: {:?}",
+                s.motor_status
+                    .iter()
+                    .map(|m| m.position)
+                    .collect::<Vec<_>>()
+            ));
+            lines.push(parking_stop(&s.parking_stop_detail));
+        }
+        Some(Status::LinearLiftStatus(s)) => {
+            lines.push(format!(
+                "Linear lift: {}, {}, position {}/{}, speed {}/{}",
+                s.state().as_str_name(),
+                if s.calibrated {
+                    "calibrated"
+                } else {
+                    "NOT calibrated"
+                },
+                s.current_pos,
+                s.max_pos,
+                s.speed,
+                s.max_speed
+            ));
+            lines.push(parking_stop(&s.parking_stop_detail));
+        }
+        Some(Status::RotateLiftStatus(s)) => {
+            lines.push(format!(
+                "Rotational lift: {}, {}, positions {:?}",
+                s.state().as_str_name(),
+                if s.calibrated {
+                    "calibrated"
+                } else {
+                    "NOT calibrated"
+                },
+                s.motor_status
+                    .iter()
+                    .map(|m| m.position)
+                    .collect::<Vec<_>>()
+            ));
+            lines.push(parking_stop(&s.parking_stop_detail));
+        }
+        Some(Status::HexCanApiCanAnyFrames(frames)) => {
+            lines.push(format!("{} CAN frames", frames.frames.len()));
+        }
+        None => lines.push("No status".to_string()),
+    }
+    if let Some(log) = &msg.log {
+        lines.push(format!("Log: {}", log));
+    }
+    lines.join("\n")
+}
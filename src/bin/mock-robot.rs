@@ -0,0 +1,101 @@
+use clap::Parser;
+use log::{debug, info};
+use robot_demos::proto_public_api::RobotType;
+use robot_demos::{init_logger, MockRobot, MockRobotConfig};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A fake robot on localhost, to run the demos without a real one.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1", help = "Address to listen on")]
+    bind: IpAddr,
+    #[arg(long, default_value_t = 8439, help = "Port to listen on")]
+    port: u16,
+    #[arg(
+        long,
+        default_value = "RtMaverX4",
+        help = "Robot type to report, as named in the proto (e.g. RtMaverX4, RtArmSaberD6X, RtLotaLinearLift, RtZeta3Lift)"
+    )]
+    robot_type: String,
+    #[arg(long, default_value_t = 1, help = "Session ID of the first connection")]
+    session_id: u32,
+    #[arg(
+        long,
+        help = "Protocol major version to report. Defaults to the current one"
+    )]
+    protocol_major_version: Option<u32>,
+    #[arg(
+        long,
+        help = "Protocol minor version to report. Defaults to the current one"
+    )]
+    protocol_minor_version: Option<u32>,
+    #[arg(
+        long,
+        help = "Number of motors to report. Defaults to a sensible number for the robot type"
+    )]
+    motor_count: Option<usize>,
+    #[arg(
+        long,
+        help = "Enter parking stop if the session holder is silent for this many milliseconds"
+    )]
+    api_timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "How fast the battery drains, in thousandths per minute"
+    )]
+    battery_drain_per_minute: f64,
+}
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+    let args = Args::parse();
+
+    let robot_type = RobotType::from_str_name(&args.robot_type)
+        .unwrap_or_else(|| panic!("Unknown robot type {}", args.robot_type));
+    let mut config = MockRobotConfig {
+        robot_type,
+        first_session_id: args.session_id,
+        motor_count: args.motor_count,
+        api_communication_timeout: args.api_timeout_ms.map(Duration::from_millis),
+        battery_drain_per_minute: args.battery_drain_per_minute,
+        ..Default::default()
+    };
+    if let Some(version) = args.protocol_major_version {
+        config.protocol_major_version = version;
+    }
+    if let Some(version) = args.protocol_minor_version {
+        config.protocol_minor_version = version;
+    }
+
+    let robot = MockRobot::start(SocketAddr::new(args.bind, args.port), config)
+        .await
+        .expect("Failed to start mock robot");
+    info!(
+        "Mock {} robot running on {}, press Ctrl-C to stop",
+        robot_type.as_str_name(),
+        robot.local_addr()
+    );
+
+    // Print what arrives, so you can see what your program sent.
+    let mut count = 0;
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let received = robot.take_received();
+                for msg in received.iter() {
+                    debug!(
+                        "[{:?}] session {} over {:?}: {:?}",
+                        msg.received_at, msg.session_id, msg.transport, msg.message.down
+                    );
+                }
+                count += received.len();
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    info!("Mock robot received {} messages", count);
+}
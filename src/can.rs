@@ -0,0 +1,221 @@
+//! Conversion between SocketCAN frames and the CAN frames of the API (`HexCanApiCanAnyFrame`).
+
+use crate::proto_public_api;
+use socketcan::{CanAnyFrame, CanDataFrame, CanFdFrame, EmbeddedFrame, ExtendedId, Id, StandardId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameConversionError {
+    UnsupportedFrameType,
+    InvalidFrame(String),
+}
+
+impl std::fmt::Display for FrameConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameConversionError::UnsupportedFrameType => {
+                write!(f, "UnsupportedFrameType")
+            }
+            FrameConversionError::InvalidFrame(msg) => {
+                write!(f, "InvalidFrame: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameConversionError {}
+
+// Helper function to convert socketcan::Id to HexCanApiCanId
+fn id_to_hex_can_api_id(id: &Id) -> crate::proto_public_api::HexCanApiCanId {
+    match id {
+        Id::Standard(standard_id) => crate::proto_public_api::HexCanApiCanId {
+            id: Some(crate::proto_public_api::hex_can_api_can_id::Id::StandardId(
+                standard_id.as_raw() as u32,
+            )),
+        },
+        Id::Extended(extended_id) => crate::proto_public_api::HexCanApiCanId {
+            id: Some(crate::proto_public_api::hex_can_api_can_id::Id::ExtendedId(
+                extended_id.as_raw(),
+            )),
+        },
+    }
+}
+
+// Helper function to convert HexCanApiCanId to socketcan::Id
+fn hex_can_api_id_to_id(
+    hex_id: &crate::proto_public_api::HexCanApiCanId,
+) -> Result<Id, FrameConversionError> {
+    match hex_id.id.as_ref() {
+        Some(crate::proto_public_api::hex_can_api_can_id::Id::StandardId(id)) => {
+            let id = *id as u16;
+            StandardId::new(id).map(Id::Standard).ok_or_else(|| {
+                FrameConversionError::InvalidFrame(format!("Invalid standard ID: {}", id))
+            })
+        }
+        Some(crate::proto_public_api::hex_can_api_can_id::Id::ExtendedId(id)) => {
+            ExtendedId::new(*id).map(Id::Extended).ok_or_else(|| {
+                FrameConversionError::InvalidFrame(format!("Invalid extended ID: {}", id))
+            })
+        }
+        None => Err(FrameConversionError::InvalidFrame("Missing ID".to_string())),
+    }
+}
+
+/// Convert a CanAnyFrame to HexCanApiCanAnyFrame with the specified bus number.
+///
+/// # Arguments
+/// * `frame` - The CAN frame to convert
+/// * `bus_number` - The CAN bus number to assign to the converted frame
+///
+/// # Returns
+/// * `Ok(HexCanApiCanAnyFrame)` - The converted frame with bus_number set
+/// * `Err(FrameConversionError)` - If conversion fails
+pub fn can_any_frame_to_hex(
+    frame: CanAnyFrame,
+    bus_number: proto_public_api::HexCanApiCanBusNumber,
+) -> Result<proto_public_api::HexCanApiCanAnyFrame, FrameConversionError> {
+    match frame {
+        CanAnyFrame::Normal(data_frame) => {
+            let id = data_frame.id();
+            let data = data_frame.data().to_vec();
+
+            // Validate data length for regular CAN frame (max 8 bytes)
+            if data.len() > 8 {
+                return Err(FrameConversionError::InvalidFrame(format!(
+                    "Regular CAN frame data length {} exceeds maximum of 8 bytes",
+                    data.len()
+                )));
+            }
+
+            Ok(proto_public_api::HexCanApiCanAnyFrame {
+                bus_number: bus_number as i32,
+                frame: Some(
+                    proto_public_api::hex_can_api_can_any_frame::Frame::CanDataFrame(
+                        proto_public_api::HexCanApiCanDataFrame {
+                            id: Some(id_to_hex_can_api_id(&id)),
+                            data,
+                        },
+                    ),
+                ),
+            })
+        }
+        CanAnyFrame::Fd(fd_frame) => {
+            let id = fd_frame.id();
+            let data = fd_frame.data().to_vec();
+
+            // Validate data length for CAN FD frame (max 64 bytes)
+            if data.len() > 64 {
+                return Err(FrameConversionError::InvalidFrame(format!(
+                    "CAN FD frame data length {} exceeds maximum of 64 bytes",
+                    data.len()
+                )));
+            }
+
+            // Extract BRS flag from FD frame flags
+            // Note: socketcan::CanFdFrame doesn't expose flags() method directly.
+            // We default to false, which is safe as frames without BRS will work correctly.
+            // The BRS flag will be preserved when converting from HexCanApiCanAnyFrame to CanAnyFrame.
+            let brs = false;
+
+            Ok(proto_public_api::HexCanApiCanAnyFrame {
+                bus_number: bus_number as i32,
+                frame: Some(
+                    proto_public_api::hex_can_api_can_any_frame::Frame::CanFdFrame(
+                        proto_public_api::HexCanApiCanFdFrame {
+                            id: Some(id_to_hex_can_api_id(&id)),
+                            data,
+                            brs,
+                        },
+                    ),
+                ),
+            })
+        }
+        CanAnyFrame::Remote(_) | CanAnyFrame::Error(_) => {
+            Err(FrameConversionError::UnsupportedFrameType)
+        }
+    }
+}
+
+/// Convert a HexCanApiCanAnyFrame to CanAnyFrame, preserving the bus number.
+///
+/// # Arguments
+/// * `hex_frame` - The protobuf CAN frame to convert
+///
+/// # Returns
+/// * `Ok((CanAnyFrame, HexCanApiCanBusNumber))` - The converted frame and its bus number
+/// * `Err(FrameConversionError)` - If conversion fails
+pub fn hex_to_can_any_frame(
+    hex_frame: proto_public_api::HexCanApiCanAnyFrame,
+) -> Result<(CanAnyFrame, proto_public_api::HexCanApiCanBusNumber), FrameConversionError> {
+    // Extract bus_number from the hex_frame
+    // prost generates a bus_number() method that returns the enum value
+    let bus_number = hex_frame.bus_number();
+
+    let frame = match hex_frame.frame {
+        Some(proto_public_api::hex_can_api_can_any_frame::Frame::CanDataFrame(data_frame)) => {
+            let id = match data_frame.id {
+                Some(hex_id) => hex_can_api_id_to_id(&hex_id)?,
+                None => {
+                    return Err(FrameConversionError::InvalidFrame(
+                        "Missing ID in data frame".to_string(),
+                    ))
+                }
+            };
+
+            let data = data_frame.data;
+
+            // Validate data length for regular CAN frame (max 8 bytes)
+            if data.len() > 8 {
+                return Err(FrameConversionError::InvalidFrame(format!(
+                    "Regular CAN frame data length {} exceeds maximum of 8 bytes",
+                    data.len()
+                )));
+            }
+
+            CanDataFrame::new(id, &data)
+                .map(CanAnyFrame::Normal)
+                .ok_or_else(|| {
+                    FrameConversionError::InvalidFrame(
+                        "Failed to create CAN data frame".to_string(),
+                    )
+                })?
+        }
+        Some(proto_public_api::hex_can_api_can_any_frame::Frame::CanFdFrame(fd_frame)) => {
+            let id = match fd_frame.id {
+                Some(hex_id) => hex_can_api_id_to_id(&hex_id)?,
+                None => {
+                    return Err(FrameConversionError::InvalidFrame(
+                        "Missing ID in FD frame".to_string(),
+                    ))
+                }
+            };
+
+            let data = fd_frame.data;
+
+            // Validate data length for CAN FD frame (max 64 bytes)
+            if data.len() > 64 {
+                return Err(FrameConversionError::InvalidFrame(format!(
+                    "CAN FD frame data length {} exceeds maximum of 64 bytes",
+                    data.len()
+                )));
+            }
+
+            // Create FD frame with BRS flag if set
+            let fd_frame_result = if fd_frame.brs {
+                CanFdFrame::with_flags(id, &data, socketcan::id::FdFlags::BRS)
+            } else {
+                CanFdFrame::new(id, &data)
+            };
+
+            fd_frame_result.map(CanAnyFrame::Fd).ok_or_else(|| {
+                FrameConversionError::InvalidFrame("Failed to create CAN FD frame".to_string())
+            })?
+        }
+        None => {
+            return Err(FrameConversionError::InvalidFrame(
+                "Missing frame data".to_string(),
+            ))
+        }
+    };
+
+    Ok((frame, bus_number))
+}
@@ -0,0 +1,329 @@
+//! Constructors for `ApiDown` messages.
+//!
+//! Every constructor stamps `protocol_major_version` / `protocol_minor_version` with
+//! `proto_public_api_version::CURRENT_PROTOCOL_*`, so you never have to fill them in by hand.
+//!
+//! ```no_run
+//! use robot_demos::proto_public_api::{ApiDown, ReportFrequency, SingleMotorTarget};
+//!
+//! let frequency = ApiDown::set_report_frequency(ReportFrequency::Rf250Hz);
+//! let rotate = ApiDown::base_xyz_speed(0.0, 0.0, 0.1);
+//! let zero_torque = ApiDown::arm_motor_targets(vec![SingleMotorTarget::torque(0.0); 6]);
+//! ```
+
+use crate::proto_public_api;
+use crate::proto_public_api::{
+    api_down, arm_api_control_command, arm_command, arm_exclusive_command, arm_shared_command,
+    base_command, hello1_j1t4b_cmd, linear_lift_command, rotate_lift_command,
+    secondary_device_command, simple_base_move_command, single_motor_target, ApiDown,
+    SingleMotorTarget,
+};
+use crate::proto_public_api_version;
+
+impl ApiDown {
+    /// Wraps `down` into an `ApiDown` with the current protocol version.
+    pub fn from_down(down: api_down::Down) -> Self {
+        ApiDown {
+            down: Some(down),
+            protocol_major_version: proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
+            protocol_minor_version: proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
+        }
+    }
+
+    /// Sets how often the robot reports `ApiUp` on the transport this is sent over.
+    pub fn set_report_frequency(frequency: proto_public_api::ReportFrequency) -> Self {
+        Self::from_down(api_down::Down::SetReportFrequency(frequency as i32))
+    }
+
+    /// Asks the robot to start a KCP server talking to `client_peer_port`. Only valid over websocket.
+    pub fn enable_kcp(client_peer_port: u16, kcp_config: proto_public_api::KcpConfig) -> Self {
+        Self::from_down(api_down::Down::EnableKcp(proto_public_api::EnableKcp {
+            client_peer_port: client_peer_port as u32,
+            kcp_config: Some(kcp_config),
+        }))
+    }
+
+    /// A message that does nothing. Useful to activate a KCP connection.
+    pub fn placeholder() -> Self {
+        Self::from_down(api_down::Down::PlaceholderMessage(true))
+    }
+
+    /// Sends a raw CAN frame to one of the robot's CAN buses.
+    pub fn hex_can_any_frame(frame: proto_public_api::HexCanApiCanAnyFrame) -> Self {
+        Self::from_down(api_down::Down::HexCanApiCanAnyFrame(frame))
+    }
+
+    /// Wraps a base command.
+    pub fn base_command(command: base_command::Command) -> Self {
+        Self::from_down(api_down::Down::BaseCommand(proto_public_api::BaseCommand {
+            command: Some(command),
+        }))
+    }
+
+    /// Initializes (`true`) or deinitializes (`false`) API control of the base.
+    pub fn base_api_control_initialize(initialize: bool) -> Self {
+        Self::base_command(base_command::Command::ApiControlInitialize(initialize))
+    }
+
+    /// Clears the parking stop of the base, if it is remotely clearable.
+    pub fn base_clear_parking_stop() -> Self {
+        Self::base_command(base_command::Command::ClearParkingStop(true))
+    }
+
+    /// Controls each motor of the base directly.
+    pub fn base_motor_targets(targets: Vec<SingleMotorTarget>) -> Self {
+        Self::base_command(base_command::Command::MotorTargets(
+            proto_public_api::MotorTargets { targets },
+        ))
+    }
+
+    /// Wraps a simple move command.
+    pub fn base_simple_move(command: simple_base_move_command::Command) -> Self {
+        Self::base_command(base_command::Command::SimpleMoveCommand(
+            proto_public_api::SimpleBaseMoveCommand {
+                command: Some(command),
+            },
+        ))
+    }
+
+    /// Moves the base at the given speed. `speed_x`/`speed_y` in m/s, `speed_z` in rad/s.
+    pub fn base_xyz_speed(speed_x: f32, speed_y: f32, speed_z: f32) -> Self {
+        Self::base_simple_move(simple_base_move_command::Command::XyzSpeed(
+            proto_public_api::XyzSpeed {
+                speed_x,
+                speed_y,
+                speed_z,
+            },
+        ))
+    }
+
+    /// Lets the base be pushed around freely.
+    pub fn base_zero_resistance(enable: bool) -> Self {
+        Self::base_simple_move(simple_base_move_command::Command::ZeroResistance(enable))
+    }
+
+    /// Brakes the base.
+    pub fn base_brake(enable: bool) -> Self {
+        Self::base_simple_move(simple_base_move_command::Command::Brake(enable))
+    }
+
+    /// Wraps an arm command.
+    pub fn arm_command(command: arm_command::Command) -> Self {
+        Self::from_down(api_down::Down::ArmCommand(proto_public_api::ArmCommand {
+            command: Some(command),
+        }))
+    }
+
+    /// Wraps an exclusive arm command. Only the session holder can send these.
+    pub fn arm_exclusive_command(command: arm_exclusive_command::ExclusiveCommand) -> Self {
+        Self::arm_command(arm_command::Command::ArmExclusiveCommand(
+            proto_public_api::ArmExclusiveCommand {
+                exclusive_command: Some(command),
+            },
+        ))
+    }
+
+    /// Wraps a shared arm command. Any session can send these.
+    pub fn arm_shared_command(command: arm_shared_command::Command) -> Self {
+        Self::arm_command(arm_command::Command::ArmSharedCommand(
+            proto_public_api::ArmSharedCommand {
+                command: Some(command),
+            },
+        ))
+    }
+
+    /// Initializes (`true`) or deinitializes (`false`) API control of the arm.
+    pub fn arm_api_control_initialize(initialize: bool) -> Self {
+        Self::arm_exclusive_command(
+            arm_exclusive_command::ExclusiveCommand::ApiControlInitialize(initialize),
+        )
+    }
+
+    /// Calibrates the arm.
+    pub fn arm_calibrate() -> Self {
+        Self::arm_exclusive_command(arm_exclusive_command::ExclusiveCommand::Calibrate(true))
+    }
+
+    /// Wraps an arm API control command.
+    pub fn arm_api_control(command: arm_api_control_command::Command) -> Self {
+        Self::arm_exclusive_command(
+            arm_exclusive_command::ExclusiveCommand::ArmApiControlCommand(
+                proto_public_api::ArmApiControlCommand {
+                    command: Some(command),
+                },
+            ),
+        )
+    }
+
+    /// Controls each motor of the arm directly.
+    pub fn arm_motor_targets(targets: Vec<SingleMotorTarget>) -> Self {
+        Self::arm_api_control(arm_api_control_command::Command::MotorTargets(
+            proto_public_api::MotorTargets { targets },
+        ))
+    }
+
+    /// Puts the arm into free drag mode.
+    pub fn arm_free_drag() -> Self {
+        Self::arm_api_control(arm_api_control_command::Command::ArmApiFreeDragCommand(
+            proto_public_api::ArmApiFreeDragCommand {},
+        ))
+    }
+
+    /// Sets the current of all arm motors to zero.
+    pub fn arm_zero_current() -> Self {
+        Self::arm_api_control(arm_api_control_command::Command::ArmApiZeroCurrentCommand(
+            proto_public_api::ArmApiZeroCurrentCommand {},
+        ))
+    }
+
+    /// Clears the parking stop of the arm, if it is remotely clearable.
+    pub fn arm_clear_parking_stop() -> Self {
+        Self::arm_shared_command(arm_shared_command::Command::ClearParkingStop(true))
+    }
+
+    /// Puts the arm into parking stop with the given reason.
+    pub fn arm_enter_parking_stop(detail: proto_public_api::ParkingStopDetail) -> Self {
+        Self::arm_shared_command(arm_shared_command::Command::EnterParkingStop(detail))
+    }
+
+    /// Wraps a linear lift command.
+    pub fn linear_lift_command(command: linear_lift_command::Command) -> Self {
+        Self::from_down(api_down::Down::LinearLiftCommand(
+            proto_public_api::LinearLiftCommand {
+                command: Some(command),
+            },
+        ))
+    }
+
+    /// Calibrates the linear lift.
+    pub fn linear_lift_calibrate() -> Self {
+        Self::linear_lift_command(linear_lift_command::Command::Calibrate(true))
+    }
+
+    /// Moves the linear lift to `pos`, in pulses. See `LinearLiftStatus::max_pos`.
+    pub fn linear_lift_target_pos(pos: i64) -> Self {
+        Self::linear_lift_command(linear_lift_command::Command::TargetPos(pos))
+    }
+
+    /// Brakes the linear lift.
+    pub fn linear_lift_brake() -> Self {
+        Self::linear_lift_command(linear_lift_command::Command::Brake(true))
+    }
+
+    /// Sets the linear lift speed, in pulses per second. See `LinearLiftStatus::max_speed`.
+    pub fn linear_lift_set_speed(speed: u32) -> Self {
+        Self::linear_lift_command(linear_lift_command::Command::SetSpeed(speed))
+    }
+
+    /// Wraps a rotate lift command.
+    pub fn rotate_lift_command(command: rotate_lift_command::Command) -> Self {
+        Self::from_down(api_down::Down::RotateLiftCommand(
+            proto_public_api::RotateLiftCommand {
+                command: Some(command),
+            },
+        ))
+    }
+
+    /// Calibrates the rotate lift.
+    pub fn rotate_lift_calibrate() -> Self {
+        Self::rotate_lift_command(rotate_lift_command::Command::Calibrate(true))
+    }
+
+    /// Controls each motor of the rotate lift directly.
+    pub fn rotate_lift_motor_targets(targets: Vec<SingleMotorTarget>) -> Self {
+        Self::rotate_lift_command(rotate_lift_command::Command::MotorTargets(
+            proto_public_api::MotorTargets { targets },
+        ))
+    }
+
+    /// Changes the runtime config of the rotate lift.
+    pub fn rotate_lift_runtime_config(config: proto_public_api::RotateLiftRuntimeConfig) -> Self {
+        Self::rotate_lift_command(rotate_lift_command::Command::RuntimeConfig(config))
+    }
+
+    /// Wraps a command for the secondary device with `device_id`.
+    pub fn secondary_device_command(
+        device_id: u32,
+        command: secondary_device_command::Command,
+    ) -> Self {
+        Self::from_down(api_down::Down::SecondaryDeviceCommand(
+            proto_public_api::SecondaryDeviceCommand {
+                device_id,
+                command: Some(command),
+            },
+        ))
+    }
+
+    /// Controls each motor of the hand with `device_id`.
+    pub fn hand_targets(device_id: u32, targets: Vec<SingleMotorTarget>) -> Self {
+        Self::secondary_device_command(
+            device_id,
+            secondary_device_command::Command::HandCommand(proto_public_api::HandCommand {
+                motor_targets: Some(proto_public_api::MotorTargets { targets }),
+            }),
+        )
+    }
+
+    /// Sets the LED colors of the HELLO controller with `device_id`.
+    ///
+    /// RGB color format: little-endian bytes [R, G, B, ignored], e.g. green is `255 << 8`.
+    /// Every command uses CAN bus bandwidth, so don't send this too often.
+    pub fn hello_rgb_stripe(device_id: u32, rgbs: Vec<u32>) -> Self {
+        Self::secondary_device_command(
+            device_id,
+            secondary_device_command::Command::Hello1j1t4bControllerCommand(
+                proto_public_api::Hello1J1t4bCmd {
+                    command: Some(hello1_j1t4b_cmd::Command::RgbStripeCommand(
+                        proto_public_api::RgbStripeCommand { rgbs },
+                    )),
+                },
+            ),
+        )
+    }
+}
+
+impl SingleMotorTarget {
+    /// Torque target.
+    pub fn torque(torque: f64) -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::Torque(torque)),
+        }
+    }
+
+    /// Speed target, in rad/s.
+    pub fn speed(speed: f64) -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::Speed(speed)),
+        }
+    }
+
+    /// Position target, in pulses. See `MotorStatus::pulse_per_rotation`.
+    pub fn position(position: i64) -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::Position(position)),
+        }
+    }
+
+    /// Brakes the motor.
+    pub fn brake() -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::Brake(true)),
+        }
+    }
+
+    /// MIT control target.
+    pub fn mit(target: proto_public_api::MitMotorTarget) -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::MitTarget(target)),
+        }
+    }
+
+    /// Speed target, in rad/s, with a current limit, in A.
+    pub fn speed_with_max_current(speed: f64, max_current: f64) -> Self {
+        SingleMotorTarget {
+            target: Some(single_motor_target::Target::SpeedWithMaxCurrent(
+                proto_public_api::SpeedWithMaxCurrent { speed, max_current },
+            )),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use crate::proto_public_api;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{BoxStream, SplitSink, SplitStream};
+use futures_util::{FutureExt, StreamExt};
+use log::warn;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// The sink half of a robot websocket connection.
+pub type WebSocketSink =
+    SplitSink<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>, tungstenite::Message>;
+
+/// The stream half of a robot websocket connection.
+pub type WebSocketSource = SplitStream<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>>;
+
+/// Decoded messages coming from the robot.
+pub type ApiUpStream = BoxStream<'static, proto_public_api::ApiUp>;
+
+/// Called with the payload of every incoming `ApiUp`, before it is decoded. See [`RobotConnection::tap_raw_api_up`].
+pub type RawApiUpTap = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// The taps of one connection, shared with its stream.
+#[derive(Clone, Default)]
+pub(crate) struct RawApiUpTaps(Arc<Mutex<Vec<RawApiUpTap>>>);
+
+impl RawApiUpTaps {
+    pub(crate) fn add(&self, tap: RawApiUpTap) {
+        self.0.lock().unwrap().push(tap);
+    }
+
+    pub(crate) fn call(&self, payload: &[u8]) {
+        for tap in self.0.lock().unwrap().iter() {
+            tap(payload);
+        }
+    }
+}
+
+/// How many decode errors a [`RobotConnection::decode_errors`] subscriber can fall behind before missing some.
+pub(crate) const DECODE_ERROR_CAPACITY: usize = 16;
+
+/// Transport used to carry the binary `ApiUp`/`ApiDown` messages.
+///
+/// See "Protocol differences" in the README. If you didn't encounter any latency issues, just use websocket.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum Transport {
+    #[value(name = "ws", alias = "websocket")]
+    #[serde(rename = "ws", alias = "websocket")]
+    WebSocket,
+    #[value(name = "kcp")]
+    #[serde(rename = "kcp")]
+    Kcp,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::WebSocket => write!(f, "ws"),
+            Transport::Kcp => write!(f, "kcp"),
+        }
+    }
+}
+
+/// A connection to a robot, independent of the transport underneath.
+///
+/// Control code written against this trait can switch between websocket and KCP without any other change.
+/// Use [`connect_robot`] to get one.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, proto_public_api, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     tokio::spawn(async move {
+///         while let Some(msg) = stream.next().await {
+///             println!("{:?}", msg.status);
+///         }
+///     });
+///     connection
+///         .send(proto_public_api::ApiDown::set_report_frequency(
+///             proto_public_api::ReportFrequency::Rf50Hz,
+///         ))
+///         .await?;
+///     Ok(())
+/// }
+/// ```
+pub trait RobotConnection: Send {
+    /// Which transport this connection is using.
+    fn transport(&self) -> Transport;
+
+    /// Session ID the robot assigned to this connection.
+    fn session_id(&self) -> u32;
+
+    /// Robot type reported in the first `ApiUp` of this connection.
+    fn robot_type(&self) -> proto_public_api::RobotType;
+
+    /// Sends a single message to the robot.
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>>;
+
+    /// Takes the stream of decoded `ApiUp` messages. Returns `None` if it was already taken.
+    ///
+    /// The stream ends when the connection is lost. Keep polling it, otherwise messages pile up on the transport.
+    /// Messages that fail to decode are skipped, see [`RobotConnection::decode_errors`].
+    fn take_stream(&mut self) -> Option<ApiUpStream>;
+
+    /// Subscribes to the errors of messages the stream skipped. Only errors after this call are received.
+    ///
+    /// A [`crate::DecodeError::MajorVersionMismatch`] means nothing from this robot will decode, so the session
+    /// should end. [`crate::ControlSession`] does that by itself.
+    ///
+    /// Returns `None` if this connection doesn't report them.
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        None
+    }
+
+    /// Calls `tap` with the payload of every `ApiUp` the stream receives from now on, before it is decoded. It
+    /// sees messages that fail to decode too, and fields this version of the proto doesn't know about.
+    ///
+    /// Add taps before polling the stream, or the first messages are missed. Returns `false` if this connection
+    /// has no payloads to show, e.g. because it doesn't decode messages itself.
+    fn tap_raw_api_up(&mut self, tap: RawApiUpTap) -> bool {
+        let _ = tap;
+        false
+    }
+}
+
+/// Connects to a robot using the given transport.
+///
+/// # Arguments
+/// * `url` - The IP address of the robot (e.g. "127.0.0.1" or "[fe80::500d:96ff:fee1:d60b%3]")
+/// * `port` - The websocket port of the robot (e.g. 8439)
+/// * `transport` - Which transport to use. `Transport::Kcp` needs the `kcp` feature.
+pub async fn connect_robot(
+    url: &str,
+    port: u16,
+    transport: Transport,
+) -> Result<Box<dyn RobotConnection>, anyhow::Error> {
+    match transport {
+        Transport::WebSocket => Ok(Box::new(WebSocketConnection::connect(url, port).await?)),
+        #[cfg(feature = "kcp")]
+        Transport::Kcp => Ok(Box::new(KcpConnection::connect(url, port).await?)),
+        #[cfg(not(feature = "kcp"))]
+        Transport::Kcp => Err(anyhow::anyhow!(
+            "KCP transport requested, but this program is built without the `kcp` feature"
+        )),
+    }
+}
+
+/// Receives the next error from [`RobotConnection::decode_errors`]. Waits forever once there are none left to
+/// receive. Errors missed by lagging behind are skipped.
+pub(crate) async fn next_decode_error(
+    errors: &mut Option<broadcast::Receiver<crate::DecodeError>>,
+) -> crate::DecodeError {
+    loop {
+        match errors {
+            Some(rx) => match rx.recv().await {
+                Ok(e) => return e,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => *errors = None,
+            },
+            None => return std::future::pending().await,
+        }
+    }
+}
+
+/// Reads websocket frames until the next binary one, and returns its payload.
+async fn next_websocket_payload(
+    ws_stream: &mut WebSocketSource,
+) -> Result<tungstenite::Bytes, anyhow::Error> {
+    loop {
+        match ws_stream.next().await {
+            Some(Ok(tungstenite::Message::Binary(bytes))) => return Ok(bytes),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(anyhow::anyhow!("Websocket closed by robot")),
+        }
+    }
+}
+
+/// Reads websocket frames until the next binary one, and decodes it.
+#[cfg(feature = "kcp")]
+pub(crate) async fn next_websocket_api_up(
+    ws_stream: &mut WebSocketSource,
+) -> Result<proto_public_api::ApiUp, anyhow::Error> {
+    let bytes = next_websocket_payload(ws_stream).await?;
+    Ok(crate::decode_message(&bytes, true)?)
+}
+
+/// Turns the websocket source into a stream of decoded messages. Every payload is shown to `taps` first. Frames
+/// that fail to decode are skipped, and their errors sent to `decode_errors`.
+fn websocket_api_up_stream(
+    ws_stream: WebSocketSource,
+    taps: RawApiUpTaps,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+) -> ApiUpStream {
+    futures_util::stream::unfold(ws_stream, move |mut ws_stream| {
+        let taps = taps.clone();
+        let decode_errors = decode_errors.clone();
+        async move {
+            loop {
+                match ws_stream.next().await {
+                    Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                        taps.call(&bytes);
+                        match crate::decode_message(&bytes, true) {
+                            Ok(msg) => return Some((msg, ws_stream)),
+                            Err(e) => {
+                                warn!("Skipping message from robot: {}", e);
+                                // Nobody listening is fine.
+                                let _ = decode_errors.send(e);
+                            }
+                        }
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("Websocket error: {}", e);
+                        return None;
+                    }
+                    None => return None,
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// [`RobotConnection`] over websocket.
+pub struct WebSocketConnection {
+    session_id: u32,
+    robot_type: proto_public_api::RobotType,
+    ws_sink: WebSocketSink,
+    stream: Option<ApiUpStream>,
+    taps: RawApiUpTaps,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+}
+
+impl WebSocketConnection {
+    /// Connects to `ws://{url}:{port}` and waits for the first message to learn the session ID.
+    pub async fn connect(url: &str, port: u16) -> Result<Self, anyhow::Error> {
+        let ws_stream = crate::connect_websocket(&format!("ws://{}:{}", url, port)).await?;
+        let (ws_sink, mut ws_stream) = ws_stream.split();
+        let first_payload = next_websocket_payload(&mut ws_stream).await?;
+        let first = crate::decode_message(&first_payload, true)?;
+        let taps = RawApiUpTaps::default();
+        let (decode_errors, _) = broadcast::channel(DECODE_ERROR_CAPACITY);
+        let first_taps = taps.clone();
+        Ok(Self {
+            session_id: first.session_id,
+            robot_type: first.robot_type(),
+            ws_sink,
+            // Don't lose the first message, the user might want it too.
+            stream: Some(
+                futures_util::stream::once(async move {
+                    first_taps.call(&first_payload);
+                    first
+                })
+                .chain(websocket_api_up_stream(
+                    ws_stream,
+                    taps.clone(),
+                    decode_errors.clone(),
+                ))
+                .boxed(),
+            ),
+            taps,
+            decode_errors,
+        })
+    }
+}
+
+impl RobotConnection for WebSocketConnection {
+    fn transport(&self) -> Transport {
+        Transport::WebSocket
+    }
+
+    fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.robot_type
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        crate::send_api_down_message_to_websocket(&mut self.ws_sink, msg).boxed()
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.stream.take()
+    }
+
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        Some(self.decode_errors.subscribe())
+    }
+
+    fn tap_raw_api_up(&mut self, tap: RawApiUpTap) -> bool {
+        self.taps.add(tap);
+        true
+    }
+}
+
+/// [`RobotConnection`] over KCP. See [`crate::establish_kcp_session`] for how the session is set up.
+#[cfg(feature = "kcp")]
+pub struct KcpConnection {
+    session: crate::KcpSession,
+}
+
+#[cfg(feature = "kcp")]
+impl KcpConnection {
+    /// Connects over websocket to `url:port`, enables KCP with default options, and switches to it.
+    pub async fn connect(url: &str, port: u16) -> Result<Self, anyhow::Error> {
+        let session =
+            crate::establish_kcp_session(url, port, &crate::KcpSessionOptions::default()).await?;
+        Ok(Self::from_session(session))
+    }
+
+    /// Wraps an already established KCP session.
+    pub fn from_session(session: crate::KcpSession) -> Self {
+        Self { session }
+    }
+
+    /// The underlying KCP session.
+    pub fn session(&mut self) -> &mut crate::KcpSession {
+        &mut self.session
+    }
+
+    /// Unwraps the KCP session, e.g. to [`crate::KcpSession::close`] it.
+    pub fn into_session(self) -> crate::KcpSession {
+        self.session
+    }
+}
+
+#[cfg(feature = "kcp")]
+impl RobotConnection for KcpConnection {
+    fn transport(&self) -> Transport {
+        Transport::Kcp
+    }
+
+    fn session_id(&self) -> u32 {
+        self.session.session_id()
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.session.robot_type()
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        self.session.send(msg).boxed()
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.session.take_stream()
+    }
+
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        Some(self.session.decode_errors())
+    }
+
+    fn tap_raw_api_up(&mut self, tap: RawApiUpTap) -> bool {
+        self.session.tap_raw_api_up(tap);
+        true
+    }
+}
@@ -0,0 +1,302 @@
+use crate::connection::RobotConnection;
+use crate::proto_public_api;
+use crate::state_cache::RobotStateCache;
+use log::warn;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// What the control closure wants done on a tick.
+#[derive(Debug, Clone)]
+pub enum ControlStep {
+    /// Send this message.
+    Send(proto_public_api::ApiDown),
+    /// Send nothing this tick, e.g. because the state we need has not arrived yet.
+    Skip,
+    /// End the loop. [`ControlLoop::run`] returns the final stats.
+    Stop,
+}
+
+/// Timing of the current tick, passed to the control closure.
+#[derive(Debug, Clone, Copy)]
+pub struct Tick {
+    /// Number of this tick, starting at 0. Missed ticks are counted too, so this stays on the schedule.
+    pub index: u64,
+    /// Time since the loop started.
+    pub elapsed: Duration,
+    /// How late this tick woke up compared to its schedule.
+    pub jitter: Duration,
+}
+
+/// Statistics of a [`ControlLoop`].
+///
+/// Counters are totals since the loop started. Rate and timing fields cover the last report window only, so
+/// a loop that stopped keeping up shows it right away instead of being averaged away.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ControlLoopStats {
+    pub ticks: u64,
+    pub sent: u64,
+    pub skipped: u64,
+    /// Ticks whose closure and send took longer than one period.
+    pub overruns: u64,
+    /// Scheduled ticks that never ran because the loop was late. Always 0 with `MissedTickBehavior::Burst`
+    /// and `MissedTickBehavior::Delay`, where late ticks run late instead.
+    pub missed_ticks: u64,
+    /// Length of the report window the fields below cover.
+    pub window: Duration,
+    /// Messages actually sent per second.
+    pub actual_rate_hz: f64,
+    pub mean_jitter: Duration,
+    pub max_jitter: Duration,
+    /// Longest closure plus send.
+    pub max_busy: Duration,
+}
+
+impl std::fmt::Display for ControlLoopStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1}Hz, jitter mean {:?} max {:?}, busy max {:?}, {} overruns, {} missed ticks, {} sent",
+            self.actual_rate_hz,
+            self.mean_jitter,
+            self.max_jitter,
+            self.max_busy,
+            self.overruns,
+            self.missed_ticks,
+            self.sent
+        )
+    }
+}
+
+/// Running sums of the current report window.
+#[derive(Default)]
+struct Window {
+    sent: u64,
+    overruns: u64,
+    missed_ticks: u64,
+    ticks: u32,
+    total_jitter: Duration,
+    max_jitter: Duration,
+    max_busy: Duration,
+}
+
+/// Sends one message per tick at a fixed rate, no matter how long sending takes.
+///
+/// Sleeping a fixed time after each send makes the real rate drift with send latency. `ControlLoop` ticks on a
+/// schedule instead, and calls your closure with the [`RobotStateCache`] to produce each message from the latest
+/// state. When a tick runs late, `MissedTickBehavior` decides what happens to the ticks it ran into, by default
+/// they are skipped so the loop does not burst to catch up.
+///
+/// Statistics are published every report interval (1s by default), see [`ControlLoop::stats`]. A warning is
+/// logged for every window with overruns, missed ticks, or a send rate more than 5% under the requested one.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::{ApiDown, SingleMotorTarget};
+/// use robot_demos::{connect_robot, ControlLoop, ControlStep, RobotStateCache, Transport};
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let cache = RobotStateCache::new();
+///     cache.spawn(connection.take_stream().unwrap());
+///     let stats = ControlLoop::new(250.0)?
+///         .run(connection.as_mut(), &cache, |cache, tick| {
+///             if tick.elapsed > Duration::from_secs(10) {
+///                 return ControlStep::Stop;
+///             }
+///             match cache.arm() {
+///                 Some(arm) => ControlStep::Send(ApiDown::arm_motor_targets(
+///                     arm.motor_status.iter().map(|_| SingleMotorTarget::torque(0.0)).collect(),
+///                 )),
+///                 None => ControlStep::Skip,
+///             }
+///         })
+///         .await?;
+///     println!("{}", stats);
+///     Ok(())
+/// }
+/// ```
+pub struct ControlLoop {
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+    report_interval: Duration,
+    stats: watch::Sender<ControlLoopStats>,
+}
+
+impl ControlLoop {
+    /// A loop ticking `frequency_hz` times per second.
+    ///
+    /// Fails unless the frequency is positive and finite, and its period neither rounds to zero nor is too long to
+    /// schedule.
+    pub fn new(frequency_hz: f64) -> Result<Self, anyhow::Error> {
+        let period = Duration::try_from_secs_f64(1.0 / frequency_hz)
+            .ok()
+            .filter(|period| {
+                frequency_hz > 0.0
+                    && !period.is_zero()
+                    && Instant::now().checked_add(*period).is_some()
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!("Invalid control loop frequency: {:?}Hz", frequency_hz)
+            })?;
+        Ok(Self {
+            period,
+            missed_tick_behavior: MissedTickBehavior::Skip,
+            report_interval: Duration::from_secs(1),
+            stats: watch::channel(ControlLoopStats::default()).0,
+        })
+    }
+
+    /// What to do with ticks the loop was too late for. Defaults to `MissedTickBehavior::Skip`.
+    pub fn missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+        self.missed_tick_behavior = behavior;
+        self
+    }
+
+    /// How often stats are published and checked. Defaults to 1s.
+    pub fn report_interval(mut self, interval: Duration) -> Self {
+        self.report_interval = interval;
+        self
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Stats, updated every report interval and once more when the loop ends.
+    pub fn stats(&self) -> watch::Receiver<ControlLoopStats> {
+        self.stats.subscribe()
+    }
+
+    /// Runs until the closure returns [`ControlStep::Stop`], or sending fails.
+    ///
+    /// Returns the stats of the whole run, with the last window's rate and timing.
+    pub async fn run<C, F>(
+        &self,
+        connection: &mut C,
+        cache: &RobotStateCache,
+        control: F,
+    ) -> Result<ControlLoopStats, anyhow::Error>
+    where
+        C: RobotConnection + ?Sized,
+        F: FnMut(&RobotStateCache, &Tick) -> ControlStep,
+    {
+        self.run_until(connection, cache, std::future::pending::<()>(), control)
+            .await
+    }
+
+    /// Same as [`ControlLoop::run`], but also stops between ticks once `stop` completes, e.g. on
+    /// `tokio::signal::ctrl_c()`. Nothing is sent after that, so the caller can still ramp down.
+    pub async fn run_until<C, F>(
+        &self,
+        connection: &mut C,
+        cache: &RobotStateCache,
+        stop: impl Future,
+        mut control: F,
+    ) -> Result<ControlLoopStats, anyhow::Error>
+    where
+        C: RobotConnection + ?Sized,
+        F: FnMut(&RobotStateCache, &Tick) -> ControlStep,
+    {
+        tokio::pin!(stop);
+        let mut interval = tokio::time::interval(self.period);
+        interval.set_missed_tick_behavior(self.missed_tick_behavior);
+        let start = Instant::now();
+        let mut totals = ControlLoopStats::default();
+        let mut window = Window::default();
+        let mut window_start = start;
+        let mut previous_scheduled: Option<Instant> = None;
+        let mut index = 0;
+
+        loop {
+            let scheduled = tokio::select! {
+                scheduled = interval.tick() => scheduled,
+                _ = &mut stop => {
+                    let now = Instant::now();
+                    self.report(&mut totals, &window, now - window_start);
+                    return Ok(totals);
+                }
+            };
+            let woke = Instant::now();
+            // Only skipping leaves holes in the schedule, `Delay` shifts it and `Burst` runs every tick.
+            if let (Some(previous), MissedTickBehavior::Skip) =
+                (previous_scheduled, self.missed_tick_behavior)
+            {
+                let skipped = (scheduled - previous).as_nanos() / self.period.as_nanos();
+                let skipped = (skipped as u64).saturating_sub(1);
+                index += skipped;
+                window.missed_ticks += skipped;
+            }
+            previous_scheduled = Some(scheduled);
+            let tick = Tick {
+                index,
+                elapsed: woke - start,
+                jitter: woke - scheduled,
+            };
+            index += 1;
+            window.ticks += 1;
+            window.total_jitter += tick.jitter;
+            window.max_jitter = window.max_jitter.max(tick.jitter);
+
+            let step = control(cache, &tick);
+            let stop = match step {
+                ControlStep::Send(msg) => {
+                    connection.send(msg).await?;
+                    window.sent += 1;
+                    false
+                }
+                ControlStep::Skip => {
+                    totals.skipped += 1;
+                    false
+                }
+                ControlStep::Stop => true,
+            };
+            let busy = woke.elapsed();
+            window.max_busy = window.max_busy.max(busy);
+            if busy > self.period {
+                window.overruns += 1;
+            }
+
+            let now = Instant::now();
+            if stop || now - window_start >= self.report_interval {
+                self.report(&mut totals, &window, now - window_start);
+                window = Window::default();
+                window_start = now;
+            }
+            if stop {
+                return Ok(totals);
+            }
+        }
+    }
+
+    /// Folds `window` into `totals`, publishes, and warns if the loop is not keeping up.
+    fn report(&self, totals: &mut ControlLoopStats, window: &Window, length: Duration) {
+        totals.ticks += window.ticks as u64;
+        totals.sent += window.sent;
+        totals.overruns += window.overruns;
+        totals.missed_ticks += window.missed_ticks;
+        totals.window = length;
+        totals.actual_rate_hz = window.sent as f64 / length.as_secs_f64().max(f64::EPSILON);
+        totals.mean_jitter = window
+            .total_jitter
+            .checked_div(window.ticks)
+            .unwrap_or_default();
+        totals.max_jitter = window.max_jitter;
+        totals.max_busy = window.max_busy;
+        self.stats.send_replace(totals.clone());
+
+        let requested_hz = 1.0 / self.period.as_secs_f64();
+        // A window with skipped ticks is expected to send less, only warn about the rate when every tick sent.
+        let slow =
+            window.sent == window.ticks as u64 && totals.actual_rate_hz < requested_hz * 0.95;
+        if window.overruns > 0 || window.missed_ticks > 0 || slow {
+            warn!(
+                "Control loop not keeping up with {:.1}Hz: {} overruns and {} missed ticks in the last {:?}. {}",
+                requested_hz, window.overruns, window.missed_ticks, length, totals
+            );
+        }
+    }
+}
@@ -0,0 +1,671 @@
+use crate::connection::{
+    next_decode_error, ApiUpStream, RobotConnection, Transport, DECODE_ERROR_CAPACITY,
+};
+use crate::proto_public_api;
+use crate::session_holder::{
+    robot_keys, takeover_socket, NotSessionHolder, Ownership, OwnershipEvent, SeenTakeoverRequests,
+    SessionHolderTracker, TakeoverRequest,
+};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use log::{info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+/// How long drop, Ctrl-C and the panic hook wait for the deinitialize message to be sent.
+const DEINITIALIZE_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often [`ControlSession::take_over`] repeats its request, in case one got lost.
+const TAKEOVER_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What a [`ControlSession`] initializes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlTarget {
+    Base,
+    Arm,
+}
+
+impl ControlTarget {
+    /// The `ApiControlInitialize` message for this target.
+    pub fn api_control_initialize(self, initialize: bool) -> proto_public_api::ApiDown {
+        match self {
+            ControlTarget::Base => {
+                proto_public_api::ApiDown::base_api_control_initialize(initialize)
+            }
+            ControlTarget::Arm => proto_public_api::ApiDown::arm_api_control_initialize(initialize),
+        }
+    }
+
+    /// `api_control_initialized` of the status for this target, if `msg` carries one.
+    fn api_control_initialized(self, msg: &proto_public_api::ApiUp) -> Option<bool> {
+        match (self, &msg.status) {
+            (ControlTarget::Base, Some(proto_public_api::api_up::Status::BaseStatus(status))) => {
+                Some(status.api_control_initialized)
+            }
+            (ControlTarget::Arm, Some(proto_public_api::api_up::Status::ArmStatus(status))) => {
+                Some(status.api_control_initialized)
+            }
+            _ => None,
+        }
+    }
+
+    /// `session_holder` of the status for this target, if `msg` carries one.
+    pub(crate) fn session_holder(self, msg: &proto_public_api::ApiUp) -> Option<u32> {
+        match (self, &msg.status) {
+            (ControlTarget::Base, Some(proto_public_api::api_up::Status::BaseStatus(status))) => {
+                Some(status.session_holder)
+            }
+            (ControlTarget::Arm, Some(proto_public_api::api_up::Status::ArmStatus(status))) => {
+                Some(status.session_holder)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ControlTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlTarget::Base => write!(f, "base"),
+            ControlTarget::Arm => write!(f, "arm"),
+        }
+    }
+}
+
+enum Request {
+    Send(
+        proto_public_api::ApiDown,
+        oneshot::Sender<Result<(), anyhow::Error>>,
+    ),
+    // Acknowledged over a std channel, so it can be waited on from drop and the panic hook.
+    // The ack is whether the message was sent.
+    Deinitialize(std::sync::mpsc::Sender<bool>),
+    // Another session asked for control, with this session id.
+    Takeover(u32),
+}
+
+/// Sessions that still need deinitializing, for the panic hook and the Ctrl-C handler.
+static LIVE_SESSIONS: Mutex<Vec<(u64, mpsc::UnboundedSender<Request>)>> = Mutex::new(Vec::new());
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+static INSTALL_PANIC_HOOK: OnceLock<()> = OnceLock::new();
+static INSTALL_CTRL_C_HANDLER: OnceLock<()> = OnceLock::new();
+
+/// Runs a blocking wait without starving the tokio worker we might be on.
+fn wait_blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// Asks every live session to deinitialize. Wait on the returned acks with [`wait_acks`].
+fn deinitialize_all() -> Vec<std::sync::mpsc::Receiver<bool>> {
+    let sessions: Vec<_> = LIVE_SESSIONS.lock().map(|s| s.clone()).unwrap_or_default();
+    sessions
+        .iter()
+        .filter_map(|(_, requests)| {
+            let (ack_tx, ack_rx) = std::sync::mpsc::channel();
+            requests.send(Request::Deinitialize(ack_tx)).ok()?;
+            Some(ack_rx)
+        })
+        .collect()
+}
+
+/// Blocks until every ack arrived, or `DEINITIALIZE_SEND_TIMEOUT` passed.
+fn wait_acks(acks: Vec<std::sync::mpsc::Receiver<bool>>) {
+    let deadline = std::time::Instant::now() + DEINITIALIZE_SEND_TIMEOUT;
+    for ack in acks {
+        let _ = ack.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()));
+    }
+}
+
+fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.get_or_init(|| {
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            let acks = deinitialize_all();
+            wait_blocking(|| wait_acks(acks));
+            previous_hook(panic_info);
+        }));
+    });
+}
+
+/// Fails on a current-thread runtime, where drop and the panic hook would block the thread the session needs.
+fn check_runtime() -> Result<(), anyhow::Error> {
+    let handle = tokio::runtime::Handle::try_current()
+        .map_err(|_| anyhow::anyhow!("ControlSession must be started within a tokio runtime"))?;
+    if handle.runtime_flavor() != tokio::runtime::RuntimeFlavor::MultiThread {
+        return Err(anyhow::anyhow!(
+            "ControlSession needs the multi-thread tokio runtime, so it can deinitialize from drop and the panic \
+             hook. Use #[tokio::main] or #[tokio::test(flavor = \"multi_thread\")]"
+        ));
+    }
+    Ok(())
+}
+
+/// Fails on an empty takeover token, which would let anyone on the network take over.
+fn check_takeover_token(token: &str) -> Result<(), anyhow::Error> {
+    if token.is_empty() {
+        return Err(anyhow::anyhow!("The takeover token must not be empty"));
+    }
+    Ok(())
+}
+
+/// Keeps API control of the base or arm initialized for as long as it lives.
+///
+/// `ApiControlInitialize(true)` is sent when the session is created. `ApiControlInitialize(false)` is sent when:
+/// - [`ControlSession::finish`] is called, or the session is dropped
+/// - any thread panics
+/// - the process gets Ctrl-C, if [`ControlSession::exit_on_ctrl_c`] was called (the process then exits with code 130)
+///
+/// Otherwise a crashed program leaves the robot initialized, until it trips into a protected state. Ctrl-C is left
+/// to the program by default, since exiting skips its own cleanup: stop your loop on `tokio::signal::ctrl_c()`
+/// (e.g. with [`crate::ControlLoop::run_until`]), ramp down, and let the session finish. Simple programs with
+/// nothing to clean up can call [`ControlSession::exit_on_ctrl_c`] instead.
+///
+/// The session also deinitializes, and its stream ends, on a [`crate::DecodeError::MajorVersionMismatch`] from the
+/// connection: the statuses can't be read anymore, so nothing can be controlled safely.
+///
+/// The session compares `session_holder` of every status with its own session id, see [`ControlSession::ownership`]
+/// and [`ControlSession::subscribe_ownership`]. Motion commands are refused with [`NotSessionHolder`] while another
+/// session holds control. To get control from another session, use [`ControlSession::take_over`]; to let other
+/// sessions take it from this one, call [`ControlSession::accept_takeover`]. Nothing is handed over unless the
+/// holder opted in, and both sides share a token.
+///
+/// Drop blocks for up to a second while the message is sent, which needs the multi-thread runtime (the default of
+/// `#[tokio::main]`, but not of `#[tokio::test]`). Starting a session on a current-thread runtime fails.
+///
+/// All messages must go through the session, so it owns the connection and implements [`RobotConnection`] itself.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::ApiDown;
+/// use robot_demos::{connect_robot, ControlSession, RobotConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut session = ControlSession::base(connection).await?;
+///     for _ in 0..500 {
+///         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+///         session.send(ApiDown::base_xyz_speed(0.0, 0.0, 0.1)).await?;
+///     }
+///     let confirmed = session.finish(std::time::Duration::from_secs(1)).await?;
+///     println!("Base confirmed deinitialize: {}", confirmed);
+///     Ok(())
+/// }
+/// ```
+pub struct ControlSession {
+    id: u64,
+    target: ControlTarget,
+    transport: Transport,
+    session_id: u32,
+    robot_type: proto_public_api::RobotType,
+    requests: mpsc::UnboundedSender<Request>,
+    deinitialize_confirmed: watch::Receiver<bool>,
+    ownership: watch::Receiver<Ownership>,
+    ownership_events: broadcast::Sender<OwnershipEvent>,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+    stream: Option<ApiUpStream>,
+}
+
+impl ControlSession {
+    /// Takes over `connection` and initializes API control of `target`.
+    ///
+    /// Installs the panic hook the first time it's called. Must be called within a multi-thread tokio runtime.
+    pub async fn new(
+        mut connection: Box<dyn RobotConnection>,
+        target: ControlTarget,
+    ) -> Result<Self, anyhow::Error> {
+        check_runtime()?;
+        let stream = connection
+            .take_stream()
+            .ok_or_else(|| anyhow::anyhow!("The stream of this connection was already taken"))?;
+        Self::start(connection, stream, target).await
+    }
+
+    /// Same as [`ControlSession::new`], but if another session holds control of `target`, first asks it to hand over
+    /// and waits up to `timeout` until nobody holds control.
+    ///
+    /// The request reaches the holder only if it is a [`ControlSession`] that called
+    /// [`ControlSession::accept_takeover`] with the same robot (by address or a host name resolving to it) and the
+    /// same `token`, on the same IPv4 network. It then deinitializes cleanly. Otherwise this still succeeds if the
+    /// holder lets go by itself within `timeout`, e.g. when its program stops and the robot times it out.
+    ///
+    /// # Returns
+    /// * `Err(anyhow::Error)` - The holder did not let go within `timeout`, the error is a [`NotSessionHolder`]
+    pub async fn take_over(
+        mut connection: Box<dyn RobotConnection>,
+        target: ControlTarget,
+        robot_address: &str,
+        token: &str,
+        timeout: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        check_runtime()?;
+        check_takeover_token(token)?;
+        let robot = robot_keys(robot_address).await;
+        let mut stream = connection
+            .take_stream()
+            .ok_or_else(|| anyhow::anyhow!("The stream of this connection was already taken"))?;
+        let session_id = connection.session_id();
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut last_request: Option<tokio::time::Instant> = None;
+        let mut holder = None;
+        loop {
+            let msg = tokio::select! {
+                msg = stream.next() => msg.ok_or_else(|| anyhow::anyhow!("Connection lost"))?,
+                _ = tokio::time::sleep_until(deadline) => {
+                    return Err(anyhow::Error::new(NotSessionHolder {
+                        target,
+                        holder: holder.unwrap_or(0),
+                    })
+                    .context(format!("Control of the {} was not handed over within {:?}", target, timeout)));
+                }
+            };
+            let Some(current) = target.session_holder(&msg) else {
+                continue;
+            };
+            if current == 0 || current == session_id {
+                break;
+            }
+            holder = Some(current);
+            if last_request.is_some_and(|at| at.elapsed() < TAKEOVER_RESEND_INTERVAL) {
+                continue;
+            }
+            if last_request.is_none() {
+                info!(
+                    "Asking session {} to hand over control of the {}",
+                    current, target
+                );
+            }
+            last_request = Some(tokio::time::Instant::now());
+            let request = TakeoverRequest::new(robot.clone(), target, current, session_id, token);
+            if let Err(e) = request.send().await {
+                warn!("Failed to send takeover request: {}", e);
+            }
+        }
+        if let Some(holder) = holder {
+            info!("Session {} handed over control of the {}", holder, target);
+        }
+        Self::start(connection, stream, target).await
+    }
+
+    async fn start(
+        mut connection: Box<dyn RobotConnection>,
+        stream: ApiUpStream,
+        target: ControlTarget,
+    ) -> Result<Self, anyhow::Error> {
+        install_panic_hook();
+
+        connection.send(target.api_control_initialize(true)).await?;
+        info!("API control of {} initialized", target);
+
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (confirmed_tx, confirmed_rx) = watch::channel(false);
+        let (ownership_tx, ownership_rx) = watch::channel(Ownership::Unknown);
+        let (ownership_events, _) = broadcast::channel(64);
+        let (decode_errors, _) = broadcast::channel(DECODE_ERROR_CAPACITY);
+        let (up_tx, up_rx) = mpsc::channel(1024);
+        let mut tracker = SessionHolderTracker::new(connection.session_id(), target);
+        tracker.initialize_sent();
+        LIVE_SESSIONS.lock().unwrap().push((id, requests.clone()));
+
+        let session = Self {
+            id,
+            target,
+            transport: connection.transport(),
+            session_id: connection.session_id(),
+            robot_type: connection.robot_type(),
+            requests,
+            deinitialize_confirmed: confirmed_rx,
+            ownership: ownership_rx,
+            ownership_events: ownership_events.clone(),
+            decode_errors: decode_errors.clone(),
+            stream: Some(
+                futures_util::stream::unfold(up_rx, |mut up_rx| async move {
+                    up_rx.recv().await.map(|msg| (msg, up_rx))
+                })
+                .boxed(),
+            ),
+        };
+        tokio::spawn(drive(
+            target,
+            connection,
+            stream,
+            requests_rx,
+            Watchers {
+                confirmed: confirmed_tx,
+                tracker,
+                ownership: ownership_tx,
+                ownership_events,
+                decode_errors,
+            },
+            up_tx,
+        ));
+        Ok(session)
+    }
+
+    /// Makes Ctrl-C deinitialize every live session and exit the process with code 130.
+    ///
+    /// Only for programs with nothing else to clean up, the exit skips everything else: ramping down, flushing
+    /// files, and so on. Call it once, before or after starting sessions. Must be called within a tokio runtime.
+    pub fn exit_on_ctrl_c() {
+        INSTALL_CTRL_C_HANDLER.get_or_init(|| {
+            tokio::spawn(async {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    warn!("Ctrl-C received, deinitializing before exit");
+                    let acks = deinitialize_all();
+                    let _ = tokio::task::spawn_blocking(move || wait_acks(acks)).await;
+                    std::process::exit(130);
+                }
+            });
+        });
+    }
+
+    /// Initializes API control of the base.
+    pub async fn base(connection: Box<dyn RobotConnection>) -> Result<Self, anyhow::Error> {
+        Self::new(connection, ControlTarget::Base).await
+    }
+
+    /// Initializes API control of the arm.
+    pub async fn arm(connection: Box<dyn RobotConnection>) -> Result<Self, anyhow::Error> {
+        Self::new(connection, ControlTarget::Arm).await
+    }
+
+    /// What this session initialized.
+    pub fn target(&self) -> ControlTarget {
+        self.target
+    }
+
+    /// Who holds control of the target, from the latest status.
+    pub fn ownership(&self) -> Ownership {
+        *self.ownership.borrow()
+    }
+
+    /// Every change of who holds control, and every takeover request this session got.
+    pub fn subscribe_ownership(&self) -> broadcast::Receiver<OwnershipEvent> {
+        self.ownership_events.subscribe()
+    }
+
+    /// Lets other sessions take control with [`ControlSession::take_over`]. On a request signed with `token`,
+    /// naming this session and the robot at `robot_address` (by address or a host name resolving to it), the
+    /// session deinitializes and every further send fails with [`NotSessionHolder`].
+    ///
+    /// Requests are plain multicast on the local network, so without `token` anyone there could stop this session.
+    /// Keep it secret, and don't call this at all if nobody should take over.
+    ///
+    /// Listens on [`crate::TAKEOVER_MULTICAST_ADDR`] until the session is gone. Fails if that port is taken, e.g. by
+    /// another program on this machine accepting takeovers, or `token` is empty.
+    pub async fn accept_takeover(
+        &self,
+        robot_address: &str,
+        token: &str,
+    ) -> Result<(), anyhow::Error> {
+        check_takeover_token(token)?;
+        let socket = takeover_socket().await?;
+        let robot = robot_keys(robot_address).await;
+        let token = token.to_string();
+        let (target, session_id) = (self.target.to_string(), self.session_id);
+        // Weak, so the listener doesn't keep the driver alive: it ends with the driver, which ends once the
+        // session is gone.
+        let requests = self.requests.downgrade();
+        let driver_ended = driver_ended(self.deinitialize_confirmed.clone());
+        tokio::spawn(async move {
+            tokio::pin!(driver_ended);
+            let mut seen = SeenTakeoverRequests::default();
+            loop {
+                tokio::select! {
+                    request = TakeoverRequest::recv(&socket) => match request {
+                        Ok(request) => {
+                            if !request.names_robot(&robot)
+                                || request.target != target
+                                || request.holder != session_id
+                            {
+                                continue;
+                            }
+                            if !request.verify(&token) {
+                                warn!(
+                                    "Ignoring takeover request from session {} with a wrong token or timestamp",
+                                    request.requester
+                                );
+                                continue;
+                            }
+                            if !seen.first_time(&request) {
+                                warn!(
+                                    "Ignoring a replayed takeover request from session {}",
+                                    request.requester
+                                );
+                                continue;
+                            }
+                            let Some(requests) = requests.upgrade() else {
+                                break;
+                            };
+                            if requests.send(Request::Takeover(request.requester)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Stopped listening for takeover requests: {}", e);
+                            break;
+                        }
+                    },
+                    _ = &mut driver_ended => break,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether a `BaseStatus`/`ArmStatus` with `api_control_initialized == false` arrived after deinitializing.
+    pub fn is_deinitialize_confirmed(&self) -> bool {
+        *self.deinitialize_confirmed.borrow()
+    }
+
+    /// Sends `ApiControlInitialize(false)`, then waits up to `timeout` for the robot to confirm it.
+    ///
+    /// # Returns
+    /// * `Ok(true)` - The robot reported `api_control_initialized == false`
+    /// * `Ok(false)` - No confirmation within `timeout`
+    /// * `Err(anyhow::Error)` - The deinitialize message could not be sent
+    pub async fn finish(mut self, timeout: Duration) -> Result<bool, anyhow::Error> {
+        let (ack_tx, ack_rx) = std::sync::mpsc::channel();
+        self.requests
+            .send(Request::Deinitialize(ack_tx))
+            .map_err(|_| anyhow::anyhow!("Control session is gone"))?;
+        let sent =
+            tokio::task::spawn_blocking(move || ack_rx.recv_timeout(DEINITIALIZE_SEND_TIMEOUT))
+                .await?
+                .unwrap_or(false);
+        if !sent {
+            return Err(anyhow::anyhow!("Failed to send deinitialize message"));
+        }
+        let mut confirmed = self.deinitialize_confirmed.clone();
+        let confirmed = tokio::time::timeout(timeout, confirmed.wait_for(|c| *c))
+            .await
+            .is_ok_and(|r| r.is_ok());
+        self.unregister();
+        Ok(confirmed)
+    }
+
+    fn unregister(&mut self) {
+        if let Ok(mut sessions) = LIVE_SESSIONS.lock() {
+            sessions.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+impl Drop for ControlSession {
+    fn drop(&mut self) {
+        self.unregister();
+        // Already done if `finish` was called, the driver ignores the second one.
+        let (ack_tx, ack_rx) = std::sync::mpsc::channel();
+        if self.requests.send(Request::Deinitialize(ack_tx)).is_ok() {
+            wait_blocking(|| {
+                let _ = ack_rx.recv_timeout(DEINITIALIZE_SEND_TIMEOUT);
+            });
+        }
+    }
+}
+
+impl RobotConnection for ControlSession {
+    fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.robot_type
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let sent = self.requests.send(Request::Send(msg, result_tx));
+        async move {
+            sent.map_err(|_| anyhow::anyhow!("Control session is gone"))?;
+            result_rx
+                .await
+                .map_err(|_| anyhow::anyhow!("Control session is gone"))?
+        }
+        .boxed()
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.stream.take()
+    }
+
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        Some(self.decode_errors.subscribe())
+    }
+}
+
+/// What the driver reports back to the [`ControlSession`].
+struct Watchers {
+    confirmed: watch::Sender<bool>,
+    tracker: SessionHolderTracker,
+    ownership: watch::Sender<Ownership>,
+    ownership_events: broadcast::Sender<OwnershipEvent>,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+}
+
+impl Watchers {
+    /// `deinitialized` if this session let go itself, so losing control is expected.
+    fn emit(&self, event: OwnershipEvent, deinitialized: bool) {
+        match &event {
+            OwnershipEvent::Lost { .. } if deinitialized => info!("{}", event),
+            OwnershipEvent::Acquired | OwnershipEvent::Released { .. } => info!("{}", event),
+            _ => warn!("{}", event),
+        }
+        // Nobody listening is fine.
+        let _ = self.ownership_events.send(event);
+    }
+}
+
+async fn deinitialize(target: ControlTarget, connection: &mut Box<dyn RobotConnection>) -> bool {
+    match connection.send(target.api_control_initialize(false)).await {
+        Ok(()) => {
+            info!("API control of {} deinitialized", target);
+            true
+        }
+        Err(e) => {
+            warn!("Failed to deinitialize {}: {}", target, e);
+            false
+        }
+    }
+}
+
+/// Completes once the driver, which holds the sender of `watched`, has ended.
+async fn driver_ended<T>(mut watched: watch::Receiver<T>) {
+    while watched.changed().await.is_ok() {}
+}
+
+async fn drive(
+    target: ControlTarget,
+    mut connection: Box<dyn RobotConnection>,
+    mut stream: ApiUpStream,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    mut watchers: Watchers,
+    up_tx: mpsc::Sender<proto_public_api::ApiUp>,
+) {
+    // Taken to end the application's stream.
+    let mut up_tx = Some(up_tx);
+    let mut connection_decode_errors = connection.decode_errors();
+    // `Some(sent)` once deinitialized.
+    let mut deinitialized: Option<bool> = None;
+    // The session control was handed over to.
+    let mut taken_over_by: Option<u32> = None;
+    let mut stream_ended = false;
+    loop {
+        tokio::select! {
+            msg = stream.next(), if !stream_ended => match msg {
+                Some(msg) => {
+                    if deinitialized.is_some() && target.api_control_initialized(&msg) == Some(false) {
+                        watchers.confirmed.send_replace(true);
+                    }
+                    if let Some(event) = watchers.tracker.update(&msg) {
+                        watchers.ownership.send_replace(watchers.tracker.ownership());
+                        watchers.emit(event, deinitialized.is_some());
+                    }
+                    if let Some(up_tx) = &up_tx {
+                        if let Err(mpsc::error::TrySendError::Full(_)) = up_tx.try_send(msg) {
+                            warn!("ApiUp stream is not being polled, dropping message");
+                        }
+                    }
+                }
+                None => stream_ended = true,
+            },
+            e = next_decode_error(&mut connection_decode_errors) => {
+                let fatal = matches!(e, crate::DecodeError::MajorVersionMismatch { .. });
+                let _ = watchers.decode_errors.send(e);
+                if fatal && !stream_ended {
+                    warn!("Robot speaks another protocol major version, ending the {} session", target);
+                    if deinitialized.is_none() {
+                        deinitialized = Some(deinitialize(target, &mut connection).await);
+                    }
+                    stream_ended = true;
+                    up_tx = None;
+                }
+            },
+            request = requests.recv() => match request {
+                Some(Request::Send(msg, result_tx)) => {
+                    let result = if let Some(holder) = taken_over_by {
+                        Err(anyhow::Error::new(NotSessionHolder { target, holder }))
+                    } else if deinitialized.is_some() {
+                        Err(anyhow::anyhow!("API control of {} is already deinitialized", target))
+                    } else if let Err(e) = watchers.tracker.check(&msg) {
+                        Err(anyhow::Error::new(e))
+                    } else {
+                        if msg == target.api_control_initialize(true) {
+                            watchers.tracker.initialize_sent();
+                        }
+                        connection.send(msg).await
+                    };
+                    let _ = result_tx.send(result);
+                }
+                Some(Request::Deinitialize(ack)) => {
+                    let sent = match deinitialized {
+                        Some(sent) => sent,
+                        None => deinitialize(target, &mut connection).await,
+                    };
+                    deinitialized = Some(sent);
+                    let _ = ack.send(sent);
+                }
+                Some(Request::Takeover(by)) => {
+                    if deinitialized.is_none() {
+                        watchers.emit(OwnershipEvent::TakeoverRequested { by }, false);
+                        deinitialized = Some(deinitialize(target, &mut connection).await);
+                        taken_over_by = Some(by);
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::proto_public_api::RobotType;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use mdns_sd::{ScopedIp, ServiceDaemon, ServiceEvent};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+/// mDNS service type every HexFellow device announces.
+pub const HEXFELLOW_SERVICE_TYPE: &str = "_hexfellow._tcp.local.";
+
+/// A HexFellow device found on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    /// e.g. "hexfellow-2d59f595ba5ad967.local."
+    pub hostname: String,
+    /// Port the device announced, the websocket port.
+    pub port: u16,
+    pub ipv4: Vec<Ipv4Addr>,
+    /// IPv6 addresses, each with the index of the interface it was seen on.
+    pub ipv6_with_scope_id: Vec<(Ipv6Addr, u32)>,
+    /// `RtUnknown` if the device did not say, or we don't know the type. See `main_robot_type_name`.
+    pub main_robot_type: RobotType,
+    pub secondary_robot_type: RobotType,
+    /// Robot type exactly as announced. Can be more specific than `main_robot_type`, e.g. "RtMaverX4D".
+    pub main_robot_type_name: String,
+    pub secondary_robot_type_name: String,
+}
+
+impl DiscoveredDevice {
+    /// The address to pass as `url` to the examples and [`crate::connect_robot`].
+    ///
+    /// IPv4 is preferred. Link-local IPv6 gets its zone id, as the interface index (e.g.
+    /// "[fe80::500d:96ff:fee1:d60b%3]"), which is the only form that works. `None` if there is no usable address.
+    pub fn connect_url(&self) -> Option<String> {
+        if let Some(ipv4) = self.ipv4.first() {
+            return Some(ipv4.to_string());
+        }
+        self.ipv6_with_scope_id
+            .iter()
+            .find_map(|(addr, scope_id)| format_ipv6_url(addr, *scope_id))
+    }
+
+    /// Full websocket URL of the device, e.g. "ws://172.18.23.92:8439".
+    pub fn websocket_url(&self) -> Option<String> {
+        self.connect_url()
+            .map(|url| format!("ws://{}:{}", url, self.port))
+    }
+
+    fn merge(&mut self, other: DiscoveredDevice) {
+        self.port = other.port;
+        for ipv4 in other.ipv4 {
+            if !self.ipv4.contains(&ipv4) {
+                self.ipv4.push(ipv4);
+            }
+        }
+        for ipv6 in other.ipv6_with_scope_id {
+            if !self.ipv6_with_scope_id.contains(&ipv6) {
+                self.ipv6_with_scope_id.push(ipv6);
+            }
+        }
+        self.main_robot_type = other.main_robot_type;
+        self.secondary_robot_type = other.secondary_robot_type;
+        self.main_robot_type_name = other.main_robot_type_name;
+        self.secondary_robot_type_name = other.secondary_robot_type_name;
+    }
+}
+
+fn is_unicast_link_local(addr: &Ipv6Addr) -> bool {
+    (addr.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Link-local addresses are useless without a zone id, and the zone id must be the interface index.
+fn format_ipv6_url(addr: &Ipv6Addr, scope_id: u32) -> Option<String> {
+    if !is_unicast_link_local(addr) {
+        Some(format!("[{}]", addr))
+    } else if scope_id != 0 {
+        Some(format!("[{}%{}]", addr, scope_id))
+    } else {
+        None
+    }
+}
+
+/// Parses an announced robot type. Announced names can carry a variant suffix the proto doesn't know
+/// (e.g. "RtMaverX4D"), in that case the longest known type it starts with is used.
+fn parse_robot_type(name: &str) -> RobotType {
+    if let Some(robot_type) = RobotType::from_str_name(name) {
+        return robot_type;
+    }
+    (0..name.len())
+        .rev()
+        .filter(|&len| name.is_char_boundary(len))
+        .find_map(|len| RobotType::from_str_name(&name[..len]))
+        .unwrap_or(RobotType::RtUnknown)
+}
+
+/// Stops the mDNS daemon thread once browsing is over.
+struct DaemonGuard(ServiceDaemon);
+
+impl Drop for DaemonGuard {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown();
+    }
+}
+
+fn device_from_resolved(resolved: &mdns_sd::ResolvedService) -> DiscoveredDevice {
+    let mut ipv4 = Vec::new();
+    let mut ipv6_with_scope_id = Vec::new();
+    for addr in resolved.get_addresses() {
+        match addr.to_ip_addr() {
+            IpAddr::V4(v4) => ipv4.push(v4),
+            IpAddr::V6(v6) => {
+                let scope_id = match addr {
+                    ScopedIp::V6(scoped) => scoped.scope_id().index,
+                    _ => 0,
+                };
+                ipv6_with_scope_id.push((v6, scope_id));
+            }
+        }
+    }
+    ipv4.sort();
+    ipv6_with_scope_id.sort();
+    let property = |key: &str| {
+        resolved
+            .txt_properties
+            .get_property_val_str(key)
+            .unwrap_or("RtUnknown")
+            .to_string()
+    };
+    let main_robot_type_name = property("MainRobotType");
+    let secondary_robot_type_name = property("SecondaryRobotType");
+    DiscoveredDevice {
+        hostname: resolved.get_hostname().to_string(),
+        port: resolved.get_port(),
+        ipv4,
+        ipv6_with_scope_id,
+        main_robot_type: parse_robot_type(&main_robot_type_name),
+        secondary_robot_type: parse_robot_type(&secondary_robot_type_name),
+        main_robot_type_name,
+        secondary_robot_type_name,
+    }
+}
+
+/// Browses for HexFellow devices until the stream is dropped.
+///
+/// A device is yielded when first found, and again whenever what we know about it changes (e.g. an IPv4
+/// address shows up after the IPv6 one). Repeated announcements with nothing new are skipped. Each yielded
+/// value has everything known about the device so far.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut devices = robot_demos::discover_devices()?;
+///     while let Some(device) = devices.next().await {
+///         println!("{} ({:?}) at {:?}", device.hostname, device.main_robot_type, device.connect_url());
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn discover_devices() -> Result<BoxStream<'static, DiscoveredDevice>, anyhow::Error> {
+    let mdns = ServiceDaemon::new()?;
+    let receiver = mdns.browse(HEXFELLOW_SERVICE_TYPE)?;
+    let known: HashMap<String, DiscoveredDevice> = HashMap::new();
+    Ok(futures_util::stream::unfold(
+        (DaemonGuard(mdns), receiver, known),
+        |(guard, receiver, mut known)| async move {
+            loop {
+                let event = receiver.recv_async().await.ok()?;
+                let ServiceEvent::ServiceResolved(resolved) = event else {
+                    continue;
+                };
+                let found = device_from_resolved(&resolved);
+                let device = match known.get(&found.hostname) {
+                    Some(existing) => {
+                        let mut merged = existing.clone();
+                        merged.merge(found);
+                        if &merged == existing {
+                            continue;
+                        }
+                        merged
+                    }
+                    None => found,
+                };
+                known.insert(device.hostname.clone(), device.clone());
+                return Some((device, (guard, receiver, known)));
+            }
+        },
+    )
+    .boxed())
+}
+
+/// Browses for `duration`, then returns every device found, sorted by hostname.
+pub async fn scan_devices(duration: Duration) -> Result<Vec<DiscoveredDevice>, anyhow::Error> {
+    let mut stream = discover_devices()?;
+    let mut devices: HashMap<String, DiscoveredDevice> = HashMap::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            device = stream.next() => match device {
+                Some(device) => {
+                    devices.insert(device.hostname.clone(), device);
+                }
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    let mut devices: Vec<_> = devices.into_values().collect();
+    devices.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    Ok(devices)
+}
@@ -0,0 +1,597 @@
+use crate::proto_public_api::{self, ApiDown, GamepadRead, SingleMotorTarget};
+use crate::velocity_smoother::VelocityLimits;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A stick or trigger of [`GamepadRead`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAxis {
+    pub fn value(self, read: &GamepadRead) -> f64 {
+        (match self {
+            GamepadAxis::LeftStickX => read.left_stick_x,
+            GamepadAxis::LeftStickY => read.left_stick_y,
+            GamepadAxis::RightStickX => read.right_stick_x,
+            GamepadAxis::RightStickY => read.right_stick_y,
+            GamepadAxis::LeftTrigger => read.left_trigger,
+            GamepadAxis::RightTrigger => read.right_trigger,
+        }) as f64
+    }
+}
+
+/// A button of [`GamepadRead`], including the bumpers, stick clicks and the dpad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadButton {
+    A,
+    B,
+    X,
+    Y,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+impl GamepadButton {
+    pub fn is_pressed(self, read: &GamepadRead) -> bool {
+        match self {
+            GamepadButton::A => read.a_button,
+            GamepadButton::B => read.b_button,
+            GamepadButton::X => read.x_button,
+            GamepadButton::Y => read.y_button,
+            GamepadButton::LeftBumper => read.left_bumper,
+            GamepadButton::RightBumper => read.right_bumper,
+            GamepadButton::Select => read.select_button,
+            GamepadButton::Start => read.start_button,
+            GamepadButton::LeftStick => read.left_stick_button,
+            GamepadButton::RightStick => read.right_stick_button,
+            GamepadButton::DpadUp => read.dpad_up,
+            GamepadButton::DpadDown => read.dpad_down,
+            GamepadButton::DpadLeft => read.dpad_left,
+            GamepadButton::DpadRight => read.dpad_right,
+        }
+    }
+}
+
+/// The first gamepad (`SdtGamepad`) reported in `msg`, if any.
+pub fn gamepad_read(msg: &proto_public_api::ApiUp) -> Option<&GamepadRead> {
+    msg.secondary_device_status.iter().find_map(|device| {
+        if device.device_type != proto_public_api::SecondaryDeviceType::SdtGamepad as i32 {
+            return None;
+        }
+        match &device.status {
+            Some(proto_public_api::secondary_device_status::Status::GamepadRead(read)) => {
+                Some(read)
+            }
+            _ => None,
+        }
+    })
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_deadzone() -> f64 {
+    0.1
+}
+
+fn default_deadman() -> GamepadButton {
+    GamepadButton::LeftBumper
+}
+
+/// One output driven by one axis, e.g. `{ axis = "left_stick_x", scale = -1.0 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AxisMapping {
+    pub axis: GamepadAxis,
+    /// Output at full deflection. Negative flips the direction. Defaults to 1.0.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Overrides [`GamepadMapping::deadzone`] for this axis.
+    pub deadzone: Option<f64>,
+}
+
+impl AxisMapping {
+    pub fn new(axis: GamepadAxis, scale: f64) -> Self {
+        Self {
+            axis,
+            scale,
+            deadzone: None,
+        }
+    }
+
+    /// The axis value with the deadzone cut out and the rest stretched back to the full range, times the scale.
+    /// A non-finite reading counts as centered.
+    fn apply(&self, read: &GamepadRead, default_deadzone: f64) -> f64 {
+        let deadzone = self.deadzone.unwrap_or(default_deadzone);
+        let value = self.axis.value(read);
+        if !value.is_finite() {
+            return 0.0;
+        }
+        let value = value.clamp(-1.0, 1.0);
+        if value.abs() <= deadzone {
+            return 0.0;
+        }
+        value.signum() * (value.abs() - deadzone) / (1.0 - deadzone) * self.scale
+    }
+}
+
+/// Sticks to base speeds, as fractions of the base's maximum velocity.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BaseMapping {
+    /// Forward.
+    pub x: Option<AxisMapping>,
+    /// Left.
+    pub y: Option<AxisMapping>,
+    /// Counter-clockwise.
+    pub z: Option<AxisMapping>,
+}
+
+/// Buttons or an axis to move a lift up and down, as a fraction of its speed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LiftMapping {
+    pub up: Option<GamepadButton>,
+    pub down: Option<GamepadButton>,
+    /// Added to the buttons, e.g. a stick for finer control.
+    pub axis: Option<AxisMapping>,
+}
+
+/// An axis to jog one arm joint at a time, and buttons to pick the joint.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArmMapping {
+    /// Speed of the selected joint, as a fraction of the jog speed.
+    pub jog: AxisMapping,
+    pub next_joint: GamepadButton,
+    pub previous_joint: GamepadButton,
+}
+
+/// Everything that can go wrong while loading a gamepad mapping.
+#[derive(Debug)]
+pub enum GamepadMappingError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for GamepadMappingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GamepadMappingError::Read(path, e) => {
+                write!(
+                    f,
+                    "Failed to read gamepad mapping {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            GamepadMappingError::Parse(path, e) => {
+                write!(
+                    f,
+                    "Failed to parse gamepad mapping {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            GamepadMappingError::Invalid(path, reason) => {
+                write!(f, "Invalid gamepad mapping {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GamepadMappingError {}
+
+/// Which sticks and buttons do what, usually loaded from a TOML file:
+///
+/// ```toml
+/// deadman = "left_bumper" # hold to move, default "left_bumper"
+/// deadzone = 0.1          # default 0.1
+///
+/// [base]
+/// x = { axis = "left_stick_y" }
+/// y = { axis = "left_stick_x", scale = -1.0 }
+/// z = { axis = "right_stick_x", scale = -1.0, deadzone = 0.2 }
+///
+/// [lift]
+/// up = "dpad_up"
+/// down = "dpad_down"
+///
+/// [arm]
+/// jog = { axis = "right_stick_y" }
+/// next_joint = "dpad_right"
+/// previous_joint = "dpad_left"
+/// ```
+///
+/// Sections left out are not mapped. Gamepads disagree on which way is positive, flip an axis with a negative
+/// `scale`. See `examples/gamepad/mapping.toml`, which is the same as [`GamepadMapping::default`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GamepadMapping {
+    /// Nothing moves unless this is held.
+    #[serde(default = "default_deadman")]
+    pub deadman: GamepadButton,
+    /// Axis readings this close to zero are zero.
+    #[serde(default = "default_deadzone")]
+    pub deadzone: f64,
+    pub base: Option<BaseMapping>,
+    pub lift: Option<LiftMapping>,
+    pub arm: Option<ArmMapping>,
+}
+
+impl Default for GamepadMapping {
+    fn default() -> Self {
+        Self {
+            deadman: default_deadman(),
+            deadzone: default_deadzone(),
+            base: Some(BaseMapping {
+                x: Some(AxisMapping::new(GamepadAxis::LeftStickY, 1.0)),
+                y: Some(AxisMapping::new(GamepadAxis::LeftStickX, -1.0)),
+                z: Some(AxisMapping::new(GamepadAxis::RightStickX, -1.0)),
+            }),
+            lift: Some(LiftMapping {
+                up: Some(GamepadButton::DpadUp),
+                down: Some(GamepadButton::DpadDown),
+                axis: None,
+            }),
+            arm: Some(ArmMapping {
+                jog: AxisMapping::new(GamepadAxis::RightStickY, 1.0),
+                next_joint: GamepadButton::DpadRight,
+                previous_joint: GamepadButton::DpadLeft,
+            }),
+        }
+    }
+}
+
+impl GamepadMapping {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GamepadMappingError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| GamepadMappingError::Read(path.to_owned(), e))?;
+        let mapping: GamepadMapping =
+            toml::from_str(&text).map_err(|e| GamepadMappingError::Parse(path.to_owned(), e))?;
+        mapping
+            .validate()
+            .map_err(|reason| GamepadMappingError::Invalid(path.to_owned(), reason))?;
+        Ok(mapping)
+    }
+
+    /// Checks what the file format can't: deadzones in [0, 1), finite scales, and the deadman button not doing
+    /// anything else.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut axes = Vec::new();
+        let mut buttons = Vec::new();
+        if let Some(base) = &self.base {
+            axes.extend([base.x, base.y, base.z].into_iter().flatten());
+        }
+        if let Some(lift) = &self.lift {
+            axes.extend(lift.axis);
+            buttons.extend([lift.up, lift.down].into_iter().flatten());
+        }
+        if let Some(arm) = &self.arm {
+            axes.push(arm.jog);
+            buttons.extend([arm.next_joint, arm.previous_joint]);
+        }
+        for deadzone in axes
+            .iter()
+            .filter_map(|axis| axis.deadzone)
+            .chain([self.deadzone])
+        {
+            if !(0.0..1.0).contains(&deadzone) {
+                return Err(format!("deadzone must be in [0, 1), got {}", deadzone));
+            }
+        }
+        if let Some(axis) = axes.iter().find(|axis| !axis.scale.is_finite()) {
+            return Err(format!("scale of {:?} is not finite", axis.axis));
+        }
+        if buttons.contains(&self.deadman) {
+            return Err(format!(
+                "the deadman button {:?} can't also be mapped to something else",
+                self.deadman
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One arm joint to jog.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArmJog {
+    pub joint: usize,
+    /// Fraction of the jog speed, -1 to 1 with the default scale.
+    pub speed: f64,
+}
+
+impl ArmJog {
+    /// Speed targets for every motor: the jogged joint at `speed` times `max_speed` (rad/s), the others held at 0.
+    pub fn motor_targets(&self, motor_count: usize, max_speed: f64) -> ApiDown {
+        ApiDown::arm_motor_targets(
+            (0..motor_count)
+                .map(|joint| {
+                    SingleMotorTarget::speed(if joint == self.joint {
+                        self.speed * max_speed
+                    } else {
+                        0.0
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// What the gamepad asks for right now. All zero unless the deadman is held.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GamepadCommand {
+    /// The deadman is held, on a gamepad that is still reporting.
+    pub enabled: bool,
+    /// Base `speed_x`, `speed_y` and `speed_z`, as fractions of the maximum velocity.
+    pub base: [f64; 3],
+    /// Lift speed as a fraction of its maximum, positive is up.
+    pub lift: f64,
+    pub arm: Option<ArmJog>,
+}
+
+impl GamepadCommand {
+    /// The base speeds in m/s and rad/s, for [`crate::VelocitySmoother::set_target`].
+    pub fn base_speed(&self, limits: &VelocityLimits) -> [f64; 3] {
+        [
+            self.base[0] * limits.x.max_velocity,
+            self.base[1] * limits.y.max_velocity,
+            self.base[2] * limits.z.max_velocity,
+        ]
+    }
+
+    /// How far to move the lift target over `dt`, in pulses, at `max_speed` pulses per second. Add it to the
+    /// current target and send it with `ApiDown::linear_lift_target_pos`.
+    pub fn lift_increment(&self, max_speed: f64, dt: Duration) -> i64 {
+        (self.lift * max_speed * dt.as_secs_f64()).round() as i64
+    }
+}
+
+/// Turns the reads of the gamepad paired to the robot into base, lift and arm commands.
+///
+/// Feed it every `ApiUp` (or every [`GamepadRead`]), then ask for [`GamepadTeleop::command`] each control tick.
+/// Everything is zero while the deadman button is released, and when no read arrived for the timeout (300ms by
+/// default), so a gamepad that runs out of battery or goes out of range stops the robot. The arm joint is picked
+/// with the next and previous joint buttons, also while the deadman is released.
+///
+/// The robot may drive itself with the same gamepad when nobody holds API control. Once your program initializes
+/// API control, only your commands move it.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, GamepadMapping, GamepadTeleop, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     let mut teleop = GamepadTeleop::new(GamepadMapping::default());
+///     while let Some(msg) = stream.next().await {
+///         if teleop.update_api_up(&msg) {
+///             println!("{:?}", teleop.command());
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct GamepadTeleop {
+    mapping: GamepadMapping,
+    timeout: Duration,
+    joint_count: usize,
+    joint: usize,
+    last: Option<(Instant, GamepadRead)>,
+}
+
+impl GamepadTeleop {
+    pub fn new(mapping: GamepadMapping) -> Self {
+        Self {
+            mapping,
+            timeout: Duration::from_millis(300),
+            joint_count: 6,
+            joint: 0,
+            last: None,
+        }
+    }
+
+    /// Everything stops when no read arrived for this long. Defaults to 300ms.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of arm joints to cycle through. Defaults to 6, set it from the arm's `motor_status` length.
+    pub fn arm_joint_count(mut self, count: usize) -> Self {
+        self.joint_count = count.max(1);
+        self.joint = self.joint.min(self.joint_count - 1);
+        self
+    }
+
+    pub fn mapping(&self) -> &GamepadMapping {
+        &self.mapping
+    }
+
+    /// The arm joint the jog axis moves.
+    pub fn selected_joint(&self) -> usize {
+        self.joint
+    }
+
+    /// Takes a new read. Returns the command it asks for.
+    pub fn update(&mut self, read: &GamepadRead) -> GamepadCommand {
+        if let Some(arm) = &self.mapping.arm {
+            let previous = self.last.as_ref().map(|(_, read)| read);
+            let pressed = |button: GamepadButton| {
+                button.is_pressed(read) && !previous.is_some_and(|p| button.is_pressed(p))
+            };
+            if pressed(arm.next_joint) {
+                self.joint = (self.joint + 1) % self.joint_count;
+            }
+            if pressed(arm.previous_joint) {
+                self.joint = (self.joint + self.joint_count - 1) % self.joint_count;
+            }
+        }
+        self.last = Some((Instant::now(), read.clone()));
+        self.command()
+    }
+
+    /// Takes the gamepad read in `msg`, if there is one. Returns whether there was.
+    pub fn update_api_up(&mut self, msg: &proto_public_api::ApiUp) -> bool {
+        match gamepad_read(msg) {
+            Some(read) => {
+                self.update(read);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// What the latest read asks for, or all zero if it is too old or the deadman is released.
+    pub fn command(&self) -> GamepadCommand {
+        let Some((at, read)) = &self.last else {
+            return GamepadCommand::default();
+        };
+        if at.elapsed() > self.timeout || !self.mapping.deadman.is_pressed(read) {
+            return GamepadCommand::default();
+        }
+        let deadzone = self.mapping.deadzone;
+        let axis = |mapping: Option<AxisMapping>| mapping.map_or(0.0, |m| m.apply(read, deadzone));
+        let base = self
+            .mapping
+            .base
+            .as_ref()
+            .map_or([0.0; 3], |base| [axis(base.x), axis(base.y), axis(base.z)]);
+        let lift = self.mapping.lift.as_ref().map_or(0.0, |lift| {
+            let button = |button: Option<GamepadButton>| {
+                if button.is_some_and(|b| b.is_pressed(read)) {
+                    1.0
+                } else {
+                    0.0
+                }
+            };
+            (button(lift.up) - button(lift.down) + axis(lift.axis)).clamp(-1.0, 1.0)
+        });
+        let arm = self.mapping.arm.as_ref().map(|arm| ArmJog {
+            joint: self.joint,
+            speed: arm.jog.apply(read, deadzone),
+        });
+        GamepadCommand {
+            enabled: true,
+            base,
+            lift,
+            arm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(left_stick_y: f32) -> GamepadRead {
+        GamepadRead {
+            left_bumper: true,
+            left_stick_y,
+            ..Default::default()
+        }
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn deadzone_is_cut_out_and_the_rest_stretched() {
+        let mut teleop = GamepadTeleop::new(GamepadMapping::default());
+        assert_eq!(teleop.update(&held(0.08)).base[0], 0.0);
+        assert_eq!(teleop.update(&held(-0.09)).base[0], 0.0);
+        assert_near(teleop.update(&held(0.55)).base[0], 0.5);
+        assert_near(teleop.update(&held(-1.0)).base[0], -1.0);
+        // Readings past full deflection are clamped.
+        assert_near(teleop.update(&held(1.5)).base[0], 1.0);
+    }
+
+    #[test]
+    fn per_axis_deadzone_and_scale() {
+        let mapping = AxisMapping {
+            axis: GamepadAxis::LeftStickY,
+            scale: -2.0,
+            deadzone: Some(0.5),
+        };
+        assert_eq!(mapping.apply(&held(0.4), 0.1), 0.0);
+        assert_near(mapping.apply(&held(0.75), 0.1), -1.0);
+    }
+
+    #[test]
+    fn non_finite_readings_are_centered() {
+        let mut teleop = GamepadTeleop::new(GamepadMapping::default());
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let command = teleop.update(&held(value));
+            assert!(command.enabled);
+            assert_eq!(command.base, [0.0; 3]);
+        }
+    }
+
+    #[test]
+    fn nothing_moves_without_the_deadman() {
+        let mut teleop = GamepadTeleop::new(GamepadMapping::default());
+        let read = GamepadRead {
+            left_bumper: false,
+            ..held(1.0)
+        };
+        assert_eq!(teleop.update(&read), GamepadCommand::default());
+        assert!(teleop.update(&held(1.0)).enabled);
+    }
+
+    #[test]
+    fn stale_reads_stop_everything() {
+        let mut teleop =
+            GamepadTeleop::new(GamepadMapping::default()).timeout(Duration::from_millis(20));
+        assert_eq!(teleop.command(), GamepadCommand::default());
+        assert_near(teleop.update(&held(1.0)).base[0], 1.0);
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(teleop.command(), GamepadCommand::default());
+    }
+
+    #[test]
+    fn joint_buttons_cycle_on_press() {
+        let mut teleop = GamepadTeleop::new(GamepadMapping::default()).arm_joint_count(3);
+        let previous = GamepadRead {
+            dpad_left: true,
+            ..Default::default()
+        };
+        teleop.update(&previous);
+        assert_eq!(teleop.selected_joint(), 2);
+        // Holding the button doesn't repeat.
+        teleop.update(&previous);
+        assert_eq!(teleop.selected_joint(), 2);
+        teleop.update(&GamepadRead::default());
+        teleop.update(&previous);
+        assert_eq!(teleop.selected_joint(), 1);
+    }
+}
@@ -0,0 +1,849 @@
+use crate::connection::{ApiUpStream, RobotConnection, Transport};
+use crate::odometry::{Pose2D, PoseTracker, PoseTrackerOptions};
+use crate::proto_public_api::{self, api_down::Down};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+fn default_margin() -> f64 {
+    0.5
+}
+
+fn default_pose_timeout_s() -> f64 {
+    0.5
+}
+
+/// A polygonal area with its own speed limits, e.g. a doorway or a crowded corner.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeedZone {
+    pub name: String,
+    /// Corners `[x, y]` in meters, in order. The last one connects back to the first.
+    pub polygon: Vec<[f64; 2]>,
+    /// Maximum speed over ground in m/s, `speed_x` and `speed_y` together.
+    pub max_linear_speed: Option<f64>,
+    /// Maximum `speed_z` in rad/s.
+    pub max_angular_speed: Option<f64>,
+}
+
+/// Everything that can go wrong while loading a geofence.
+#[derive(Debug)]
+pub enum GeofenceError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for GeofenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeofenceError::Read(path, e) => {
+                write!(f, "Failed to read geofence {}: {}", path.display(), e)
+            }
+            GeofenceError::Parse(path, e) => {
+                write!(f, "Failed to parse geofence {}: {}", path.display(), e)
+            }
+            GeofenceError::Invalid(path, reason) => {
+                write!(f, "Invalid geofence {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeofenceError {}
+
+/// Why a speed command was changed. See [`Geofence::limit`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Intervention {
+    /// No pose yet, or none for longer than the pose timeout. Nothing moves.
+    NoPose { age: Option<Duration> },
+    /// Outside the boundary, by `distance` meters. Only driving back in is let through.
+    Outside { distance: f64 },
+    /// Heading out, `distance` meters from the boundary. Linear speed was scaled by `scale`.
+    NearBoundary { distance: f64, scale: f64 },
+    /// Clamped to the limits of a speed zone.
+    SpeedZone { name: String },
+    /// A speed was NaN or infinite. Nothing moves.
+    NonFinite,
+}
+
+impl Intervention {
+    /// Same kind of intervention, ignoring the numbers. Used to log changes instead of every command.
+    fn same_kind(&self, other: &Intervention) -> bool {
+        match (self, other) {
+            (Intervention::SpeedZone { name: a }, Intervention::SpeedZone { name: b }) => a == b,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl std::fmt::Display for Intervention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Intervention::NoPose { age: None } => write!(f, "no pose yet, stopping"),
+            Intervention::NoPose { age: Some(age) } => {
+                write!(f, "no pose for {:?}, stopping", age)
+            }
+            Intervention::Outside { distance } => write!(
+                f,
+                "{:.2}m outside the boundary, only driving back in",
+                distance
+            ),
+            Intervention::NearBoundary { distance, scale } => write!(
+                f,
+                "{:.2}m from the boundary, slowed to {:.0}%",
+                distance,
+                scale * 100.0
+            ),
+            Intervention::SpeedZone { name } => write!(f, "in speed zone \"{}\"", name),
+            Intervention::NonFinite => write!(f, "speed is not finite, stopping"),
+        }
+    }
+}
+
+/// A speed command after [`Geofence::limit`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limited {
+    /// `speed_x`, `speed_y` and `speed_z`, in the base frame.
+    pub speed: [f64; 3],
+    /// Empty if the command went through unchanged.
+    pub interventions: Vec<Intervention>,
+}
+
+/// Where the base may drive, and how fast, usually loaded from a TOML file:
+///
+/// ```toml
+/// margin = 0.5        # start slowing down this far from the boundary, default 0.5
+/// pose_timeout_s = 0.5  # stop when odometry is older than this, default 0.5
+/// start_pose = [1.0, 0.5, 0.0]  # x, y, yaw of the base at startup, default the origin
+/// boundary = [[0.0, 0.0], [6.0, 0.0], [6.0, 4.0], [0.0, 4.0]]
+///
+/// [[zone]]
+/// name = "doorway"
+/// polygon = [[5.0, 1.5], [6.0, 1.5], [6.0, 2.5], [5.0, 2.5]]
+/// max_linear_speed = 0.2
+/// max_angular_speed = 0.3
+/// ```
+///
+/// Coordinates are meters in the odometry frame of [`PoseTracker`]: the base starts at `start_pose`, x forward and
+/// y left of where it started if that is left at the origin. Odometry drifts, so leave room, and make the margin
+/// larger than half the base's footprint: the fence is checked against the center of the base only.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Geofence {
+    /// Corners `[x, y]` of the allowed area, in order.
+    pub boundary: Vec<[f64; 2]>,
+    /// Linear speed heading out is scaled down linearly over this distance from the boundary, to zero on it.
+    #[serde(default = "default_margin")]
+    pub margin: f64,
+    /// Nothing moves when the pose is older than this.
+    #[serde(default = "default_pose_timeout_s")]
+    pub pose_timeout_s: f64,
+    /// `[x, y, yaw]` of the base when the connection starts.
+    #[serde(default)]
+    pub start_pose: [f64; 3],
+    #[serde(default, rename = "zone")]
+    pub zones: Vec<SpeedZone>,
+}
+
+impl Geofence {
+    /// A fence around `boundary`, with the default margin and pose timeout and no speed zones.
+    pub fn new(boundary: Vec<[f64; 2]>) -> Self {
+        Self {
+            boundary,
+            margin: default_margin(),
+            pose_timeout_s: default_pose_timeout_s(),
+            start_pose: [0.0; 3],
+            zones: Vec::new(),
+        }
+    }
+
+    pub fn margin(mut self, margin: f64) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    pub fn start_pose(mut self, pose: Pose2D) -> Self {
+        self.start_pose = [pose.x, pose.y, pose.yaw];
+        self
+    }
+
+    pub fn zone(mut self, zone: SpeedZone) -> Self {
+        self.zones.push(zone);
+        self
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GeofenceError> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|e| GeofenceError::Read(path.to_owned(), e))?;
+        let fence: Geofence =
+            toml::from_str(&text).map_err(|e| GeofenceError::Parse(path.to_owned(), e))?;
+        fence
+            .validate()
+            .map_err(|reason| GeofenceError::Invalid(path.to_owned(), reason))?;
+        Ok(fence)
+    }
+
+    /// Checks what the file format can't: polygons with at least 3 finite corners, a start pose inside the
+    /// boundary, and positive margin, timeout and limits.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.boundary.len() < 3 {
+            return Err("boundary needs at least 3 corners".to_string());
+        }
+        if let Some(corner) = non_finite_corner(&self.boundary) {
+            return Err(format!("boundary has a non-finite corner {:?}", corner));
+        }
+        if self.start_pose.iter().any(|v| !v.is_finite()) {
+            return Err(format!("start pose {:?} is not finite", self.start_pose));
+        }
+        if !self.margin.is_finite() || self.margin <= 0.0 {
+            return Err(format!("margin must be positive, got {}", self.margin));
+        }
+        if !self.pose_timeout_s.is_finite() || self.pose_timeout_s <= 0.0 {
+            return Err(format!(
+                "pose_timeout_s must be positive, got {}",
+                self.pose_timeout_s
+            ));
+        }
+        if !contains(&self.boundary, [self.start_pose[0], self.start_pose[1]]) {
+            return Err(format!(
+                "start pose ({}, {}) is outside the boundary",
+                self.start_pose[0], self.start_pose[1]
+            ));
+        }
+        for zone in &self.zones {
+            if zone.polygon.len() < 3 {
+                return Err(format!("zone \"{}\" needs at least 3 corners", zone.name));
+            }
+            if let Some(corner) = non_finite_corner(&zone.polygon) {
+                return Err(format!(
+                    "zone \"{}\" has a non-finite corner {:?}",
+                    zone.name, corner
+                ));
+            }
+            for limit in [zone.max_linear_speed, zone.max_angular_speed]
+                .into_iter()
+                .flatten()
+            {
+                if !limit.is_finite() || limit < 0.0 {
+                    return Err(format!(
+                        "zone \"{}\" has an invalid speed limit {}",
+                        zone.name, limit
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits `speed` (`speed_x`, `speed_y`, `speed_z` in the base frame) for a base at `pose`.
+    ///
+    /// - Outside the boundary, linear speed is zeroed unless it heads back towards the closest point of the
+    ///   boundary. Right on the boundary counts as outside, and only driving inward across it goes through.
+    /// - Inside, within `margin` of the boundary, linear speed heading out is scaled down with the distance left.
+    /// - In speed zones, linear and angular speed are clamped to the lowest limits of the zones the base is in.
+    ///
+    /// Turning on the spot is always allowed, within the speed zone limits. A speed that is NaN or infinite stops
+    /// the base, as nothing above could be checked against it.
+    pub fn limit(&self, pose: Pose2D, speed: [f64; 3]) -> Limited {
+        if speed.iter().any(|v| !v.is_finite()) {
+            return Limited {
+                speed: [0.0; 3],
+                interventions: vec![Intervention::NonFinite],
+            };
+        }
+        let mut interventions = Vec::new();
+        let [mut vx, mut vy, mut vz] = speed;
+        let point = [pose.x, pose.y];
+        let (sin, cos) = pose.yaw.sin_cos();
+        // Linear speed in the odometry frame.
+        let world = [vx * cos - vy * sin, vx * sin + vy * cos];
+
+        let (distance, closest, inward) = closest_point(&self.boundary, point);
+        let towards_boundary = [closest[0] - point[0], closest[1] - point[1]];
+        let heading = world[0] * towards_boundary[0] + world[1] * towards_boundary[1];
+        if distance == 0.0 || !contains(&self.boundary, point) {
+            // On the boundary there is no direction to the closest point, the edge says which way is in.
+            let heading_in = if distance == 0.0 {
+                world[0] * inward[0] + world[1] * inward[1]
+            } else {
+                heading
+            };
+            if (vx != 0.0 || vy != 0.0) && heading_in <= 0.0 {
+                vx = 0.0;
+                vy = 0.0;
+                interventions.push(Intervention::Outside { distance });
+            }
+        } else if distance < self.margin && heading > 0.0 {
+            let scale = distance / self.margin;
+            vx *= scale;
+            vy *= scale;
+            interventions.push(Intervention::NearBoundary { distance, scale });
+        }
+
+        for zone in &self.zones {
+            if !contains(&zone.polygon, point) {
+                continue;
+            }
+            let mut clamped = false;
+            if let Some(max) = zone.max_linear_speed {
+                let linear = vx.hypot(vy);
+                if linear > max {
+                    vx *= max / linear;
+                    vy *= max / linear;
+                    clamped = true;
+                }
+            }
+            if let Some(max) = zone.max_angular_speed {
+                if vz.abs() > max {
+                    vz = vz.clamp(-max, max);
+                    clamped = true;
+                }
+            }
+            if clamped {
+                interventions.push(Intervention::SpeedZone {
+                    name: zone.name.clone(),
+                });
+            }
+        }
+
+        Limited {
+            speed: [vx, vy, vz],
+            interventions,
+        }
+    }
+}
+
+/// Whether `point` is inside `polygon`, by ray casting.
+fn contains(polygon: &[[f64; 2]], point: [f64; 2]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[j]);
+        if (a[1] > point[1]) != (b[1] > point[1])
+            && point[0] < (b[0] - a[0]) * (point[1] - a[1]) / (b[1] - a[1]) + a[0]
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Distance from `point` to the closest point on the edges of `polygon`, that point, and the unit normal of its edge
+/// pointing into the polygon.
+fn closest_point(polygon: &[[f64; 2]], point: [f64; 2]) -> (f64, [f64; 2], [f64; 2]) {
+    // Twice the signed area, positive when the corners go counter-clockwise and the inside is left of each edge.
+    let area: f64 = (0..polygon.len())
+        .map(|i| {
+            let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+            a[0] * b[1] - b[0] * a[1]
+        })
+        .sum();
+    let mut best = (f64::INFINITY, point, [0.0; 2]);
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let edge = [b[0] - a[0], b[1] - a[1]];
+        let length_squared = edge[0] * edge[0] + edge[1] * edge[1];
+        if length_squared == 0.0 {
+            // A repeated corner, the edges on either side cover it.
+            continue;
+        }
+        let t = (((point[0] - a[0]) * edge[0] + (point[1] - a[1]) * edge[1]) / length_squared)
+            .clamp(0.0, 1.0);
+        let closest = [a[0] + t * edge[0], a[1] + t * edge[1]];
+        let distance = (point[0] - closest[0]).hypot(point[1] - closest[1]);
+        if distance < best.0 {
+            let length = length_squared.sqrt();
+            let left = [-edge[1] / length, edge[0] / length];
+            let inward = if area >= 0.0 {
+                left
+            } else {
+                [-left[0], -left[1]]
+            };
+            best = (distance, closest, inward);
+        }
+    }
+    best
+}
+
+fn non_finite_corner(polygon: &[[f64; 2]]) -> Option<[f64; 2]> {
+    polygon
+        .iter()
+        .find(|corner| !corner[0].is_finite() || !corner[1].is_finite())
+        .copied()
+}
+
+/// The pose the fence checks against, fed from the stream.
+struct Tracked {
+    tracker: PoseTracker,
+    updated_at: Option<Instant>,
+}
+
+/// The limits a [`GeofencedConnection`] applies, against its latest pose. See [`GeofencedConnection::limiter`].
+#[derive(Clone)]
+pub struct GeofenceLimiter {
+    fence: Geofence,
+    tracked: Arc<Mutex<Tracked>>,
+}
+
+impl GeofenceLimiter {
+    pub fn fence(&self) -> &Geofence {
+        &self.fence
+    }
+
+    /// Latest pose, and how old it is. `None` before the first odometry.
+    pub fn pose(&self) -> Option<(Pose2D, Duration)> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked
+            .updated_at
+            .map(|at| (tracked.tracker.pose(), at.elapsed()))
+    }
+
+    /// `speed` as the connection would limit it right now: [`Geofence::limit`] at the latest pose, or zero without a
+    /// pose younger than `pose_timeout_s`.
+    pub fn limit(&self, speed: [f64; 3]) -> Limited {
+        match self.pose() {
+            Some((pose, age)) if age.as_secs_f64() <= self.fence.pose_timeout_s => {
+                self.fence.limit(pose, speed)
+            }
+            pose => Limited {
+                speed: [0.0; 3],
+                interventions: if speed == [0.0; 3] {
+                    Vec::new()
+                } else {
+                    vec![Intervention::NoPose {
+                        age: pose.map(|(_, age)| age),
+                    }]
+                },
+            },
+        }
+    }
+}
+
+/// [`RobotConnection`] that keeps the base inside a [`Geofence`].
+///
+/// Every base `XyzSpeed` sent through it goes through [`Geofence::limit`], against the pose dead-reckoned from
+/// the odometry in its own stream. Raw base motor targets are refused, as the fence can't tell where they go. Until
+/// the first odometry arrives, and whenever it is older than `pose_timeout_s`, speeds are zeroed; so the stream must
+/// be taken and polled, which a [`crate::ControlSession`] wrapped around this does.
+///
+/// Wrap the connection before anything else gets it. There is no way to turn the fence off or reach the inner
+/// connection afterwards. Interventions are logged at warn when they start and at info when they end, and every
+/// changed command at debug. [`GeofencedConnection::interventions`] has them too, e.g. for a UI.
+///
+/// A [`crate::VelocitySmoother`] in front of the connection doesn't know its output was cut, and would jump back to
+/// the speed it thinks it is at once the fence lets go. Limit the target with [`GeofencedConnection::limiter`]
+/// before it is ramped instead; the connection still checks every command that goes out.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, ControlSession, Geofence, GeofencedConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let fence = Geofence::load("lab.toml")?;
+///     let session = ControlSession::base(Box::new(GeofencedConnection::new(connection, fence)?)).await?;
+///     // Drive `session` as usual, the fence applies to everything sent through it.
+///     Ok(())
+/// }
+/// ```
+pub struct GeofencedConnection {
+    inner: Box<dyn RobotConnection>,
+    limiter: GeofenceLimiter,
+    active: watch::Sender<Vec<Intervention>>,
+}
+
+impl GeofencedConnection {
+    /// Fails if `fence` does not pass [`Geofence::validate`], which [`Geofence::load`] already checks.
+    pub fn new(inner: Box<dyn RobotConnection>, fence: Geofence) -> Result<Self, anyhow::Error> {
+        fence
+            .validate()
+            .map_err(|reason| anyhow::anyhow!("Invalid geofence: {}", reason))?;
+        let [x, y, yaw] = fence.start_pose;
+        let tracker = PoseTracker::with_options(PoseTrackerOptions {
+            origin: Pose2D::new(x, y, yaw),
+            record_trajectory: false,
+            ..Default::default()
+        });
+        Ok(Self {
+            inner,
+            limiter: GeofenceLimiter {
+                fence,
+                tracked: Arc::new(Mutex::new(Tracked {
+                    tracker,
+                    updated_at: None,
+                })),
+            },
+            active: watch::channel(Vec::new()).0,
+        })
+    }
+
+    pub fn fence(&self) -> &Geofence {
+        self.limiter.fence()
+    }
+
+    /// Latest pose, and how old it is. `None` before the first odometry.
+    pub fn pose(&self) -> Option<(Pose2D, Duration)> {
+        self.limiter.pose()
+    }
+
+    /// A handle that limits speeds the way this connection does, e.g. a target before it goes through a
+    /// [`crate::VelocitySmoother`]. Keeps working after the connection is boxed.
+    pub fn limiter(&self) -> GeofenceLimiter {
+        self.limiter.clone()
+    }
+
+    /// Interventions on the latest speed command, empty while the fence is not doing anything.
+    pub fn interventions(&self) -> watch::Receiver<Vec<Intervention>> {
+        self.active.subscribe()
+    }
+
+    /// Publishes the interventions on this command, and logs the ones that started or ended with it.
+    fn update_active(&mut self, interventions: Vec<Intervention>) {
+        let previous = self.active.send_replace(interventions);
+        let current = self.active.borrow();
+        for intervention in current.iter() {
+            if !previous.iter().any(|p| p.same_kind(intervention)) {
+                warn!("Geofence: {}", intervention);
+            }
+        }
+        for intervention in &previous {
+            if !current.iter().any(|c| c.same_kind(intervention)) {
+                info!("Geofence: no longer {}", ended(intervention));
+            }
+        }
+    }
+
+    /// `msg` as it may be sent, or why it may not.
+    fn filter(
+        &mut self,
+        mut msg: proto_public_api::ApiDown,
+    ) -> Result<proto_public_api::ApiDown, anyhow::Error> {
+        use proto_public_api::base_command::Command as BaseCommand;
+        use proto_public_api::simple_base_move_command::Command as SimpleMove;
+        let Some(Down::BaseCommand(command)) = &mut msg.down else {
+            return Ok(msg);
+        };
+        let speed = match &mut command.command {
+            Some(BaseCommand::MotorTargets(_)) => {
+                warn!("Geofence: refused raw base motor targets");
+                return Err(anyhow::anyhow!(
+                    "Base motor targets can't be checked against the geofence, send XyzSpeed instead"
+                ));
+            }
+            Some(BaseCommand::SimpleMoveCommand(proto_public_api::SimpleBaseMoveCommand {
+                command: Some(SimpleMove::XyzSpeed(speed)),
+            })) => speed,
+            _ => return Ok(msg),
+        };
+
+        let requested = [
+            speed.speed_x as f64,
+            speed.speed_y as f64,
+            speed.speed_z as f64,
+        ];
+        let limited = self.limiter.limit(requested);
+        if !limited.interventions.is_empty() {
+            debug!(
+                "Geofence: ({:+.3}, {:+.3}, {:+.3}) limited to ({:+.3}, {:+.3}, {:+.3})",
+                requested[0],
+                requested[1],
+                requested[2],
+                limited.speed[0],
+                limited.speed[1],
+                limited.speed[2]
+            );
+        }
+        speed.speed_x = limited.speed[0] as f32;
+        speed.speed_y = limited.speed[1] as f32;
+        speed.speed_z = limited.speed[2] as f32;
+        self.update_active(limited.interventions);
+        Ok(msg)
+    }
+}
+
+fn ended(intervention: &Intervention) -> String {
+    match intervention {
+        Intervention::NoPose { .. } => "missing the pose".to_string(),
+        Intervention::Outside { .. } => "outside the boundary".to_string(),
+        Intervention::NearBoundary { .. } => "slowed near the boundary".to_string(),
+        Intervention::SpeedZone { name } => format!("limited by speed zone \"{}\"", name),
+        Intervention::NonFinite => "getting non-finite speeds".to_string(),
+    }
+}
+
+impl RobotConnection for GeofencedConnection {
+    fn transport(&self) -> Transport {
+        self.inner.transport()
+    }
+
+    fn session_id(&self) -> u32 {
+        self.inner.session_id()
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.inner.robot_type()
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        match self.filter(msg) {
+            Ok(msg) => self.inner.send(msg),
+            Err(e) => async move { Err(e) }.boxed(),
+        }
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        let tracked = self.limiter.tracked.clone();
+        let stream = self.inner.take_stream()?;
+        Some(
+            stream
+                .inspect(move |msg| {
+                    let mut tracked = tracked.lock().unwrap();
+                    if tracked.tracker.update(msg).is_some() {
+                        tracked.updated_at = Some(Instant::now());
+                    }
+                })
+                .boxed(),
+        )
+    }
+
+    fn decode_errors(&self) -> Option<tokio::sync::broadcast::Receiver<crate::DecodeError>> {
+        self.inner.decode_errors()
+    }
+
+    fn tap_raw_api_up(&mut self, tap: crate::connection::RawApiUpTap) -> bool {
+        self.inner.tap_raw_api_up(tap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4m x 4m, counter-clockwise.
+    fn square() -> Vec<[f64; 2]> {
+        vec![[0.0, 0.0], [4.0, 0.0], [4.0, 4.0], [0.0, 4.0]]
+    }
+
+    /// An L: the square without its top right quarter.
+    fn l_shape() -> Vec<[f64; 2]> {
+        vec![
+            [0.0, 0.0],
+            [4.0, 0.0],
+            [4.0, 2.0],
+            [2.0, 2.0],
+            [2.0, 4.0],
+            [0.0, 4.0],
+        ]
+    }
+
+    fn assert_speed(actual: [f64; 3], expected: [f64; 3]) {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() < 1e-9),
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn contains_inside_and_outside() {
+        assert!(contains(&square(), [2.0, 2.0]));
+        assert!(!contains(&square(), [5.0, 2.0]));
+        assert!(!contains(&square(), [2.0, -0.1]));
+        let reversed: Vec<_> = square().into_iter().rev().collect();
+        assert!(contains(&reversed, [2.0, 2.0]));
+    }
+
+    #[test]
+    fn contains_concave() {
+        assert!(contains(&l_shape(), [1.0, 3.0]));
+        assert!(contains(&l_shape(), [3.0, 1.0]));
+        // In the notch, inside the bounding box.
+        assert!(!contains(&l_shape(), [3.0, 3.0]));
+    }
+
+    #[test]
+    fn closest_point_and_inward_normal() {
+        let (distance, closest, inward) = closest_point(&square(), [3.5, 2.0]);
+        assert!((distance - 0.5).abs() < 1e-9);
+        assert_eq!(closest, [4.0, 2.0]);
+        assert_eq!(inward, [-1.0, 0.0]);
+
+        // Outside, past a corner.
+        let (distance, closest, _) = closest_point(&square(), [5.0, 5.0]);
+        assert!((distance - 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(closest, [4.0, 4.0]);
+
+        // Clockwise corners give the same inside.
+        let reversed: Vec<_> = square().into_iter().rev().collect();
+        let (_, _, inward) = closest_point(&reversed, [3.5, 2.0]);
+        assert_eq!(inward, [-1.0, 0.0]);
+    }
+
+    #[test]
+    fn closest_point_concave() {
+        // In the notch, the closest point is on an inner edge.
+        let (distance, closest, inward) = closest_point(&l_shape(), [3.0, 3.5]);
+        assert!((distance - 1.0).abs() < 1e-9);
+        assert_eq!(closest, [2.0, 3.5]);
+        assert_eq!(inward, [-1.0, 0.0]);
+        let (_, closest, inward) = closest_point(&l_shape(), [3.0, 1.8]);
+        assert!((closest[1] - 2.0).abs() < 1e-9);
+        assert_eq!(inward, [0.0, -1.0]);
+    }
+
+    #[test]
+    fn limit_passes_inside() {
+        let fence = Geofence::new(square());
+        let limited = fence.limit(Pose2D::new(2.0, 2.0, 0.0), [0.5, 0.2, 0.3]);
+        assert_speed(limited.speed, [0.5, 0.2, 0.3]);
+        assert!(limited.interventions.is_empty());
+    }
+
+    #[test]
+    fn limit_scales_heading_out_within_margin() {
+        let fence = Geofence::new(square()).margin(0.5);
+        let limited = fence.limit(Pose2D::new(3.8, 2.0, 0.0), [0.5, 0.0, 0.3]);
+        assert_speed(limited.speed, [0.2, 0.0, 0.3]);
+        assert_eq!(limited.interventions.len(), 1);
+        assert!(matches!(
+            limited.interventions[0],
+            Intervention::NearBoundary { scale, .. } if (scale - 0.4).abs() < 1e-9
+        ));
+
+        // The same in the base frame of a base facing the boundary sideways.
+        let limited = fence.limit(
+            Pose2D::new(3.8, 2.0, std::f64::consts::FRAC_PI_2),
+            [0.0, -0.5, 0.0],
+        );
+        assert_speed(limited.speed, [0.0, -0.2, 0.0]);
+    }
+
+    #[test]
+    fn limit_passes_heading_in_within_margin() {
+        let fence = Geofence::new(square()).margin(0.5);
+        let limited = fence.limit(Pose2D::new(3.8, 2.0, 0.0), [-0.5, 0.0, 0.0]);
+        assert_speed(limited.speed, [-0.5, 0.0, 0.0]);
+        assert!(limited.interventions.is_empty());
+    }
+
+    #[test]
+    fn limit_outside_only_lets_back_in() {
+        let fence = Geofence::new(square());
+        let pose = Pose2D::new(4.5, 2.0, 0.0);
+        let limited = fence.limit(pose, [0.5, 0.0, 0.3]);
+        assert_speed(limited.speed, [0.0, 0.0, 0.3]);
+        assert!(matches!(
+            limited.interventions[..],
+            [Intervention::Outside { distance }] if (distance - 0.5).abs() < 1e-9
+        ));
+
+        let limited = fence.limit(pose, [-0.5, 0.0, 0.0]);
+        assert_speed(limited.speed, [-0.5, 0.0, 0.0]);
+        assert!(limited.interventions.is_empty());
+    }
+
+    #[test]
+    fn limit_stops_non_finite_speeds() {
+        let fence = Geofence::new(square());
+        for speed in [
+            [f64::NAN, 0.0, 0.0],
+            [0.0, f64::INFINITY, 0.0],
+            [0.1, 0.0, f64::NAN],
+        ] {
+            // Outside, where a NaN would otherwise slip through every comparison, and inside.
+            for pose in [Pose2D::new(4.5, 2.0, 0.0), Pose2D::new(2.0, 2.0, 0.0)] {
+                let limited = fence.limit(pose, speed);
+                assert_speed(limited.speed, [0.0, 0.0, 0.0]);
+                assert_eq!(limited.interventions, vec![Intervention::NonFinite]);
+            }
+        }
+    }
+
+    #[test]
+    fn limit_on_the_boundary_counts_as_outside() {
+        let fence = Geofence::new(square());
+        let pose = Pose2D::new(4.0, 2.0, 0.0);
+        let limited = fence.limit(pose, [0.5, 0.0, 0.0]);
+        assert_speed(limited.speed, [0.0, 0.0, 0.0]);
+        assert!(matches!(
+            limited.interventions[..],
+            [Intervention::Outside { distance }] if distance == 0.0
+        ));
+        // Along the edge doesn't get anywhere inside either.
+        let limited = fence.limit(pose, [0.0, 0.5, 0.0]);
+        assert_speed(limited.speed, [0.0, 0.0, 0.0]);
+
+        let limited = fence.limit(pose, [-0.5, 0.0, 0.0]);
+        assert_speed(limited.speed, [-0.5, 0.0, 0.0]);
+        assert!(limited.interventions.is_empty());
+    }
+
+    #[test]
+    fn limit_concave_notch_is_outside() {
+        let fence = Geofence::new(l_shape());
+        let limited = fence.limit(Pose2D::new(3.0, 3.0, 0.0), [0.5, 0.0, 0.0]);
+        assert_speed(limited.speed, [0.0, 0.0, 0.0]);
+        // Back down into the bottom arm.
+        let limited = fence.limit(Pose2D::new(3.0, 2.5, 0.0), [0.0, -0.5, 0.0]);
+        assert_speed(limited.speed, [0.0, -0.5, 0.0]);
+    }
+
+    #[test]
+    fn limit_clamps_to_the_lowest_zone() {
+        let zone = |name: &str, linear, angular| SpeedZone {
+            name: name.to_string(),
+            polygon: vec![[1.0, 1.0], [3.0, 1.0], [3.0, 3.0], [1.0, 3.0]],
+            max_linear_speed: linear,
+            max_angular_speed: angular,
+        };
+        let fence = Geofence::new(square())
+            .zone(zone("slow", Some(0.3), None))
+            .zone(zone("slower", Some(0.1), Some(0.2)));
+        let limited = fence.limit(Pose2D::new(2.0, 2.0, 0.0), [0.3, 0.4, -0.5]);
+        assert_speed(limited.speed, [0.06, 0.08, -0.2]);
+        assert_eq!(
+            limited.interventions,
+            vec![
+                Intervention::SpeedZone {
+                    name: "slow".to_string()
+                },
+                Intervention::SpeedZone {
+                    name: "slower".to_string()
+                },
+            ]
+        );
+
+        // Outside the zones nothing is clamped.
+        let limited = fence.limit(Pose2D::new(0.5, 2.0, 0.0), [0.3, 0.4, -0.5]);
+        assert_speed(limited.speed, [0.3, 0.4, -0.5]);
+    }
+
+    #[test]
+    fn validate_rejects_non_finite_corners() {
+        assert!(Geofence::new(square()).validate().is_ok());
+        let mut boundary = square();
+        boundary[1] = [f64::NAN, 0.0];
+        assert!(Geofence::new(boundary).validate().is_err());
+
+        let fence = Geofence::new(square()).zone(SpeedZone {
+            name: "bad".to_string(),
+            polygon: vec![[1.0, 1.0], [f64::INFINITY, 1.0], [1.0, 2.0]],
+            max_linear_speed: Some(0.1),
+            max_angular_speed: None,
+        });
+        assert!(fence.validate().is_err());
+    }
+}
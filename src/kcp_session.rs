@@ -0,0 +1,461 @@
+use crate::connection::{
+    next_websocket_api_up, ApiUpStream, RawApiUpTap, RawApiUpTaps, WebSocketSink,
+    DECODE_ERROR_CAPACITY,
+};
+use crate::proto_public_api;
+use futures_util::{SinkExt, StreamExt};
+use kcp_bindings::{HexSocketOpcode, HexSocketParser, KcpPortOwner};
+use log::{info, warn};
+use prost::Message;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// The `KcpConfig` all our demos use. Please just use this, unless you really know what you are doing.
+pub fn default_kcp_config() -> proto_public_api::KcpConfig {
+    proto_public_api::KcpConfig {
+        window_size_snd_wnd: 64,
+        window_size_rcv_wnd: 64,
+        interval_ms: 10,
+        no_delay: true,
+        nc: true,
+        resend: 2,
+    }
+}
+
+/// Options for [`establish_kcp_session`].
+#[derive(Debug, Clone)]
+pub struct KcpSessionOptions {
+    /// Sent to the robot in `EnableKcp`.
+    pub kcp_config: proto_public_api::KcpConfig,
+    /// How long to wait for the websocket handshake.
+    pub connect_timeout: Duration,
+    /// How long to wait for the first `ApiUp`, which carries the session ID.
+    pub first_message_timeout: Duration,
+    /// How long to wait for `EnableKcp` to be sent, and then for `kcp_server_status`.
+    pub enable_kcp_timeout: Duration,
+    /// How long to wait for the KCP port to be created and the placeholder message to be sent, and then for
+    /// the websocket report frequency to be lowered.
+    pub activate_timeout: Duration,
+}
+
+impl Default for KcpSessionOptions {
+    fn default() -> Self {
+        Self {
+            kcp_config: default_kcp_config(),
+            connect_timeout: Duration::from_secs(5),
+            first_message_timeout: Duration::from_secs(3),
+            enable_kcp_timeout: Duration::from_secs(3),
+            activate_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Step of the KCP bootstrap, used to tell where a timeout happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KcpSessionStep {
+    ConnectWebSocket,
+    FirstMessage,
+    SendEnableKcp,
+    EnableKcp,
+    Activate,
+    SetReportFrequency,
+}
+
+impl std::fmt::Display for KcpSessionStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KcpSessionStep::ConnectWebSocket => write!(f, "connecting websocket"),
+            KcpSessionStep::FirstMessage => write!(f, "waiting for first message"),
+            KcpSessionStep::SendEnableKcp => write!(f, "sending EnableKcp"),
+            KcpSessionStep::EnableKcp => write!(f, "waiting for KCP server status"),
+            KcpSessionStep::Activate => write!(f, "activating KCP connection"),
+            KcpSessionStep::SetReportFrequency => write!(f, "setting websocket report frequency"),
+        }
+    }
+}
+
+/// Everything that can go wrong in [`establish_kcp_session`].
+#[derive(Debug)]
+pub enum KcpSessionError {
+    Timeout(KcpSessionStep),
+    ConnectWebSocket(anyhow::Error),
+    FirstMessage(anyhow::Error),
+    CreateSocket(anyhow::Error),
+    SendEnableKcp(anyhow::Error),
+    KcpServerStatus(anyhow::Error),
+    InvalidServerAddress(String),
+    CreatePort(String),
+    SendPlaceholder(anyhow::Error),
+    SetReportFrequency(anyhow::Error),
+}
+
+impl std::fmt::Display for KcpSessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KcpSessionError::Timeout(step) => write!(f, "Timeout while {}", step),
+            KcpSessionError::ConnectWebSocket(e) => write!(f, "Failed to connect websocket: {}", e),
+            KcpSessionError::FirstMessage(e) => write!(f, "Failed to read first message: {}", e),
+            KcpSessionError::CreateSocket(e) => write!(f, "Failed to create KCP socket: {}", e),
+            KcpSessionError::SendEnableKcp(e) => write!(f, "Failed to send EnableKcp: {}", e),
+            KcpSessionError::KcpServerStatus(e) => {
+                write!(f, "Failed to read KCP server status: {}", e)
+            }
+            KcpSessionError::InvalidServerAddress(addr) => {
+                write!(f, "Invalid KCP server address: {}", addr)
+            }
+            KcpSessionError::CreatePort(e) => write!(f, "Failed to create KCP port: {}", e),
+            KcpSessionError::SendPlaceholder(e) => {
+                write!(f, "Failed to send placeholder message over KCP: {}", e)
+            }
+            KcpSessionError::SetReportFrequency(e) => {
+                write!(f, "Failed to set websocket report frequency: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KcpSessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KcpSessionError::ConnectWebSocket(e)
+            | KcpSessionError::FirstMessage(e)
+            | KcpSessionError::CreateSocket(e)
+            | KcpSessionError::SendEnableKcp(e)
+            | KcpSessionError::KcpServerStatus(e)
+            | KcpSessionError::SendPlaceholder(e)
+            | KcpSessionError::SetReportFrequency(e) => Some(e.as_ref()),
+            KcpSessionError::Timeout(_)
+            | KcpSessionError::InvalidServerAddress(_)
+            | KcpSessionError::CreatePort(_) => None,
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    duration: Duration,
+    step: KcpSessionStep,
+    future: impl Future<Output = Result<T, KcpSessionError>>,
+) -> Result<T, KcpSessionError> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| KcpSessionError::Timeout(step))?
+}
+
+type KcpSendRequest = (Vec<u8>, oneshot::Sender<Result<(), anyhow::Error>>);
+
+/// Cloneable handle to send messages over a KCP session.
+#[derive(Clone)]
+pub struct KcpSender {
+    requests: mpsc::Sender<KcpSendRequest>,
+}
+
+impl KcpSender {
+    /// Sends a single message over KCP.
+    pub async fn send(&self, msg: proto_public_api::ApiDown) -> Result<(), anyhow::Error> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.requests
+            .send((msg.encode_to_vec(), done_tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("KCP session is closed"))?;
+        done_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("KCP session is closed"))?
+    }
+}
+
+/// A ready to use KCP session, returned by [`establish_kcp_session`].
+///
+/// The websocket connection is kept open at `Rf1Hz`, because the robot considers the KCP connection dead once
+/// the websocket dies. Dropping the session closes both.
+pub struct KcpSession {
+    session_id: u32,
+    robot_type: proto_public_api::RobotType,
+    kcp_server_status: proto_public_api::KcpServerStatus,
+    sender: KcpSender,
+    stream: Option<ApiUpStream>,
+    taps: RawApiUpTaps,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+    ws_sink: WebSocketSink,
+    /// Reads the websocket so it stays alive. Owns its read half, so it is aborted on drop to close it.
+    ws_reader: JoinHandle<()>,
+    // Must live as long as the session does.
+    _kcp_port_owner: KcpPortOwner,
+}
+
+impl KcpSession {
+    /// Session ID the robot assigned to this connection.
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Robot type reported in the first `ApiUp`.
+    pub fn robot_type(&self) -> proto_public_api::RobotType {
+        self.robot_type
+    }
+
+    /// What the robot answered to `EnableKcp`.
+    pub fn kcp_server_status(&self) -> &proto_public_api::KcpServerStatus {
+        &self.kcp_server_status
+    }
+
+    /// Returns a cloneable handle to send messages over KCP.
+    pub fn sender(&self) -> KcpSender {
+        self.sender.clone()
+    }
+
+    /// Sends a single message over KCP.
+    pub async fn send(&mut self, msg: proto_public_api::ApiDown) -> Result<(), anyhow::Error> {
+        self.sender.send(msg).await
+    }
+
+    /// Sends a single message over the websocket that is kept alive alongside KCP.
+    ///
+    /// Use this for the last message of a session (e.g. deinitialize), to make absolutely sure it arrives.
+    pub async fn send_over_websocket(
+        &mut self,
+        msg: proto_public_api::ApiDown,
+    ) -> Result<(), anyhow::Error> {
+        crate::send_api_down_message_to_websocket(&mut self.ws_sink, msg).await
+    }
+
+    /// Takes the stream of decoded `ApiUp` messages received over KCP. Returns `None` if it was already taken.
+    pub fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.stream.take()
+    }
+
+    /// Calls `tap` with the payload of every `ApiUp` received over KCP, see
+    /// [`crate::RobotConnection::tap_raw_api_up`].
+    pub fn tap_raw_api_up(&mut self, tap: RawApiUpTap) {
+        self.taps.add(tap);
+    }
+
+    /// Subscribes to the errors of messages the stream skipped, see [`crate::RobotConnection::decode_errors`].
+    pub fn decode_errors(&self) -> broadcast::Receiver<crate::DecodeError> {
+        self.decode_errors.subscribe()
+    }
+
+    /// Closes the websocket, which also ends the KCP session on the robot side.
+    pub async fn close(mut self) -> Result<(), anyhow::Error> {
+        self.ws_sink.close().await?;
+        Ok(())
+    }
+}
+
+impl Drop for KcpSession {
+    fn drop(&mut self) {
+        self.ws_reader.abort();
+    }
+}
+
+/// Connects to a robot over websocket and switches to KCP.
+///
+/// Does the whole handshake:
+/// 1. Read the first `ApiUp` for `session_id`.
+/// 2. Create a local UDP socket, send `EnableKcp` with its port and `options.kcp_config`.
+/// 3. Wait for `kcp_server_status`, then create the KCP port towards `server_port`.
+/// 4. Send a `PlaceholderMessage` over KCP, since the server will not start sending before it hears from us.
+/// 5. Set websocket report frequency to `Rf1Hz`, and keep draining the websocket in the background.
+///
+/// # Arguments
+/// * `url` - The IP address of the robot (e.g. "127.0.0.1" or "[fe80::500d:96ff:fee1:d60b%3]")
+/// * `port` - The websocket port of the robot (e.g. 8439)
+/// * `options` - KCP config and timeouts of each step
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{establish_kcp_session, KcpSessionOptions};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let mut session = establish_kcp_session("127.0.0.1", 8439, &KcpSessionOptions::default())
+///         .await
+///         .expect("Failed to establish KCP session");
+///     let stream = session.take_stream().unwrap();
+///     // ... use the session
+/// }
+/// ```
+pub async fn establish_kcp_session(
+    url: &str,
+    port: u16,
+    options: &KcpSessionOptions,
+) -> Result<KcpSession, KcpSessionError> {
+    let ws_stream = with_timeout(
+        options.connect_timeout,
+        KcpSessionStep::ConnectWebSocket,
+        async {
+            crate::connect_websocket(&format!("ws://{}:{}", url, port))
+                .await
+                .map_err(KcpSessionError::ConnectWebSocket)
+        },
+    )
+    .await?;
+    let (mut ws_sink, mut ws_stream) = ws_stream.split();
+
+    let first = with_timeout(
+        options.first_message_timeout,
+        KcpSessionStep::FirstMessage,
+        async {
+            next_websocket_api_up(&mut ws_stream)
+                .await
+                .map_err(KcpSessionError::FirstMessage)
+        },
+    )
+    .await?;
+    let session_id = first.session_id;
+    let robot_type = first.robot_type();
+
+    let (kcp_socket, local_port) = crate::create_kcp_socket(url)
+        .await
+        .map_err(KcpSessionError::CreateSocket)?;
+
+    with_timeout(
+        options.enable_kcp_timeout,
+        KcpSessionStep::SendEnableKcp,
+        async {
+            crate::send_api_down_message_to_websocket(
+                &mut ws_sink,
+                proto_public_api::ApiDown::enable_kcp(local_port, options.kcp_config.clone()),
+            )
+            .await
+            .map_err(KcpSessionError::SendEnableKcp)
+        },
+    )
+    .await?;
+
+    let kcp_server_status = with_timeout(
+        options.enable_kcp_timeout,
+        KcpSessionStep::EnableKcp,
+        async {
+            loop {
+                let msg = next_websocket_api_up(&mut ws_stream)
+                    .await
+                    .map_err(KcpSessionError::KcpServerStatus)?;
+                if let Some(kcp_server_status) = msg.kcp_server_status {
+                    info!("KCP Enabled");
+                    return Ok(kcp_server_status);
+                }
+            }
+        },
+    )
+    .await?;
+
+    let kcp_server_addr = format!("{}:{}", url, kcp_server_status.server_port);
+    let kcp_server_addr = kcp_server_addr
+        .parse()
+        .map_err(|_| KcpSessionError::InvalidServerAddress(kcp_server_addr))?;
+
+    let (kcp_port_owner, sender, rx) =
+        with_timeout(options.activate_timeout, KcpSessionStep::Activate, async {
+            let (kcp_port_owner, tx, rx) =
+                KcpPortOwner::new_costom_socket(kcp_socket, session_id, kcp_server_addr)
+                    .await
+                    .map_err(|e| KcpSessionError::CreatePort(format!("{:?}", e)))?;
+
+            // Only this task touches the KCP sender, everyone else goes through `KcpSender`.
+            let (requests_tx, mut requests_rx) = mpsc::channel::<KcpSendRequest>(64);
+            tokio::spawn(async move {
+                while let Some((bytes, done)) = requests_rx.recv().await {
+                    let result = KcpPortOwner::send_binary(&tx, bytes)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Failed to send over KCP: {:?}", e));
+                    let _ = done.send(result);
+                }
+            });
+            let sender = KcpSender {
+                requests: requests_tx,
+            };
+
+            // Send any message to activate KCP connection.
+            sender
+                .send(proto_public_api::ApiDown::placeholder())
+                .await
+                .map_err(KcpSessionError::SendPlaceholder)?;
+            Ok((kcp_port_owner, sender, rx))
+        })
+        .await?;
+
+    // We will be decoding KCP messages from now on, websocket only needs to stay alive.
+    with_timeout(
+        options.activate_timeout,
+        KcpSessionStep::SetReportFrequency,
+        async {
+            crate::send_api_down_message_to_websocket(
+                &mut ws_sink,
+                proto_public_api::ApiDown::set_report_frequency(
+                    proto_public_api::ReportFrequency::Rf1Hz,
+                ),
+            )
+            .await
+            .map_err(KcpSessionError::SetReportFrequency)
+        },
+    )
+    .await?;
+    let ws_reader = tokio::spawn(async move { while ws_stream.next().await.is_some() {} });
+
+    let (decode_errors, _) = broadcast::channel(DECODE_ERROR_CAPACITY);
+    let stream_decode_errors = decode_errors.clone();
+    let taps = RawApiUpTaps::default();
+    let stream_taps = taps.clone();
+    let stream = futures_util::stream::unfold(
+        (
+            rx,
+            HexSocketParser::new(),
+            std::collections::VecDeque::new(),
+        ),
+        move |(mut rx, mut parser, mut pending)| {
+            let decode_errors = stream_decode_errors.clone();
+            let taps = stream_taps.clone();
+            async move {
+                loop {
+                    if let Some(msg) = pending.pop_front() {
+                        return Some((msg, (rx, parser, pending)));
+                    }
+                    let bytes = match rx.recv().await {
+                        Some(bytes) => bytes,
+                        None => {
+                            warn!("KCP connection lost");
+                            return None;
+                        }
+                    };
+                    let messages = match parser.parse(&bytes) {
+                        Ok(Some(messages)) => messages,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Failed to parse KCP data: {:?}", e);
+                            continue;
+                        }
+                    };
+                    for (opcode, bytes) in messages {
+                        if opcode == HexSocketOpcode::Binary {
+                            taps.call(&bytes);
+                            match crate::decode_message(&bytes, true) {
+                                Ok(msg) => pending.push_back(msg),
+                                Err(e) => {
+                                    warn!("Skipping message from robot: {}", e);
+                                    // Nobody listening is fine.
+                                    let _ = decode_errors.send(e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    )
+    .boxed();
+
+    Ok(KcpSession {
+        session_id,
+        robot_type,
+        kcp_server_status,
+        sender,
+        stream: Some(stream),
+        taps,
+        decode_errors,
+        ws_sink,
+        ws_reader,
+        _kcp_port_owner: kcp_port_owner,
+    })
+}
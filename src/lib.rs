@@ -1,4 +1,3 @@
-use futures_util::stream::SplitSink;
 use futures_util::SinkExt;
 use log::{warn, info};
 use prost::Message;
@@ -9,6 +8,93 @@ use tokio::time::sleep;
 use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
 #[path = "proto-public-api/version.rs"]
 pub mod proto_public_api_version;
+#[cfg(feature = "socketcan")]
+pub mod can;
+mod base_drive;
+pub mod base_kinematics;
+pub use base_kinematics::{BaseKinematics, KinematicsError, Twist, Wheel};
+pub mod battery;
+pub use battery::{
+    AlertSink, BatteryEvent, BatteryMonitor, BatteryMonitorOptions, BatterySample, ChargeCycle,
+    ChargeCycleLog, CycleKind,
+};
+pub mod benchmark;
+pub use benchmark::{
+    run_benchmark, BenchmarkOptions, BenchmarkReport, DurationSamples, HistogramBucket,
+    InterArrivalResult,
+};
+pub mod commands;
+pub mod connection;
+#[cfg(feature = "kcp")]
+pub mod kcp_session;
+#[cfg(feature = "kcp")]
+pub use connection::KcpConnection;
+#[cfg(feature = "kcp")]
+pub use kcp_session::{
+    default_kcp_config, establish_kcp_session, KcpSender, KcpSession, KcpSessionError,
+    KcpSessionOptions, KcpSessionStep,
+};
+pub use connection::{
+    connect_robot, ApiUpStream, RawApiUpTap, RobotConnection, Transport, WebSocketConnection,
+    WebSocketSink,
+};
+pub mod control_loop;
+pub use control_loop::{ControlLoop, ControlLoopStats, ControlStep, Tick};
+pub mod control_session;
+pub use control_session::{ControlSession, ControlTarget};
+pub mod discovery;
+pub use discovery::{
+    discover_devices, scan_devices, DiscoveredDevice, HEXFELLOW_SERVICE_TYPE,
+};
+pub mod gamepad;
+pub use gamepad::{
+    gamepad_read, ArmJog, ArmMapping, AxisMapping, BaseMapping, GamepadAxis, GamepadButton,
+    GamepadCommand, GamepadMapping, GamepadMappingError, GamepadTeleop, LiftMapping,
+};
+pub mod geofence;
+pub use geofence::{
+    Geofence, GeofenceError, GeofenceLimiter, GeofencedConnection, Intervention, Limited,
+    SpeedZone,
+};
+pub mod mock_robot;
+pub use mock_robot::{MockRobot, MockRobotConfig, ReceivedMessage};
+pub mod odometry;
+pub use odometry::{
+    normalize_angle, trajectory_to_geojson, write_trajectory_csv, write_trajectory_geojson, Pose2D,
+    PoseSample, PoseTracker, PoseTrackerOptions,
+};
+pub mod parking_stop;
+pub use parking_stop::{
+    ParkingStopDevice, ParkingStopEvent, ParkingStopHandler, ParkingStopPolicy, RefusalReason,
+};
+pub mod profile;
+pub use profile::{
+    KcpProfile, ProfileError, RobotProfile, RobotProfiles, RobotTarget, DEFAULT_PROFILES_FILE,
+    PROFILES_FILE_ENV,
+};
+pub mod reconnect;
+pub mod recording;
+pub use recording::{
+    read_recording, replay, replay_api_up, RecordedDirection, RecordedMessage, RecordedTransport,
+    Recorder, RecordingConnection, ReplayConnection,
+};
+pub use reconnect::{ConnectionEvent, ReconnectOptions, ReconnectingConnection};
+pub mod session_holder;
+pub use session_holder::{
+    NotSessionHolder, Ownership, OwnershipEvent, SessionHolderTracker, TAKEOVER_MULTICAST_ADDR,
+};
+pub mod state_cache;
+pub use state_cache::RobotStateCache;
+pub mod teach_repeat;
+pub use teach_repeat::{
+    PathError, PathPoint, PathRecorder, PathReplayer, ReplayEvent, ReplayReport, TaughtPath,
+};
+pub mod velocity_smoother;
+pub use velocity_smoother::{AxisLimits, VelocityLimits, VelocitySmoother, VelocitySmootherError};
+pub mod waypoints;
+pub use waypoints::{
+    Mission, MissionError, MissionEvent, MissionReport, TimeoutAction, Waypoint, WaypointFollower,
+};
 pub const ACCEPTABLE_PROTOCOL_MAJOR_VERSION: u32 = 1;
 pub const MINIMUM_PROTOCOL_MINOR_VERSION: u32 = 0;
 
@@ -17,40 +103,89 @@ pub mod proto_public_api {
     include!(concat!(env!("OUT_DIR"), "/_.rs"));
 }
 
+/// Why an incoming message could not be decoded.
+///
+/// None of these are fatal by themselves. A corrupted frame can just be skipped, while a version mismatch
+/// usually means you should stop and upgrade the firmware (or this library).
+#[derive(Debug, Clone)]
+pub enum DecodeError {
+    /// The bytes are not a valid `ApiUp` protobuf message.
+    Protobuf(prost::DecodeError),
+    /// The robot speaks a different protocol major version.
+    MajorVersionMismatch { expected: u32, actual: u32 },
+    /// The robot's protocol minor version is older than the minimum required.
+    MinorVersionTooOld { minimum: u32, actual: u32 },
+    /// The websocket frame is not a binary frame (e.g. text, ping, pong or close).
+    NonBinaryFrame,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Protobuf(e) => write!(f, "Failed to decode protobuf message: {}", e),
+            DecodeError::MajorVersionMismatch { expected, actual } => write!(
+                f,
+                "Protocol major version is not {}, current version: {}. This might cause compatibility issues. Consider upgrading the base firmware.",
+                expected, actual
+            ),
+            DecodeError::MinorVersionTooOld { minimum, actual } => write!(
+                f,
+                "Protocol minor version is less than {}, current version: {}. This might cause compatibility issues. Consider upgrading the base firmware.",
+                minimum, actual
+            ),
+            DecodeError::NonBinaryFrame => write!(f, "Unexpected message type"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Protobuf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<prost::DecodeError> for DecodeError {
+    fn from(e: prost::DecodeError) -> Self {
+        DecodeError::Protobuf(e)
+    }
+}
+
 pub fn decode_message_with_minimum_protocol_minor_version(
     bytes: &[u8],
     log: bool,
     minimum_protocol_minor_version: u32,
-) -> Result<proto_public_api::ApiUp, anyhow::Error> {
-    let msg = proto_public_api::ApiUp::decode(bytes).unwrap();
-    let ret = msg.clone();
+) -> Result<proto_public_api::ApiUp, DecodeError> {
+    let msg = proto_public_api::ApiUp::decode(bytes)?;
     if log {
-        if let Some(log) = msg.log {
+        if let Some(log) = &msg.log {
             warn!("Log from base: {:?}", log); // Having a log usually means something went boom, so lets print it.
         }
     }
     if msg.protocol_major_version != ACCEPTABLE_PROTOCOL_MAJOR_VERSION {
-        let w = format!(
-            "Protocol major version is not {}, current version: {}. This might cause compatibility issues. Consider upgrading the base firmware.",
-            ACCEPTABLE_PROTOCOL_MAJOR_VERSION, msg.protocol_major_version
-        );
-        warn!("{}", w);
+        let e = DecodeError::MajorVersionMismatch {
+            expected: ACCEPTABLE_PROTOCOL_MAJOR_VERSION,
+            actual: msg.protocol_major_version,
+        };
+        warn!("{}", e);
         // If protocol major version does not match, lets just stop printing odometry.
-        return Err(anyhow::anyhow!(w));
+        return Err(e);
     }
     if msg.protocol_minor_version < minimum_protocol_minor_version {
-        let w = format!(
-            "Protocol minor version is less than {}, current version: {}. This might cause compatibility issues. Consider upgrading the base firmware.",
-            minimum_protocol_minor_version, msg.protocol_minor_version
-        );
-        warn!("{}", w);
+        let e = DecodeError::MinorVersionTooOld {
+            minimum: minimum_protocol_minor_version,
+            actual: msg.protocol_minor_version,
+        };
+        warn!("{}", e);
         // If protocol minor version does not match, lets just stop printing odometry.
-        return Err(anyhow::anyhow!(w));
+        return Err(e);
     }
-    Ok(ret)
+    Ok(msg)
 }
 
-pub fn decode_message(bytes: &[u8], log: bool) -> Result<proto_public_api::ApiUp, anyhow::Error> {
+pub fn decode_message(bytes: &[u8], log: bool) -> Result<proto_public_api::ApiUp, DecodeError> {
     decode_message_with_minimum_protocol_minor_version(bytes, log, MINIMUM_PROTOCOL_MINOR_VERSION)
 }
 
@@ -58,32 +193,29 @@ pub fn decode_websocket_message_with_minimum_protocol_minor_version(
     msg: tungstenite::Message,
     log: bool,
     minimum_protocol_minor_version: u32,
-) -> Result<proto_public_api::ApiUp, anyhow::Error> {
+) -> Result<proto_public_api::ApiUp, DecodeError> {
     match msg {
         tungstenite::Message::Binary(bytes) => decode_message_with_minimum_protocol_minor_version(
             &bytes,
             log,
             minimum_protocol_minor_version,
         ),
-        _ => Err(anyhow::anyhow!("Unexpected message type")),
+        _ => Err(DecodeError::NonBinaryFrame),
     }
 }
 
 pub fn decode_websocket_message(
     msg: tungstenite::Message,
     log: bool,
-) -> Result<proto_public_api::ApiUp, anyhow::Error> {
+) -> Result<proto_public_api::ApiUp, DecodeError> {
     match msg {
         tungstenite::Message::Binary(bytes) => decode_message(&bytes, log),
-        _ => Err(anyhow::anyhow!("Unexpected message type")),
+        _ => Err(DecodeError::NonBinaryFrame),
     }
 }
 
 pub async fn send_api_down_message_to_websocket(
-    ws_sink: &mut SplitSink<
-        WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-        tungstenite::Message,
-    >,
+    ws_sink: &mut WebSocketSink,
     msg: proto_public_api::ApiDown,
 ) -> Result<(), anyhow::Error> {
     ws_sink
@@ -225,9 +357,10 @@ pub fn init_logger() {
 /// use robot_demos::connect_websocket;
 ///
 /// #[tokio::main]
-/// async fn main() {
+/// async fn main() -> Result<(), anyhow::Error> {
 ///     let ws_stream = connect_websocket("ws://127.0.0.1:8439").await?;
 ///     // ... use the stream
+///     Ok(())
 /// }
 /// ```
 pub async fn connect_websocket(
@@ -266,9 +399,10 @@ pub async fn connect_websocket(
 /// use robot_demos::create_kcp_socket;
 ///
 /// #[tokio::main]
-/// async fn main() {
+/// async fn main() -> Result<(), anyhow::Error> {
 ///     let (kcp_socket, local_port) = create_kcp_socket("127.0.0.1").await?;
 ///     // ... use the socket
+///     Ok(())
 /// }
 /// ```
 pub async fn create_kcp_socket(
@@ -1,25 +1,54 @@
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use clap::Parser;
+use futures_util::StreamExt;
+use robot_demos::{discover_devices, scan_devices, DiscoveredDevice};
+use std::time::Duration;
+
+/// Find HexFellow devices on the local network using mDNS.
+#[derive(Parser)]
+struct Args {
+    #[arg(
+        long,
+        help = "Scan for this many seconds, then print what was found and exit. By default, keeps printing devices as they are found"
+    )]
+    scan_seconds: Option<u64>,
+}
+
+fn print_device(device: &DiscoveredDevice) {
+    println!(
+        "Found {}: main {} ({}), secondary {} ({}), connect with `{} {}`, ipv4 {:?}, ipv6 {:?}",
+        device.hostname,
+        device.main_robot_type_name,
+        device.main_robot_type.as_str_name(),
+        device.secondary_robot_type_name,
+        device.secondary_robot_type.as_str_name(),
+        device
+            .connect_url()
+            .as_deref()
+            .unwrap_or("<no usable address>"),
+        device.port,
+        device.ipv4,
+        device.ipv6_with_scope_id
+    );
+}
 
 #[tokio::main]
 async fn main() {
-    // Create a daemon
-    let mdns = ServiceDaemon::new().expect("Failed to create daemon");
+    let args = Args::parse();
 
-    // Browse for a service type.
-    let service_type = "_hexfellow._tcp.local.";
-    let receiver = mdns.browse(service_type).expect("Failed to browse");
-    while let Ok(event) = receiver.recv_async().await {
-        #[allow(clippy::single_match)]
-        match event {
-            ServiceEvent::ServiceResolved(resolved) => {
-                println!(
-                    "Found {:?}: {:?}, {:?}",
-                    resolved.get_hostname(),
-                    resolved.get_addresses(),
-                    resolved.txt_properties
-                );
-            }
-            _ => {}
+    if let Some(seconds) = args.scan_seconds {
+        let devices = scan_devices(Duration::from_secs(seconds))
+            .await
+            .expect("Failed to browse mDNS");
+        for device in &devices {
+            print_device(device);
         }
+        println!("{} device(s) found", devices.len());
+        return;
+    }
+
+    // The same device shows up again whenever we learn more about it, e.g. its IPv4 address.
+    let mut devices = discover_devices().expect("Failed to browse mDNS");
+    while let Some(device) = devices.next().await {
+        print_device(&device);
     }
 }
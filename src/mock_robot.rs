@@ -0,0 +1,892 @@
+//! A fake robot that speaks the same websocket (and KCP) protocol as the real one.
+//!
+//! It is good enough to run the demos and your own control code end-to-end on localhost, without a robot on the
+//! network. It is NOT a simulator: motion is integrated in the simplest way possible, and there is no physics.
+
+use crate::connection::Transport;
+use crate::proto_public_api;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Configuration of a [`MockRobot`].
+#[derive(Debug, Clone)]
+pub struct MockRobotConfig {
+    /// Reported in every `ApiUp`. Decides which status is sent.
+    pub robot_type: proto_public_api::RobotType,
+    /// Session ID of the first connection. Each later connection gets the next one.
+    pub first_session_id: u32,
+    pub protocol_major_version: u32,
+    pub protocol_minor_version: u32,
+    /// Report frequency of a new session, before it sends `SetReportFrequency`.
+    pub default_report_frequency: proto_public_api::ReportFrequency,
+    /// Number of motors in the status. `None` picks a sensible number for `robot_type`.
+    pub motor_count: Option<usize>,
+    /// Enter parking stop if the session holder sends nothing for this long. `None` never times out.
+    pub api_communication_timeout: Option<Duration>,
+    /// How fast the battery drains, in thousandths per minute. It fills at the same rate while charging.
+    pub battery_drain_per_minute: f64,
+}
+
+impl Default for MockRobotConfig {
+    fn default() -> Self {
+        Self {
+            robot_type: proto_public_api::RobotType::RtMaverX4,
+            first_session_id: 1,
+            protocol_major_version: crate::proto_public_api_version::CURRENT_PROTOCOL_MAJOR_VERSION,
+            protocol_minor_version: crate::proto_public_api_version::CURRENT_PROTOCOL_MINOR_VERSION,
+            default_report_frequency: proto_public_api::ReportFrequency::Rf1000Hz,
+            motor_count: None,
+            api_communication_timeout: None,
+            battery_drain_per_minute: 0.0,
+        }
+    }
+}
+
+/// A message the mock robot received.
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub session_id: u32,
+    pub transport: Transport,
+    /// Time since the mock robot was started.
+    pub received_at: Duration,
+    pub message: proto_public_api::ApiDown,
+}
+
+/// What kind of status a robot type reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MockKind {
+    Base,
+    Arm,
+    LinearLift,
+    RotateLift,
+}
+
+fn mock_kind(robot_type: proto_public_api::RobotType) -> MockKind {
+    use proto_public_api::RobotType;
+    match robot_type {
+        RobotType::RtArmSaber750d3Lr3DmDriver
+        | RobotType::RtArmSaber750d4Lr3DmDriver
+        | RobotType::RtArmSaber750h3Lr3DmDriver
+        | RobotType::RtArmSaber750h4Lr3DmDriver
+        | RobotType::RtArmSaberD6x
+        | RobotType::RtArmSaberD7x
+        | RobotType::RtArmArcherD6y => MockKind::Arm,
+        RobotType::RtLotaLinearLift => MockKind::LinearLift,
+        RobotType::RtZeta3Lift => MockKind::RotateLift,
+        _ => MockKind::Base,
+    }
+}
+
+fn default_motor_count(robot_type: proto_public_api::RobotType) -> usize {
+    use proto_public_api::RobotType;
+    match robot_type {
+        RobotType::RtMark1DiffBbDriver | RobotType::RtMaverL2 | RobotType::RtArk2LrDriver => 2,
+        RobotType::RtArmSaberD7x => 7,
+        RobotType::RtArmSaber750d3Lr3DmDriver
+        | RobotType::RtArmSaber750d4Lr3DmDriver
+        | RobotType::RtArmSaber750h3Lr3DmDriver
+        | RobotType::RtArmSaber750h4Lr3DmDriver
+        | RobotType::RtArmSaberD6x
+        | RobotType::RtArmArcherD6y => 6,
+        RobotType::RtLotaLinearLift => 1,
+        RobotType::RtZeta3Lift => 2,
+        _ => 4,
+    }
+}
+
+fn report_interval(frequency: proto_public_api::ReportFrequency) -> tokio::time::Interval {
+    let mut interval = tokio::time::interval(crate::benchmark::report_period(frequency));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    interval
+}
+
+const PULSE_PER_ROTATION: u32 = 65536;
+const WHEEL_RADIUS: f64 = 0.08;
+/// Below this the base warns `WcBatteryLow`.
+const LOW_BATTERY_THOUSANDTH: f64 = 200.0;
+const LINEAR_LIFT_MAX_POS: i64 = 100_000;
+const LINEAR_LIFT_MAX_SPEED: u32 = 20_000;
+
+#[derive(Debug, Clone, Default)]
+struct MockMotor {
+    /// rad/s
+    speed: f64,
+    /// Pulses, kept as f64 so slow speeds still move.
+    position: f64,
+    target: Option<proto_public_api::SingleMotorTarget>,
+}
+
+/// Everything that is shared between sessions, just like the real robot.
+struct MockState {
+    config: MockRobotConfig,
+    started_at: Instant,
+    last_update: Instant,
+    next_session_id: u32,
+    /// Connections that haven't closed yet.
+    open_sessions: usize,
+    session_holder: u32,
+    api_control_initialized: bool,
+    /// Last time the session holder sent anything.
+    last_holder_message: Instant,
+    calibrated: bool,
+    parking_stop: Option<proto_public_api::ParkingStopDetail>,
+    /// In thousandths, kept as f64 so slow drain rates still drain.
+    battery: f64,
+    battery_charging: bool,
+    speed: (f32, f32, f32),
+    pose: (f64, f64, f64),
+    motors: Vec<MockMotor>,
+    linear_lift_target: i64,
+    linear_lift_speed: u32,
+    /// Reported as secondary device 1 when set.
+    gamepad: Option<proto_public_api::GamepadRead>,
+    received: Vec<ReceivedMessage>,
+}
+
+impl MockState {
+    fn new(config: MockRobotConfig) -> Self {
+        let now = Instant::now();
+        let motor_count = config
+            .motor_count
+            .unwrap_or_else(|| default_motor_count(config.robot_type));
+        Self {
+            next_session_id: config.first_session_id,
+            config,
+            started_at: now,
+            last_update: now,
+            open_sessions: 0,
+            session_holder: 0,
+            api_control_initialized: false,
+            last_holder_message: now,
+            calibrated: true,
+            parking_stop: None,
+            battery: 800.0,
+            battery_charging: false,
+            speed: (0.0, 0.0, 0.0),
+            pose: (0.0, 0.0, 0.0),
+            motors: vec![MockMotor::default(); motor_count],
+            linear_lift_target: 0,
+            linear_lift_speed: LINEAR_LIFT_MAX_SPEED,
+            gamepad: None,
+            received: Vec::new(),
+        }
+    }
+
+    fn kind(&self) -> MockKind {
+        mock_kind(self.config.robot_type)
+    }
+
+    fn enter_parking_stop(&mut self, detail: proto_public_api::ParkingStopDetail) {
+        warn!("Mock robot enters parking stop: {}", detail.reason);
+        self.parking_stop = Some(detail);
+        self.api_control_initialized = false;
+        self.session_holder = 0;
+        self.speed = (0.0, 0.0, 0.0);
+        for motor in &mut self.motors {
+            motor.speed = 0.0;
+            motor.target = None;
+        }
+    }
+
+    /// Integrates everything up to now.
+    fn update(&mut self) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        if let Some(timeout) = self.config.api_communication_timeout {
+            if self.api_control_initialized && self.last_holder_message.elapsed() > timeout {
+                self.enter_parking_stop(proto_public_api::ParkingStopDetail {
+                    reason: "API communication timeout".to_string(),
+                    category: proto_public_api::ParkingStopCategory::PscApiCommunicationTimeout
+                        as i32,
+                    is_remotely_clearable: true,
+                });
+            }
+        }
+
+        let drain = self.config.battery_drain_per_minute * dt / 60.0;
+        self.battery = if self.battery_charging {
+            self.battery + drain
+        } else {
+            self.battery - drain
+        }
+        .clamp(0.0, 1000.0);
+
+        let (vx, vy, wz) = self.speed;
+        let (x, y, yaw) = self.pose;
+        let (sin, cos) = yaw.sin_cos();
+        self.pose = (
+            x + (vx as f64 * cos - vy as f64 * sin) * dt,
+            y + (vx as f64 * sin + vy as f64 * cos) * dt,
+            yaw + wz as f64 * dt,
+        );
+
+        let pulses_per_rad = PULSE_PER_ROTATION as f64 / std::f64::consts::TAU;
+        for motor in &mut self.motors {
+            match motor.target.as_ref().and_then(|t| t.target.clone()) {
+                Some(proto_public_api::single_motor_target::Target::Speed(speed))
+                | Some(proto_public_api::single_motor_target::Target::SpeedWithMaxCurrent(
+                    proto_public_api::SpeedWithMaxCurrent { speed, .. },
+                )) => motor.speed = speed,
+                Some(proto_public_api::single_motor_target::Target::Position(position)) => {
+                    motor.position = position as f64;
+                    motor.speed = 0.0;
+                }
+                Some(proto_public_api::single_motor_target::Target::MitTarget(mit)) => {
+                    motor.position = mit.position * pulses_per_rad;
+                    motor.speed = mit.speed;
+                }
+                _ => motor.speed = 0.0,
+            }
+            motor.position += motor.speed * pulses_per_rad * dt;
+        }
+
+        if self.kind() == MockKind::LinearLift {
+            if let Some(motor) = self.motors.first_mut() {
+                let step = self.linear_lift_speed as f64 * dt;
+                let error = self.linear_lift_target as f64 - motor.position;
+                motor.position += error.clamp(-step, step);
+            }
+        }
+    }
+
+    /// Applies a message from `session_id`. Exclusive commands from anyone but the session holder are ignored.
+    fn handle(
+        &mut self,
+        session_id: u32,
+        transport: Transport,
+        message: proto_public_api::ApiDown,
+    ) {
+        self.update();
+        self.received.push(ReceivedMessage {
+            session_id,
+            transport,
+            received_at: self.started_at.elapsed(),
+            message: message.clone(),
+        });
+        if session_id == self.session_holder {
+            self.last_holder_message = Instant::now();
+        }
+        let Some(down) = message.down else {
+            return;
+        };
+        use proto_public_api::api_down::Down;
+        match down {
+            Down::BaseCommand(proto_public_api::BaseCommand {
+                command: Some(command),
+            }) => {
+                use proto_public_api::base_command::Command;
+                match command {
+                    Command::ApiControlInitialize(initialize) => {
+                        self.api_control_initialize(session_id, initialize)
+                    }
+                    Command::ClearParkingStop(_) => self.clear_parking_stop(),
+                    Command::MotorTargets(targets) if self.is_controlling(session_id) => {
+                        self.set_motor_targets(targets)
+                    }
+                    Command::SimpleMoveCommand(proto_public_api::SimpleBaseMoveCommand {
+                        command: Some(command),
+                    }) if self.is_controlling(session_id) => {
+                        use proto_public_api::simple_base_move_command::Command;
+                        self.speed = match command {
+                            Command::XyzSpeed(speed) => {
+                                (speed.speed_x, speed.speed_y, speed.speed_z)
+                            }
+                            Command::ZeroResistance(_) | Command::Brake(_) => (0.0, 0.0, 0.0),
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            Down::ArmCommand(proto_public_api::ArmCommand {
+                command: Some(command),
+            }) => {
+                use proto_public_api::arm_command::Command;
+                match command {
+                    Command::ArmExclusiveCommand(proto_public_api::ArmExclusiveCommand {
+                        exclusive_command: Some(command),
+                    }) => {
+                        use proto_public_api::arm_exclusive_command::ExclusiveCommand;
+                        match command {
+                            ExclusiveCommand::ApiControlInitialize(initialize) => {
+                                self.api_control_initialize(session_id, initialize)
+                            }
+                            ExclusiveCommand::Calibrate(_) => self.calibrated = true,
+                            ExclusiveCommand::ArmApiControlCommand(
+                                proto_public_api::ArmApiControlCommand {
+                                    command:
+                                        Some(
+                                            proto_public_api::arm_api_control_command::Command::MotorTargets(
+                                                targets,
+                                            ),
+                                        ),
+                                },
+                            ) if self.is_controlling(session_id) => self.set_motor_targets(targets),
+                            ExclusiveCommand::ArmApiControlCommand(_) => {}
+                        }
+                    }
+                    Command::ArmSharedCommand(proto_public_api::ArmSharedCommand {
+                        command: Some(command),
+                    }) => match command {
+                        proto_public_api::arm_shared_command::Command::ClearParkingStop(_) => {
+                            self.clear_parking_stop()
+                        }
+                        proto_public_api::arm_shared_command::Command::EnterParkingStop(detail) => {
+                            self.enter_parking_stop(detail)
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            Down::LinearLiftCommand(proto_public_api::LinearLiftCommand {
+                command: Some(command),
+            }) => {
+                use proto_public_api::linear_lift_command::Command;
+                match command {
+                    Command::Calibrate(_) => {
+                        self.calibrated = true;
+                        self.linear_lift_target = 0;
+                    }
+                    Command::TargetPos(pos) => {
+                        self.linear_lift_target = pos.clamp(0, LINEAR_LIFT_MAX_POS)
+                    }
+                    Command::Brake(_) => {
+                        if let Some(motor) = self.motors.first() {
+                            self.linear_lift_target = motor.position as i64;
+                        }
+                    }
+                    Command::SetSpeed(speed) => {
+                        self.linear_lift_speed = speed.min(LINEAR_LIFT_MAX_SPEED)
+                    }
+                }
+            }
+            Down::RotateLiftCommand(proto_public_api::RotateLiftCommand {
+                command: Some(command),
+            }) => {
+                use proto_public_api::rotate_lift_command::Command;
+                match command {
+                    Command::Calibrate(_) => self.calibrated = true,
+                    Command::MotorTargets(targets) => self.set_motor_targets(targets),
+                    Command::RuntimeConfig(_) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_controlling(&self, session_id: u32) -> bool {
+        self.api_control_initialized
+            && self.session_holder == session_id
+            && self.parking_stop.is_none()
+    }
+
+    fn api_control_initialize(&mut self, session_id: u32, initialize: bool) {
+        if initialize {
+            if self.session_holder != 0 && self.session_holder != session_id {
+                warn!(
+                    "Session {} tried to initialize, but session {} is holding the robot",
+                    session_id, self.session_holder
+                );
+                return;
+            }
+            self.api_control_initialized = true;
+            self.session_holder = session_id;
+            self.last_holder_message = Instant::now();
+        } else if self.session_holder == session_id {
+            self.api_control_initialized = false;
+            self.session_holder = 0;
+            self.speed = (0.0, 0.0, 0.0);
+            for motor in &mut self.motors {
+                motor.target = None;
+            }
+        }
+    }
+
+    fn clear_parking_stop(&mut self) {
+        if self
+            .parking_stop
+            .as_ref()
+            .is_some_and(|detail| detail.is_remotely_clearable)
+        {
+            info!("Mock robot parking stop cleared");
+            self.parking_stop = None;
+        }
+    }
+
+    fn set_motor_targets(&mut self, targets: proto_public_api::MotorTargets) {
+        if targets.targets.len() != self.motors.len() {
+            warn!(
+                "Expected {} motor targets, got {}",
+                self.motors.len(),
+                targets.targets.len()
+            );
+            return;
+        }
+        for (motor, target) in self.motors.iter_mut().zip(targets.targets) {
+            motor.target = Some(target);
+        }
+    }
+
+    fn motor_status(&self) -> Vec<proto_public_api::MotorStatus> {
+        self.motors
+            .iter()
+            .map(|motor| proto_public_api::MotorStatus {
+                speed: motor.speed,
+                position: motor.position as i64,
+                pulse_per_rotation: PULSE_PER_ROTATION,
+                wheel_radius: WHEEL_RADIUS,
+                current_target: motor.target.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn status(&self) -> proto_public_api::api_up::Status {
+        use proto_public_api::api_up::Status;
+        match self.kind() {
+            MockKind::Base => Status::BaseStatus(proto_public_api::BaseStatus {
+                state: if self.parking_stop.is_some() {
+                    proto_public_api::BaseState::BsEmergencyStop
+                } else if self.api_control_initialized {
+                    proto_public_api::BaseState::BsAlgrithmControl
+                } else {
+                    proto_public_api::BaseState::BsParked
+                } as i32,
+                api_control_initialized: self.api_control_initialized,
+                battery_voltage: 20.0 + 5.2 * self.battery as f32 / 1000.0,
+                battery_thousandth: self.battery.round() as u32,
+                motor_status: self.motor_status(),
+                session_holder: self.session_holder,
+                battery_charging: Some(self.battery_charging),
+                parking_stop_detail: self.parking_stop.clone(),
+                warning: (self.battery < LOW_BATTERY_THOUSANDTH)
+                    .then_some(proto_public_api::WarningCategory::WcBatteryLow as i32),
+                estimated_odometry: Some(proto_public_api::BaseEstimatedOdometry {
+                    speed_x: self.speed.0,
+                    speed_y: self.speed.1,
+                    speed_z: self.speed.2,
+                    pos_x: self.pose.0,
+                    pos_y: self.pose.1,
+                    pos_z: self.pose.2,
+                }),
+                battery_current: Some(1.0),
+            }),
+            MockKind::Arm => Status::ArmStatus(proto_public_api::ArmStatus {
+                api_control_initialized: self.api_control_initialized,
+                calibrated: self.calibrated,
+                motor_status: self.motor_status(),
+                parking_stop_detail: self.parking_stop.clone(),
+                session_holder: self.session_holder,
+            }),
+            MockKind::LinearLift => {
+                let current_pos = self.motors.first().map_or(0, |m| m.position as i64);
+                Status::LinearLiftStatus(proto_public_api::LinearLiftStatus {
+                    calibrated: self.calibrated,
+                    state: if current_pos == self.linear_lift_target {
+                        proto_public_api::LiftState::LsBrake
+                    } else {
+                        proto_public_api::LiftState::LsAlgrithmControl
+                    } as i32,
+                    max_pos: LINEAR_LIFT_MAX_POS,
+                    current_pos,
+                    pulse_per_rotation: PULSE_PER_ROTATION,
+                    max_speed: LINEAR_LIFT_MAX_SPEED,
+                    speed: self.linear_lift_speed,
+                    parking_stop_detail: self.parking_stop.clone(),
+                    custom_button_pressed: Some(false),
+                })
+            }
+            MockKind::RotateLift => Status::RotateLiftStatus(proto_public_api::RotateLiftStatus {
+                calibrated: self.calibrated,
+                state: proto_public_api::LiftState::LsAlgrithmControl as i32,
+                motor_status: self.motor_status(),
+                max_pos: vec![PULSE_PER_ROTATION as i64 * 10; self.motors.len()],
+                min_pos: vec![-(PULSE_PER_ROTATION as i64) * 10; self.motors.len()],
+                parking_stop_detail: self.parking_stop.clone(),
+                session_holder: self.session_holder,
+            }),
+        }
+    }
+
+    fn api_up(
+        &mut self,
+        session_id: u32,
+        report_frequency: proto_public_api::ReportFrequency,
+        kcp_server_status: Option<proto_public_api::KcpServerStatus>,
+    ) -> proto_public_api::ApiUp {
+        self.update();
+        let elapsed = self.started_at.elapsed();
+        proto_public_api::ApiUp {
+            robot_type: self.config.robot_type as i32,
+            status: Some(self.status()),
+            report_frequency: report_frequency as i32,
+            session_id,
+            protocol_major_version: self.config.protocol_major_version,
+            protocol_minor_version: self.config.protocol_minor_version,
+            kcp_server_status,
+            time_stamp: Some(proto_public_api::TimeStamp {
+                monotonic_time_stamp: Some(proto_public_api::MonotonicTimeStamp {
+                    seconds: elapsed.as_secs(),
+                    nanoseconds: elapsed.subsec_nanos(),
+                }),
+                ptp_time_stamp: None,
+            }),
+            main_bus_voltage: Some(24.0),
+            secondary_device_status: self
+                .gamepad
+                .iter()
+                .map(|read| proto_public_api::SecondaryDeviceStatus {
+                    device_id: 1,
+                    device_type: proto_public_api::SecondaryDeviceType::SdtGamepad as i32,
+                    status: Some(
+                        proto_public_api::secondary_device_status::Status::GamepadRead(
+                            read.clone(),
+                        ),
+                    ),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A mock robot listening for websocket connections. Stops when dropped.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::ApiDown;
+/// use robot_demos::{connect_robot, MockRobot, MockRobotConfig, RobotConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let robot = MockRobot::start("127.0.0.1:0".parse()?, MockRobotConfig::default()).await?;
+///     let addr = robot.local_addr();
+///     let mut connection = connect_robot(&addr.ip().to_string(), addr.port(), Transport::WebSocket).await?;
+///     connection.send(ApiDown::base_api_control_initialize(true)).await?;
+///     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+///     assert_eq!(robot.received().len(), 1);
+///     Ok(())
+/// }
+/// ```
+pub struct MockRobot {
+    local_addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockRobot {
+    /// Starts listening on `addr`. Use port 0 to let the OS pick a free port, then read it from [`MockRobot::local_addr`].
+    pub async fn start(addr: SocketAddr, config: MockRobotConfig) -> Result<Self, anyhow::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::new(config)));
+        info!("Mock robot listening on {}", local_addr);
+
+        let accept_state = state.clone();
+        let accept_task = tokio::spawn(async move {
+            let mut sessions = tokio::task::JoinSet::new();
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Mock robot failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let session_id = {
+                    let mut state = accept_state.lock().unwrap();
+                    let session_id = state.next_session_id;
+                    state.next_session_id = state.next_session_id.wrapping_add(1);
+                    state.open_sessions += 1;
+                    session_id
+                };
+                let state = accept_state.clone();
+                // Sessions live in the set, so they are aborted together with the accept task.
+                sessions.spawn(async move {
+                    if let Err(e) = run_session(state.clone(), stream, peer, session_id).await {
+                        warn!("Session {} ended: {}", session_id, e);
+                    }
+                    {
+                        let mut state = state.lock().unwrap();
+                        state.api_control_initialize(session_id, false);
+                        state.open_sessions -= 1;
+                    }
+                    info!("Session {} from {} closed", session_id, peer);
+                });
+                while sessions.try_join_next().is_some() {}
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            state,
+            accept_task,
+        })
+    }
+
+    /// The address the mock robot is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Everything received so far, from all sessions, oldest first.
+    pub fn received(&self) -> Vec<ReceivedMessage> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Everything received so far, and forgets about it.
+    pub fn take_received(&self) -> Vec<ReceivedMessage> {
+        std::mem::take(&mut self.state.lock().unwrap().received)
+    }
+
+    /// Connections currently open.
+    pub fn open_sessions(&self) -> usize {
+        self.state.lock().unwrap().open_sessions
+    }
+
+    /// Session currently holding API control, 0 if none.
+    pub fn session_holder(&self) -> u32 {
+        self.state.lock().unwrap().session_holder
+    }
+
+    /// Whether API control is currently initialized.
+    pub fn api_control_initialized(&self) -> bool {
+        self.state.lock().unwrap().api_control_initialized
+    }
+
+    /// Puts the robot into parking stop, as if e.g. the emergency stop button was pressed.
+    pub fn enter_parking_stop(&self, detail: proto_public_api::ParkingStopDetail) {
+        self.state.lock().unwrap().enter_parking_stop(detail);
+    }
+
+    /// Sets the battery level, in thousandths.
+    pub fn set_battery_thousandth(&self, battery_thousandth: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.update();
+        state.battery = battery_thousandth.min(1000) as f64;
+    }
+
+    /// Plugs in or unplugs the charger.
+    pub fn set_battery_charging(&self, charging: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.update();
+        state.battery_charging = charging;
+    }
+
+    /// Pairs a gamepad reading `read`, or unpairs it with `None`. The mock only reports it, it never drives
+    /// itself with it.
+    pub fn set_gamepad(&self, read: Option<proto_public_api::GamepadRead>) {
+        self.state.lock().unwrap().gamepad = read;
+    }
+
+    /// Where a base really is, `(x, y, yaw)`, integrated from the commanded `XyzSpeed` since the mock started.
+    ///
+    /// The mock follows commands exactly, with no acceleration limit or slip, so this is the ground truth to
+    /// compare odometry based code against.
+    pub fn base_pose(&self) -> (f64, f64, f64) {
+        let mut state = self.state.lock().unwrap();
+        state.update();
+        state.pose
+    }
+}
+
+impl Drop for MockRobot {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+async fn run_session(
+    state: Arc<Mutex<MockState>>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    session_id: u32,
+) -> Result<(), anyhow::Error> {
+    stream.set_nodelay(true)?;
+    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+    let (mut ws_sink, mut ws_stream) = ws_stream.split();
+    info!("Session {} from {} connected", session_id, peer);
+
+    let mut report_frequency = state.lock().unwrap().config.default_report_frequency;
+    let mut interval = report_interval(report_frequency);
+    let mut kcp_server_status = None;
+    let mut kcp_endpoint: Option<JoinHandle<()>> = None;
+
+    let result = loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let msg = state
+                    .lock()
+                    .unwrap()
+                    .api_up(session_id, report_frequency, kcp_server_status.clone());
+                if let Err(e) = ws_sink
+                    .send(tungstenite::Message::Binary(msg.encode_to_vec().into()))
+                    .await
+                {
+                    break Err(e.into());
+                }
+            }
+            msg = ws_stream.next() => {
+                let bytes = match msg {
+                    Some(Ok(tungstenite::Message::Binary(bytes))) => bytes,
+                    Some(Ok(tungstenite::Message::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => break Err(e.into()),
+                };
+                let msg = match proto_public_api::ApiDown::decode(&bytes[..]) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("Session {} sent an invalid message: {}", session_id, e);
+                        continue;
+                    }
+                };
+                debug!("Session {} sent over websocket: {:?}", session_id, msg.down);
+                match &msg.down {
+                    Some(proto_public_api::api_down::Down::SetReportFrequency(frequency)) => {
+                        if let Ok(frequency) = proto_public_api::ReportFrequency::try_from(*frequency) {
+                            report_frequency = frequency;
+                            interval = report_interval(report_frequency);
+                        }
+                    }
+                    Some(proto_public_api::api_down::Down::EnableKcp(enable_kcp)) => {
+                        if let Some(endpoint) = kcp_endpoint.take() {
+                            endpoint.abort();
+                        }
+                        match start_kcp_endpoint(state.clone(), session_id, peer, enable_kcp).await {
+                            Ok((status, endpoint)) => {
+                                info!("Session {} enabled KCP on port {}", session_id, status.server_port);
+                                kcp_server_status = Some(status);
+                                kcp_endpoint = Some(endpoint);
+                            }
+                            Err(e) => warn!("Session {} failed to enable KCP: {}", session_id, e),
+                        }
+                    }
+                    _ => {}
+                }
+                state.lock().unwrap().handle(session_id, Transport::WebSocket, msg);
+            }
+        }
+    };
+    if let Some(endpoint) = kcp_endpoint {
+        endpoint.abort();
+    }
+    result
+}
+
+#[cfg(not(feature = "kcp"))]
+async fn start_kcp_endpoint(
+    _state: Arc<Mutex<MockState>>,
+    _session_id: u32,
+    _peer: SocketAddr,
+    _enable_kcp: &proto_public_api::EnableKcp,
+) -> Result<(proto_public_api::KcpServerStatus, JoinHandle<()>), anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Mock robot is built without the `kcp` feature"
+    ))
+}
+
+/// Binds a UDP port for the session, and spawns the task serving KCP on it.
+#[cfg(feature = "kcp")]
+async fn start_kcp_endpoint(
+    state: Arc<Mutex<MockState>>,
+    session_id: u32,
+    peer: SocketAddr,
+    enable_kcp: &proto_public_api::EnableKcp,
+) -> Result<(proto_public_api::KcpServerStatus, JoinHandle<()>), anyhow::Error> {
+    let bind_addr: SocketAddr = if peer.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(bind_addr).await?;
+    let server_port = socket.local_addr()?.port();
+    let kcp_config = enable_kcp
+        .kcp_config
+        .clone()
+        .unwrap_or_else(crate::default_kcp_config);
+    let client_addr = SocketAddr::new(peer.ip(), enable_kcp.client_peer_port as u16);
+    let endpoint = tokio::spawn(async move {
+        if let Err(e) = run_kcp_endpoint(state, session_id, socket, client_addr).await {
+            warn!("KCP endpoint of session {} ended: {}", session_id, e);
+        }
+    });
+    Ok((
+        proto_public_api::KcpServerStatus {
+            server_port: server_port as u32,
+            kcp_config: Some(kcp_config),
+        },
+        endpoint,
+    ))
+}
+
+#[cfg(feature = "kcp")]
+async fn run_kcp_endpoint(
+    state: Arc<Mutex<MockState>>,
+    session_id: u32,
+    socket: tokio::net::UdpSocket,
+    client_addr: SocketAddr,
+) -> Result<(), anyhow::Error> {
+    use kcp_bindings::{HexSocketOpcode, HexSocketParser, KcpPortOwner};
+
+    let (_kcp_port_owner, tx, mut rx) =
+        KcpPortOwner::new_costom_socket(socket, session_id, client_addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create KCP port: {:?}", e))?;
+    let mut parser = HexSocketParser::new();
+    let mut report_frequency = state.lock().unwrap().config.default_report_frequency;
+    let mut interval = report_interval(report_frequency);
+    // Like the real robot, only start reporting after the client said something.
+    let mut active = false;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if active => {
+                let msg = state.lock().unwrap().api_up(session_id, report_frequency, None);
+                KcpPortOwner::send_binary(&tx, msg.encode_to_vec())
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to send over KCP: {:?}", e))?;
+            }
+            bytes = rx.recv() => {
+                let Some(bytes) = bytes else {
+                    return Ok(());
+                };
+                let messages = match parser.parse(&bytes) {
+                    Ok(Some(messages)) => messages,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Failed to parse KCP data: {:?}", e);
+                        continue;
+                    }
+                };
+                for (opcode, bytes) in messages {
+                    if opcode != HexSocketOpcode::Binary {
+                        continue;
+                    }
+                    let msg = match proto_public_api::ApiDown::decode(&bytes[..]) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!("Session {} sent an invalid KCP message: {}", session_id, e);
+                            continue;
+                        }
+                    };
+                    debug!("Session {} sent over KCP: {:?}", session_id, msg.down);
+                    active = true;
+                    if let Some(proto_public_api::api_down::Down::SetReportFrequency(frequency)) = msg.down {
+                        if let Ok(frequency) = proto_public_api::ReportFrequency::try_from(frequency) {
+                            report_frequency = frequency;
+                            interval = report_interval(report_frequency);
+                        }
+                    }
+                    state.lock().unwrap().handle(session_id, Transport::Kcp, msg);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::proto_public_api;
+use log::warn;
+use serde_json::json;
+use std::io::Write;
+use std::time::Duration;
+
+/// A 2D pose. `x` and `y` in meters, `yaw` in radians, counter-clockwise from the x axis.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pose2D {
+    pub x: f64,
+    pub y: f64,
+    pub yaw: f64,
+}
+
+impl Pose2D {
+    pub fn new(x: f64, y: f64, yaw: f64) -> Self {
+        Self { x, y, yaw }
+    }
+
+    /// `other`, given relative to this pose, expressed in the frame this pose is in.
+    pub fn compose(&self, other: &Pose2D) -> Pose2D {
+        let (sin, cos) = self.yaw.sin_cos();
+        Pose2D {
+            x: self.x + other.x * cos - other.y * sin,
+            y: self.y + other.x * sin + other.y * cos,
+            yaw: normalize_angle(self.yaw + other.yaw),
+        }
+    }
+
+    /// The pose that composes with this one to give the identity.
+    pub fn inverse(&self) -> Pose2D {
+        let (sin, cos) = self.yaw.sin_cos();
+        Pose2D {
+            x: -self.x * cos - self.y * sin,
+            y: self.x * sin - self.y * cos,
+            yaw: normalize_angle(-self.yaw),
+        }
+    }
+}
+
+/// Wraps an angle into (-pi, pi].
+pub fn normalize_angle(angle: f64) -> f64 {
+    use std::f64::consts::{PI, TAU};
+    let wrapped = (angle + PI).rem_euclid(TAU) - PI;
+    if wrapped == -PI {
+        PI
+    } else {
+        wrapped
+    }
+}
+
+/// A pose at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoseSample {
+    /// The robot's monotonic timestamp of the `ApiUp` this pose was computed from.
+    pub time: Duration,
+    pub pose: Pose2D,
+}
+
+/// Options for [`PoseTracker`].
+#[derive(Debug, Clone)]
+pub struct PoseTrackerOptions {
+    /// Pose of the robot at the start (and after [`PoseTracker::reset`]), in the frame poses are reported in.
+    pub origin: Pose2D,
+    /// Gaps between timestamps longer than this are not integrated over, the pose just holds. Integrating a long
+    /// gap with the speed at either end of it would be a guess.
+    pub max_gap: Duration,
+    /// Keep every pose, for [`PoseTracker::trajectory`] and the exports. Off by default: the trajectory grows
+    /// with every status, about 100MB an hour at `Rf1000Hz`.
+    pub record_trajectory: bool,
+}
+
+impl Default for PoseTrackerOptions {
+    fn default() -> Self {
+        Self {
+            origin: Pose2D::default(),
+            max_gap: Duration::from_millis(500),
+            record_trajectory: false,
+        }
+    }
+}
+
+/// Dead-reckons a 2D pose from the base's own odometry.
+///
+/// Integrates the body frame speeds of `BaseEstimatedOdometry` (`speed_x`, `speed_y`, and `speed_z` as yaw rate)
+/// over the `ApiUp` monotonic timestamps, so the result does not depend on when messages arrive or how fast you
+/// read them. There is no covariance, errors accumulate like any dead reckoning.
+///
+/// Messages without a base status, odometry or monotonic timestamp are ignored. A timestamp going backwards
+/// (e.g. the base restarted) restarts integration from the current pose.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, PoseTracker, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     let mut tracker = PoseTracker::new();
+///     while let Some(msg) = stream.next().await {
+///         if let Some(sample) = tracker.update(&msg) {
+///             println!("{:?}", sample.pose);
+///         }
+///     }
+///     tracker.write_csv(std::fs::File::create("trajectory.csv")?)?;
+///     Ok(())
+/// }
+/// ```
+pub struct PoseTracker {
+    options: PoseTrackerOptions,
+    pose: Pose2D,
+    /// Time and body frame speeds of the last integrated message.
+    last: Option<(Duration, [f64; 3])>,
+    trajectory: Vec<PoseSample>,
+}
+
+impl Default for PoseTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PoseTracker {
+    pub fn new() -> Self {
+        Self::with_options(PoseTrackerOptions::default())
+    }
+
+    pub fn with_options(options: PoseTrackerOptions) -> Self {
+        Self {
+            pose: options.origin,
+            options,
+            last: None,
+            trajectory: Vec::new(),
+        }
+    }
+
+    /// Current pose, in the origin's frame.
+    pub fn pose(&self) -> Pose2D {
+        self.pose
+    }
+
+    pub fn origin(&self) -> Pose2D {
+        self.options.origin
+    }
+
+    /// Moves the robot back to the origin. The trajectory is kept.
+    pub fn reset(&mut self) {
+        self.reset_to(self.options.origin);
+    }
+
+    /// Declares the robot to be at `pose` now. The trajectory is kept.
+    pub fn reset_to(&mut self, pose: Pose2D) {
+        self.pose = pose;
+        if let Some((time, _)) = self.last {
+            self.push(PoseSample { time, pose });
+        }
+    }
+
+    /// Changes the frame poses are reported in, so that the robot's start is at `origin`. The current pose and
+    /// the recorded trajectory move along.
+    pub fn set_origin(&mut self, origin: Pose2D) {
+        let change = origin.compose(&self.options.origin.inverse());
+        self.pose = change.compose(&self.pose);
+        for sample in &mut self.trajectory {
+            sample.pose = change.compose(&sample.pose);
+        }
+        self.options.origin = origin;
+    }
+
+    /// Every pose so far, oldest first. Empty unless `record_trajectory` is set.
+    pub fn trajectory(&self) -> &[PoseSample] {
+        &self.trajectory
+    }
+
+    pub fn clear_trajectory(&mut self) {
+        self.trajectory.clear();
+    }
+
+    fn push(&mut self, sample: PoseSample) {
+        if self.options.record_trajectory {
+            self.trajectory.push(sample);
+        }
+    }
+
+    /// Integrates one `ApiUp`. Returns the new pose, or `None` if the message carries no odometry or a corrupt timestamp.
+    pub fn update(&mut self, msg: &proto_public_api::ApiUp) -> Option<PoseSample> {
+        let Some(proto_public_api::api_up::Status::BaseStatus(status)) = &msg.status else {
+            return None;
+        };
+        let odometry = status.estimated_odometry.as_ref()?;
+        let stamp = msg.time_stamp.as_ref()?.monotonic_time_stamp.as_ref()?;
+        // A corrupt stamp would overflow `Duration::new`, skip it like a message without one.
+        let time = Duration::from_secs(stamp.seconds)
+            .checked_add(Duration::from_nanos(stamp.nanoseconds.into()))?;
+        let speed = [
+            odometry.speed_x as f64,
+            odometry.speed_y as f64,
+            odometry.speed_z as f64,
+        ];
+
+        match self.last {
+            Some((last_time, _)) if time <= last_time => {
+                if time < last_time {
+                    warn!(
+                        "Odometry timestamp went back from {:?} to {:?}, restarting integration",
+                        last_time, time
+                    );
+                } else {
+                    // Same message twice, nothing to integrate.
+                    return Some(PoseSample {
+                        time,
+                        pose: self.pose,
+                    });
+                }
+            }
+            Some((last_time, _)) if time - last_time > self.options.max_gap => {
+                warn!(
+                    "No odometry for {:?}, holding pose over the gap",
+                    time - last_time
+                );
+            }
+            Some((last_time, last_speed)) => {
+                self.integrate((time - last_time).as_secs_f64(), last_speed, speed);
+            }
+            None => {}
+        }
+        self.last = Some((time, speed));
+        let sample = PoseSample {
+            time,
+            pose: self.pose,
+        };
+        self.push(sample);
+        Some(sample)
+    }
+
+    /// Trapezoidal integration of body frame speeds, rotated at the midpoint heading.
+    fn integrate(&mut self, dt: f64, from: [f64; 3], to: [f64; 3]) {
+        let vx = (from[0] + to[0]) / 2.0;
+        let vy = (from[1] + to[1]) / 2.0;
+        let wz = (from[2] + to[2]) / 2.0;
+        let (sin, cos) = (self.pose.yaw + wz * dt / 2.0).sin_cos();
+        self.pose = Pose2D {
+            x: self.pose.x + (vx * cos - vy * sin) * dt,
+            y: self.pose.y + (vx * sin + vy * cos) * dt,
+            yaw: normalize_angle(self.pose.yaw + wz * dt),
+        };
+    }
+
+    /// Writes the trajectory as CSV, see [`write_trajectory_csv`].
+    pub fn write_csv(&self, writer: impl Write) -> Result<(), std::io::Error> {
+        write_trajectory_csv(&self.trajectory, writer)
+    }
+
+    /// The trajectory as GeoJSON, see [`trajectory_to_geojson`].
+    pub fn to_geojson(&self) -> serde_json::Value {
+        trajectory_to_geojson(&self.trajectory, self.options.origin)
+    }
+
+    pub fn write_geojson(&self, writer: impl Write) -> Result<(), std::io::Error> {
+        write_trajectory_geojson(&self.trajectory, self.options.origin, writer)
+    }
+}
+
+/// Writes `trajectory` as CSV, with a `time_s,x,y,yaw` header.
+pub fn write_trajectory_csv(
+    trajectory: &[PoseSample],
+    writer: impl Write,
+) -> Result<(), std::io::Error> {
+    let mut writer = std::io::BufWriter::new(writer);
+    writeln!(writer, "time_s,x,y,yaw")?;
+    for sample in trajectory {
+        writeln!(
+            writer,
+            "{:.6},{:.6},{:.6},{:.6}",
+            sample.time.as_secs_f64(),
+            sample.pose.x,
+            sample.pose.y,
+            sample.pose.yaw
+        )?;
+    }
+    writer.flush()
+}
+
+/// `trajectory` as a GeoJSON `FeatureCollection` holding one `LineString`.
+///
+/// Coordinates are `[x, y]` in meters in the frame of `origin`, not longitude and latitude, so most GeoJSON
+/// viewers need a local/cartesian CRS to show it. Time and yaw of every point are in the properties.
+pub fn trajectory_to_geojson(trajectory: &[PoseSample], origin: Pose2D) -> serde_json::Value {
+    json!({
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": trajectory
+                    .iter()
+                    .map(|sample| [sample.pose.x, sample.pose.y])
+                    .collect::<Vec<_>>(),
+            },
+            "properties": {
+                "frame": "local",
+                "units": "m",
+                "origin": [origin.x, origin.y, origin.yaw],
+                "time_s": trajectory
+                    .iter()
+                    .map(|sample| sample.time.as_secs_f64())
+                    .collect::<Vec<_>>(),
+                "yaw": trajectory
+                    .iter()
+                    .map(|sample| sample.pose.yaw)
+                    .collect::<Vec<_>>(),
+            },
+        }],
+    })
+}
+
+pub fn write_trajectory_geojson(
+    trajectory: &[PoseSample],
+    origin: Pose2D,
+    writer: impl Write,
+) -> Result<(), std::io::Error> {
+    let mut writer = std::io::BufWriter::new(writer);
+    serde_json::to_writer_pretty(&mut writer, &trajectory_to_geojson(trajectory, origin))?;
+    writeln!(writer)?;
+    writer.flush()
+}
@@ -0,0 +1,501 @@
+use crate::connection::ApiUpStream;
+use crate::proto_public_api::{
+    self, api_up::Status, ApiDown, ParkingStopCategory, ParkingStopDetail,
+};
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+/// What a parking stop belongs to. Each has its own parking stop, and its own way to clear it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParkingStopDevice {
+    Base,
+    Arm,
+    LinearLift,
+    RotateLift,
+}
+
+impl ParkingStopDevice {
+    /// The device an `ApiUp` reports on, and its parking stop. `None` for messages without a status.
+    pub fn of(msg: &proto_public_api::ApiUp) -> Option<(Self, Option<&ParkingStopDetail>)> {
+        match msg.status.as_ref()? {
+            Status::BaseStatus(s) => Some((Self::Base, s.parking_stop_detail.as_ref())),
+            Status::ArmStatus(s) => Some((Self::Arm, s.parking_stop_detail.as_ref())),
+            Status::LinearLiftStatus(s) => Some((Self::LinearLift, s.parking_stop_detail.as_ref())),
+            Status::RotateLiftStatus(s) => Some((Self::RotateLift, s.parking_stop_detail.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Whether clearing the parking stop moves the device. True for lifts, see
+    /// [`ParkingStopDevice::clear_command`].
+    pub fn clear_moves(&self) -> bool {
+        matches!(self, Self::LinearLift | Self::RotateLift)
+    }
+
+    /// The message that clears a remotely clearable parking stop.
+    ///
+    /// Lifts have no clear command, a calibrate clears them instead. That moves the lift, so
+    /// [`ParkingStopHandler`] only sends it with [`ParkingStopPolicy::calibrate_lifts`], and only once per
+    /// parking stop.
+    pub fn clear_command(&self) -> ApiDown {
+        match self {
+            Self::Base => ApiDown::base_clear_parking_stop(),
+            Self::Arm => ApiDown::arm_clear_parking_stop(),
+            Self::LinearLift => ApiDown::linear_lift_calibrate(),
+            Self::RotateLift => ApiDown::rotate_lift_calibrate(),
+        }
+    }
+}
+
+impl fmt::Display for ParkingStopDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Base => write!(f, "base"),
+            Self::Arm => write!(f, "arm"),
+            Self::LinearLift => write!(f, "linear lift"),
+            Self::RotateLift => write!(f, "rotate lift"),
+        }
+    }
+}
+
+/// Which parking stops may be cleared without a human looking at the robot, and how hard to try.
+///
+/// Only stops that are `is_remotely_clearable` and in an allowed category are ever cleared. The default allows
+/// only [`ParkingStopCategory::PscApiCommunicationTimeout`], which our own program caused by going quiet. An
+/// emergency stop button, motor error or battery protection is there for a reason, and is never cleared unless
+/// explicitly allowed. Lifts are never calibrated to clear their parking stop unless
+/// [`ParkingStopPolicy::calibrate_lifts`] is set, as that moves them.
+#[derive(Debug, Clone)]
+pub struct ParkingStopPolicy {
+    allowed: Vec<ParkingStopCategory>,
+    max_attempts: u32,
+    retry_interval: Duration,
+    lift_calibrate_time: Option<Duration>,
+}
+
+impl Default for ParkingStopPolicy {
+    fn default() -> Self {
+        Self::new().allow(ParkingStopCategory::PscApiCommunicationTimeout)
+    }
+}
+
+impl ParkingStopPolicy {
+    /// A policy that clears nothing. Allow categories with [`ParkingStopPolicy::allow`].
+    pub fn new() -> Self {
+        Self {
+            allowed: Vec::new(),
+            max_attempts: 3,
+            retry_interval: Duration::from_secs(1),
+            lift_calibrate_time: None,
+        }
+    }
+
+    /// Also clear parking stops of `category`.
+    pub fn allow(mut self, category: ParkingStopCategory) -> Self {
+        if !self.allowed.contains(&category) {
+            self.allowed.push(category);
+        }
+        self
+    }
+
+    /// How many clears to send for one parking stop before giving up. Defaults to 3.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// How long to wait for a clear to take effect before sending the next. Defaults to 1 second.
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    /// Also clear parking stops of lifts, which calibrates them and so moves them. Off by default.
+    ///
+    /// Each parking stop of a lift gets a single calibrate, whatever [`ParkingStopPolicy::max_attempts`] says.
+    /// If the lift is still in parking stop `calibrate_time` later, the handler gives up.
+    pub fn calibrate_lifts(mut self, calibrate_time: Duration) -> Self {
+        self.lift_calibrate_time = Some(calibrate_time);
+        self
+    }
+
+    pub fn allowed(&self) -> &[ParkingStopCategory] {
+        &self.allowed
+    }
+
+    /// How many clears `device` gets, and how long to wait after each.
+    fn attempts(&self, device: ParkingStopDevice) -> (u32, Duration) {
+        match self.lift_calibrate_time {
+            Some(calibrate_time) if device.clear_moves() => (1, calibrate_time),
+            _ => (self.max_attempts, self.retry_interval),
+        }
+    }
+
+    pub fn allows(&self, category: ParkingStopCategory) -> bool {
+        self.allowed.contains(&category)
+    }
+}
+
+/// Why a parking stop is left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefusalReason {
+    /// The robot says it can't be cleared remotely.
+    NotRemotelyClearable,
+    /// The policy does not allow clearing its category.
+    CategoryNotAllowed,
+    /// Clearing it would calibrate a lift, and the policy does not allow moving lifts.
+    LiftCalibrateNotAllowed,
+}
+
+impl fmt::Display for RefusalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotRemotelyClearable => write!(f, "not remotely clearable"),
+            Self::CategoryNotAllowed => write!(f, "category not allowed by policy"),
+            Self::LiftCalibrateNotAllowed => {
+                write!(f, "clearing calibrates the lift, not allowed by policy")
+            }
+        }
+    }
+}
+
+/// What a [`ParkingStopHandler`] saw and did, see [`ParkingStopHandler::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParkingStopEvent {
+    /// The device entered parking stop, or the detail of its parking stop changed.
+    Entered {
+        device: ParkingStopDevice,
+        detail: ParkingStopDetail,
+    },
+    /// A clear was sent. `attempt` counts from 1.
+    ClearSent {
+        device: ParkingStopDevice,
+        detail: ParkingStopDetail,
+        attempt: u32,
+    },
+    /// The parking stop will not be cleared. Someone has to look at the robot.
+    Refused {
+        device: ParkingStopDevice,
+        detail: ParkingStopDetail,
+        reason: RefusalReason,
+    },
+    /// Still in parking stop after the policy's maximum number of clears. Someone has to look at the robot.
+    GaveUp {
+        device: ParkingStopDevice,
+        detail: ParkingStopDetail,
+        attempts: u32,
+    },
+    /// The parking stop is gone, after `attempts` clears. 0 if something else cleared it.
+    Released {
+        device: ParkingStopDevice,
+        detail: ParkingStopDetail,
+        attempts: u32,
+    },
+}
+
+impl ParkingStopEvent {
+    /// Whether a human has to step in.
+    pub fn is_escalation(&self) -> bool {
+        matches!(self, Self::Refused { .. } | Self::GaveUp { .. })
+    }
+}
+
+impl fmt::Display for ParkingStopEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |detail: &ParkingStopDetail| {
+            format!(
+                "{} ({:?}, {}remotely clearable)",
+                detail.category().as_str_name(),
+                detail.reason,
+                if detail.is_remotely_clearable {
+                    ""
+                } else {
+                    "not "
+                }
+            )
+        };
+        match self {
+            Self::Entered { device, detail } => {
+                write!(
+                    f,
+                    "The {} entered parking stop: {}",
+                    device,
+                    describe(detail)
+                )
+            }
+            Self::ClearSent {
+                device,
+                detail,
+                attempt,
+            } => write!(
+                f,
+                "Clearing parking stop of the {}, attempt {}: {}",
+                device,
+                attempt,
+                describe(detail)
+            ),
+            Self::Refused {
+                device,
+                detail,
+                reason,
+            } => write!(
+                f,
+                "Not clearing parking stop of the {}, {}: {}",
+                device,
+                reason,
+                describe(detail)
+            ),
+            Self::GaveUp {
+                device,
+                detail,
+                attempts,
+            } => write!(
+                f,
+                "The {} is still in parking stop after {} clears, giving up: {}",
+                device,
+                attempts,
+                describe(detail)
+            ),
+            Self::Released {
+                device,
+                detail,
+                attempts,
+            } => write!(
+                f,
+                "Parking stop of the {} is gone after {} clears: {}",
+                device,
+                attempts,
+                describe(detail)
+            ),
+        }
+    }
+}
+
+/// One parking stop of one device, from entering to leaving it.
+#[derive(Debug, Clone)]
+struct Episode {
+    detail: ParkingStopDetail,
+    attempts: u32,
+    last_attempt: Option<Instant>,
+    escalated: bool,
+}
+
+/// Decides, for every status the robot sends, whether its parking stop should be cleared, following a
+/// [`ParkingStopPolicy`].
+///
+/// Feed it every `ApiUp` with [`ParkingStopHandler::update`], and send what it returns. Every parking stop, clear
+/// and refusal is logged with its category and reason, and sent to [`ParkingStopHandler::subscribe`]rs. To clear
+/// whatever is there right after connecting, use [`ParkingStopHandler::settle`].
+///
+/// # Example
+/// ```no_run
+/// use futures_util::FutureExt;
+/// use robot_demos::{connect_robot, ParkingStopHandler, ParkingStopPolicy, RobotConnection, Transport};
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     // Fails if the robot is in a parking stop the policy does not allow clearing.
+///     ParkingStopHandler::new(ParkingStopPolicy::default())
+///         .settle(
+///             &mut connection,
+///             &mut stream,
+///             Duration::from_secs(5),
+///             |connection, msg| connection.send(msg).boxed(),
+///         )
+///         .await?;
+///     Ok(())
+/// }
+/// ```
+pub struct ParkingStopHandler {
+    policy: ParkingStopPolicy,
+    episodes: HashMap<ParkingStopDevice, Episode>,
+    events: broadcast::Sender<ParkingStopEvent>,
+}
+
+impl ParkingStopHandler {
+    pub fn new(policy: ParkingStopPolicy) -> Self {
+        Self {
+            policy,
+            episodes: HashMap::new(),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn policy(&self) -> &ParkingStopPolicy {
+        &self.policy
+    }
+
+    /// Everything the handler sees and does from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ParkingStopEvent> {
+        self.events.subscribe()
+    }
+
+    /// The current parking stop of `device`, as of the last status.
+    pub fn parking_stop(&self, device: ParkingStopDevice) -> Option<&ParkingStopDetail> {
+        self.episodes.get(&device).map(|episode| &episode.detail)
+    }
+
+    /// Whether the parking stop of `device` was refused or given up on, so nothing will clear it.
+    pub fn is_escalated(&self, device: ParkingStopDevice) -> bool {
+        self.episodes
+            .get(&device)
+            .is_some_and(|episode| episode.escalated)
+    }
+
+    /// Takes in one `ApiUp`. Returns the clear to send, if the policy says this parking stop should be cleared now.
+    pub fn update(&mut self, msg: &proto_public_api::ApiUp) -> Option<ApiDown> {
+        let (device, detail) = ParkingStopDevice::of(msg)?;
+        let Some(detail) = detail else {
+            if let Some(episode) = self.episodes.remove(&device) {
+                self.emit(ParkingStopEvent::Released {
+                    device,
+                    detail: episode.detail,
+                    attempts: episode.attempts,
+                });
+            }
+            return None;
+        };
+
+        if self
+            .episodes
+            .get(&device)
+            .is_none_or(|episode| episode.detail != *detail)
+        {
+            self.episodes.insert(
+                device,
+                Episode {
+                    detail: detail.clone(),
+                    attempts: 0,
+                    last_attempt: None,
+                    escalated: false,
+                },
+            );
+            self.emit(ParkingStopEvent::Entered {
+                device,
+                detail: detail.clone(),
+            });
+        }
+        let episode = self.episodes.get_mut(&device).unwrap();
+        if episode.escalated {
+            return None;
+        }
+
+        let refusal = if !detail.is_remotely_clearable {
+            Some(RefusalReason::NotRemotelyClearable)
+        } else if !self.policy.allows(detail.category()) {
+            Some(RefusalReason::CategoryNotAllowed)
+        } else if device.clear_moves() && self.policy.lift_calibrate_time.is_none() {
+            Some(RefusalReason::LiftCalibrateNotAllowed)
+        } else {
+            None
+        };
+        let (max_attempts, retry_interval) = self.policy.attempts(device);
+        let waited = episode
+            .last_attempt
+            .is_none_or(|last| last.elapsed() >= retry_interval);
+        let event = if let Some(reason) = refusal {
+            episode.escalated = true;
+            ParkingStopEvent::Refused {
+                device,
+                detail: detail.clone(),
+                reason,
+            }
+        } else if !waited {
+            return None;
+        } else if episode.attempts >= max_attempts {
+            episode.escalated = true;
+            ParkingStopEvent::GaveUp {
+                device,
+                detail: detail.clone(),
+                attempts: episode.attempts,
+            }
+        } else {
+            episode.attempts += 1;
+            episode.last_attempt = Some(Instant::now());
+            let attempt = episode.attempts;
+            self.emit(ParkingStopEvent::ClearSent {
+                device,
+                detail: detail.clone(),
+                attempt,
+            });
+            return Some(device.clear_command());
+        };
+        self.emit(event);
+        None
+    }
+
+    /// Waits for a status on `stream`, and clears its parking stop as far as the policy allows, sending clears with
+    /// `send`.
+    ///
+    /// Use it right after connecting, instead of clearing unconditionally. `send` gets `connection` back, so it can
+    /// pick the transport, e.g. `|session, msg| session.send_over_websocket(msg).boxed()` for a KCP session.
+    ///
+    /// # Returns
+    /// * `Ok(clears)` - The robot reports no parking stop, after sending `clears` clears
+    /// * `Err(anyhow::Error)` - The policy refused or gave up, the connection was lost, or `timeout` passed first
+    pub async fn settle<C: ?Sized>(
+        &mut self,
+        connection: &mut C,
+        stream: &mut ApiUpStream,
+        timeout: Duration,
+        mut send: impl for<'c> FnMut(&'c mut C, ApiDown) -> BoxFuture<'c, Result<(), anyhow::Error>>,
+    ) -> Result<u32, anyhow::Error> {
+        let deadline = Instant::now() + timeout;
+        let mut clears = 0;
+        loop {
+            let msg = tokio::time::timeout_at(deadline, stream.next())
+                .await
+                .map_err(|_| match self.episodes.values().next() {
+                    Some(episode) => anyhow::anyhow!(
+                        "Still in parking stop after {:?}: {}",
+                        timeout,
+                        episode.detail.reason
+                    ),
+                    None => anyhow::anyhow!("No status within {:?}", timeout),
+                })?
+                .ok_or_else(|| anyhow::anyhow!("Connection lost while clearing parking stop"))?;
+            let Some((device, _)) = ParkingStopDevice::of(&msg) else {
+                continue;
+            };
+            if let Some(clear) = self.update(&msg) {
+                send(connection, clear).await?;
+                clears += 1;
+            }
+            match self.episodes.get(&device) {
+                None => return Ok(clears),
+                Some(episode) if episode.escalated => {
+                    return Err(anyhow::anyhow!(
+                        "The {} is in parking stop {} ({}), which is not cleared automatically. Check the robot",
+                        device,
+                        episode.detail.category().as_str_name(),
+                        episode.detail.reason
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn emit(&self, event: ParkingStopEvent) {
+        match &event {
+            ParkingStopEvent::Refused { .. } | ParkingStopEvent::GaveUp { .. } => {
+                error!("{}", event)
+            }
+            ParkingStopEvent::Entered { .. } => warn!("{}", event),
+            ParkingStopEvent::ClearSent { .. } | ParkingStopEvent::Released { .. } => {
+                info!("{}", event)
+            }
+        }
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::connection::{connect_robot, RobotConnection, Transport};
+use crate::proto_public_api::{self, ReportFrequency, RobotType};
+use log::warn;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Environment variable naming the profiles file, used when no file is given explicitly.
+pub const PROFILES_FILE_ENV: &str = "HEXFELLOW_PROFILES";
+
+/// Profiles file used when neither a file nor [`PROFILES_FILE_ENV`] is given.
+pub const DEFAULT_PROFILES_FILE: &str = "robots.toml";
+
+/// KCP settings of a profile. Anything left out keeps the value from [`crate::default_kcp_config`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KcpProfile {
+    pub window_size_snd_wnd: Option<i32>,
+    pub window_size_rcv_wnd: Option<i32>,
+    pub interval_ms: Option<i32>,
+    pub no_delay: Option<bool>,
+    pub nc: Option<bool>,
+    pub resend: Option<i32>,
+}
+
+impl KcpProfile {
+    /// `base` with every setting of this profile applied on top.
+    pub fn apply(&self, base: proto_public_api::KcpConfig) -> proto_public_api::KcpConfig {
+        proto_public_api::KcpConfig {
+            window_size_snd_wnd: self.window_size_snd_wnd.unwrap_or(base.window_size_snd_wnd),
+            window_size_rcv_wnd: self.window_size_rcv_wnd.unwrap_or(base.window_size_rcv_wnd),
+            interval_ms: self.interval_ms.unwrap_or(base.interval_ms),
+            no_delay: self.no_delay.unwrap_or(base.no_delay),
+            nc: self.nc.unwrap_or(base.nc),
+            resend: self.resend.unwrap_or(base.resend),
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    8439
+}
+
+fn deserialize_robot_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<RobotType>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|name| {
+            RobotType::from_str_name(&name).ok_or_else(|| {
+                D::Error::custom(format!(
+                    "unknown robot type \"{}\", expected a name like \"RtMaverX4\"",
+                    name
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn deserialize_report_frequency<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ReportFrequency>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|name| {
+            ReportFrequency::from_str_name(&name).ok_or_else(|| {
+                D::Error::custom(format!(
+                    "unknown report frequency \"{}\", expected a name like \"Rf250Hz\"",
+                    name
+                ))
+            })
+        })
+        .transpose()
+}
+
+/// Everything needed to connect to one robot, usually loaded from a profiles file with [`RobotProfiles`].
+///
+/// Only `address` is required. A profile without `robot_type` connects to any robot.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RobotProfile {
+    /// The IP address of the robot (e.g. "172.18.23.92" or "[fe80::500d:96ff:fee1:d60b%3]").
+    pub address: String,
+    /// The websocket port of the robot.
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// `None` lets the program pick. Programs that only speak one transport warn if it's not this one.
+    #[serde(default)]
+    pub transport: Option<Transport>,
+    #[serde(default)]
+    pub kcp: KcpProfile,
+    /// Report frequency to ask the robot for, instead of the program's own default.
+    #[serde(default, deserialize_with = "deserialize_report_frequency")]
+    pub report_frequency: Option<ReportFrequency>,
+    /// The robot type we expect to find at `address`. Connecting fails if the robot reports another one.
+    #[serde(default, deserialize_with = "deserialize_robot_type")]
+    pub robot_type: Option<RobotType>,
+}
+
+/// Everything that can go wrong while loading or using a profile.
+#[derive(Debug)]
+pub enum ProfileError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownProfile {
+        name: String,
+        available: Vec<String>,
+    },
+    RobotTypeMismatch {
+        expected: RobotType,
+        actual: RobotType,
+    },
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::Read(path, e) => {
+                write!(f, "Failed to read profiles file {}: {}", path.display(), e)
+            }
+            ProfileError::Parse(path, e) => {
+                write!(f, "Failed to parse profiles file {}: {}", path.display(), e)
+            }
+            ProfileError::UnknownProfile { name, available } => write!(
+                f,
+                "No profile named \"{}\". Available profiles: {}",
+                name,
+                available.join(", ")
+            ),
+            ProfileError::RobotTypeMismatch { expected, actual } => write!(
+                f,
+                "Profile expects a {} robot, but the robot says it is a {}. Refusing to continue.",
+                expected.as_str_name(),
+                actual.as_str_name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl RobotProfile {
+    /// A profile with only an address and port, as given on the command line.
+    pub fn new(address: &str, port: u16) -> Self {
+        Self {
+            address: address.to_string(),
+            port,
+            transport: None,
+            kcp: KcpProfile::default(),
+            report_frequency: None,
+            robot_type: None,
+        }
+    }
+
+    /// Full websocket URL, e.g. "ws://172.18.23.92:8439".
+    pub fn websocket_url(&self) -> String {
+        format!("ws://{}:{}", self.address, self.port)
+    }
+
+    /// Report frequency of this profile, or `default` if it doesn't set one.
+    pub fn report_frequency_or(&self, default: ReportFrequency) -> ReportFrequency {
+        self.report_frequency.unwrap_or(default)
+    }
+
+    /// Fails if the profile expects another robot type than `actual`.
+    pub fn check_robot_type(&self, actual: RobotType) -> Result<(), ProfileError> {
+        match self.robot_type {
+            Some(expected) if expected != actual => {
+                Err(ProfileError::RobotTypeMismatch { expected, actual })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn warn_if_transport_is_not(&self, transport: Transport) {
+        if let Some(wanted) = self.transport {
+            if wanted != transport {
+                warn!(
+                    "Profile asks for {}, but this program only speaks {}. Using {}.",
+                    wanted, transport, transport
+                );
+            }
+        }
+    }
+
+    /// Options for [`crate::establish_kcp_session`], with the KCP settings of this profile.
+    #[cfg(feature = "kcp")]
+    pub fn kcp_session_options(&self) -> crate::KcpSessionOptions {
+        crate::KcpSessionOptions {
+            kcp_config: self.kcp.apply(crate::default_kcp_config()),
+            ..Default::default()
+        }
+    }
+
+    /// Connects with the profile's transport (websocket if it has none), checks the robot type, and sets the
+    /// profile's report frequency if it has one.
+    pub async fn connect(&self) -> Result<Box<dyn RobotConnection>, anyhow::Error> {
+        let transport = self.transport.unwrap_or(Transport::WebSocket);
+        let mut connection: Box<dyn RobotConnection> = match transport {
+            #[cfg(feature = "kcp")]
+            Transport::Kcp => Box::new(crate::KcpConnection::from_session(
+                crate::establish_kcp_session(&self.address, self.port, &self.kcp_session_options())
+                    .await?,
+            )),
+            _ => connect_robot(&self.address, self.port, transport).await?,
+        };
+        self.check_robot_type(connection.robot_type())?;
+        if let Some(report_frequency) = self.report_frequency {
+            connection
+                .send(proto_public_api::ApiDown::set_report_frequency(
+                    report_frequency,
+                ))
+                .await?;
+        }
+        Ok(connection)
+    }
+
+    /// Connects a plain websocket, for programs that handle the websocket themselves.
+    ///
+    /// The first message is read to check the robot type, and is not passed on. Setting the report frequency
+    /// is left to the caller, see [`RobotProfile::report_frequency_or`].
+    pub async fn connect_websocket(
+        &self,
+    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>, anyhow::Error> {
+        use futures_util::StreamExt;
+
+        self.warn_if_transport_is_not(Transport::WebSocket);
+        let mut ws_stream = crate::connect_websocket(&self.websocket_url()).await?;
+        let first = loop {
+            match ws_stream.next().await {
+                Some(Ok(msg @ tungstenite::Message::Binary(_))) => {
+                    break crate::decode_websocket_message(msg, true)?;
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(anyhow::anyhow!("Websocket closed by robot")),
+            }
+        };
+        self.check_robot_type(first.robot_type())?;
+        Ok(ws_stream)
+    }
+
+    /// Establishes a KCP session with the profile's KCP settings, and checks the robot type.
+    ///
+    /// Setting the report frequency is left to the caller, see [`RobotProfile::report_frequency_or`].
+    #[cfg(feature = "kcp")]
+    pub async fn establish_kcp_session(&self) -> Result<crate::KcpSession, anyhow::Error> {
+        self.warn_if_transport_is_not(Transport::Kcp);
+        let session =
+            crate::establish_kcp_session(&self.address, self.port, &self.kcp_session_options())
+                .await?;
+        self.check_robot_type(session.robot_type())?;
+        Ok(session)
+    }
+}
+
+/// Named robot profiles, loaded from a TOML file.
+///
+/// Each table is one profile, named by its key:
+///
+/// ```toml
+/// [lab-base-1]
+/// address = "172.18.23.92"
+/// port = 8439
+/// transport = "kcp"
+/// robot_type = "RtMaverX4"
+/// report_frequency = "Rf250Hz"
+///
+/// [lab-base-1.kcp]
+/// window_size_snd_wnd = 128
+/// window_size_rcv_wnd = 128
+/// interval_ms = 5
+/// ```
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::RobotProfiles;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let profiles = RobotProfiles::load("robots.toml")?;
+///     let connection = profiles.get("lab-base-1")?.connect().await?;
+///     println!("Connected to {:?}", connection.robot_type());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct RobotProfiles {
+    pub profiles: BTreeMap<String, RobotProfile>,
+}
+
+impl RobotProfiles {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ProfileError> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ProfileError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ProfileError::Parse(path.to_path_buf(), e))
+    }
+
+    /// `$HEXFELLOW_PROFILES` if set, [`DEFAULT_PROFILES_FILE`] otherwise.
+    pub fn default_path() -> PathBuf {
+        std::env::var_os(PROFILES_FILE_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PROFILES_FILE))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&RobotProfile, ProfileError> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| ProfileError::UnknownProfile {
+                name: name.to_string(),
+                available: self.profiles.keys().cloned().collect(),
+            })
+    }
+}
+
+/// Command line arguments picking a robot: either `url` and `port` (default 8439), or `--profile`.
+///
+/// Flatten it into a program's own arguments, then call [`RobotTarget::resolve`].
+///
+/// # Example
+/// ```no_run
+/// use clap::Parser;
+/// use robot_demos::RobotTarget;
+///
+/// #[derive(Parser)]
+/// struct Args {
+///     #[command(flatten)]
+///     robot: RobotTarget,
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let profile = Args::parse().robot.resolve()?;
+///     let connection = profile.connect().await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct RobotTarget {
+    #[arg(
+        required_unless_present = "profile",
+        help = "WebSocket URL to connect to (e.g. 127.0.0.1 or [fe80::500d:96ff:fee1:d60b%3]). If you use ipv6, please make sure IPV6's zone id is correct. The zone id must be interface id not interface name. If you don't understand what this means, please use ipv4."
+    )]
+    pub url: Option<String>,
+    #[arg(default_value_t = 8439, help = "Port to connect to")]
+    pub port: u16,
+    #[arg(
+        long,
+        conflicts_with_all = ["url", "port"],
+        help = "Connect to the robot of this profile, instead of url and port"
+    )]
+    pub profile: Option<String>,
+    #[arg(
+        long,
+        requires = "profile",
+        help = "File to read profiles from. Defaults to $HEXFELLOW_PROFILES, then robots.toml"
+    )]
+    pub profiles_file: Option<PathBuf>,
+}
+
+impl RobotTarget {
+    /// The profile picked with `--profile`, or one made from `url` and `port`.
+    pub fn resolve(&self) -> Result<RobotProfile, ProfileError> {
+        match (&self.profile, &self.url) {
+            (Some(name), _) => {
+                let path = self
+                    .profiles_file
+                    .clone()
+                    .unwrap_or_else(RobotProfiles::default_path);
+                Ok(RobotProfiles::load(path)?.get(name)?.clone())
+            }
+            (None, Some(url)) => Ok(RobotProfile::new(url, self.port)),
+            // clap requires url without a profile.
+            (None, None) => unreachable!("url is required without --profile"),
+        }
+    }
+}
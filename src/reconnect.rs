@@ -0,0 +1,482 @@
+use crate::connection::{
+    connect_robot, next_decode_error, ApiUpStream, RawApiUpTap, RawApiUpTaps, RobotConnection,
+    Transport, DECODE_ERROR_CAPACITY,
+};
+use crate::proto_public_api;
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// How [`ReconnectingConnection`] waits between reconnect attempts.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    /// Delay before the first reconnect attempt.
+    pub initial_backoff: Duration,
+    /// The delay never grows past this.
+    pub max_backoff: Duration,
+    /// The delay is multiplied by this after each failed attempt.
+    pub backoff_multiplier: f64,
+    /// Give up after this many failed attempts in a row. `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Reconnect when no `ApiUp` arrived for this many report periods, at the last `SetReportFrequency` sent
+    /// (1 Hz before any was sent). This catches half-open links that never report an error. `None` only
+    /// reconnects when the transport notices the loss.
+    pub liveness_periods: Option<u32>,
+    /// The liveness timeout is never shorter than this, so high report frequencies don't trip it on a hiccup.
+    pub min_liveness_timeout: Duration,
+    /// A connect attempt, up to the first `ApiUp` (and KCP being enabled), fails when it takes longer than this.
+    pub connect_timeout: Duration,
+    /// Used for every KCP session, the first one and the reconnected ones, e.g.
+    /// [`crate::RobotProfile::kcp_session_options`].
+    #[cfg(feature = "kcp")]
+    pub kcp_session_options: crate::KcpSessionOptions,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            backoff_multiplier: 2.0,
+            max_attempts: None,
+            liveness_periods: Some(5),
+            min_liveness_timeout: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(10),
+            #[cfg(feature = "kcp")]
+            kcp_session_options: crate::KcpSessionOptions::default(),
+        }
+    }
+}
+
+impl ReconnectOptions {
+    /// Checks for options that would make the backoff shrink, stay at zero, or never time out: a multiplier that
+    /// is not finite or below 1, and zero backoffs or connect timeout.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.backoff_multiplier.is_finite() || self.backoff_multiplier < 1.0 {
+            return Err(format!(
+                "backoff_multiplier must be at least 1, got {}",
+                self.backoff_multiplier
+            ));
+        }
+        if self.initial_backoff.is_zero() || self.max_backoff.is_zero() {
+            return Err("initial_backoff and max_backoff must not be zero".to_string());
+        }
+        if self.connect_timeout.is_zero() {
+            return Err("connect_timeout must not be zero".to_string());
+        }
+        Ok(())
+    }
+
+    /// Delay before the `attempt`-th reconnect attempt, starting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .backoff_multiplier
+            .powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait for an `ApiUp` at `report_frequency` before reconnecting. `None` if disabled.
+    pub fn liveness_timeout(
+        &self,
+        report_frequency: Option<proto_public_api::ReportFrequency>,
+    ) -> Option<Duration> {
+        let period = crate::benchmark::report_period(
+            report_frequency.unwrap_or(proto_public_api::ReportFrequency::Rf1Hz),
+        );
+        self.liveness_periods
+            .map(|periods| (period * periods).max(self.min_liveness_timeout))
+    }
+}
+
+/// Connection state changes reported by [`ReconnectingConnection`].
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// A connection is up. If `reconnected` is true, this is a new session on the robot:
+    /// API control is no longer initialized, send `ApiControlInitialize` again before moving anything.
+    Connected {
+        session_id: u32,
+        robot_type: proto_public_api::RobotType,
+        reconnected: bool,
+    },
+    /// The connection was lost, or went silent for longer than the liveness timeout.
+    /// Messages sent from now on fail until the next `Connected`.
+    Disconnected,
+    /// Waiting `delay` before reconnect attempt number `attempt`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// Reconnect attempt number `attempt` failed.
+    ReconnectFailed { attempt: u32, error: String },
+    /// `max_attempts` was reached. The connection will not come back.
+    GaveUp,
+}
+
+struct Current {
+    connected: bool,
+    session_id: u32,
+    robot_type: proto_public_api::RobotType,
+}
+
+type SendRequest = (
+    proto_public_api::ApiDown,
+    oneshot::Sender<Result<(), anyhow::Error>>,
+);
+
+/// [`RobotConnection`] that reconnects by itself when the underlying connection is lost.
+///
+/// A connection that stays up but goes silent for longer than [`ReconnectOptions::liveness_timeout`] is treated as
+/// lost too. On every reconnect the last `SetReportFrequency` you sent is sent again. With `Transport::Kcp`, KCP is
+/// enabled again as part of setting up the new connection.
+///
+/// The robot can't tell a reconnect from a brand new client, so API control has to be initialized again.
+/// Watch for [`ConnectionEvent::Connected`] with `reconnected: true` from [`ReconnectingConnection::subscribe`].
+///
+/// Sending while disconnected fails right away instead of queueing, old motion commands should not be
+/// replayed once the robot is back. A `SetReportFrequency` sent while disconnected is still remembered.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::ApiDown;
+/// use robot_demos::{ConnectionEvent, ReconnectOptions, ReconnectingConnection, RobotConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = ReconnectingConnection::connect(
+///         "127.0.0.1",
+///         8439,
+///         Transport::WebSocket,
+///         ReconnectOptions::default(),
+///     )
+///     .await?;
+///     let mut events = connection.subscribe();
+///     connection.send(ApiDown::base_api_control_initialize(true)).await?;
+///     while let Ok(event) = events.recv().await {
+///         if let ConnectionEvent::Connected { reconnected: true, .. } = event {
+///             connection.send(ApiDown::base_api_control_initialize(true)).await?;
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct ReconnectingConnection {
+    transport: Transport,
+    current: Arc<Mutex<Current>>,
+    requests: mpsc::Sender<SendRequest>,
+    events: broadcast::Sender<ConnectionEvent>,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+    taps: RawApiUpTaps,
+    taps_supported: bool,
+    stream: Option<ApiUpStream>,
+}
+
+impl ReconnectingConnection {
+    /// Connects to the robot. The first connection must succeed, only later drops are retried.
+    ///
+    /// # Arguments
+    /// * `url` - The IP address of the robot (e.g. "127.0.0.1" or "[fe80::500d:96ff:fee1:d60b%3]")
+    /// * `port` - The websocket port of the robot (e.g. 8439)
+    /// * `transport` - Which transport to use
+    /// * `options` - Backoff settings, checked with [`ReconnectOptions::validate`]
+    pub async fn connect(
+        url: &str,
+        port: u16,
+        transport: Transport,
+        options: ReconnectOptions,
+    ) -> Result<Self, anyhow::Error> {
+        options
+            .validate()
+            .map_err(|reason| anyhow::anyhow!("Invalid reconnect options: {}", reason))?;
+        let mut connection = connect_once(url, port, transport, &options).await?;
+        let taps = RawApiUpTaps::default();
+        let taps_supported = connection.tap_raw_api_up(forward_raw_api_up(&taps));
+        let current = Arc::new(Mutex::new(Current {
+            connected: true,
+            session_id: connection.session_id(),
+            robot_type: connection.robot_type(),
+        }));
+        let (requests, requests_rx) = mpsc::channel(64);
+        let (events, _) = broadcast::channel(64);
+        let (decode_errors, _) = broadcast::channel(DECODE_ERROR_CAPACITY);
+        let (up_tx, up_rx) = mpsc::channel(1024);
+
+        tokio::spawn(drive(
+            url.to_string(),
+            port,
+            transport,
+            options,
+            connection,
+            current.clone(),
+            requests_rx,
+            events.clone(),
+            decode_errors.clone(),
+            taps.clone(),
+            up_tx,
+        ));
+
+        let stream = futures_util::stream::unfold(up_rx, |mut up_rx| async move {
+            up_rx.recv().await.map(|msg| (msg, up_rx))
+        })
+        .boxed();
+
+        Ok(Self {
+            transport,
+            current,
+            requests,
+            events,
+            decode_errors,
+            taps,
+            taps_supported,
+            stream: Some(stream),
+        })
+    }
+
+    /// Subscribes to connection state changes. Only events after this call are received.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Whether the underlying connection is currently up.
+    pub fn is_connected(&self) -> bool {
+        self.current.lock().unwrap().connected
+    }
+}
+
+impl RobotConnection for ReconnectingConnection {
+    fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Session ID of the current underlying connection. Changes after every reconnect.
+    fn session_id(&self) -> u32 {
+        self.current.lock().unwrap().session_id
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.current.lock().unwrap().robot_type
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        let requests = self.requests.clone();
+        async move {
+            let (result_tx, result_rx) = oneshot::channel();
+            requests
+                .send((msg, result_tx))
+                .await
+                .map_err(|_| anyhow::anyhow!("Reconnecting connection gave up"))?;
+            result_rx
+                .await
+                .map_err(|_| anyhow::anyhow!("Reconnecting connection gave up"))?
+        }
+        .boxed()
+    }
+
+    /// Takes the stream of decoded `ApiUp` messages from all connections, one after another.
+    ///
+    /// The stream only ends when reconnecting gives up, or this connection is dropped.
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.stream.take()
+    }
+
+    /// Errors from all connections, one after another.
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        Some(self.decode_errors.subscribe())
+    }
+
+    /// Payloads from all connections, one after another.
+    fn tap_raw_api_up(&mut self, tap: RawApiUpTap) -> bool {
+        self.taps.add(tap);
+        self.taps_supported
+    }
+}
+
+/// Connects with the KCP options of `options`, giving up after its connect timeout.
+async fn connect_once(
+    url: &str,
+    port: u16,
+    transport: Transport,
+    options: &ReconnectOptions,
+) -> Result<Box<dyn RobotConnection>, anyhow::Error> {
+    let connect = async {
+        match transport {
+            #[cfg(feature = "kcp")]
+            Transport::Kcp => {
+                let session =
+                    crate::establish_kcp_session(url, port, &options.kcp_session_options).await?;
+                Ok(Box::new(crate::KcpConnection::from_session(session))
+                    as Box<dyn RobotConnection>)
+            }
+            _ => connect_robot(url, port, transport).await,
+        }
+    };
+    tokio::time::timeout(options.connect_timeout, connect)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Connecting to {}:{} timed out after {:?}",
+                url,
+                port,
+                options.connect_timeout
+            )
+        })?
+}
+
+/// A tap that passes payloads on to `taps`, for installing on each underlying connection.
+fn forward_raw_api_up(taps: &RawApiUpTaps) -> RawApiUpTap {
+    let taps = taps.clone();
+    Arc::new(move |payload: &[u8]| taps.call(payload))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn drive(
+    url: String,
+    port: u16,
+    transport: Transport,
+    options: ReconnectOptions,
+    mut connection: Box<dyn RobotConnection>,
+    current: Arc<Mutex<Current>>,
+    mut requests: mpsc::Receiver<SendRequest>,
+    events: broadcast::Sender<ConnectionEvent>,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+    taps: RawApiUpTaps,
+    up_tx: mpsc::Sender<proto_public_api::ApiUp>,
+) {
+    let mut last_report_frequency: Option<proto_public_api::ApiDown> = None;
+    let mut reconnected = false;
+    loop {
+        // Put back what the application has set up on the previous session.
+        if let Some(msg) = last_report_frequency.clone() {
+            if let Err(e) = connection.send(msg).await {
+                warn!("Failed to restore report frequency: {}", e);
+            }
+        }
+        {
+            let mut current = current.lock().unwrap();
+            current.connected = true;
+            current.session_id = connection.session_id();
+            current.robot_type = connection.robot_type();
+        }
+        let _ = events.send(ConnectionEvent::Connected {
+            session_id: connection.session_id(),
+            robot_type: connection.robot_type(),
+            reconnected,
+        });
+
+        let mut stream = match connection.take_stream() {
+            Some(stream) => stream,
+            None => futures_util::stream::empty().boxed(),
+        };
+        let mut connection_decode_errors = connection.decode_errors();
+        let mut last_message = tokio::time::Instant::now();
+        loop {
+            let liveness_timeout =
+                options.liveness_timeout(report_frequency(&last_report_frequency));
+            let liveness_deadline = last_message + liveness_timeout.unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep_until(liveness_deadline), if liveness_timeout.is_some() => {
+                    warn!(
+                        "No message from {}:{} for {:?}",
+                        url,
+                        port,
+                        last_message.elapsed()
+                    );
+                    break;
+                },
+                e = next_decode_error(&mut connection_decode_errors) => {
+                    let _ = decode_errors.send(e);
+                },
+                msg = stream.next() => match msg {
+                    Some(msg) => {
+                        last_message = tokio::time::Instant::now();
+                        if let Err(mpsc::error::TrySendError::Full(_)) = up_tx.try_send(msg) {
+                            warn!("ApiUp stream is not being polled, dropping message");
+                        }
+                    }
+                    None => break,
+                },
+                request = requests.recv() => match request {
+                    Some((msg, result_tx)) => {
+                        if let Some(proto_public_api::api_down::Down::SetReportFrequency(_)) = msg.down {
+                            last_report_frequency = Some(msg.clone());
+                        }
+                        let result = connection.send(msg).await;
+                        let failed = result.is_err();
+                        let _ = result_tx.send(result);
+                        if failed {
+                            break;
+                        }
+                    }
+                    // The application dropped us.
+                    None => return,
+                },
+            }
+        }
+        drop(stream);
+        drop(connection);
+        current.lock().unwrap().connected = false;
+        warn!("Connection to {}:{} lost", url, port);
+        let _ = events.send(ConnectionEvent::Disconnected);
+
+        let mut attempt = 0;
+        connection = loop {
+            attempt += 1;
+            if options.max_attempts.is_some_and(|max| attempt > max) {
+                warn!("Giving up reconnecting to {}:{}", url, port);
+                let _ = events.send(ConnectionEvent::GaveUp);
+                return;
+            }
+            let delay = options.backoff(attempt);
+            let _ = events.send(ConnectionEvent::Reconnecting { attempt, delay });
+            let (url, options) = (&url, &options);
+            let try_connect = async move {
+                tokio::time::sleep(delay).await;
+                info!("Reconnecting to {}:{}, attempt {}", url, port, attempt);
+                connect_once(url, port, transport, options).await
+            };
+            tokio::pin!(try_connect);
+            // Keep answering while waiting, so senders don't hang.
+            let result = loop {
+                tokio::select! {
+                    result = &mut try_connect => break result,
+                    request = requests.recv() => match request {
+                        Some((msg, result_tx)) => {
+                            if let Some(proto_public_api::api_down::Down::SetReportFrequency(_)) = msg.down {
+                                last_report_frequency = Some(msg.clone());
+                            }
+                            let _ = result_tx.send(Err(anyhow::anyhow!("Not connected to robot")));
+                        }
+                        None => return,
+                    },
+                }
+            };
+            match result {
+                Ok(mut connection) => {
+                    connection.tap_raw_api_up(forward_raw_api_up(&taps));
+                    break connection;
+                }
+                Err(e) => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    let _ = events.send(ConnectionEvent::ReconnectFailed {
+                        attempt,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        };
+        reconnected = true;
+    }
+}
+
+/// The frequency `msg`, a `SetReportFrequency`, asks for.
+fn report_frequency(
+    msg: &Option<proto_public_api::ApiDown>,
+) -> Option<proto_public_api::ReportFrequency> {
+    match msg.as_ref()?.down {
+        Some(proto_public_api::api_down::Down::SetReportFrequency(frequency)) => {
+            proto_public_api::ReportFrequency::try_from(frequency).ok()
+        }
+        _ => None,
+    }
+}
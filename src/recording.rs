@@ -0,0 +1,434 @@
+//! Recording robot traffic to disk, and replaying it later.
+//!
+//! A recording is a file of length-delimited [`RecordedMessage`]s, one per `ApiUp` or `ApiDown`, in the order
+//! they were seen. Each one keeps the encoded message, so replay goes through [`crate::decode_message`] just like
+//! live traffic does, version checks included.
+
+use crate::connection::{
+    ApiUpStream, RawApiUpTap, RobotConnection, Transport, DECODE_ERROR_CAPACITY,
+};
+use crate::proto_public_api;
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use log::{debug, warn};
+use prost::Message;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Which way a recorded message went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum RecordedDirection {
+    /// `ApiUp`, robot to us.
+    Up = 0,
+    /// `ApiDown`, us to robot.
+    Down = 1,
+}
+
+/// [`Transport`] as stored in a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum RecordedTransport {
+    WebSocket = 0,
+    Kcp = 1,
+}
+
+impl From<Transport> for RecordedTransport {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::WebSocket => RecordedTransport::WebSocket,
+            Transport::Kcp => RecordedTransport::Kcp,
+        }
+    }
+}
+
+impl From<RecordedTransport> for Transport {
+    fn from(transport: RecordedTransport) -> Self {
+        match transport {
+            RecordedTransport::WebSocket => Transport::WebSocket,
+            RecordedTransport::Kcp => Transport::Kcp,
+        }
+    }
+}
+
+/// One entry of a recording.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RecordedMessage {
+    /// Local monotonic time since the recording started, in nanoseconds. Replay timing is based on this.
+    #[prost(uint64, tag = "1")]
+    pub elapsed_ns: u64,
+    /// Local wall clock time the message was seen, in nanoseconds since the unix epoch.
+    #[prost(uint64, tag = "2")]
+    pub unix_time_ns: u64,
+    #[prost(enumeration = "RecordedDirection", tag = "3")]
+    pub direction: i32,
+    #[prost(enumeration = "RecordedTransport", tag = "4")]
+    pub transport: i32,
+    /// The encoded `ApiUp` or `ApiDown`.
+    #[prost(bytes = "vec", tag = "5")]
+    pub payload: Vec<u8>,
+}
+
+impl RecordedMessage {
+    /// Time since the recording started.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns)
+    }
+
+    /// Decodes the payload as `ApiUp`, through [`crate::decode_message`].
+    pub fn decode_api_up(&self) -> Result<proto_public_api::ApiUp, crate::DecodeError> {
+        crate::decode_message(&self.payload, false)
+    }
+
+    /// Decodes the payload as `ApiDown`.
+    pub fn decode_api_down(&self) -> Result<proto_public_api::ApiDown, crate::DecodeError> {
+        Ok(proto_public_api::ApiDown::decode(&self.payload[..])?)
+    }
+}
+
+struct RecorderInner {
+    started_at: Instant,
+    writer: Mutex<std::io::BufWriter<std::fs::File>>,
+}
+
+/// Writes a recording. Cheap to clone, all clones write to the same file.
+///
+/// Writes are buffered, call [`Recorder::flush`] if you need the file up to date before the recorder is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<RecorderInner>,
+}
+
+impl Recorder {
+    /// Creates (or truncates) the recording file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file = std::fs::File::create(path)?;
+        Ok(Self {
+            inner: Arc::new(RecorderInner {
+                started_at: Instant::now(),
+                writer: Mutex::new(std::io::BufWriter::new(file)),
+            }),
+        })
+    }
+
+    /// Records an already encoded message, stamped with the current time.
+    pub fn record_raw(
+        &self,
+        direction: RecordedDirection,
+        transport: Transport,
+        payload: Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
+        let msg = RecordedMessage {
+            elapsed_ns: self.inner.started_at.elapsed().as_nanos() as u64,
+            unix_time_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            direction: direction as i32,
+            transport: RecordedTransport::from(transport) as i32,
+            payload,
+        };
+        let mut writer = self.inner.writer.lock().unwrap();
+        writer.write_all(&msg.encode_length_delimited_to_vec())?;
+        Ok(())
+    }
+
+    /// Records a received `ApiUp`.
+    pub fn record_api_up(
+        &self,
+        transport: Transport,
+        msg: &proto_public_api::ApiUp,
+    ) -> Result<(), anyhow::Error> {
+        self.record_raw(RecordedDirection::Up, transport, msg.encode_to_vec())
+    }
+
+    /// Records a sent `ApiDown`.
+    pub fn record_api_down(
+        &self,
+        transport: Transport,
+        msg: &proto_public_api::ApiDown,
+    ) -> Result<(), anyhow::Error> {
+        self.record_raw(RecordedDirection::Down, transport, msg.encode_to_vec())
+    }
+
+    /// Writes everything buffered so far to disk.
+    pub fn flush(&self) -> Result<(), anyhow::Error> {
+        self.inner.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+/// [`RobotConnection`] that records everything going through it.
+///
+/// `ApiUp` is recorded as received, before it is decoded, so messages that fail to decode and fields unknown to
+/// this version of the proto are kept too. Connections that can't show their payloads (see
+/// [`RobotConnection::tap_raw_api_up`]) fall back to recording the decoded messages encoded again.
+///
+/// Wrap the connection right after connecting. Messages it already received, e.g. ones a
+/// [`crate::ReconnectingConnection`] buffered in the meantime, are not recorded.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, Recorder, RecordingConnection, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let recorder = Recorder::create("session.rec")?;
+///     let mut connection = RecordingConnection::new(connection, recorder);
+///     // Use `connection` as usual, everything is recorded.
+///     Ok(())
+/// }
+/// ```
+pub struct RecordingConnection {
+    inner: Box<dyn RobotConnection>,
+    recorder: Recorder,
+    records_raw_api_up: bool,
+}
+
+impl RecordingConnection {
+    pub fn new(mut inner: Box<dyn RobotConnection>, recorder: Recorder) -> Self {
+        let tap_recorder = recorder.clone();
+        let transport = inner.transport();
+        let records_raw_api_up = inner.tap_raw_api_up(Arc::new(move |payload: &[u8]| {
+            if let Err(e) =
+                tap_recorder.record_raw(RecordedDirection::Up, transport, payload.to_vec())
+            {
+                warn!("Failed to record ApiUp: {}", e);
+            }
+        }));
+        Self {
+            inner,
+            recorder,
+            records_raw_api_up,
+        }
+    }
+
+    /// The recorder this connection writes to.
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+}
+
+impl RobotConnection for RecordingConnection {
+    fn transport(&self) -> Transport {
+        self.inner.transport()
+    }
+
+    fn session_id(&self) -> u32 {
+        self.inner.session_id()
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.inner.robot_type()
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        if let Err(e) = self.recorder.record_api_down(self.inner.transport(), &msg) {
+            warn!("Failed to record ApiDown: {}", e);
+        }
+        self.inner.send(msg)
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        let stream = self.inner.take_stream()?;
+        if self.records_raw_api_up {
+            return Some(stream);
+        }
+        let recorder = self.recorder.clone();
+        let transport = self.inner.transport();
+        Some(
+            stream
+                .inspect(move |msg| {
+                    if let Err(e) = recorder.record_api_up(transport, msg) {
+                        warn!("Failed to record ApiUp: {}", e);
+                    }
+                })
+                .boxed(),
+        )
+    }
+
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        self.inner.decode_errors()
+    }
+
+    fn tap_raw_api_up(&mut self, tap: RawApiUpTap) -> bool {
+        self.inner.tap_raw_api_up(tap)
+    }
+}
+
+/// Reads a whole recording into memory.
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedMessage>, anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    let mut buf = &bytes[..];
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        match RecordedMessage::decode_length_delimited(&mut buf) {
+            Ok(msg) => messages.push(msg),
+            Err(e) => {
+                // Most likely the program was killed mid-write. Keep what we have.
+                warn!(
+                    "Recording is truncated after {} messages: {}",
+                    messages.len(),
+                    e
+                );
+                break;
+            }
+        }
+    }
+    Ok(messages)
+}
+
+/// Yields recorded messages with their original timing, divided by `speed`.
+///
+/// `speed` of 2.0 replays twice as fast, `f64::INFINITY` replays as fast as possible.
+///
+/// # Arguments
+/// * `messages` - Recorded messages, e.g. from [`read_recording`]
+/// * `speed` - Playback speed multiplier, must be positive. Anything else (including NaN) is an error, as is a
+///   speed so slow that the recording would take longer than the clock can represent.
+pub fn replay(
+    messages: Vec<RecordedMessage>,
+    speed: f64,
+) -> Result<futures_util::stream::BoxStream<'static, RecordedMessage>, anyhow::Error> {
+    if speed.is_nan() || speed <= 0.0 {
+        anyhow::bail!("Replay speed must be positive, got {}", speed);
+    }
+    let first = messages.first().map_or(0, |msg| msg.elapsed_ns);
+    let length = messages
+        .iter()
+        .map(|msg| msg.elapsed_ns.saturating_sub(first))
+        .max();
+    let scaled =
+        move |offset: Duration| Duration::try_from_secs_f64(offset.as_secs_f64() / speed).ok();
+    if let Some(length) = length.map(Duration::from_nanos) {
+        if scaled(length)
+            .and_then(|length| tokio::time::Instant::now().checked_add(length))
+            .is_none()
+        {
+            anyhow::bail!(
+                "Replay speed {:?} is too slow for a recording of {:?}",
+                speed,
+                length
+            );
+        }
+    }
+    // The clock starts when the stream is first polled, not when it is created.
+    Ok(futures_util::stream::unfold(
+        (messages.into_iter(), None),
+        move |(mut messages, start)| async move {
+            let msg = messages.next()?;
+            let start = start.unwrap_or_else(tokio::time::Instant::now);
+            let offset = Duration::from_nanos(msg.elapsed_ns.saturating_sub(first));
+            if speed.is_finite() {
+                // Checked above, but the clock may have moved on since.
+                if let Some(at) = scaled(offset).and_then(|offset| start.checked_add(offset)) {
+                    tokio::time::sleep_until(at).await;
+                }
+            }
+            Some((msg, (messages, Some(start))))
+        },
+    )
+    .boxed())
+}
+
+/// Replays only the `ApiUp` messages, decoded, as if they came from a robot.
+///
+/// Messages that fail to decode are skipped with a warning, like a live connection does.
+pub fn replay_api_up(
+    messages: Vec<RecordedMessage>,
+    speed: f64,
+) -> Result<ApiUpStream, anyhow::Error> {
+    let (decode_errors, _) = broadcast::channel(1);
+    replay_api_up_with_decode_errors(messages, speed, decode_errors)
+}
+
+fn replay_api_up_with_decode_errors(
+    messages: Vec<RecordedMessage>,
+    speed: f64,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+) -> Result<ApiUpStream, anyhow::Error> {
+    let messages = messages
+        .into_iter()
+        .filter(|msg| msg.direction() == RecordedDirection::Up)
+        .collect();
+    Ok(replay(messages, speed)?
+        .filter_map(move |msg| {
+            let decode_errors = decode_errors.clone();
+            async move {
+                match msg.decode_api_up() {
+                    Ok(msg) => Some(msg),
+                    Err(e) => {
+                        warn!("Skipping recorded message: {}", e);
+                        // Nobody listening is fine.
+                        let _ = decode_errors.send(e);
+                        None
+                    }
+                }
+            }
+        })
+        .boxed())
+}
+
+/// [`RobotConnection`] that plays back a recording. What you send is logged and dropped.
+///
+/// Useful to run code written against a live robot on a recorded session.
+pub struct ReplayConnection {
+    transport: Transport,
+    session_id: u32,
+    robot_type: proto_public_api::RobotType,
+    stream: Option<ApiUpStream>,
+    decode_errors: broadcast::Sender<crate::DecodeError>,
+}
+
+impl ReplayConnection {
+    /// Session ID, robot type and transport are taken from the first `ApiUp` in the recording.
+    ///
+    /// Fails if the recording has no `ApiUp` that decodes, or `speed` isn't positive.
+    pub fn new(messages: Vec<RecordedMessage>, speed: f64) -> Result<Self, anyhow::Error> {
+        let (first, transport) = messages
+            .iter()
+            .filter(|msg| msg.direction() == RecordedDirection::Up)
+            .find_map(|msg| Some((msg.decode_api_up().ok()?, msg.transport().into())))
+            .ok_or_else(|| anyhow::anyhow!("Recording has no decodable ApiUp"))?;
+        let (decode_errors, _) = broadcast::channel(DECODE_ERROR_CAPACITY);
+        let stream = replay_api_up_with_decode_errors(messages, speed, decode_errors.clone())?;
+        Ok(Self {
+            transport,
+            session_id: first.session_id,
+            robot_type: first.robot_type(),
+            stream: Some(stream),
+            decode_errors,
+        })
+    }
+}
+
+impl RobotConnection for ReplayConnection {
+    fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.robot_type
+    }
+
+    fn send(&mut self, msg: proto_public_api::ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        debug!("Replay connection dropping {:?}", msg.down);
+        async { Ok(()) }.boxed()
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        self.stream.take()
+    }
+
+    fn decode_errors(&self) -> Option<broadcast::Receiver<crate::DecodeError>> {
+        Some(self.decode_errors.subscribe())
+    }
+}
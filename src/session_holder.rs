@@ -0,0 +1,404 @@
+use crate::control_session::ControlTarget;
+use crate::proto_public_api::{self, api_down::Down, ApiDown};
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// Where takeover requests are multicast. Every [`crate::ControlSession`] that accepts takeovers listens here.
+pub const TAKEOVER_MULTICAST_ADDR: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 255, 84, 39), 8449);
+
+/// Takeover requests sent longer ago than this, or this far in the future, are ignored, so a captured one can't
+/// be replayed later. Within it, [`SeenTakeoverRequests`] refuses the same request twice.
+const TAKEOVER_REQUEST_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// How long after sending `ApiControlInitialize(true)` another holder is not yet taken as a conflict. Statuses
+/// already on their way were sent before the robot saw the initialize.
+const INITIALIZE_GRACE: Duration = Duration::from_millis(500);
+
+/// Who holds API control of the base or arm, as seen by one session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership {
+    /// No status received yet.
+    Unknown,
+    /// Nobody holds it.
+    Free,
+    /// This session holds it.
+    Holder,
+    /// Another session, with this id, holds it.
+    Other(u32),
+}
+
+impl Ownership {
+    fn of(holder: u32, session_id: u32) -> Self {
+        match holder {
+            0 => Self::Free,
+            holder if holder == session_id => Self::Holder,
+            holder => Self::Other(holder),
+        }
+    }
+}
+
+/// A change of who holds API control, or a request to hand it over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnershipEvent {
+    /// This session became the holder.
+    Acquired,
+    /// This session was the holder, and now `holder` is. 0 if nobody is, e.g. after a parking stop.
+    Lost { holder: u32 },
+    /// Another session holds control, so this one can not move anything. Sent again if the holder changes.
+    HeldByOther { holder: u32 },
+    /// The other session released control, nobody holds it now.
+    Released { holder: u32 },
+    /// Session `by` asked this session to hand over control.
+    TakeoverRequested { by: u32 },
+}
+
+impl OwnershipEvent {
+    /// Whether this session can no longer move the robot, or never could.
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Self::Lost { .. } | Self::HeldByOther { .. })
+    }
+}
+
+impl fmt::Display for OwnershipEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Acquired => write!(f, "This session holds API control"),
+            Self::Lost { holder: 0 } => write!(f, "Lost API control, nobody holds it now"),
+            Self::Lost { holder } => write!(f, "Lost API control to session {}", holder),
+            Self::HeldByOther { holder } => {
+                write!(f, "Session {} holds API control, not this session", holder)
+            }
+            Self::Released { holder } => write!(f, "Session {} released API control", holder),
+            Self::TakeoverRequested { by } => {
+                write!(f, "Session {} asked to take over API control", by)
+            }
+        }
+    }
+}
+
+/// A motion command was not sent, because another session (or nobody) holds API control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotSessionHolder {
+    pub target: ControlTarget,
+    /// The session holding control, 0 if nobody.
+    pub holder: u32,
+}
+
+impl fmt::Display for NotSessionHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.holder {
+            0 => write!(f, "Nobody holds API control of the {}", self.target),
+            holder => write!(
+                f,
+                "Session {} holds API control of the {}",
+                holder, self.target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NotSessionHolder {}
+
+/// Compares `session_holder` of the base or arm status with our own session id.
+///
+/// [`crate::ControlSession`] uses one to refuse motion commands when it is not the holder. Use it directly with a
+/// bare connection.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, ControlTarget, RobotConnection, SessionHolderTracker, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut tracker = SessionHolderTracker::new(connection.session_id(), ControlTarget::Base);
+///     let mut stream = connection.take_stream().unwrap();
+///     while let Some(msg) = stream.next().await {
+///         if let Some(event) = tracker.update(&msg) {
+///             println!("{}", event);
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SessionHolderTracker {
+    session_id: u32,
+    target: ControlTarget,
+    ownership: Ownership,
+    was_holder: bool,
+    initialized_at: Option<Instant>,
+}
+
+impl SessionHolderTracker {
+    pub fn new(session_id: u32, target: ControlTarget) -> Self {
+        Self {
+            session_id,
+            target,
+            ownership: Ownership::Unknown,
+            was_holder: false,
+            initialized_at: None,
+        }
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    pub fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    /// Call when `ApiControlInitialize(true)` was sent.
+    pub fn initialize_sent(&mut self) {
+        self.initialized_at = Some(Instant::now());
+    }
+
+    /// Updates from the base or arm status in `msg`, if it has one. Returns the change of ownership, if any.
+    pub fn update(&mut self, msg: &proto_public_api::ApiUp) -> Option<OwnershipEvent> {
+        let holder = self.target.session_holder(msg)?;
+        let ownership = Ownership::of(holder, self.session_id);
+        let previous = std::mem::replace(&mut self.ownership, ownership);
+        if ownership == previous {
+            return None;
+        }
+        match (previous, ownership) {
+            (_, Ownership::Holder) => {
+                self.was_holder = true;
+                Some(OwnershipEvent::Acquired)
+            }
+            (Ownership::Holder, _) => Some(OwnershipEvent::Lost { holder }),
+            (_, Ownership::Other(holder)) => Some(OwnershipEvent::HeldByOther { holder }),
+            (Ownership::Other(holder), Ownership::Free) => {
+                Some(OwnershipEvent::Released { holder })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `msg` may be sent. Motion commands are refused while another session holds control, and after this
+    /// session lost it. Everything else, including `ApiControlInitialize` and `ClearParkingStop`, always may.
+    pub fn check(&self, msg: &ApiDown) -> Result<(), NotSessionHolder> {
+        if !is_motion_command(msg) {
+            return Ok(());
+        }
+        let refused = match self.ownership {
+            Ownership::Unknown | Ownership::Holder => None,
+            // Before the first initialize took effect. The robot ignores the command anyway.
+            Ownership::Free if !self.was_holder => None,
+            Ownership::Free => Some(0),
+            Ownership::Other(_)
+                if self
+                    .initialized_at
+                    .is_some_and(|at| at.elapsed() < INITIALIZE_GRACE) =>
+            {
+                None
+            }
+            Ownership::Other(holder) => Some(holder),
+        };
+        match refused {
+            Some(holder) => Err(NotSessionHolder {
+                target: self.target,
+                holder,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Commands only the session holder may send, except `ApiControlInitialize`.
+fn is_motion_command(msg: &ApiDown) -> bool {
+    use proto_public_api::arm_command::Command as ArmCommand;
+    use proto_public_api::arm_exclusive_command::ExclusiveCommand;
+    use proto_public_api::base_command::Command as BaseCommand;
+    match &msg.down {
+        Some(Down::BaseCommand(command)) => matches!(
+            command.command,
+            Some(BaseCommand::MotorTargets(_)) | Some(BaseCommand::SimpleMoveCommand(_))
+        ),
+        Some(Down::ArmCommand(command)) => match &command.command {
+            Some(ArmCommand::ArmExclusiveCommand(exclusive)) => !matches!(
+                exclusive.exclusive_command,
+                Some(ExclusiveCommand::ApiControlInitialize(_)) | None
+            ),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Asks session `holder` to hand over control of `target`, multicast to [`TAKEOVER_MULTICAST_ADDR`].
+///
+/// Signed with a token both sides share, so only programs that know it can take control.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TakeoverRequest {
+    /// The robot, as from [`robot_keys`].
+    pub robot: Vec<String>,
+    /// "base" or "arm".
+    pub target: String,
+    pub holder: u32,
+    pub requester: u32,
+    /// When the request was sent, in milliseconds since the unix epoch.
+    pub sent_at_ms: u64,
+    /// HMAC-SHA256 of all of the above with the shared token, hex encoded.
+    pub mac: String,
+}
+
+impl TakeoverRequest {
+    pub fn new(
+        robot: Vec<String>,
+        target: ControlTarget,
+        holder: u32,
+        requester: u32,
+        token: &str,
+    ) -> Self {
+        let mut request = Self {
+            robot,
+            target: target.to_string(),
+            holder,
+            requester,
+            sent_at_ms: unix_time_ms(),
+            mac: String::new(),
+        };
+        request.mac = request
+            .signature(token)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        request
+    }
+
+    /// Whether the request was signed with `token`, and sent recently.
+    pub fn verify(&self, token: &str) -> bool {
+        let age = unix_time_ms().abs_diff(self.sent_at_ms);
+        if age > TAKEOVER_REQUEST_MAX_AGE.as_millis() as u64 {
+            return false;
+        }
+        decode_hex(&self.mac).is_some_and(|mac| self.signature(token).verify_slice(&mac).is_ok())
+    }
+
+    fn signature(&self, token: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes())
+            .expect("HMAC takes keys of any length");
+        let fields = format!(
+            "{}|{}|{}|{}|{}",
+            self.robot.join(","),
+            self.target,
+            self.holder,
+            self.requester,
+            self.sent_at_ms
+        );
+        mac.update(fields.as_bytes());
+        mac
+    }
+
+    /// Whether the request names one of `robot`, see [`robot_keys`].
+    pub fn names_robot(&self, robot: &[String]) -> bool {
+        self.robot.iter().any(|key| robot.contains(key))
+    }
+
+    pub async fn send(&self) -> Result<(), anyhow::Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_multicast_loop_v4(true)?;
+        socket
+            .send_to(&serde_json::to_vec(self)?, TAKEOVER_MULTICAST_ADDR)
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the next takeover request. Invalid datagrams are skipped.
+    pub async fn recv(socket: &UdpSocket) -> Result<Self, anyhow::Error> {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).await?;
+            if let Ok(request) = serde_json::from_slice(&buf[..len]) {
+                return Ok(request);
+            }
+        }
+    }
+}
+
+/// Takeover requests already accepted, kept until they are too old to pass [`TakeoverRequest::verify`]. The
+/// requester and timestamp are signed, so a replayed request has the same ones as the original.
+#[derive(Debug, Default)]
+pub(crate) struct SeenTakeoverRequests(HashSet<(u32, u64)>);
+
+impl SeenTakeoverRequests {
+    /// Remembers `request`, and whether it was new.
+    pub fn first_time(&mut self, request: &TakeoverRequest) -> bool {
+        let now = unix_time_ms();
+        let max_age = TAKEOVER_REQUEST_MAX_AGE.as_millis() as u64;
+        self.0
+            .retain(|(_, sent_at_ms)| now.abs_diff(*sent_at_ms) <= max_age);
+        self.0.insert((request.requester, request.sent_at_ms))
+    }
+}
+
+/// Joins the takeover multicast group. Only one process per machine can listen.
+pub(crate) async fn takeover_socket() -> Result<UdpSocket, anyhow::Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, TAKEOVER_MULTICAST_ADDR.port()))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen for takeover requests: {}", e))?;
+    socket.join_multicast_v4(*TAKEOVER_MULTICAST_ADDR.ip(), Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// Both sides name the robot as they connected to it, so "[fe80::1%3]" and "FE80::1%eth0" must match.
+fn robot_key(address: &str) -> String {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    address
+        .split('%')
+        .next()
+        .unwrap_or(address)
+        .to_ascii_lowercase()
+}
+
+/// Every name of the robot at `address`: the IP address it resolves to, and the host name if it is one. A side
+/// that connected to "robot-1.local" then matches one that connected to its IP address.
+pub(crate) async fn robot_keys(address: &str) -> Vec<String> {
+    let key = robot_key(address);
+    if let Ok(ip) = key.parse::<IpAddr>() {
+        // Also normalizes how IPv6 addresses are written.
+        return vec![ip.to_string()];
+    }
+    let mut keys: Vec<String> = match tokio::net::lookup_host((key.as_str(), 0)).await {
+        Ok(addrs) => addrs.map(|addr| addr.ip().to_string()).collect(),
+        Err(e) => {
+            warn!(
+                "Failed to resolve {}, matching takeovers by name only: {}",
+                key, e
+            );
+            Vec::new()
+        }
+    };
+    keys.push(key);
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
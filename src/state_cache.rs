@@ -0,0 +1,180 @@
+use crate::connection::ApiUpStream;
+use crate::proto_public_api;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+struct Channels {
+    base: watch::Sender<Option<proto_public_api::BaseStatus>>,
+    arm: watch::Sender<Option<proto_public_api::ArmStatus>>,
+    linear_lift: watch::Sender<Option<proto_public_api::LinearLiftStatus>>,
+    rotate_lift: watch::Sender<Option<proto_public_api::RotateLiftStatus>>,
+    secondary_devices: watch::Sender<HashMap<u32, proto_public_api::SecondaryDeviceStatus>>,
+    log: watch::Sender<Option<String>>,
+}
+
+/// Latest state reported by the robot, readable from any number of tasks.
+///
+/// Feed it from the one task reading the `ApiUp` stream, with [`RobotStateCache::update`] or
+/// [`RobotStateCache::track`], or let [`RobotStateCache::spawn`] read the stream for you. Everyone else
+/// subscribes to the parts they care about. Each part is `None` until the robot first reports it.
+///
+/// Cheap to clone, all clones share the same channels.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, RobotStateCache, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let cache = RobotStateCache::new();
+///     cache.spawn(connection.take_stream().unwrap());
+///     let mut base = cache.subscribe_base();
+///     let base = base.wait_for(|base| base.is_some()).await?.clone().unwrap();
+///     println!("Battery: {}", base.battery_thousandth);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RobotStateCache {
+    channels: Arc<Channels>,
+}
+
+impl Default for RobotStateCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RobotStateCache {
+    pub fn new() -> Self {
+        Self {
+            channels: Arc::new(Channels {
+                base: watch::channel(None).0,
+                arm: watch::channel(None).0,
+                linear_lift: watch::channel(None).0,
+                rotate_lift: watch::channel(None).0,
+                secondary_devices: watch::channel(HashMap::new()).0,
+                log: watch::channel(None).0,
+            }),
+        }
+    }
+
+    /// Updates the cache from one `ApiUp`.
+    ///
+    /// Secondary devices are merged by `device_id`, a device missing from this message keeps its last status.
+    /// Every log line notifies subscribers, even if it repeats the previous one.
+    pub fn update(&self, msg: &proto_public_api::ApiUp) {
+        use proto_public_api::api_up::Status;
+        let channels = &self.channels;
+        match &msg.status {
+            Some(Status::BaseStatus(status)) => {
+                channels.base.send_replace(Some(status.clone()));
+            }
+            Some(Status::ArmStatus(status)) => {
+                channels.arm.send_replace(Some(status.clone()));
+            }
+            Some(Status::LinearLiftStatus(status)) => {
+                channels.linear_lift.send_replace(Some(status.clone()));
+            }
+            Some(Status::RotateLiftStatus(status)) => {
+                channels.rotate_lift.send_replace(Some(status.clone()));
+            }
+            // CAN frames are not state.
+            _ => {}
+        }
+        if !msg.secondary_device_status.is_empty() {
+            channels.secondary_devices.send_modify(|devices| {
+                for status in &msg.secondary_device_status {
+                    devices.insert(status.device_id, status.clone());
+                }
+            });
+        }
+        if let Some(log) = &msg.log {
+            channels.log.send_replace(Some(log.clone()));
+        }
+    }
+
+    /// Passes `stream` through, updating the cache with every message on the way.
+    pub fn track(&self, stream: ApiUpStream) -> ApiUpStream {
+        let cache = self.clone();
+        stream.inspect(move |msg| cache.update(msg)).boxed()
+    }
+
+    /// Spawns a task that reads `stream` into the cache until the stream ends.
+    pub fn spawn(&self, mut stream: ApiUpStream) -> JoinHandle<()> {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = stream.next().await {
+                cache.update(&msg);
+            }
+        })
+    }
+
+    pub fn subscribe_base(&self) -> watch::Receiver<Option<proto_public_api::BaseStatus>> {
+        self.channels.base.subscribe()
+    }
+
+    pub fn subscribe_arm(&self) -> watch::Receiver<Option<proto_public_api::ArmStatus>> {
+        self.channels.arm.subscribe()
+    }
+
+    pub fn subscribe_linear_lift(
+        &self,
+    ) -> watch::Receiver<Option<proto_public_api::LinearLiftStatus>> {
+        self.channels.linear_lift.subscribe()
+    }
+
+    pub fn subscribe_rotate_lift(
+        &self,
+    ) -> watch::Receiver<Option<proto_public_api::RotateLiftStatus>> {
+        self.channels.rotate_lift.subscribe()
+    }
+
+    /// Secondary devices (hands, IMUs, gamepads, ...) keyed by `device_id`.
+    pub fn subscribe_secondary_devices(
+        &self,
+    ) -> watch::Receiver<HashMap<u32, proto_public_api::SecondaryDeviceStatus>> {
+        self.channels.secondary_devices.subscribe()
+    }
+
+    /// The last log line from the robot.
+    pub fn subscribe_log(&self) -> watch::Receiver<Option<String>> {
+        self.channels.log.subscribe()
+    }
+
+    /// Current base status, if reported yet.
+    pub fn base(&self) -> Option<proto_public_api::BaseStatus> {
+        self.channels.base.borrow().clone()
+    }
+
+    /// Current arm status, if reported yet.
+    pub fn arm(&self) -> Option<proto_public_api::ArmStatus> {
+        self.channels.arm.borrow().clone()
+    }
+
+    /// Current linear lift status, if reported yet.
+    pub fn linear_lift(&self) -> Option<proto_public_api::LinearLiftStatus> {
+        self.channels.linear_lift.borrow().clone()
+    }
+
+    /// Current rotate lift status, if reported yet.
+    pub fn rotate_lift(&self) -> Option<proto_public_api::RotateLiftStatus> {
+        self.channels.rotate_lift.borrow().clone()
+    }
+
+    /// Current status of one secondary device, if reported yet.
+    pub fn secondary_device(
+        &self,
+        device_id: u32,
+    ) -> Option<proto_public_api::SecondaryDeviceStatus> {
+        self.channels
+            .secondary_devices
+            .borrow()
+            .get(&device_id)
+            .cloned()
+    }
+}
@@ -0,0 +1,651 @@
+use crate::base_drive::{BaseDrive, DriveStep};
+use crate::connection::RobotConnection;
+use crate::odometry::{normalize_angle, Pose2D, PoseSample, PoseTracker, PoseTrackerOptions};
+use crate::proto_public_api;
+use crate::velocity_smoother::VelocityLimits;
+use log::{info, warn};
+use std::future::Future;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Below this speed on every axis (m/s and rad/s), the base counts as standing still.
+const IDLE_SPEED: f64 = 1e-3;
+
+const PATH_CSV_HEADER: &str = "time_s,x,y,yaw,speed_x,speed_y,speed_z";
+
+/// One point of a [`TaughtPath`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathPoint {
+    /// Since the start of the path.
+    pub time: Duration,
+    pub pose: Pose2D,
+    /// Body frame `speed_x`, `speed_y` and `speed_z` the base reported here.
+    pub speed: [f64; 3],
+}
+
+/// Everything that can go wrong while saving or loading a path.
+#[derive(Debug)]
+pub enum PathError {
+    Read(PathBuf, std::io::Error),
+    Write(PathBuf, std::io::Error),
+    /// Line number, starting at 1, and what is wrong with it.
+    Parse(PathBuf, usize, String),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Read(path, e) => {
+                write!(f, "Failed to read path file {}: {}", path.display(), e)
+            }
+            PathError::Write(path, e) => {
+                write!(f, "Failed to write path file {}: {}", path.display(), e)
+            }
+            PathError::Parse(path, line, reason) => write!(
+                f,
+                "Failed to parse path file {}, line {}: {}",
+                path.display(),
+                line,
+                reason
+            ),
+            PathError::Invalid(path, reason) => {
+                write!(f, "Invalid path file {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// A path driven by hand, with the speed at every point, for [`PathReplayer`].
+///
+/// Poses are in the frame of the pose the base was in when recording started. Saved as CSV with a
+/// `time_s,x,y,yaw,speed_x,speed_y,speed_z` header, so it opens in anything that plots a trajectory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaughtPath {
+    /// Oldest first, times strictly increasing.
+    pub points: Vec<PathPoint>,
+}
+
+impl TaughtPath {
+    pub fn new(points: Vec<PathPoint>) -> Self {
+        Self { points }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.points
+            .last()
+            .map_or(Duration::ZERO, |point| point.time)
+    }
+
+    /// Distance driven, in meters.
+    pub fn length(&self) -> f64 {
+        self.points
+            .windows(2)
+            .map(|pair| (pair[1].pose.x - pair[0].pose.x).hypot(pair[1].pose.y - pair[0].pose.y))
+            .sum()
+    }
+
+    /// Where the path is at `time`, interpolated between points. Holds the first and last point outside the path.
+    /// `None` for an empty path.
+    pub fn sample(&self, time: Duration) -> Option<PathPoint> {
+        let after = self.points.partition_point(|point| point.time <= time);
+        let (from, to) = match after {
+            0 => return self.points.first().copied(),
+            after if after == self.points.len() => return self.points.last().copied(),
+            after => (self.points[after - 1], self.points[after]),
+        };
+        let span = (to.time - from.time).as_secs_f64();
+        let t = (time - from.time).as_secs_f64() / span;
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        Some(PathPoint {
+            time,
+            pose: Pose2D::new(
+                lerp(from.pose.x, to.pose.x),
+                lerp(from.pose.y, to.pose.y),
+                normalize_angle(from.pose.yaw + normalize_angle(to.pose.yaw - from.pose.yaw) * t),
+            ),
+            speed: [0, 1, 2].map(|i| lerp(from.speed[i], to.speed[i])),
+        })
+    }
+
+    /// Checks what the file format can't: at least two points, increasing times, finite numbers.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.points.len() < 2 {
+            return Err(format!(
+                "a path needs at least 2 points, got {}",
+                self.points.len()
+            ));
+        }
+        for (index, pair) in self.points.windows(2).enumerate() {
+            if pair[1].time <= pair[0].time {
+                return Err(format!("time goes back after point {}", index));
+            }
+        }
+        for (index, point) in self.points.iter().enumerate() {
+            let [x, y, z] = point.speed;
+            if ![point.pose.x, point.pose.y, point.pose.yaw, x, y, z]
+                .iter()
+                .all(|value| value.is_finite())
+            {
+                return Err(format!("point {} is not finite", index));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_csv(&self, writer: impl Write) -> Result<(), std::io::Error> {
+        let mut writer = std::io::BufWriter::new(writer);
+        writeln!(writer, "{}", PATH_CSV_HEADER)?;
+        for point in &self.points {
+            writeln!(
+                writer,
+                "{:.6},{:.6},{:.6},{:.6},{:.6},{:.6},{:.6}",
+                point.time.as_secs_f64(),
+                point.pose.x,
+                point.pose.y,
+                point.pose.yaw,
+                point.speed[0],
+                point.speed[1],
+                point.speed[2]
+            )?;
+        }
+        writer.flush()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PathError> {
+        let path = path.as_ref();
+        std::fs::File::create(path)
+            .and_then(|file| self.write_csv(file))
+            .map_err(|e| PathError::Write(path.to_owned(), e))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PathError> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|e| PathError::Read(path.to_owned(), e))?;
+        let mut points = Vec::new();
+        for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| PathError::Read(path.to_owned(), e))?;
+            let line = line.trim();
+            if index == 0 {
+                if line != PATH_CSV_HEADER {
+                    return Err(PathError::Parse(
+                        path.to_owned(),
+                        1,
+                        format!("expected the header {}", PATH_CSV_HEADER),
+                    ));
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let values = line
+                .split(',')
+                .map(|value| value.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| PathError::Parse(path.to_owned(), index + 1, e.to_string()))?;
+            let [time, x, y, yaw, speed_x, speed_y, speed_z] = values[..] else {
+                return Err(PathError::Parse(
+                    path.to_owned(),
+                    index + 1,
+                    format!("expected 7 values, got {}", values.len()),
+                ));
+            };
+            let time = Duration::try_from_secs_f64(time)
+                .map_err(|e| PathError::Parse(path.to_owned(), index + 1, e.to_string()))?;
+            points.push(PathPoint {
+                time,
+                pose: Pose2D::new(x, y, yaw),
+                speed: [speed_x, speed_y, speed_z],
+            });
+        }
+        let taught = Self { points };
+        taught
+            .validate()
+            .map_err(|reason| PathError::Invalid(path.to_owned(), reason))?;
+        Ok(taught)
+    }
+}
+
+/// Records a [`TaughtPath`] from the base's odometry while someone drives it.
+///
+/// It only listens, so it does not matter what drives the base: the keyboard (see the `base-teleop` example), the
+/// robot's own gamepad, or another program. Points closer together than the minimum interval (20ms by default)
+/// are dropped.
+///
+/// # Example
+/// ```no_run
+/// use futures_util::StreamExt;
+/// use robot_demos::{connect_robot, PathRecorder, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut stream = connection.take_stream().unwrap();
+///     let mut recorder = PathRecorder::new();
+///     let deadline = tokio::time::sleep(std::time::Duration::from_secs(30));
+///     tokio::pin!(deadline);
+///     loop {
+///         tokio::select! {
+///             Some(msg) = stream.next() => { recorder.update(&msg); }
+///             _ = &mut deadline => break,
+///         }
+///     }
+///     recorder.finish().save("path.csv")?;
+///     Ok(())
+/// }
+/// ```
+pub struct PathRecorder {
+    tracker: PoseTracker,
+    min_interval: Duration,
+    /// Robot timestamp of the first point.
+    start: Option<Duration>,
+    points: Vec<PathPoint>,
+}
+
+impl Default for PathRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathRecorder {
+    pub fn new() -> Self {
+        Self {
+            tracker: PoseTracker::with_options(PoseTrackerOptions {
+                record_trajectory: false,
+                ..Default::default()
+            }),
+            min_interval: Duration::from_millis(20),
+            start: None,
+            points: Vec::new(),
+        }
+    }
+
+    /// Points closer together than this are dropped. Defaults to 20ms.
+    pub fn min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = interval;
+        self
+    }
+
+    /// Every point so far, including standing still at either end.
+    pub fn points(&self) -> &[PathPoint] {
+        &self.points
+    }
+
+    /// Adds the odometry of `msg`. Returns the point, if one was recorded.
+    pub fn update(&mut self, msg: &proto_public_api::ApiUp) -> Option<PathPoint> {
+        let sample = self.tracker.update(msg)?;
+        let Some(proto_public_api::api_up::Status::BaseStatus(status)) = &msg.status else {
+            return None;
+        };
+        let odometry = status.estimated_odometry.as_ref()?;
+        let start = *self.start.get_or_insert(sample.time);
+        let time = sample.time.checked_sub(start)?;
+        if self
+            .points
+            .last()
+            .is_some_and(|last| time < last.time + self.min_interval)
+        {
+            return None;
+        }
+        let point = PathPoint {
+            time,
+            pose: sample.pose,
+            speed: [
+                odometry.speed_x as f64,
+                odometry.speed_y as f64,
+                odometry.speed_z as f64,
+            ],
+        };
+        self.points.push(point);
+        Some(point)
+    }
+
+    /// The path, without the standing still before the base started moving and after it stopped. Time starts at 0
+    /// at the first point left.
+    pub fn finish(self) -> TaughtPath {
+        let moving = |point: &PathPoint| point.speed.iter().any(|speed| speed.abs() > IDLE_SPEED);
+        let (Some(first), Some(last)) = (
+            self.points.iter().position(moving),
+            self.points.iter().rposition(moving),
+        ) else {
+            return TaughtPath::default();
+        };
+        // Keep the still point on either side, so the path starts and ends at zero speed.
+        let first = first.saturating_sub(1);
+        let last = (last + 1).min(self.points.len() - 1);
+        let offset = self.points[first].time;
+        TaughtPath::new(
+            self.points[first..=last]
+                .iter()
+                .map(|point| PathPoint {
+                    time: point.time - offset,
+                    ..*point
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Progress of a [`PathReplayer`], see [`PathReplayer::subscribe`].
+#[derive(Debug, Clone)]
+pub enum ReplayEvent {
+    Started {
+        /// How long the replay takes, at the speed scale.
+        duration: Duration,
+    },
+    /// Sent every progress interval.
+    Progress {
+        /// Time along the taught path.
+        path_time: Duration,
+        pose: Pose2D,
+        reference: Pose2D,
+        position_error: f64,
+        yaw_error: f64,
+    },
+    /// The base got further from the path than allowed. The replay is aborted right after.
+    Diverged {
+        path_time: Duration,
+        pose: Pose2D,
+        reference: Pose2D,
+        position_error: f64,
+        yaw_error: f64,
+    },
+    /// The replay stopped early. The base is ramped down afterwards.
+    Aborted { reason: String },
+    /// The end of the path was reached.
+    Finished { position_error: f64, yaw_error: f64 },
+}
+
+/// How a replay went.
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    /// Why the replay stopped early, `None` if it ran to the end.
+    pub aborted: Option<String>,
+    /// Largest distance from the path, in meters.
+    pub max_position_error: f64,
+    pub elapsed: Duration,
+    /// Every pose the odometry reported during the replay, in the frame of the path.
+    pub trajectory: Vec<PoseSample>,
+}
+
+/// Drives a base along a [`TaughtPath`], with the recorded timing or scaled.
+///
+/// The base must start where the path starts: the odometry is started at the first pose of the path. Each tick,
+/// the recorded speed at the current path time is sent (times the speed scale), plus a P correction towards the
+/// recorded pose, ramped by a [`crate::VelocitySmoother`]. Bases that can't move sideways correct a sideways error by
+/// steering.
+///
+/// The replay aborts when the base gets further than the maximum position or yaw error from the path, reports a
+/// parking stop, or odometry stops arriving. However it ends, the base is ramped down to zero speed before `run`
+/// returns. Initializing and deinitializing API control is up to the caller, e.g. with a
+/// [`crate::ControlSession`].
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, ControlSession, PathReplayer, RobotConnection, TaughtPath, Transport};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let replayer = PathReplayer::for_robot_type(TaughtPath::load("path.csv")?, connection.robot_type())
+///         .expect("Not a base")
+///         .speed_scale(0.5);
+///     let mut session = ControlSession::base(connection).await?;
+///     let report = replayer.run(&mut session).await?;
+///     session.finish(std::time::Duration::from_secs(1)).await?;
+///     println!("Off the path by {:.2}m at most", report.max_position_error);
+///     Ok(())
+/// }
+/// ```
+pub struct PathReplayer {
+    path: TaughtPath,
+    limits: VelocityLimits,
+    rate_hz: f64,
+    speed_scale: f64,
+    max_position_error: f64,
+    max_yaw_error: f64,
+    linear_gain: f64,
+    angular_gain: f64,
+    progress_interval: Duration,
+    stale_odometry: Duration,
+    events: broadcast::Sender<ReplayEvent>,
+}
+
+impl PathReplayer {
+    pub fn new(path: TaughtPath, limits: VelocityLimits) -> Self {
+        Self {
+            path,
+            limits,
+            rate_hz: 50.0,
+            speed_scale: 1.0,
+            max_position_error: 0.3,
+            max_yaw_error: 0.5,
+            linear_gain: 1.0,
+            angular_gain: 1.5,
+            progress_interval: Duration::from_millis(500),
+            stale_odometry: Duration::from_millis(500),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    /// A replayer with the default limits of `robot_type`, see [`VelocityLimits::for_robot_type`].
+    pub fn for_robot_type(
+        path: TaughtPath,
+        robot_type: proto_public_api::RobotType,
+    ) -> Option<Self> {
+        VelocityLimits::for_robot_type(robot_type).map(|limits| Self::new(path, limits))
+    }
+
+    /// Control loop rate. Defaults to 50Hz. [`PathReplayer::run`] fails on a rate [`crate::ControlLoop::new`]
+    /// refuses, before anything is sent.
+    pub fn rate_hz(mut self, rate_hz: f64) -> Self {
+        self.rate_hz = rate_hz;
+        self
+    }
+
+    /// Replay speed relative to the recording, e.g. 0.5 takes twice as long. Defaults to 1.0. Speeds are still
+    /// clamped to the velocity limits. Must be positive and finite, or [`PathReplayer::run`] fails.
+    pub fn speed_scale(mut self, scale: f64) -> Self {
+        self.speed_scale = scale;
+        self
+    }
+
+    /// Abort when the base is further than this from the path, in meters. Defaults to 0.3.
+    pub fn max_position_error(mut self, meters: f64) -> Self {
+        self.max_position_error = meters;
+        self
+    }
+
+    /// Abort when the heading is further than this from the path, in radians. Defaults to 0.5.
+    pub fn max_yaw_error(mut self, radians: f64) -> Self {
+        self.max_yaw_error = radians;
+        self
+    }
+
+    /// Correction speed per meter of position error, in 1/s. Defaults to 1.0.
+    pub fn linear_gain(mut self, gain: f64) -> Self {
+        self.linear_gain = gain;
+        self
+    }
+
+    /// Correction yaw rate per radian of heading error, in 1/s. Defaults to 1.5.
+    pub fn angular_gain(mut self, gain: f64) -> Self {
+        self.angular_gain = gain;
+        self
+    }
+
+    /// How often [`ReplayEvent::Progress`] is sent. Defaults to 500ms.
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    pub fn path(&self) -> &TaughtPath {
+        &self.path
+    }
+
+    /// Events of every run from now on. Slow receivers miss events rather than slowing down the loop.
+    pub fn subscribe(&self) -> broadcast::Receiver<ReplayEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: ReplayEvent) {
+        match &event {
+            ReplayEvent::Diverged { .. } | ReplayEvent::Aborted { .. } => warn!("{:?}", event),
+            _ => info!("{:?}", event),
+        }
+        let _ = self.events.send(event);
+    }
+
+    /// Position error and yaw error of `pose` from `reference`, the position error in the body frame of `pose`.
+    fn errors(pose: Pose2D, reference: Pose2D) -> ((f64, f64), f64) {
+        let (dx, dy) = (reference.x - pose.x, reference.y - pose.y);
+        let (sin, cos) = pose.yaw.sin_cos();
+        (
+            (cos * dx + sin * dy, -sin * dx + cos * dy),
+            normalize_angle(reference.yaw - pose.yaw),
+        )
+    }
+
+    /// Body frame speed to follow `reference` from `pose`.
+    fn control(&self, pose: Pose2D, reference: &PathPoint) -> [f64; 3] {
+        let ((ex, ey), yaw_error) = Self::errors(pose, reference.pose);
+        let [vx, vy, wz] = reference.speed.map(|speed| speed * self.speed_scale);
+        let speed = if self.limits.y.max_velocity > 0.0 {
+            [
+                vx + self.linear_gain * ex,
+                vy + self.linear_gain * ey,
+                wz + self.angular_gain * yaw_error,
+            ]
+        } else {
+            // Steer towards the path, the way the base is driving.
+            let steer = if vx < 0.0 { -ey } else { ey };
+            [
+                vx + self.linear_gain * ex,
+                0.0,
+                wz + self.angular_gain * yaw_error + self.linear_gain * steer,
+            ]
+        };
+        let max = [
+            self.limits.x.max_velocity,
+            self.limits.y.max_velocity,
+            self.limits.z.max_velocity,
+        ];
+        [0, 1, 2].map(|i| speed[i].clamp(-max[i], max[i]))
+    }
+
+    /// Replays the whole path. Takes the connection's stream, which must not have been taken yet.
+    ///
+    /// Returns `Err` if the path, the speed scale, the rate or the limits are invalid, or talking to the robot
+    /// fails. A replay that aborts still returns a report.
+    pub async fn run<C: RobotConnection + ?Sized>(
+        &self,
+        connection: &mut C,
+    ) -> Result<ReplayReport, anyhow::Error> {
+        self.run_until(connection, std::future::pending::<()>())
+            .await
+    }
+
+    /// Same as [`PathReplayer::run`], but aborts the replay once `stop` completes, e.g. on
+    /// `tokio::signal::ctrl_c()`. The base is ramped down all the same.
+    pub async fn run_until<C: RobotConnection + ?Sized>(
+        &self,
+        connection: &mut C,
+        stop: impl Future,
+    ) -> Result<ReplayReport, anyhow::Error> {
+        self.path.validate().map_err(anyhow::Error::msg)?;
+        if !self.speed_scale.is_finite() || self.speed_scale <= 0.0 {
+            anyhow::bail!("Speed scale must be positive, got: {}", self.speed_scale);
+        }
+        let drive = BaseDrive {
+            limits: self.limits,
+            rate_hz: self.rate_hz,
+            stale_odometry: self.stale_odometry,
+        };
+        let tracker = PoseTracker::with_options(PoseTrackerOptions {
+            origin: self.path.points[0].pose,
+            record_trajectory: true,
+            ..Default::default()
+        });
+        let mut max_position_error: f64 = 0.0;
+        let duration = self.path.duration();
+        let mut started: Option<Duration> = None;
+        let mut last_progress = Duration::ZERO;
+
+        let outcome = drive
+            .run(
+                connection,
+                tracker,
+                stop,
+                |pose, tick| {
+                    let started = *started.get_or_insert_with(|| {
+                        self.emit(ReplayEvent::Started {
+                            duration: duration.div_f64(self.speed_scale),
+                        });
+                        tick.elapsed
+                    });
+                    let path_time = (tick.elapsed - started).mul_f64(self.speed_scale);
+                    let reference = self.path.sample(path_time.min(duration)).unwrap();
+                    let ((ex, ey), yaw_error) = Self::errors(pose, reference.pose);
+                    let position_error = ex.hypot(ey);
+                    max_position_error = max_position_error.max(position_error);
+
+                    if position_error > self.max_position_error
+                        || yaw_error.abs() > self.max_yaw_error
+                    {
+                        self.emit(ReplayEvent::Diverged {
+                            path_time,
+                            pose,
+                            reference: reference.pose,
+                            position_error,
+                            yaw_error,
+                        });
+                        return DriveStep::Abort(format!(
+                            "{:.2}m and {:.2}rad off the path at {:.1}s",
+                            position_error,
+                            yaw_error,
+                            path_time.as_secs_f64()
+                        ));
+                    }
+                    if path_time >= duration {
+                        self.emit(ReplayEvent::Finished {
+                            position_error,
+                            yaw_error,
+                        });
+                        return DriveStep::Finish;
+                    }
+                    if tick.elapsed - last_progress >= self.progress_interval {
+                        last_progress = tick.elapsed;
+                        self.emit(ReplayEvent::Progress {
+                            path_time,
+                            pose,
+                            reference: reference.pose,
+                            position_error,
+                            yaw_error,
+                        });
+                    }
+                    DriveStep::Target(self.control(pose, &reference))
+                },
+                |reason| {
+                    self.emit(ReplayEvent::Aborted {
+                        reason: reason.to_string(),
+                    })
+                },
+            )
+            .await?;
+
+        let report = ReplayReport {
+            aborted: outcome.aborted,
+            max_position_error,
+            elapsed: outcome.elapsed,
+            trajectory: outcome.trajectory,
+        };
+        Ok(report)
+    }
+}
@@ -0,0 +1,516 @@
+use crate::connection::RobotConnection;
+use crate::proto_public_api::{ApiDown, RobotType};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Limits of one velocity axis. Units are m/s (or rad/s for yaw) and their derivatives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisLimits {
+    pub max_velocity: f64,
+    pub max_acceleration: f64,
+    pub max_jerk: f64,
+}
+
+impl AxisLimits {
+    pub const fn new(max_velocity: f64, max_acceleration: f64, max_jerk: f64) -> Self {
+        Self {
+            max_velocity,
+            max_acceleration,
+            max_jerk,
+        }
+    }
+
+    /// An axis the base can't move along. Targets on it are always 0.
+    pub const fn locked() -> Self {
+        Self::new(0.0, 1.0, 1.0)
+    }
+
+    /// Checks that every limit is finite and positive. Only the velocity may be 0, which locks the axis.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.max_velocity.is_finite() && self.max_velocity >= 0.0) {
+            return Err(format!(
+                "max_velocity must be finite and not negative, got {}",
+                self.max_velocity
+            ));
+        }
+        if !(self.max_acceleration.is_finite() && self.max_acceleration > 0.0) {
+            return Err(format!(
+                "max_acceleration must be finite and positive, got {}",
+                self.max_acceleration
+            ));
+        }
+        if !(self.max_jerk.is_finite() && self.max_jerk > 0.0) {
+            return Err(format!(
+                "max_jerk must be finite and positive, got {}",
+                self.max_jerk
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the three `XyzSpeed` axes: `x` and `y` in the base frame, `z` the yaw rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityLimits {
+    pub x: AxisLimits,
+    pub y: AxisLimits,
+    pub z: AxisLimits,
+}
+
+impl VelocityLimits {
+    /// Conservative defaults for each base, well inside what the hardware does. `None` for robots that are not
+    /// bases.
+    ///
+    /// Differential, skid steer and forward-only bases get a locked `y` axis. Heavier bases get lower acceleration
+    /// and jerk.
+    pub fn for_robot_type(robot_type: RobotType) -> Option<Self> {
+        let limits = match robot_type {
+            RobotType::RtMaverX4 => Self {
+                x: AxisLimits::new(1.0, 0.5, 2.0),
+                y: AxisLimits::new(1.0, 0.5, 2.0),
+                z: AxisLimits::new(1.0, 1.0, 4.0),
+            },
+            RobotType::RtMaverL4 | RobotType::RtMaverL2 => Self {
+                x: AxisLimits::new(1.0, 0.5, 2.0),
+                y: AxisLimits::locked(),
+                z: AxisLimits::new(1.0, 1.0, 4.0),
+            },
+            RobotType::RtMark1McnmBbDriver => Self {
+                x: AxisLimits::new(0.8, 0.8, 4.0),
+                y: AxisLimits::new(0.8, 0.8, 4.0),
+                z: AxisLimits::new(1.5, 1.5, 6.0),
+            },
+            RobotType::RtMark1DiffBbDriver | RobotType::RtArk2LrDriver => Self {
+                x: AxisLimits::new(0.8, 0.8, 4.0),
+                y: AxisLimits::locked(),
+                z: AxisLimits::new(1.5, 1.5, 6.0),
+            },
+            RobotType::RtTriggerA3 | RobotType::RtCustomPcwVehicle => Self {
+                x: AxisLimits::new(0.8, 0.5, 2.0),
+                y: AxisLimits::new(0.8, 0.5, 2.0),
+                z: AxisLimits::new(1.0, 1.0, 4.0),
+            },
+            RobotType::RtPureForwardOnly => Self {
+                x: AxisLimits::new(0.8, 0.5, 2.0),
+                y: AxisLimits::locked(),
+                z: AxisLimits::locked(),
+            },
+            // Not knowing the base is no reason to lurch, use the most careful limits.
+            RobotType::RtUnknown => Self {
+                x: AxisLimits::new(0.5, 0.3, 1.0),
+                y: AxisLimits::new(0.5, 0.3, 1.0),
+                z: AxisLimits::new(0.5, 0.5, 2.0),
+            },
+            RobotType::RtLotaLinearLift
+            | RobotType::RtZeta3Lift
+            | RobotType::RtArmSaber750d3Lr3DmDriver
+            | RobotType::RtArmSaber750d4Lr3DmDriver
+            | RobotType::RtArmSaber750h3Lr3DmDriver
+            | RobotType::RtArmSaber750h4Lr3DmDriver
+            | RobotType::RtArmSaberD6x
+            | RobotType::RtArmSaberD7x
+            | RobotType::RtArmArcherD6y => return None,
+        };
+        Some(limits)
+    }
+
+    /// Checks every axis, see [`AxisLimits::validate`].
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, axis) in [("x", &self.x), ("y", &self.y), ("z", &self.z)] {
+            axis.validate()
+                .map_err(|reason| format!("{} axis: {}", name, reason))?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a [`VelocitySmoother`] refused limits or a target.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VelocitySmootherError {
+    /// A limit is NaN, infinite or out of range.
+    InvalidLimits(String),
+    /// A target speed is NaN or infinite.
+    NonFiniteTarget([f64; 3]),
+}
+
+impl std::fmt::Display for VelocitySmootherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VelocitySmootherError::InvalidLimits(reason) => {
+                write!(f, "Invalid velocity limits: {}", reason)
+            }
+            VelocitySmootherError::NonFiniteTarget(target) => {
+                write!(f, "Target speed {:?} is not finite", target)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VelocitySmootherError {}
+
+/// Velocity and acceleration of one axis.
+#[derive(Debug, Clone, Copy, Default)]
+struct AxisState {
+    velocity: f64,
+    acceleration: f64,
+}
+
+impl AxisState {
+    /// Moves one step of `dt` towards `target`, without exceeding `limits`.
+    ///
+    /// The acceleration aimed for is the largest from which jerk-limited braking still ends exactly at the target
+    /// (a change of `a² / 2j` in velocity), so the velocity eases in instead of overshooting.
+    fn step(&mut self, target: f64, limits: &AxisLimits, dt: f64) {
+        let target = target.clamp(-limits.max_velocity, limits.max_velocity);
+        let error = target - self.velocity;
+        let desired_acceleration = error.signum()
+            * (2.0 * limits.max_jerk * error.abs())
+                .sqrt()
+                .min(limits.max_acceleration);
+        let max_change = limits.max_jerk * dt;
+        self.acceleration +=
+            (desired_acceleration - self.acceleration).clamp(-max_change, max_change);
+        let next = self.velocity + self.acceleration * dt;
+        // Landing on or past the target in this step: snap to it instead of oscillating around it.
+        if (target - next) * error <= 0.0 {
+            self.velocity = target;
+            self.acceleration = 0.0;
+        } else {
+            self.velocity = next;
+        }
+    }
+}
+
+/// Smooths base `XyzSpeed` commands, so a step in the requested speed does not make the base lurch.
+///
+/// Set where you want to go with [`VelocitySmoother::set_target`], then call [`VelocitySmoother::command`] once
+/// per control period and send what it returns. Each axis ramps towards its target with bounded acceleration and
+/// jerk, and targets are clamped to the maximum velocity.
+///
+/// When done, [`VelocitySmoother::deinitialize`] ramps down to zero before deinitializing, instead of stopping
+/// the base dead from whatever speed it had.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::proto_public_api::ApiDown;
+/// use robot_demos::{connect_robot, RobotConnection, Transport, VelocitySmoother};
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let mut connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let mut smoother = VelocitySmoother::for_robot_type(connection.robot_type())
+///         .expect("Not a base");
+///     connection.send(ApiDown::base_api_control_initialize(true)).await?;
+///     smoother.set_target(0.5, 0.0, 0.0)?;
+///     let period = Duration::from_millis(20);
+///     for _ in 0..250 {
+///         tokio::time::sleep(period).await;
+///         connection.send(smoother.command(period)).await?;
+///     }
+///     smoother.deinitialize(connection.as_mut(), period).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct VelocitySmoother {
+    limits: VelocityLimits,
+    target: [f64; 3],
+    state: [AxisState; 3],
+}
+
+impl VelocitySmoother {
+    /// A smoother starting at rest. Fails if `limits` don't pass [`VelocityLimits::validate`].
+    pub fn new(limits: VelocityLimits) -> Result<Self, VelocitySmootherError> {
+        limits
+            .validate()
+            .map_err(VelocitySmootherError::InvalidLimits)?;
+        Ok(Self::at_rest(limits))
+    }
+
+    /// A smoother with the default limits of `robot_type`, see [`VelocityLimits::for_robot_type`].
+    pub fn for_robot_type(robot_type: RobotType) -> Option<Self> {
+        VelocityLimits::for_robot_type(robot_type).map(Self::at_rest)
+    }
+
+    fn at_rest(limits: VelocityLimits) -> Self {
+        Self {
+            limits,
+            target: [0.0; 3],
+            state: [AxisState::default(); 3],
+        }
+    }
+
+    pub fn limits(&self) -> &VelocityLimits {
+        &self.limits
+    }
+
+    /// Replaces the limits. Invalid ones are refused, and the old ones kept.
+    pub fn set_limits(&mut self, limits: VelocityLimits) -> Result<(), VelocitySmootherError> {
+        limits
+            .validate()
+            .map_err(VelocitySmootherError::InvalidLimits)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// The speed to ramp to, in `XyzSpeed` units. Clamped to the maximum velocity of each axis.
+    ///
+    /// A NaN or infinite speed is refused, and the previous target kept.
+    pub fn set_target(
+        &mut self,
+        speed_x: f64,
+        speed_y: f64,
+        speed_z: f64,
+    ) -> Result<(), VelocitySmootherError> {
+        let target = [speed_x, speed_y, speed_z];
+        if !target.iter().all(|speed| speed.is_finite()) {
+            return Err(VelocitySmootherError::NonFiniteTarget(target));
+        }
+        self.target = target;
+        Ok(())
+    }
+
+    pub fn target(&self) -> [f64; 3] {
+        self.target
+    }
+
+    /// The speed last returned by [`VelocitySmoother::step`].
+    pub fn velocity(&self) -> [f64; 3] {
+        self.state.map(|axis| axis.velocity)
+    }
+
+    /// Whether every axis is at rest with a zero target.
+    pub fn is_stopped(&self) -> bool {
+        self.target == [0.0; 3] && self.velocity() == [0.0; 3]
+    }
+
+    /// Forgets the current velocity, e.g. after the base was stopped some other way.
+    pub fn reset(&mut self) {
+        self.target = [0.0; 3];
+        self.state = [AxisState::default(); 3];
+    }
+
+    /// Advances by `dt` and returns the speed to command now.
+    pub fn step(&mut self, dt: Duration) -> [f64; 3] {
+        let dt = dt.as_secs_f64();
+        let limits = [self.limits.x, self.limits.y, self.limits.z];
+        for ((axis, target), limits) in self.state.iter_mut().zip(self.target).zip(&limits) {
+            axis.step(target, limits, dt);
+        }
+        self.velocity()
+    }
+
+    /// [`VelocitySmoother::step`], as the `XyzSpeed` message to send.
+    pub fn command(&mut self, dt: Duration) -> ApiDown {
+        let [x, y, z] = self.step(dt);
+        ApiDown::base_xyz_speed(x as f32, y as f32, z as f32)
+    }
+
+    /// Targets zero and sends a smoothed command every `period` until the base is commanded to rest, then one
+    /// explicit zero.
+    ///
+    /// Gives up with an error after the longest ramp the limits allow, plus a margin, in case the limits are such
+    /// that it never settles. The explicit zero is sent in any case, also when ramping failed.
+    pub async fn ramp_down<C: RobotConnection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+        period: Duration,
+    ) -> Result<(), anyhow::Error> {
+        let ramp = self.ramp_to_rest(connection, period).await;
+        // The loop may have ended without sending a zero, or given up halfway.
+        let zero = connection
+            .send(ApiDown::base_xyz_speed(0.0, 0.0, 0.0))
+            .await;
+        ramp.and(zero)
+    }
+
+    async fn ramp_to_rest<C: RobotConnection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+        period: Duration,
+    ) -> Result<(), anyhow::Error> {
+        self.target = [0.0; 3];
+        let limit = self
+            .ramp_down_time_bound()
+            .saturating_add(Duration::from_secs(1));
+        let start = tokio::time::Instant::now();
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        while !self.is_stopped() {
+            if start.elapsed() > limit {
+                return Err(anyhow::anyhow!(
+                    "Base speed did not ramp down to zero within {:?}, still at {:?}",
+                    limit,
+                    self.velocity()
+                ));
+            }
+            interval.tick().await;
+            connection.send(self.command(period)).await?;
+        }
+        Ok(())
+    }
+
+    /// Ramps down to zero, then sends `ApiControlInitialize(false)`.
+    ///
+    /// If ramping down fails to send, deinitializing is still attempted, so the base is not left initialized.
+    pub async fn deinitialize<C: RobotConnection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+        period: Duration,
+    ) -> Result<(), anyhow::Error> {
+        let ramp = self.ramp_down(connection, period).await;
+        if let Err(e) = &ramp {
+            warn!("Ramping down failed, deinitializing anyway: {}", e);
+        }
+        connection
+            .send(ApiDown::base_api_control_initialize(false))
+            .await?;
+        info!("Base ramped down and deinitialized");
+        ramp
+    }
+
+    /// Upper bound on the time to ramp from the current velocity to zero.
+    fn ramp_down_time_bound(&self) -> Duration {
+        let limits = [self.limits.x, self.limits.y, self.limits.z];
+        let seconds = self
+            .state
+            .iter()
+            .zip(&limits)
+            .map(|(axis, limits)| {
+                // Reversing the current acceleration, then the velocity at max acceleration, then easing out.
+                let jerk_time = 2.0 * limits.max_acceleration / limits.max_jerk;
+                let velocity_time = axis.velocity.abs() / limits.max_acceleration;
+                axis.acceleration.abs() / limits.max_jerk + jerk_time + velocity_time
+            })
+            .fold(0.0, f64::max);
+        // Limits are validated, but the velocity may still be huge.
+        Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ApiUpStream, Transport};
+    use futures_util::future::BoxFuture;
+    use futures_util::FutureExt;
+
+    const DT: Duration = Duration::from_millis(10);
+
+    fn limits() -> VelocityLimits {
+        VelocityLimits {
+            x: AxisLimits::new(1.0, 0.5, 2.0),
+            y: AxisLimits::locked(),
+            z: AxisLimits::new(2.0, 4.0, 40.0),
+        }
+    }
+
+    /// Keeps what is sent.
+    #[derive(Default)]
+    struct Sent(Vec<ApiDown>);
+
+    impl RobotConnection for Sent {
+        fn transport(&self) -> Transport {
+            Transport::WebSocket
+        }
+
+        fn session_id(&self) -> u32 {
+            0
+        }
+
+        fn robot_type(&self) -> RobotType {
+            RobotType::RtUnknown
+        }
+
+        fn send(&mut self, msg: ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+            self.0.push(msg);
+            async { Ok(()) }.boxed()
+        }
+
+        fn take_stream(&mut self) -> Option<ApiUpStream> {
+            None
+        }
+    }
+
+    #[test]
+    fn ramps_within_the_limits() {
+        let limits = limits();
+        let mut smoother = VelocitySmoother::new(limits).unwrap();
+        smoother.set_target(0.8, 0.0, 0.0).unwrap();
+        let dt = DT.as_secs_f64();
+        let (mut velocity, mut acceleration) = (0.0, 0.0);
+        let mut steps = 0;
+        while smoother.velocity()[0] != 0.8 {
+            steps += 1;
+            assert!(steps < 1000, "never reached the target");
+            let next = smoother.step(DT)[0];
+            let next_acceleration = (next - velocity) / dt;
+            assert!(
+                next >= velocity && next <= 0.8,
+                "{} after {}",
+                next,
+                velocity
+            );
+            assert!(next_acceleration <= limits.x.max_acceleration + 1e-9);
+            // The last step snaps to the target, which may stop the acceleration at once.
+            if next != 0.8 {
+                assert!(
+                    (next_acceleration - acceleration).abs() / dt <= limits.x.max_jerk + 1e-6,
+                    "jerk {} at {}",
+                    (next_acceleration - acceleration).abs() / dt,
+                    next
+                );
+            }
+            (velocity, acceleration) = (next, next_acceleration);
+        }
+        // 0.8 m/s at 0.5 m/s² takes at least 1.6s.
+        assert!(steps as f64 * dt >= 1.6);
+        // Stays there.
+        assert_eq!(smoother.step(DT), [0.8, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn targets_are_clamped_and_locked_axes_stay_still() {
+        let mut smoother = VelocitySmoother::new(limits()).unwrap();
+        smoother.set_target(-5.0, 1.0, 0.0).unwrap();
+        for _ in 0..1000 {
+            smoother.step(DT);
+        }
+        assert_eq!(smoother.velocity(), [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn refuses_non_finite_targets_and_limits() {
+        let mut smoother = VelocitySmoother::new(limits()).unwrap();
+        smoother.set_target(0.5, 0.0, 0.0).unwrap();
+        assert!(smoother.set_target(f64::NAN, 0.0, 0.0).is_err());
+        assert_eq!(smoother.target(), [0.5, 0.0, 0.0]);
+
+        let mut bad = limits();
+        bad.z.max_jerk = f64::INFINITY;
+        assert!(VelocitySmoother::new(bad).is_err());
+        assert!(smoother.set_limits(bad).is_err());
+        assert_eq!(smoother.limits(), &limits());
+    }
+
+    #[tokio::test]
+    async fn ramp_down_ends_with_an_explicit_zero() {
+        let mut smoother = VelocitySmoother::new(limits()).unwrap();
+        smoother.set_target(0.0, 0.0, 1.0).unwrap();
+        for _ in 0..100 {
+            smoother.step(DT);
+        }
+        assert_eq!(smoother.velocity()[2], 1.0);
+
+        let mut sent = Sent::default();
+        smoother
+            .ramp_down(&mut sent, Duration::from_millis(1))
+            .await
+            .unwrap();
+        assert!(smoother.is_stopped());
+        // Eased down over several steps, not stopped dead.
+        assert!(sent.0.len() > 10, "{} messages", sent.0.len());
+        assert_eq!(sent.0.last(), Some(&ApiDown::base_xyz_speed(0.0, 0.0, 0.0)));
+    }
+}
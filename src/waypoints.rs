@@ -0,0 +1,524 @@
+use crate::base_drive::{BaseDrive, DriveStep};
+use crate::connection::RobotConnection;
+use crate::odometry::{normalize_angle, Pose2D, PoseSample, PoseTracker, PoseTrackerOptions};
+use crate::proto_public_api;
+use crate::velocity_smoother::VelocityLimits;
+use log::info;
+use serde::Deserialize;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// One place to drive to. Coordinates are relative to where the base was when the mission started.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Waypoint {
+    /// Meters, forward of the start pose.
+    pub x: f64,
+    /// Meters, left of the start pose.
+    pub y: f64,
+    /// Radians, counter-clockwise. `None` arrives with any heading.
+    pub yaw: Option<f64>,
+    /// Overrides [`Mission::position_tolerance`] for this waypoint.
+    pub position_tolerance: Option<f64>,
+    /// Overrides [`Mission::yaw_tolerance`] for this waypoint.
+    pub yaw_tolerance: Option<f64>,
+    /// Overrides [`Mission::timeout_s`] for this waypoint.
+    pub timeout_s: Option<f64>,
+}
+
+impl Waypoint {
+    pub fn new(x: f64, y: f64, yaw: Option<f64>) -> Self {
+        Self {
+            x,
+            y,
+            yaw,
+            position_tolerance: None,
+            yaw_tolerance: None,
+            timeout_s: None,
+        }
+    }
+}
+
+/// What to do when a waypoint is not reached in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutAction {
+    /// Stop the mission.
+    #[default]
+    Abort,
+    /// Carry on with the next waypoint.
+    Skip,
+}
+
+fn default_position_tolerance() -> f64 {
+    0.05
+}
+
+fn default_yaw_tolerance() -> f64 {
+    0.05
+}
+
+fn default_timeout_s() -> f64 {
+    30.0
+}
+
+fn default_speed_scale() -> f64 {
+    0.5
+}
+
+/// Waypoints to drive through in order, usually loaded from a TOML mission file:
+///
+/// ```toml
+/// position_tolerance = 0.05 # m, default 0.05
+/// yaw_tolerance = 0.05      # rad, default 0.05
+/// timeout_s = 30.0          # per waypoint, default 30
+/// on_timeout = "abort"      # or "skip", default "abort"
+/// speed_scale = 0.5         # fraction of the base's maximum velocity, default 0.5
+///
+/// [[waypoint]]
+/// x = 1.0
+/// y = 0.0
+///
+/// [[waypoint]]
+/// x = 1.0
+/// y = 1.0
+/// yaw = 1.5708
+/// timeout_s = 60.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mission {
+    /// Meters from the waypoint that count as arrived.
+    #[serde(default = "default_position_tolerance")]
+    pub position_tolerance: f64,
+    /// Radians from the waypoint's yaw that count as arrived.
+    #[serde(default = "default_yaw_tolerance")]
+    pub yaw_tolerance: f64,
+    /// Seconds to reach each waypoint.
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: f64,
+    #[serde(default)]
+    pub on_timeout: TimeoutAction,
+    /// Fraction of the maximum velocity in the base's [`VelocityLimits`] to drive at.
+    #[serde(default = "default_speed_scale")]
+    pub speed_scale: f64,
+    #[serde(rename = "waypoint")]
+    pub waypoints: Vec<Waypoint>,
+}
+
+/// Everything that can go wrong while loading a mission.
+#[derive(Debug)]
+pub enum MissionError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for MissionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissionError::Read(path, e) => {
+                write!(f, "Failed to read mission file {}: {}", path.display(), e)
+            }
+            MissionError::Parse(path, e) => {
+                write!(f, "Failed to parse mission file {}: {}", path.display(), e)
+            }
+            MissionError::Invalid(path, reason) => {
+                write!(f, "Invalid mission file {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MissionError {}
+
+impl Mission {
+    /// A mission with the default tolerances, timeout and speed.
+    pub fn new(waypoints: Vec<Waypoint>) -> Self {
+        Self {
+            position_tolerance: default_position_tolerance(),
+            yaw_tolerance: default_yaw_tolerance(),
+            timeout_s: default_timeout_s(),
+            on_timeout: TimeoutAction::default(),
+            speed_scale: default_speed_scale(),
+            waypoints,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MissionError> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|e| MissionError::Read(path.to_owned(), e))?;
+        let mission: Mission =
+            toml::from_str(&text).map_err(|e| MissionError::Parse(path.to_owned(), e))?;
+        mission
+            .validate()
+            .map_err(|reason| MissionError::Invalid(path.to_owned(), reason))?;
+        Ok(mission)
+    }
+
+    /// Checks what the file format can't: at least one waypoint, and positive tolerances, timeouts and speed.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.waypoints.is_empty() {
+            return Err("no waypoints, add at least one [[waypoint]]".to_string());
+        }
+        if !(self.speed_scale > 0.0 && self.speed_scale <= 1.0) {
+            return Err(format!(
+                "speed_scale must be in (0, 1], got {}",
+                self.speed_scale
+            ));
+        }
+        for (index, waypoint) in self.waypoints.iter().enumerate() {
+            let timeout_s = waypoint.timeout_s.unwrap_or(self.timeout_s);
+            let (position_tolerance, yaw_tolerance, _) = self.tolerances(waypoint);
+            if !(position_tolerance > 0.0
+                && yaw_tolerance > 0.0
+                && timeout_s > 0.0
+                && timeout_s.is_finite())
+            {
+                return Err(format!(
+                    "waypoint {} needs positive tolerances and timeout",
+                    index
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Position tolerance, yaw tolerance and timeout of `waypoint`, with the mission defaults filled in.
+    fn tolerances(&self, waypoint: &Waypoint) -> (f64, f64, Duration) {
+        (
+            waypoint
+                .position_tolerance
+                .unwrap_or(self.position_tolerance),
+            waypoint.yaw_tolerance.unwrap_or(self.yaw_tolerance),
+            Duration::try_from_secs_f64(waypoint.timeout_s.unwrap_or(self.timeout_s))
+                .unwrap_or(Duration::ZERO),
+        )
+    }
+}
+
+/// Progress of a [`WaypointFollower`], see [`WaypointFollower::subscribe`].
+#[derive(Debug, Clone)]
+pub enum MissionEvent {
+    /// Now driving to waypoint `index`.
+    WaypointStarted { index: usize, waypoint: Waypoint },
+    /// Sent every progress interval while driving.
+    Progress {
+        index: usize,
+        pose: Pose2D,
+        distance: f64,
+        yaw_error: f64,
+    },
+    WaypointReached {
+        index: usize,
+        pose: Pose2D,
+        elapsed: Duration,
+    },
+    WaypointTimedOut {
+        index: usize,
+        pose: Pose2D,
+        distance: f64,
+    },
+    /// The mission stopped early. The base is ramped down afterwards.
+    Aborted { reason: String },
+    /// Every waypoint was either reached or skipped.
+    Finished,
+}
+
+/// How a mission went.
+#[derive(Debug, Clone)]
+pub struct MissionReport {
+    /// Indices of the waypoints that were reached.
+    pub reached: Vec<usize>,
+    /// Indices of the waypoints that timed out, including the one that aborted the mission.
+    pub timed_out: Vec<usize>,
+    /// Why the mission stopped early, `None` if it ran to the end.
+    pub aborted: Option<String>,
+    pub elapsed: Duration,
+    /// Every pose the odometry reported during the mission.
+    pub trajectory: Vec<PoseSample>,
+}
+
+/// Drives a base through the waypoints of a [`Mission`], closed-loop on its estimated odometry.
+///
+/// Poses come from a [`PoseTracker`] started at the origin, so waypoints are relative to the pose the base is in
+/// when [`WaypointFollower::run`] is called. Each tick, a P controller turns the position and yaw error into an
+/// `XyzSpeed`, ramped by a [`crate::VelocitySmoother`]:
+/// - Bases that can move sideways drive straight at the waypoint while turning to its yaw.
+/// - Bases that can't (a zero `y` velocity limit) first turn towards the waypoint, drive there, then turn to its
+///   yaw.
+///
+/// The mission aborts if the base reports a parking stop or odometry stops arriving. However the mission ends,
+/// the base is ramped down to zero speed before `run` returns. Initializing and deinitializing API control is
+/// up to the caller, e.g. with a [`crate::ControlSession`].
+///
+/// The mock robot follows `XyzSpeed` exactly, so `mock-robot` (and [`crate::MockRobot::base_pose`]) is a good
+/// place to try a mission first.
+///
+/// # Example
+/// ```no_run
+/// use robot_demos::{connect_robot, ControlSession, Mission, RobotConnection, Transport, WaypointFollower};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), anyhow::Error> {
+///     let connection = connect_robot("127.0.0.1", 8439, Transport::WebSocket).await?;
+///     let follower = WaypointFollower::for_robot_type(Mission::load("mission.toml")?, connection.robot_type())?;
+///     let mut events = follower.subscribe();
+///     tokio::spawn(async move {
+///         while let Ok(event) = events.recv().await {
+///             println!("{:?}", event);
+///         }
+///     });
+///     let mut session = ControlSession::base(connection).await?;
+///     let report = follower.run(&mut session).await?;
+///     session.finish(std::time::Duration::from_secs(1)).await?;
+///     println!("Reached {} waypoints", report.reached.len());
+///     Ok(())
+/// }
+/// ```
+pub struct WaypointFollower {
+    mission: Mission,
+    limits: VelocityLimits,
+    rate_hz: f64,
+    linear_gain: f64,
+    angular_gain: f64,
+    heading_threshold: f64,
+    progress_interval: Duration,
+    stale_odometry: Duration,
+    events: broadcast::Sender<MissionEvent>,
+}
+
+impl WaypointFollower {
+    /// Fails if `mission` does not pass [`Mission::validate`], which [`Mission::load`] already checks.
+    pub fn new(mission: Mission, limits: VelocityLimits) -> Result<Self, anyhow::Error> {
+        mission
+            .validate()
+            .map_err(|reason| anyhow::anyhow!("Invalid mission: {}", reason))?;
+        Ok(Self {
+            mission,
+            limits,
+            rate_hz: 50.0,
+            linear_gain: 1.0,
+            angular_gain: 1.5,
+            heading_threshold: 0.3,
+            progress_interval: Duration::from_millis(500),
+            stale_odometry: Duration::from_millis(500),
+            events: broadcast::channel(64).0,
+        })
+    }
+
+    /// A follower with the default limits of `robot_type`, see [`VelocityLimits::for_robot_type`]. Fails if
+    /// `robot_type` is not a base, or the mission is invalid.
+    pub fn for_robot_type(
+        mission: Mission,
+        robot_type: proto_public_api::RobotType,
+    ) -> Result<Self, anyhow::Error> {
+        let limits = VelocityLimits::for_robot_type(robot_type)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a base", robot_type.as_str_name()))?;
+        Self::new(mission, limits)
+    }
+
+    /// Control loop rate. Defaults to 50Hz. [`WaypointFollower::run`] fails on a rate [`crate::ControlLoop::new`]
+    /// refuses, before anything is sent.
+    pub fn rate_hz(mut self, rate_hz: f64) -> Self {
+        self.rate_hz = rate_hz;
+        self
+    }
+
+    /// Speed per meter of position error, in 1/s. Defaults to 1.0.
+    pub fn linear_gain(mut self, gain: f64) -> Self {
+        self.linear_gain = gain;
+        self
+    }
+
+    /// Yaw rate per radian of heading error, in 1/s. Defaults to 1.5.
+    pub fn angular_gain(mut self, gain: f64) -> Self {
+        self.angular_gain = gain;
+        self
+    }
+
+    /// Bases that can't move sideways only drive forward once they face the waypoint within this many radians.
+    /// Defaults to 0.3.
+    pub fn heading_threshold(mut self, radians: f64) -> Self {
+        self.heading_threshold = radians;
+        self
+    }
+
+    /// How often [`MissionEvent::Progress`] is sent. Defaults to 500ms.
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    pub fn mission(&self) -> &Mission {
+        &self.mission
+    }
+
+    /// Events of every run from now on. Slow receivers miss events rather than slowing down the loop.
+    pub fn subscribe(&self) -> broadcast::Receiver<MissionEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: MissionEvent) {
+        info!("{:?}", event);
+        let _ = self.events.send(event);
+    }
+
+    /// Body frame speed towards `waypoint`, and whether it has been reached.
+    fn control(&self, pose: Pose2D, waypoint: &Waypoint) -> ([f64; 3], bool) {
+        let (position_tolerance, yaw_tolerance, _) = self.mission.tolerances(waypoint);
+        let (dx, dy) = (waypoint.x - pose.x, waypoint.y - pose.y);
+        let (sin, cos) = pose.yaw.sin_cos();
+        // Error in the body frame.
+        let (ex, ey) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+        let at_position = dx.hypot(dy) <= position_tolerance;
+        let yaw_error = waypoint
+            .yaw
+            .map_or(0.0, |yaw| normalize_angle(yaw - pose.yaw));
+        if at_position && yaw_error.abs() <= yaw_tolerance {
+            return ([0.0; 3], true);
+        }
+
+        let scale = self.mission.speed_scale;
+        let max = [
+            self.limits.x.max_velocity * scale,
+            self.limits.y.max_velocity * scale,
+            self.limits.z.max_velocity * scale,
+        ];
+        let speed = if at_position {
+            [0.0, 0.0, self.angular_gain * yaw_error]
+        } else if self.limits.y.max_velocity > 0.0 {
+            let (vx, vy) = (self.linear_gain * ex, self.linear_gain * ey);
+            // Scale both together, so the base keeps heading straight at the waypoint.
+            let factor = [(vx, max[0]), (vy, max[1])]
+                .iter()
+                .filter(|(v, _)| v.abs() > 0.0)
+                .map(|(v, max)| max / v.abs())
+                .fold(1.0, f64::min);
+            [vx * factor, vy * factor, self.angular_gain * yaw_error]
+        } else {
+            let bearing = ey.atan2(ex);
+            let vx = if bearing.abs() > self.heading_threshold {
+                0.0
+            } else {
+                self.linear_gain * ex * bearing.cos()
+            };
+            [vx, 0.0, self.angular_gain * bearing]
+        };
+        let clamped = [0, 1, 2].map(|i| speed[i].clamp(-max[i], max[i]));
+        (clamped, false)
+    }
+
+    /// Drives the whole mission. Takes the connection's stream, which must not have been taken yet.
+    ///
+    /// Returns `Err` only if the rate or the limits are invalid, or talking to the robot fails. A mission that
+    /// aborts still returns a report.
+    pub async fn run<C: RobotConnection + ?Sized>(
+        &self,
+        connection: &mut C,
+    ) -> Result<MissionReport, anyhow::Error> {
+        self.run_until(connection, std::future::pending::<()>())
+            .await
+    }
+
+    /// Same as [`WaypointFollower::run`], but aborts the mission once `stop` completes, e.g. on
+    /// `tokio::signal::ctrl_c()`. The base is ramped down all the same.
+    pub async fn run_until<C: RobotConnection + ?Sized>(
+        &self,
+        connection: &mut C,
+        stop: impl Future,
+    ) -> Result<MissionReport, anyhow::Error> {
+        let drive = BaseDrive {
+            limits: self.limits,
+            rate_hz: self.rate_hz,
+            stale_odometry: self.stale_odometry,
+        };
+        let mut report = MissionReport {
+            reached: Vec::new(),
+            timed_out: Vec::new(),
+            aborted: None,
+            elapsed: Duration::ZERO,
+            trajectory: Vec::new(),
+        };
+        let mut index = 0;
+        let mut waypoint_started: Option<Duration> = None;
+        let mut last_progress = Duration::ZERO;
+
+        let outcome = drive
+            .run(
+                connection,
+                PoseTracker::with_options(PoseTrackerOptions {
+                    record_trajectory: true,
+                    ..Default::default()
+                }),
+                stop,
+                |pose, tick| {
+                    let Some(waypoint) = self.mission.waypoints.get(index) else {
+                        self.emit(MissionEvent::Finished);
+                        return DriveStep::Finish;
+                    };
+                    let started = *waypoint_started.get_or_insert_with(|| {
+                        self.emit(MissionEvent::WaypointStarted {
+                            index,
+                            waypoint: waypoint.clone(),
+                        });
+                        tick.elapsed
+                    });
+                    let (_, _, timeout) = self.mission.tolerances(waypoint);
+                    let distance = (waypoint.x - pose.x).hypot(waypoint.y - pose.y);
+                    let (target, arrived) = self.control(pose, waypoint);
+
+                    if arrived {
+                        report.reached.push(index);
+                        self.emit(MissionEvent::WaypointReached {
+                            index,
+                            pose,
+                            elapsed: tick.elapsed - started,
+                        });
+                        index += 1;
+                        waypoint_started = None;
+                    } else if tick.elapsed - started > timeout {
+                        report.timed_out.push(index);
+                        self.emit(MissionEvent::WaypointTimedOut {
+                            index,
+                            pose,
+                            distance,
+                        });
+                        if self.mission.on_timeout == TimeoutAction::Abort {
+                            return DriveStep::Abort(format!(
+                                "waypoint {} timed out after {:?}",
+                                index, timeout
+                            ));
+                        }
+                        index += 1;
+                        waypoint_started = None;
+                    } else if tick.elapsed - last_progress >= self.progress_interval {
+                        last_progress = tick.elapsed;
+                        self.emit(MissionEvent::Progress {
+                            index,
+                            pose,
+                            distance,
+                            yaw_error: waypoint
+                                .yaw
+                                .map_or(0.0, |yaw| normalize_angle(yaw - pose.yaw)),
+                        });
+                    }
+                    DriveStep::Target(target)
+                },
+                |reason| {
+                    self.emit(MissionEvent::Aborted {
+                        reason: reason.to_string(),
+                    })
+                },
+            )
+            .await?;
+
+        report.aborted = outcome.aborted;
+        report.elapsed = outcome.elapsed;
+        report.trajectory = outcome.trajectory;
+        Ok(report)
+    }
+}
@@ -0,0 +1,123 @@
+//! Drives a `ControlSession` against the mock robot over each transport, and checks what the robot received.
+
+use robot_demos::proto_public_api::{self, ApiDown};
+use robot_demos::{
+    connect_robot, ControlSession, MockRobot, MockRobotConfig, RobotConnection, Transport,
+};
+use std::time::Duration;
+
+/// Initializes the base, sends a few speeds and finishes. Returns the base commands the robot got from the session,
+/// each with the transport it arrived over.
+async fn drive_base(transport: Transport) -> Vec<(Transport, ApiDown)> {
+    let robot = MockRobot::start("127.0.0.1:0".parse().unwrap(), MockRobotConfig::default())
+        .await
+        .unwrap();
+    let addr = robot.local_addr();
+    let connection = connect_robot(&addr.ip().to_string(), addr.port(), transport)
+        .await
+        .unwrap();
+    assert_eq!(connection.transport(), transport);
+    let session_id = connection.session_id();
+
+    let mut session = ControlSession::base(connection).await.unwrap();
+    assert_eq!(session.transport(), transport);
+    for _ in 0..5 {
+        session
+            .send(ApiDown::base_xyz_speed(0.1, 0.0, 0.2))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(robot.api_control_initialized());
+    assert_eq!(robot.session_holder(), session_id);
+
+    assert!(session.finish(Duration::from_secs(1)).await.unwrap());
+    assert!(!robot.api_control_initialized());
+
+    robot
+        .received()
+        .into_iter()
+        .filter(|received| received.session_id == session_id)
+        .filter(|received| {
+            matches!(
+                received.message.down,
+                Some(proto_public_api::api_down::Down::BaseCommand(_))
+            )
+        })
+        .map(|received| (received.transport, received.message))
+        .collect()
+}
+
+fn expected_base_commands() -> Vec<ApiDown> {
+    let mut expected = vec![ApiDown::base_api_control_initialize(true)];
+    expected.extend(std::iter::repeat_n(
+        ApiDown::base_xyz_speed(0.1, 0.0, 0.2),
+        5,
+    ));
+    expected.push(ApiDown::base_api_control_initialize(false));
+    expected
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn control_session_over_websocket() {
+    let received = drive_base(Transport::WebSocket).await;
+    assert!(received
+        .iter()
+        .all(|(transport, _)| *transport == Transport::WebSocket));
+    let messages: Vec<_> = received.into_iter().map(|(_, msg)| msg).collect();
+    assert_eq!(messages, expected_base_commands());
+}
+
+#[cfg(feature = "kcp")]
+#[tokio::test(flavor = "multi_thread")]
+async fn control_session_over_kcp() {
+    let received = drive_base(Transport::Kcp).await;
+    assert!(received
+        .iter()
+        .all(|(transport, _)| *transport == Transport::Kcp));
+    let messages: Vec<_> = received.into_iter().map(|(_, msg)| msg).collect();
+    assert_eq!(messages, expected_base_commands());
+}
+
+#[tokio::test]
+async fn control_session_refuses_current_thread_runtime() {
+    let robot = MockRobot::start("127.0.0.1:0".parse().unwrap(), MockRobotConfig::default())
+        .await
+        .unwrap();
+    let addr = robot.local_addr();
+    let connection = connect_robot(&addr.ip().to_string(), addr.port(), Transport::WebSocket)
+        .await
+        .unwrap();
+    assert!(ControlSession::base(connection).await.is_err());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(robot.received().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_a_session_that_accepts_takeovers_closes_it() {
+    let robot = MockRobot::start("127.0.0.1:0".parse().unwrap(), MockRobotConfig::default())
+        .await
+        .unwrap();
+    let addr = robot.local_addr();
+    for _ in 0..2 {
+        let connection = connect_robot(&addr.ip().to_string(), addr.port(), Transport::WebSocket)
+            .await
+            .unwrap();
+        let session = ControlSession::base(connection).await.unwrap();
+        // Fails the second time round if the first listener still holds the port.
+        session
+            .accept_takeover(&addr.ip().to_string(), "secret")
+            .await
+            .unwrap();
+        assert_eq!(robot.open_sessions(), 1);
+        drop(session);
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while robot.open_sessions() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The websocket was not closed");
+    }
+}
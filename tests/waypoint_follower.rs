@@ -0,0 +1,194 @@
+//! Runs missions on the mock robot, and checks where its base really ended up.
+
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use robot_demos::proto_public_api::{self, ApiDown};
+use robot_demos::{
+    connect_robot, ApiUpStream, ControlSession, Mission, MockRobot, MockRobotConfig,
+    RobotConnection, TimeoutAction, Transport, Waypoint, WaypointFollower,
+};
+use std::time::Duration;
+
+async fn start_session() -> (MockRobot, ControlSession) {
+    let robot = MockRobot::start("127.0.0.1:0".parse().unwrap(), MockRobotConfig::default())
+        .await
+        .unwrap();
+    let addr = robot.local_addr();
+    let connection = connect_robot(&addr.ip().to_string(), addr.port(), Transport::WebSocket)
+        .await
+        .unwrap();
+    let session = ControlSession::base(connection).await.unwrap();
+    (robot, session)
+}
+
+fn follower(mission: Mission) -> WaypointFollower {
+    WaypointFollower::for_robot_type(mission, proto_public_api::RobotType::RtMaverX4).unwrap()
+}
+
+fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "expected {} within {}, got {}",
+        expected,
+        tolerance,
+        actual
+    );
+}
+
+/// The last move command the robot received.
+fn last_move(robot: &MockRobot) -> Option<ApiDown> {
+    robot
+        .received()
+        .into_iter()
+        .rev()
+        .map(|received| received.message)
+        .find(|msg| {
+            matches!(
+                &msg.down,
+                Some(proto_public_api::api_down::Down::BaseCommand(
+                    proto_public_api::BaseCommand {
+                        command: Some(proto_public_api::base_command::Command::SimpleMoveCommand(
+                            _
+                        )),
+                    }
+                ))
+            )
+        })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reaches_waypoints() {
+    let (robot, mut session) = start_session().await;
+    let mission = Mission::new(vec![
+        Waypoint::new(0.3, 0.0, None),
+        Waypoint::new(0.3, 0.3, Some(std::f64::consts::FRAC_PI_2)),
+    ]);
+    let report = follower(mission).run(&mut session).await.unwrap();
+    assert!(session.finish(Duration::from_secs(1)).await.unwrap());
+
+    assert_eq!(report.reached, vec![0, 1]);
+    assert!(report.timed_out.is_empty());
+    assert_eq!(report.aborted, None);
+    // The mock follows commands exactly, so its pose is where odometry says the base is, plus the ramp down.
+    let (x, y, yaw) = robot.base_pose();
+    assert_near(x, 0.3, 0.1);
+    assert_near(y, 0.3, 0.1);
+    assert_near(yaw, std::f64::consts::FRAC_PI_2, 0.1);
+    assert_eq!(
+        last_move(&robot),
+        Some(ApiDown::base_xyz_speed(0.0, 0.0, 0.0))
+    );
+}
+
+/// A mission whose first waypoint is too far away to reach in time, and whose second one is close.
+fn unreachable_first(on_timeout: TimeoutAction) -> Mission {
+    let mut far = Waypoint::new(10.0, 0.0, None);
+    far.timeout_s = Some(0.5);
+    let mut mission = Mission::new(vec![far, Waypoint::new(0.0, 0.2, None)]);
+    mission.on_timeout = on_timeout;
+    mission
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn skips_waypoint_on_timeout() {
+    let (robot, mut session) = start_session().await;
+    let report = follower(unreachable_first(TimeoutAction::Skip))
+        .run(&mut session)
+        .await
+        .unwrap();
+    assert!(session.finish(Duration::from_secs(1)).await.unwrap());
+
+    assert_eq!(report.timed_out, vec![0]);
+    assert_eq!(report.reached, vec![1]);
+    assert_eq!(report.aborted, None);
+    let (x, y, _) = robot.base_pose();
+    assert_near(x, 0.0, 0.1);
+    assert_near(y, 0.2, 0.1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn aborts_on_timeout() {
+    let (robot, mut session) = start_session().await;
+    let report = follower(unreachable_first(TimeoutAction::Abort))
+        .run(&mut session)
+        .await
+        .unwrap();
+    assert!(session.finish(Duration::from_secs(1)).await.unwrap());
+
+    assert_eq!(report.timed_out, vec![0]);
+    assert!(report.reached.is_empty());
+    assert!(report.aborted.unwrap().contains("timed out"));
+    // Stopped on the way to the far waypoint.
+    let (x, _, _) = robot.base_pose();
+    assert!(x > 0.0 && x < 1.0, "x is {}", x);
+}
+
+/// Passes everything through, but ends the stream after `after`, as if the robot stopped reporting.
+struct GoesQuiet<C> {
+    inner: C,
+    after: Duration,
+}
+
+impl<C: RobotConnection> RobotConnection for GoesQuiet<C> {
+    fn transport(&self) -> Transport {
+        self.inner.transport()
+    }
+
+    fn session_id(&self) -> u32 {
+        self.inner.session_id()
+    }
+
+    fn robot_type(&self) -> proto_public_api::RobotType {
+        self.inner.robot_type()
+    }
+
+    fn send(&mut self, msg: ApiDown) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+        self.inner.send(msg)
+    }
+
+    fn take_stream(&mut self) -> Option<ApiUpStream> {
+        let stream = self.inner.take_stream()?;
+        Some(stream.take_until(tokio::time::sleep(self.after)).boxed())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_on_stale_odometry() {
+    let (robot, session) = start_session().await;
+    let mut connection = GoesQuiet {
+        inner: session,
+        after: Duration::from_millis(500),
+    };
+    let mission = Mission::new(vec![Waypoint::new(10.0, 0.0, None)]);
+    let report = follower(mission).run(&mut connection).await.unwrap();
+    assert!(connection
+        .inner
+        .finish(Duration::from_secs(1))
+        .await
+        .unwrap());
+
+    assert_eq!(report.aborted.as_deref(), Some("no odometry from the base"));
+    assert!(report.reached.is_empty());
+    assert_eq!(
+        last_move(&robot),
+        Some(ApiDown::base_xyz_speed(0.0, 0.0, 0.0))
+    );
+    // Moved for the half second odometry lasted, then half a second blind, then ramped down.
+    let (x, _, _) = robot.base_pose();
+    assert!(x > 0.0 && x < 1.0, "x is {}", x);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_invalid_missions_and_rates() {
+    let empty = Mission::new(Vec::new());
+    assert!(
+        WaypointFollower::for_robot_type(empty, proto_public_api::RobotType::RtMaverX4).is_err()
+    );
+
+    let (robot, mut session) = start_session().await;
+    let mission = Mission::new(vec![Waypoint::new(0.3, 0.0, None)]);
+    let result = follower(mission).rate_hz(0.0).run(&mut session).await;
+    assert!(result.is_err());
+    assert!(session.finish(Duration::from_secs(1)).await.unwrap());
+    assert_eq!(last_move(&robot), None);
+}